@@ -0,0 +1,489 @@
+use crate::gen::escape;
+use crate::ir::{
+    BinOp, BlockId, CastKind, CmpOp, Function, Inst, Module, Operand, Reg, Terminator, Ty,
+};
+use crate::options::Options;
+use crate::regalloc::{self, Allocation};
+use crate::tailcall;
+
+// 中間表現から AArch64 のアセンブリ (GNU アセンブラの記法) を出力する
+// 呼び出し規約は AAPCS64 に従い、引数は x0-x7、戻り値は x0 に置く
+
+// 割り当てに使う物理レジスタ (x10-x15 は caller-saved、x19-x28 は callee-saved)
+// 引数レジスタと x9, x16, x17 は命令の選択で一時的に使うので割り当てない
+const REGS: [u8; 16] = [
+    10, 11, 12, 13, 14, 15, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28,
+];
+
+const TMP: u8 = 9; // 結果を一時的に置く
+const LHS: u8 = 16; // 左辺やアドレスを一時的に置く
+const RHS: u8 = 17; // 右辺や格納する値を一時的に置く
+const SP: u8 = 31;
+
+// 指定したバイト数のレジスタの名前 (4 バイト以下は w レジスタ)
+fn reg(reg: u8, size: usize) -> String {
+    match (reg, size) {
+        (SP, _) => "sp".to_string(),
+        (_, 8) => format!("x{}", reg),
+        _ => format!("w{}", reg),
+    }
+}
+
+// add / sub / cmp の即値は 12 ビットの符号なし整数
+fn fits_imm12(val: i64) -> bool {
+    (0..4096).contains(&val)
+}
+
+// 即値をオペランドの大きさで解釈した値
+fn imm(op: Operand, size: usize) -> Option<i64> {
+    match op {
+        Operand::Imm(val) if size == 8 => Some(val),
+        Operand::Imm(val) => Some(val as i32 as i64),
+        Operand::Reg(_) => None,
+    }
+}
+
+pub fn gen(module: &Module, opts: &Options) {
+    for (i, lit) in module.strings.iter().enumerate() {
+        println!(".LC{}:", i);
+        println!("  .string \"{}\"", escape(lit));
+    }
+
+    for global in module.globals.iter() {
+        println!("  .bss");
+        println!("  .global {}", global.name);
+        println!("{}:", global.name);
+        println!("  .zero {}\n", global.size);
+    }
+
+    for func in module.functions.iter() {
+        println!("  .text");
+        // 命令は 4 バイト境界に置く
+        println!("  .p2align 2");
+        if !func.is_static {
+            println!("  .global {}", func.name);
+        }
+        for line in Emitter::new(func, opts.optimize_sibling_calls).function() {
+            println!("{}", line);
+        }
+    }
+}
+
+// 仮想レジスタはレジスタ割り当ての結果に従って物理レジスタかスピル領域に置く
+// ローカル変数とスピル領域は x29 (フレームポインタ) より下に並べる
+struct Emitter<'a> {
+    func: &'a Function,
+    alloc: Allocation,
+    slot_offsets: Vec<usize>,        // x29 からのオフセット
+    spill_base: usize,               // スピル領域の始まり (スロットの終わり)
+    saved_offsets: Vec<(u8, usize)>, // (callee-saved レジスタ, 退避先のオフセット)
+    stack_size: usize,
+    sibling_calls: bool, // 末尾呼び出しをジャンプにする
+    out: Vec<String>,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(func: &'a Function, sibling_calls: bool) -> Self {
+        let callee_saved = REGS.map(|preg| preg >= 19);
+        let alloc = regalloc::allocate(func, &callee_saved);
+        let mut offset = 0;
+        let mut slot_offsets = Vec::new();
+        for slot in func.slots.iter() {
+            offset = (offset + slot.size).next_multiple_of(slot.align.max(8));
+            slot_offsets.push(offset);
+        }
+        let spill_base = offset;
+        offset = alloc.spill_end(spill_base, 8);
+        let mut saved_offsets = Vec::new();
+        for preg in alloc.callee_saved.iter() {
+            offset = offset.next_multiple_of(8) + 8;
+            saved_offsets.push((REGS[*preg], offset));
+        }
+        Emitter {
+            func,
+            alloc,
+            slot_offsets,
+            spill_base,
+            saved_offsets,
+            stack_size: offset.next_multiple_of(16),
+            sibling_calls: sibling_calls && tailcall::may_reuse_frame(func),
+            out: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        self.out.push(format!("  {}", line));
+    }
+
+    fn label(&self, block: BlockId) -> String {
+        format!(".L.{}.{}", self.func.name, block)
+    }
+
+    fn size(&self, reg: Reg) -> usize {
+        self.func.reg_ty(reg).size(self.func.layout)
+    }
+
+    // 仮想レジスタが置かれている物理レジスタ
+    fn phys(&self, op: Operand) -> Option<u8> {
+        self.alloc.phys(op, &REGS)
+    }
+
+    fn spill_offset(&self, reg: Reg) -> usize {
+        self.alloc.spill_offset(reg, self.spill_base, 8)
+    }
+
+    // 即値をレジスタに置く (16 ビットずつ movz と movk で組み立てる)
+    fn mov_imm(&mut self, dst: u8, val: i64, size: usize) {
+        let (val, chunks) = match size {
+            8 => (val, 4),
+            _ => (val as i32 as i64, 2),
+        };
+        let name = reg(dst, size);
+        if (-0x10000..0x10000).contains(&val) {
+            self.emit(format!("mov {}, #{}", name, val));
+            return;
+        }
+        let bits = val as u64;
+        self.emit(format!("movz {}, #{}", name, bits & 0xffff));
+        for i in 1..chunks {
+            let chunk = (bits >> (16 * i)) & 0xffff;
+            if chunk != 0 {
+                self.emit(format!("movk {}, #{}, lsl #{}", name, chunk, 16 * i));
+            }
+        }
+    }
+
+    // x29 から offset だけ下の領域を読む。遠ければ dst をアドレスの計算に使う
+    fn frame_load(&mut self, dst: u8, offset: usize, size: usize) {
+        if offset <= 256 {
+            self.emit(format!("ldur {}, [x29, #-{}]", reg(dst, size), offset));
+        } else {
+            self.mov_imm(dst, -(offset as i64), 8);
+            self.emit(format!("ldr {}, [x29, x{}]", reg(dst, size), dst));
+        }
+    }
+
+    // x29 から offset だけ下の領域に書く。遠ければ x17 をアドレスの計算に使う
+    fn frame_store(&mut self, src: u8, offset: usize, size: usize) {
+        if offset <= 256 {
+            self.emit(format!("stur {}, [x29, #-{}]", reg(src, size), offset));
+        } else {
+            self.mov_imm(RHS, -(offset as i64), 8);
+            self.emit(format!("str {}, [x29, x{}]", reg(src, size), RHS));
+        }
+    }
+
+    // オペランドをレジスタに読み込む
+    fn load(&mut self, dst: u8, op: Operand, size: usize) {
+        match op {
+            Operand::Imm(val) => self.mov_imm(dst, val, size),
+            Operand::Reg(r) => match self.phys(op) {
+                Some(preg) if preg == dst => {}
+                Some(preg) => self.emit(format!("mov {}, {}", reg(dst, size), reg(preg, size))),
+                None => self.frame_load(dst, self.spill_offset(r), size),
+            },
+        }
+    }
+
+    // 物理レジスタにあればそのレジスタを、なければ scratch に読み込んで返す
+    fn in_reg(&mut self, op: Operand, scratch: u8, size: usize) -> u8 {
+        match self.phys(op) {
+            Some(preg) => preg,
+            None => {
+                self.load(scratch, op, size);
+                scratch
+            }
+        }
+    }
+
+    // 結果を書き込むレジスタ (物理レジスタになければ x9)
+    fn target(&self, dst: Reg) -> u8 {
+        self.phys(Operand::Reg(dst)).unwrap_or(TMP)
+    }
+
+    fn store(&mut self, dst: Reg, src: u8) {
+        match self.phys(Operand::Reg(dst)) {
+            Some(preg) if preg == src => {}
+            Some(preg) => self.emit(format!("mov x{}, {}", preg, reg(src, 8))),
+            None => self.frame_store(src, self.spill_offset(dst), 8),
+        }
+    }
+
+    fn function(mut self) -> Vec<String> {
+        let func = self.func;
+        self.out.push(format!("{}:", func.name));
+
+        // prologue
+        self.emit("stp x29, x30, [sp, #-16]!".to_string());
+        self.emit("mov x29, sp".to_string());
+        self.sub_sp(self.stack_size);
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.frame_store(preg, offset, 8);
+        }
+        for (i, param) in func.params.iter().enumerate() {
+            if self.alloc.locs[param.0].is_some() {
+                self.store(*param, i as u8);
+            }
+        }
+
+        for (i, block) in func.blocks.iter().enumerate() {
+            self.out.push(format!("{}:", self.label(BlockId(i))));
+            if let Some(Inst::Call { name, args, .. }) =
+                tailcall::tail_call(func, block).filter(|_| self.sibling_calls)
+            {
+                for inst in block.insts[..block.insts.len() - 1].iter() {
+                    self.inst(inst);
+                }
+                self.sibling_call(name, args);
+                continue;
+            }
+            for inst in block.insts.iter() {
+                self.inst(inst);
+            }
+            self.terminator(&block.term, BlockId(i + 1));
+        }
+        self.out
+    }
+
+    fn sub_sp(&mut self, size: usize) {
+        if size == 0 {
+            return;
+        }
+        if fits_imm12(size as i64) {
+            self.emit(format!("sub sp, sp, #{}", size));
+        } else {
+            self.mov_imm(LHS, size as i64, 8);
+            self.emit(format!("sub sp, sp, x{}", LHS));
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => match self.phys(Operand::Reg(*dst)) {
+                Some(preg) => self.load(preg, *src, 8),
+                None => {
+                    let src = self.in_reg(*src, TMP, 8);
+                    self.store(*dst, src);
+                }
+            },
+            // phi はコード生成の前にコピーに置き換えてある
+            Inst::Phi { .. } => unreachable!(),
+            Inst::Bin { op, dst, lhs, rhs } => self.bin(*op, *dst, *lhs, *rhs),
+            Inst::Cmp {
+                op,
+                ty,
+                dst,
+                lhs,
+                rhs,
+            } => {
+                let size = ty.size(self.func.layout);
+                let lhs = reg(self.in_reg(*lhs, LHS, size), size);
+                match imm(*rhs, size) {
+                    Some(val) if fits_imm12(val) => self.emit(format!("cmp {}, #{}", lhs, val)),
+                    Some(val) if fits_imm12(-val) => self.emit(format!("cmn {}, #{}", lhs, -val)),
+                    _ => {
+                        let rhs = self.in_reg(*rhs, RHS, size);
+                        self.emit(format!("cmp {}, {}", lhs, reg(rhs, size)));
+                    }
+                }
+                let cond = match op {
+                    CmpOp::Eq => "eq",
+                    CmpOp::Ne => "ne",
+                    CmpOp::Slt => "lt",
+                    CmpOp::Sle => "le",
+                    CmpOp::Sgt => "gt",
+                    CmpOp::Sge => "ge",
+                    CmpOp::Ult => "lo",
+                    CmpOp::Ule => "ls",
+                    CmpOp::Ugt => "hi",
+                    CmpOp::Uge => "hs",
+                };
+                let target = self.target(*dst);
+                self.emit(format!("cset w{}, {}", target, cond));
+                self.store(*dst, target);
+            }
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                signed,
+            } => {
+                let addr = self.in_reg(*addr, LHS, 8);
+                let target = self.target(*dst);
+                let (op, size) = match (ty, signed) {
+                    (Ty::I8, true) => ("ldrsb", 4),
+                    (Ty::I8, false) => ("ldrb", 4),
+                    (Ty::I32, _) => ("ldr", 4),
+                    _ => ("ldr", 8),
+                };
+                self.emit(format!("{} {}, [x{}]", op, reg(target, size), addr));
+                self.store(*dst, target);
+            }
+            Inst::Store { addr, val, ty } => {
+                let addr = self.in_reg(*addr, LHS, 8);
+                let size = ty.size(self.func.layout);
+                let val = self.in_reg(*val, RHS, size);
+                let op = if *ty == Ty::I8 { "strb" } else { "str" };
+                self.emit(format!("{} {}, [x{}]", op, reg(val, size), addr));
+            }
+            Inst::LocalAddr { dst, slot } => {
+                let target = self.target(*dst);
+                let offset = self.slot_offsets[slot.0];
+                if fits_imm12(offset as i64) {
+                    self.emit(format!("sub x{}, x29, #{}", target, offset));
+                } else {
+                    self.mov_imm(target, offset as i64, 8);
+                    self.emit(format!("sub x{}, x29, x{}", target, target));
+                }
+                self.store(*dst, target);
+            }
+            Inst::GlobalAddr { dst, name } => {
+                let target = self.target(*dst);
+                self.emit(format!("adrp x{}, {}", target, name));
+                self.emit(format!("add x{}, x{}, :lo12:{}", target, target, name));
+                self.store(*dst, target);
+            }
+            Inst::Call { dst, name, args } => {
+                self.args(args);
+                self.emit(format!("bl {}", name));
+                if let Some(dst) = dst {
+                    self.store(*dst, 0);
+                }
+            }
+            Inst::Cast { dst, src, kind } => {
+                let src = self.in_reg(*src, LHS, 8);
+                let target = self.target(*dst);
+                let wide = self.size(*dst) == 8;
+                self.emit(match kind {
+                    CastKind::Sext(Ty::I8) => {
+                        format!("sxtb {}, w{}", reg(target, if wide { 8 } else { 4 }), src)
+                    }
+                    CastKind::Zext(Ty::I8) => format!("and w{}, w{}, #255", target, src),
+                    CastKind::Sext(_) => format!("sxtw x{}, w{}", target, src),
+                    // w レジスタへの書き込みは上位 32 ビットを 0 にする
+                    CastKind::Zext(_) | CastKind::Trunc => format!("mov w{}, w{}", target, src),
+                });
+                self.store(*dst, target);
+            }
+            Inst::Alloca { dst, size } => {
+                self.load(LHS, *size, 8);
+                self.emit(format!("add x{}, x{}, #15", LHS, LHS));
+                self.emit(format!("and x{}, x{}, #-16", LHS, LHS));
+                self.emit(format!("sub sp, sp, x{}", LHS));
+                let target = self.target(*dst);
+                self.emit(format!("mov x{}, sp", target));
+                self.store(*dst, target);
+            }
+            Inst::StackSave { dst } => {
+                let target = self.target(*dst);
+                self.emit(format!("mov x{}, sp", target));
+                self.store(*dst, target);
+            }
+            Inst::StackRestore { src } => {
+                let src = self.in_reg(*src, LHS, 8);
+                self.emit(format!("mov sp, x{}", src));
+            }
+        }
+    }
+
+    fn args(&mut self, args: &[Operand]) {
+        for (i, arg) in args.iter().enumerate() {
+            self.load(i as u8, *arg, 8);
+        }
+    }
+
+    // 呼び出した結果をそのまま返すなら、フレームを片付けてから呼び出し先へ飛ぶ
+    // 戻り先は x30 に戻してあるので、呼び出し先は呼び出し元の戻り先へ直接戻る
+    fn sibling_call(&mut self, name: &str, args: &[Operand]) {
+        self.args(args);
+        self.epilogue();
+        self.emit(format!("b {}", name));
+    }
+
+    fn epilogue(&mut self) {
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.frame_load(preg, offset, 8);
+        }
+        self.emit("mov sp, x29".to_string());
+        self.emit("ldp x29, x30, [sp], #16".to_string());
+    }
+
+    fn bin(&mut self, op: BinOp, dst: Reg, lhs: Operand, rhs: Operand) {
+        let size = self.size(dst);
+        let lhs = reg(self.in_reg(lhs, LHS, size), size);
+        let target = self.target(dst);
+        let name = reg(target, size);
+        let bits = if size == 8 { 64 } else { 32 };
+        match (op, imm(rhs, size)) {
+            (BinOp::Add | BinOp::Sub, Some(val)) if fits_imm12(val) || fits_imm12(-val) => {
+                // 負の即値は逆の演算にする
+                let (add, val) = if fits_imm12(val) {
+                    (op == BinOp::Add, val)
+                } else {
+                    (op == BinOp::Sub, -val)
+                };
+                let op = if add { "add" } else { "sub" };
+                self.emit(format!("{} {}, {}, #{}", op, name, lhs, val));
+            }
+            (BinOp::Shl | BinOp::LShr | BinOp::AShr, Some(val)) => {
+                let op = match op {
+                    BinOp::Shl => "lsl",
+                    BinOp::LShr => "lsr",
+                    _ => "asr",
+                };
+                self.emit(format!("{} {}, {}, #{}", op, name, lhs, val & (bits - 1)));
+            }
+            _ => {
+                let rhs = reg(self.in_reg(rhs, RHS, size), size);
+                let op = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::SDiv => "sdiv",
+                    BinOp::UDiv => "udiv",
+                    BinOp::And => "and",
+                    BinOp::Or => "orr",
+                    BinOp::Shl => "lsl",
+                    BinOp::LShr => "lsr",
+                    BinOp::AShr => "asr",
+                };
+                self.emit(format!("{} {}, {}, {}", op, name, lhs, rhs));
+            }
+        }
+        self.store(dst, target);
+    }
+
+    fn terminator(&mut self, term: &Terminator, next: BlockId) {
+        match term {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    self.emit(format!("b {}", self.label(*target)));
+                }
+            }
+            Terminator::Branch { cond, then, els } => {
+                let size = match cond {
+                    Operand::Reg(r) => self.size(*r),
+                    Operand::Imm(_) => 8,
+                };
+                let cond = reg(self.in_reg(*cond, TMP, size), size);
+                // 条件が成り立つ側が次のブロックなら、成り立たないときだけ飛ぶ
+                if *then == next {
+                    self.emit(format!("cbz {}, {}", cond, self.label(*els)));
+                    return;
+                }
+                self.emit(format!("cbnz {}, {}", cond, self.label(*then)));
+                if *els != next {
+                    self.emit(format!("b {}", self.label(*els)));
+                }
+            }
+            Terminator::Ret(val) => {
+                if let Some(val) = val {
+                    self.load(0, *val, 8);
+                }
+                self.epilogue();
+                self.emit("ret".to_string());
+            }
+            Terminator::Unreachable => {}
+        }
+    }
+}
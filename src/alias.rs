@@ -0,0 +1,274 @@
+use crate::cfg;
+use crate::ir::{BinOp, Function, Inst, Operand, Reg, SlotId, Terminator, Ty};
+use crate::layout::DataLayout;
+use std::collections::{HashMap, HashSet};
+
+// 別名解析
+// 二つのメモリアクセスが同じ場所に触れうるかを、ポインタの指す先と
+// アクセスする型 (strict aliasing の規則) から判定する
+
+// ポインタが指すオブジェクト
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Base {
+    Slot(SlotId),
+    Global(String), // 文字列リテラル (.LC) を含む
+    Alloca(Reg),
+    Value(Reg), // 引数や読み出した値など、指す先の分からないポインタ
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PointsTo {
+    Known(Base, Option<i64>), // オブジェクトとその先頭からのオフセット (分からなければ None)
+    Unknown,
+}
+
+pub struct AliasInfo {
+    pointers: HashMap<Reg, PointsTo>,
+    escaped: HashSet<Base>, // アドレスが追跡できないところに渡るローカルなオブジェクト
+    strict: bool,
+    layout: DataLayout,
+}
+
+impl AliasInfo {
+    // strict なら型の違うアクセスは別の場所に触れるとみなす
+    pub fn new(func: &Function, strict: bool) -> Self {
+        let mut info = AliasInfo {
+            pointers: HashMap::new(),
+            escaped: HashSet::new(),
+            strict,
+            layout: func.layout,
+        };
+        for param in func.params.iter() {
+            if func.reg_ty(*param) == Ty::Ptr {
+                info.pointers
+                    .insert(*param, PointsTo::Known(Base::Value(*param), Some(0)));
+            }
+        }
+
+        // ループを回る phi があるので、変わらなくなるまで繰り返す
+        let rpo = cfg::reverse_postorder(func);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in rpo.iter() {
+                for inst in func.blocks[block.0].insts.iter() {
+                    let Some(dst) = inst.dst() else {
+                        continue;
+                    };
+                    if func.reg_ty(dst) != Ty::Ptr {
+                        continue;
+                    }
+                    let Some(new) = info.transfer(func, inst, dst) else {
+                        continue;
+                    };
+                    let new = match info.pointers.get(&dst) {
+                        Some(old) => join(old, &new),
+                        None => new,
+                    };
+                    if info.pointers.get(&dst) != Some(&new) {
+                        info.pointers.insert(dst, new);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        info.find_escapes(func);
+        info
+    }
+
+    // 命令の結果のポインタが指す先 (phi の入力がまだ分からなければ None)
+    fn transfer(&self, func: &Function, inst: &Inst, dst: Reg) -> Option<PointsTo> {
+        Some(match inst {
+            Inst::LocalAddr { slot, .. } => PointsTo::Known(Base::Slot(*slot), Some(0)),
+            Inst::GlobalAddr { name, .. } => PointsTo::Known(Base::Global(name.clone()), Some(0)),
+            Inst::Alloca { dst, .. } => PointsTo::Known(Base::Alloca(*dst), Some(0)),
+            Inst::Copy { src, .. } => self.get(*src)?,
+            Inst::Bin {
+                op: op @ (BinOp::Add | BinOp::Sub),
+                lhs,
+                rhs,
+                ..
+            } => {
+                // ポインタに整数を足し引きしても同じオブジェクトを指す
+                let (ptr, delta) = match (lhs, rhs) {
+                    (Operand::Reg(reg), _) if func.reg_ty(*reg) == Ty::Ptr => (*lhs, *rhs),
+                    _ if *op == BinOp::Add => (*rhs, *lhs),
+                    _ => return Some(PointsTo::Unknown),
+                };
+                match self.get(ptr)? {
+                    PointsTo::Known(base, offset) => {
+                        let offset = match (offset, delta) {
+                            (Some(offset), Operand::Imm(delta)) if *op == BinOp::Add => {
+                                Some(offset + delta)
+                            }
+                            (Some(offset), Operand::Imm(delta)) => Some(offset - delta),
+                            _ => None,
+                        };
+                        PointsTo::Known(base, offset)
+                    }
+                    PointsTo::Unknown => PointsTo::Unknown,
+                }
+            }
+            Inst::Phi { incoming, .. } => {
+                let mut result: Option<PointsTo> = None;
+                // 即値 (ヌルポインタ) はどこも指さない
+                for (_, val) in incoming
+                    .iter()
+                    .filter(|(_, val)| matches!(val, Operand::Reg(_)))
+                {
+                    let Some(ptr) = self.get(*val) else {
+                        continue;
+                    };
+                    result = Some(match result {
+                        Some(result) => join(&result, &ptr),
+                        None => ptr,
+                    });
+                }
+                result?
+            }
+            _ => PointsTo::Known(Base::Value(dst), Some(0)),
+        })
+    }
+
+    fn get(&self, op: Operand) -> Option<PointsTo> {
+        match op {
+            Operand::Reg(reg) => self.pointers.get(&reg).cloned(),
+            Operand::Imm(_) => Some(PointsTo::Unknown),
+        }
+    }
+
+    fn pointer(&self, addr: Operand) -> PointsTo {
+        self.get(addr).unwrap_or(PointsTo::Unknown)
+    }
+
+    // アドレスが読み書き以外に使われ、追跡できなくなるローカルなオブジェクトを探す
+    fn find_escapes(&mut self, func: &Function) {
+        let mut escaped = HashSet::new();
+        let mut escape = |op: Operand, info: &AliasInfo| {
+            if let PointsTo::Known(base, _) = info.pointer(op) {
+                escaped.insert(base);
+            }
+        };
+        for block in func.blocks.iter() {
+            for inst in block.insts.iter() {
+                match inst {
+                    Inst::Load { .. } | Inst::Cmp { .. } => {}
+                    Inst::Store { val, .. } => escape(*val, self),
+                    // 結果も同じオブジェクトを指すなら、結果を追跡すればよい
+                    Inst::Copy { dst, .. } | Inst::Bin { dst, .. } | Inst::Phi { dst, .. }
+                        if self
+                            .pointers
+                            .get(dst)
+                            .is_some_and(|ptr| *ptr != PointsTo::Unknown) => {}
+                    _ => inst.operands().into_iter().for_each(|op| escape(op, self)),
+                }
+            }
+            match &block.term {
+                Terminator::Branch { .. } => {}
+                term => term.operands().into_iter().for_each(|op| escape(op, self)),
+            }
+        }
+        self.escaped = escaped;
+    }
+
+    // 関数の外や指す先の分からないポインタからも触れうるか
+    fn is_visible(&self, base: &Base) -> bool {
+        match base {
+            Base::Slot(_) | Base::Alloca(_) => self.escaped.contains(base),
+            Base::Global(_) | Base::Value(_) => true,
+        }
+    }
+
+    // 二つのメモリアクセスが重なりうるか
+    pub fn may_alias(&self, a: Operand, a_ty: Ty, b: Operand, b_ty: Ty) -> bool {
+        if self.strict && !compatible(a_ty, b_ty) {
+            return false;
+        }
+        match (self.pointer(a), self.pointer(b)) {
+            (PointsTo::Known(a_base, a_offset), PointsTo::Known(b_base, b_offset)) => {
+                if a_base != b_base {
+                    return match (&a_base, &b_base) {
+                        (Base::Value(_), other) | (other, Base::Value(_)) => self.is_visible(other),
+                        _ => false,
+                    };
+                }
+                match (a_offset, b_offset) {
+                    (Some(a), Some(b)) => {
+                        a < b + b_ty.size(self.layout) as i64
+                            && b < a + a_ty.size(self.layout) as i64
+                    }
+                    _ => true,
+                }
+            }
+            (PointsTo::Known(base, _), PointsTo::Unknown)
+            | (PointsTo::Unknown, PointsTo::Known(base, _)) => self.is_visible(&base),
+            (PointsTo::Unknown, PointsTo::Unknown) => true,
+        }
+    }
+
+    // 呼び出し先から読まれうるか
+    pub fn call_may_read(&self, addr: Operand) -> bool {
+        match self.pointer(addr) {
+            PointsTo::Known(base, _) => self.is_visible(&base),
+            PointsTo::Unknown => true,
+        }
+    }
+
+    // 呼び出し先から書き換えられうるか (文字列リテラルは書き換えられない)
+    pub fn call_may_write(&self, addr: Operand) -> bool {
+        match self.pointer(addr) {
+            PointsTo::Known(Base::Global(name), _) => !name.starts_with(".LC"),
+            PointsTo::Known(base, _) => self.is_visible(&base),
+            PointsTo::Unknown => true,
+        }
+    }
+
+    // 命令が addr から ty を読んだ値を変えうるか
+    pub fn clobbers(&self, inst: &Inst, addr: Operand, ty: Ty) -> bool {
+        match inst {
+            Inst::Store { addr: a, ty: t, .. } => self.may_alias(*a, *t, addr, ty),
+            Inst::Call { .. } => self.call_may_write(addr),
+            Inst::StackRestore { .. } => true,
+            _ => false,
+        }
+    }
+
+    // 関数の外から見えないローカルなオブジェクトを指すか
+    pub fn is_local(&self, addr: Operand) -> bool {
+        matches!(self.pointer(addr), PointsTo::Known(base, _)
+            if matches!(base, Base::Slot(_) | Base::Alloca(_)) && !self.is_visible(&base))
+    }
+
+    // どこで読んでも例外にならないか (スロットの範囲内を指すとわかっている)
+    pub fn is_dereferenceable(&self, func: &Function, addr: Operand, ty: Ty) -> bool {
+        match self.pointer(addr) {
+            PointsTo::Known(Base::Slot(slot), Some(offset)) => {
+                offset >= 0 && offset as usize + ty.size(func.layout) <= func.slots[slot.0].size
+            }
+            _ => false,
+        }
+    }
+}
+
+fn join(a: &PointsTo, b: &PointsTo) -> PointsTo {
+    match (a, b) {
+        (PointsTo::Known(a_base, a_offset), PointsTo::Known(b_base, b_offset))
+            if a_base == b_base =>
+        {
+            let offset = if a_offset == b_offset {
+                *a_offset
+            } else {
+                None
+            };
+            PointsTo::Known(a_base.clone(), offset)
+        }
+        _ => PointsTo::Unknown,
+    }
+}
+
+// strict aliasing の規則で同じ場所を指しうる型か
+// char はどの型とも重なりうる。i64 は構造体のコピーなどコンパイラが作るアクセスにしか使わない
+fn compatible(a: Ty, b: Ty) -> bool {
+    a == b || [a, b].iter().any(|ty| matches!(ty, Ty::I8 | Ty::IPtr))
+}
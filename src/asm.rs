@@ -0,0 +1,413 @@
+use crate::layout::DataLayout;
+use std::fmt;
+
+// x86-64 (と -m32 の i386) の命令
+// コード生成は命令をこの形で並べ、のぞき穴最適化をしてから文字列にする
+
+// 汎用レジスタ (命令の符号化での番号の順)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gpr {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+const NAMES64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const NAMES32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const NAMES8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+impl Gpr {
+    // 指定したバイト数の名前
+    pub fn name(self, size: usize) -> &'static str {
+        match size {
+            1 => NAMES8[self as usize],
+            4 => NAMES32[self as usize],
+            _ => NAMES64[self as usize],
+        }
+    }
+}
+
+// メモリのアドレス
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mem {
+    Base(Gpr, i64), // [base+disp]
+    Rip(String),    // name[rip]
+    Abs(String),    // [name] (i386 と -fno-pic では絶対アドレスで指す)
+    Got(String),    // name@GOTPCREL[rip] (name のアドレスを入れた GOT の項目)
+}
+
+impl Mem {
+    // アドレスの計算に使うレジスタ
+    pub fn base(&self) -> Option<Gpr> {
+        match self {
+            Mem::Base(base, _) => Some(*base),
+            Mem::Rip(_) | Mem::Abs(_) | Mem::Got(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Gpr, usize), // レジスタとバイト数
+    Imm(i64),
+    Mem(Mem, usize), // メモリとバイト数
+}
+
+impl Operand {
+    // 読み書きするレジスタ (メモリならアドレスの計算に使うレジスタ)
+    pub fn reg(&self) -> Option<Gpr> {
+        match self {
+            Operand::Reg(reg, _) => Some(*reg),
+            Operand::Imm(_) => None,
+            Operand::Mem(mem, _) => mem.base(),
+        }
+    }
+
+    pub fn is_mem(&self) -> bool {
+        matches!(self, Operand::Mem(..))
+    }
+}
+
+// 条件コード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    E,
+    Ne,
+    L,
+    Le,
+    G,
+    Ge,
+    B,
+    Be,
+    A,
+    Ae,
+}
+
+impl Cond {
+    pub fn negate(self) -> Cond {
+        match self {
+            Cond::E => Cond::Ne,
+            Cond::Ne => Cond::E,
+            Cond::L => Cond::Ge,
+            Cond::Le => Cond::G,
+            Cond::G => Cond::Le,
+            Cond::Ge => Cond::L,
+            Cond::B => Cond::Ae,
+            Cond::Be => Cond::A,
+            Cond::A => Cond::Be,
+            Cond::Ae => Cond::B,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::L => "l",
+            Cond::Le => "le",
+            Cond::G => "g",
+            Cond::Ge => "ge",
+            Cond::B => "b",
+            Cond::Be => "be",
+            Cond::A => "a",
+            Cond::Ae => "ae",
+        }
+    }
+}
+
+// 二つのオペランドをとり、左辺を書き換える (cmp と test はフラグだけ) 命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Cmp,
+    Test,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftOp {
+    Shl,
+    Shr,
+    Sar,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inst {
+    Label(String),
+    Mov(Operand, Operand),
+    Movsx(Operand, Operand), // 32 ビットからの符号拡張は movsxd
+    Movzx(Operand, Operand),
+    Lea(Gpr, Mem),
+    Alu(AluOp, Operand, Operand),
+    Imul(Operand, Operand),
+    Imul3(Operand, Operand, i64),
+    Shift(ShiftOp, Operand, Operand), // シフト量は即値か cl
+    Cqo,
+    Cdq,
+    Idiv(Operand),
+    Div(Operand),
+    Set(Cond, Operand),
+    Jmp(String),
+    Jcc(Cond, String),
+    Push(Operand),
+    Pop(Operand),
+    Call(String),
+    Ret,
+}
+
+// 命令を出力するときの記法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    Intel, // .intel_syntax noprefix
+    Att,
+}
+
+impl Inst {
+    // 指定した記法で出力する
+    // アドレスの計算に使うレジスタの幅はデータレイアウトのポインタの幅になる
+    pub fn display(&self, syntax: Syntax, layout: DataLayout) -> Printer<'_> {
+        Printer {
+            inst: self,
+            syntax,
+            ptr_size: layout.ptr_size,
+        }
+    }
+}
+
+pub struct Printer<'a> {
+    inst: &'a Inst,
+    syntax: Syntax,
+    ptr_size: usize,
+}
+
+impl fmt::Display for Printer<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.syntax {
+            Syntax::Intel => intel(self.inst, self.ptr_size, f),
+            Syntax::Att => att(self.inst, self.ptr_size, f),
+        }
+    }
+}
+
+impl AluOp {
+    fn name(self) -> &'static str {
+        match self {
+            AluOp::Add => "add",
+            AluOp::Sub => "sub",
+            AluOp::And => "and",
+            AluOp::Or => "or",
+            AluOp::Xor => "xor",
+            AluOp::Cmp => "cmp",
+            AluOp::Test => "test",
+        }
+    }
+}
+
+impl ShiftOp {
+    fn name(self) -> &'static str {
+        match self {
+            ShiftOp::Shl => "shl",
+            ShiftOp::Shr => "shr",
+            ShiftOp::Sar => "sar",
+        }
+    }
+}
+
+// Intel 記法のアドレス
+// アドレスはポインタの幅のレジスタで計算する
+fn intel_mem(mem: &Mem, ptr_size: usize) -> String {
+    match mem {
+        Mem::Base(base, 0) => format!("[{}]", base.name(ptr_size)),
+        Mem::Base(base, disp) if *disp < 0 => format!("[{}{}]", base.name(ptr_size), disp),
+        Mem::Base(base, disp) => format!("[{}+{}]", base.name(ptr_size), disp),
+        Mem::Rip(name) => format!("{}[rip]", name),
+        Mem::Abs(name) => format!("[{}]", name),
+        Mem::Got(name) => format!("{}@GOTPCREL[rip]", name),
+    }
+}
+
+fn intel_operand(op: &Operand, ptr_size: usize) -> String {
+    match op {
+        Operand::Reg(reg, size) => reg.name(*size).to_string(),
+        Operand::Imm(val) => val.to_string(),
+        Operand::Mem(mem, size) => {
+            let ptr = match size {
+                1 => "BYTE PTR",
+                4 => "DWORD PTR",
+                _ => "QWORD PTR",
+            };
+            format!("{} {}", ptr, intel_mem(mem, ptr_size))
+        }
+    }
+}
+
+// Intel 記法で出力する (書き込み先が先)
+fn intel(inst: &Inst, ptr_size: usize, f: &mut fmt::Formatter) -> fmt::Result {
+    let op = |operand: &Operand| intel_operand(operand, ptr_size);
+    match inst {
+        Inst::Label(name) => write!(f, "{}:", name),
+        Inst::Mov(dst, src) => write!(f, "  mov {}, {}", op(dst), op(src)),
+        Inst::Movsx(dst, src) => match src {
+            Operand::Reg(_, 4) | Operand::Mem(_, 4) => {
+                write!(f, "  movsxd {}, {}", op(dst), op(src))
+            }
+            _ => write!(f, "  movsx {}, {}", op(dst), op(src)),
+        },
+        Inst::Movzx(dst, src) => write!(f, "  movzx {}, {}", op(dst), op(src)),
+        Inst::Lea(dst, mem) => write!(
+            f,
+            "  lea {}, {}",
+            dst.name(ptr_size),
+            intel_mem(mem, ptr_size)
+        ),
+        Inst::Alu(alu, dst, src) => write!(f, "  {} {}, {}", alu.name(), op(dst), op(src)),
+        Inst::Imul(dst, src) => write!(f, "  imul {}, {}", op(dst), op(src)),
+        Inst::Imul3(dst, src, imm) => write!(f, "  imul {}, {}, {}", op(dst), op(src), imm),
+        Inst::Shift(shift, dst, amount) => {
+            write!(f, "  {} {}, {}", shift.name(), op(dst), op(amount))
+        }
+        Inst::Cqo => write!(f, "  cqo"),
+        Inst::Cdq => write!(f, "  cdq"),
+        Inst::Idiv(src) => write!(f, "  idiv {}", op(src)),
+        Inst::Div(src) => write!(f, "  div {}", op(src)),
+        Inst::Set(cond, dst) => write!(f, "  set{} {}", cond.suffix(), op(dst)),
+        Inst::Jmp(label) => write!(f, "  jmp {}", label),
+        Inst::Jcc(cond, label) => write!(f, "  j{} {}", cond.suffix(), label),
+        Inst::Push(src) => write!(f, "  push {}", op(src)),
+        Inst::Pop(dst) => write!(f, "  pop {}", op(dst)),
+        Inst::Call(name) => write!(f, "  call {}", name),
+        Inst::Ret => write!(f, "  ret"),
+    }
+}
+
+// AT&T 記法のアドレス
+fn att_mem(mem: &Mem, ptr_size: usize) -> String {
+    match mem {
+        Mem::Base(base, 0) => format!("(%{})", base.name(ptr_size)),
+        Mem::Base(base, disp) => format!("{}(%{})", disp, base.name(ptr_size)),
+        Mem::Rip(name) => format!("{}(%rip)", name),
+        Mem::Abs(name) => name.clone(),
+        Mem::Got(name) => format!("{}@GOTPCREL(%rip)", name),
+    }
+}
+
+fn att_operand(op: &Operand, ptr_size: usize) -> String {
+    match op {
+        Operand::Reg(reg, size) => format!("%{}", reg.name(*size)),
+        Operand::Imm(val) => format!("${}", val),
+        Operand::Mem(mem, _) => att_mem(mem, ptr_size),
+    }
+}
+
+// AT&T 記法の命令の接尾辞 (オペランドのバイト数)
+fn att_suffix(size: usize) -> &'static str {
+    match size {
+        1 => "b",
+        4 => "l",
+        _ => "q",
+    }
+}
+
+// オペランドのバイト数 (即値には幅がない)
+fn size_of(op: &Operand) -> Option<usize> {
+    match op {
+        Operand::Reg(_, size) | Operand::Mem(_, size) => Some(*size),
+        Operand::Imm(_) => None,
+    }
+}
+
+// AT&T 記法で出力する (読み出し元が先で、命令にはオペランドの幅の接尾辞を付ける)
+// Intel 記法と同じ機械語になる
+fn att(inst: &Inst, ptr_size: usize, f: &mut fmt::Formatter) -> fmt::Result {
+    let op = |operand: &Operand| att_operand(operand, ptr_size);
+    let suffix =
+        |dst: &Operand, src: &Operand| att_suffix(size_of(dst).or(size_of(src)).unwrap_or(8));
+    match inst {
+        Inst::Label(name) => write!(f, "{}:", name),
+        Inst::Mov(dst, src) => write!(f, "  mov{} {}, {}", suffix(dst, src), op(src), op(dst)),
+        Inst::Movsx(dst, src) => write!(
+            f,
+            "  movs{}{} {}, {}",
+            suffix(src, dst),
+            suffix(dst, src),
+            op(src),
+            op(dst)
+        ),
+        Inst::Movzx(dst, src) => write!(
+            f,
+            "  movz{}{} {}, {}",
+            suffix(src, dst),
+            suffix(dst, src),
+            op(src),
+            op(dst)
+        ),
+        Inst::Lea(dst, mem) => write!(
+            f,
+            "  lea{} {}, %{}",
+            att_suffix(ptr_size),
+            att_mem(mem, ptr_size),
+            dst.name(ptr_size)
+        ),
+        Inst::Alu(alu, dst, src) => write!(
+            f,
+            "  {}{} {}, {}",
+            alu.name(),
+            suffix(dst, src),
+            op(src),
+            op(dst)
+        ),
+        Inst::Imul(dst, src) => write!(f, "  imul{} {}, {}", suffix(dst, src), op(src), op(dst)),
+        Inst::Imul3(dst, src, imm) => write!(
+            f,
+            "  imul{} ${}, {}, {}",
+            suffix(dst, src),
+            imm,
+            op(src),
+            op(dst)
+        ),
+        Inst::Shift(shift, dst, amount) => write!(
+            f,
+            "  {}{} {}, {}",
+            shift.name(),
+            suffix(dst, dst),
+            op(amount),
+            op(dst)
+        ),
+        Inst::Cqo => write!(f, "  cqto"),
+        Inst::Cdq => write!(f, "  cltd"),
+        Inst::Idiv(src) => write!(f, "  idiv{} {}", suffix(src, src), op(src)),
+        Inst::Div(src) => write!(f, "  div{} {}", suffix(src, src), op(src)),
+        Inst::Set(cond, dst) => write!(f, "  set{} {}", cond.suffix(), op(dst)),
+        Inst::Jmp(label) => write!(f, "  jmp {}", label),
+        Inst::Jcc(cond, label) => write!(f, "  j{} {}", cond.suffix(), label),
+        Inst::Push(src) => write!(f, "  push{} {}", suffix(src, src), op(src)),
+        Inst::Pop(dst) => write!(f, "  pop{} {}", suffix(dst, dst), op(dst)),
+        Inst::Call(name) => write!(f, "  call {}", name),
+        Inst::Ret => write!(f, "  ret"),
+    }
+}
@@ -0,0 +1,670 @@
+use crate::asm::{AluOp, Cond, Gpr, Inst, Mem, Operand, ShiftOp};
+use crate::elf::{self, Object, Reloc, Section, SectionKind, Symbol, SymbolKind};
+use crate::ir::{Module, Visibility};
+use std::collections::{HashMap, HashSet};
+
+// x86-64 の命令を機械語にして ELF の再配置可能オブジェクトファイルを組み立てる
+// 符号化の選び方 (即値の幅やジャンプの長さ) は GNU as に合わせ、同じバイト列になるようにする
+
+const TEXT: usize = 0;
+const BSS: usize = 2;
+const RODATA: usize = 3;
+
+// 後で値を埋める 4 バイトの PC 相対の参照
+struct Fixup {
+    offset: usize, // 命令の先頭から
+    target: String,
+    kind: u32,
+    addend: i64,
+}
+
+// 符号化した命令
+#[derive(Default)]
+struct Code {
+    bytes: Vec<u8>,
+    fixups: Vec<Fixup>,
+}
+
+enum Item {
+    Code(Code),
+    Label(String),
+    // このオブジェクトの中のラベルへのジャンプは短い形から始めて、届かなければ長くする
+    Jump {
+        cond: Option<Cond>,
+        target: String,
+        long: bool,
+    },
+    // ほかの関数 (name@PLT や未定義の関数) へのジャンプは常に長い形にする
+    // GNU as と同じく、その再配置はほかの再配置の後に並べる
+    FarJump {
+        cond: Option<Cond>,
+        target: String,
+    },
+}
+
+// REX プレフィックスが必要な 8 ビットのレジスタ (spl, bpl, sil, dil)
+fn needs_rex(op: &Operand) -> bool {
+    matches!(op, Operand::Reg(reg, 1) if (4..8).contains(&(*reg as u8)))
+}
+
+fn size_of(op: &Operand) -> usize {
+    match op {
+        Operand::Reg(_, size) | Operand::Mem(_, size) => *size,
+        Operand::Imm(_) => unreachable!("即値には大きさがありません"),
+    }
+}
+
+fn fits_i8(val: i64) -> bool {
+    i8::try_from(val).is_ok()
+}
+
+fn cond_code(cond: Cond) -> u8 {
+    match cond {
+        Cond::B => 0x2,
+        Cond::Ae => 0x3,
+        Cond::E => 0x4,
+        Cond::Ne => 0x5,
+        Cond::Be => 0x6,
+        Cond::A => 0x7,
+        Cond::L => 0xc,
+        Cond::Ge => 0xd,
+        Cond::Le => 0xe,
+        Cond::G => 0xf,
+    }
+}
+
+// ALU 命令のオペコードの基準値と、即値をとる形の ModRM の reg フィールド
+fn alu_code(op: AluOp) -> (u8, u8) {
+    match op {
+        AluOp::Add => (0x00, 0),
+        AluOp::Or => (0x08, 1),
+        AluOp::And => (0x20, 4),
+        AluOp::Sub => (0x28, 5),
+        AluOp::Xor => (0x30, 6),
+        AluOp::Cmp => (0x38, 7),
+        AluOp::Test => unreachable!("test は別に符号化します"),
+    }
+}
+
+fn shift_code(op: ShiftOp) -> u8 {
+    match op {
+        ShiftOp::Shl => 4,
+        ShiftOp::Shr => 5,
+        ShiftOp::Sar => 7,
+    }
+}
+
+impl Code {
+    fn imm(&mut self, val: i64, size: usize) {
+        self.bytes.extend_from_slice(&val.to_le_bytes()[..size]);
+    }
+
+    // REX プレフィックスとオペコード、ModRM (と SIB、変位) を出力する
+    // reg は ModRM の reg フィールド、imm_size は後に続く即値のバイト数 (RIP 相対の補正に使う)
+    fn modrm(
+        &mut self,
+        opcode: &[u8],
+        w: bool,
+        reg: u8,
+        force_rex: bool,
+        rm: &Operand,
+        imm_size: usize,
+    ) {
+        let mut rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2;
+        match rm {
+            Operand::Reg(base, _) => rex |= *base as u8 >> 3,
+            Operand::Mem(Mem::Base(base, _), _) => rex |= *base as u8 >> 3,
+            _ => {}
+        }
+        if rex != 0x40 || force_rex || needs_rex(rm) {
+            self.bytes.push(rex);
+        }
+        self.bytes.extend_from_slice(opcode);
+        let reg = (reg & 7) << 3;
+        match rm {
+            Operand::Reg(base, _) => self.bytes.push(0xc0 | reg | (*base as u8 & 7)),
+            Operand::Mem(Mem::Base(base, disp), _) => {
+                let base = *base as u8 & 7;
+                // rbp と r13 は変位なしの形がないので 0 の 8 ビットの変位を付ける
+                let mode = if *disp == 0 && base != 5 {
+                    0x00
+                } else if fits_i8(*disp) {
+                    0x40
+                } else {
+                    0x80
+                };
+                self.bytes.push(mode | reg | base);
+                // rsp と r12 は SIB が必要
+                if base == 4 {
+                    self.bytes.push(0x24);
+                }
+                match mode {
+                    0x40 => self.imm(*disp, 1),
+                    0x80 => self.imm(*disp, 4),
+                    _ => {}
+                }
+            }
+            Operand::Mem(Mem::Rip(name) | Mem::Got(name), _) => {
+                self.bytes.push(0x05 | reg);
+                // GOT からの読み込みは、リンカが lea に書き換えられるように専用の種類にする
+                let kind = match rm {
+                    Operand::Mem(Mem::Rip(_), _) => elf::R_X86_64_PC32,
+                    _ if rex != 0x40 => elf::R_X86_64_REX_GOTPCRELX,
+                    _ => elf::R_X86_64_GOTPCRELX,
+                };
+                self.fixups.push(Fixup {
+                    offset: self.bytes.len(),
+                    target: name.clone(),
+                    kind,
+                    addend: -4 - imm_size as i64,
+                });
+                self.imm(0, 4);
+            }
+            // 絶対アドレスは SIB でベースもインデックスもない形にする
+            Operand::Mem(Mem::Abs(name), _) => {
+                self.bytes.extend_from_slice(&[0x04 | reg, 0x25]);
+                self.fixups.push(Fixup {
+                    offset: self.bytes.len(),
+                    target: name.clone(),
+                    kind: elf::R_X86_64_32S,
+                    addend: 0,
+                });
+                self.imm(0, 4);
+            }
+            Operand::Imm(_) => unreachable!("即値は r/m オペランドになりません"),
+        }
+    }
+
+    // 命令の 32 ビットの PC 相対の飛び先 (name@PLT も同じ再配置になる)
+    fn rel32(&mut self, opcode: &[u8], target: &str) {
+        self.bytes.extend_from_slice(opcode);
+        self.fixups.push(Fixup {
+            offset: self.bytes.len(),
+            target: target.strip_suffix("@PLT").unwrap_or(target).to_string(),
+            kind: elf::R_X86_64_PLT32,
+            addend: -4,
+        });
+        self.imm(0, 4);
+    }
+
+    fn mov(&mut self, dst: &Operand, src: &Operand) {
+        let size = size_of(dst);
+        let w = size == 8;
+        match (dst, src) {
+            (Operand::Reg(dst, _), Operand::Imm(val)) => {
+                if size == 8 && i32::try_from(*val).is_ok() {
+                    self.modrm(&[0xc7], true, 0, false, &Operand::Reg(*dst, 8), 4);
+                    self.imm(*val, 4);
+                } else {
+                    let rex = 0x40 | (w as u8) << 3 | *dst as u8 >> 3;
+                    if rex != 0x40 || needs_rex(&Operand::Reg(*dst, size)) {
+                        self.bytes.push(rex);
+                    }
+                    let base = if size == 1 { 0xb0 } else { 0xb8 };
+                    self.bytes.push(base + (*dst as u8 & 7));
+                    self.imm(*val, size);
+                }
+            }
+            (Operand::Mem(..), Operand::Imm(val)) => {
+                let imm_size = size.min(4);
+                let opcode = if size == 1 { 0xc6 } else { 0xc7 };
+                self.modrm(&[opcode], w, 0, false, dst, imm_size);
+                self.imm(*val, imm_size);
+            }
+            (_, Operand::Reg(src, _)) => {
+                let opcode = if size == 1 { 0x88 } else { 0x89 };
+                self.modrm(
+                    &[opcode],
+                    w,
+                    *src as u8,
+                    needs_rex(&Operand::Reg(*src, size)),
+                    dst,
+                    0,
+                );
+            }
+            (Operand::Reg(dst, _), Operand::Mem(..)) => {
+                let opcode = if size == 1 { 0x8a } else { 0x8b };
+                let reg = Operand::Reg(*dst, size);
+                self.modrm(&[opcode], w, *dst as u8, needs_rex(&reg), src, 0);
+            }
+            _ => unreachable!("不正な mov のオペランドです"),
+        }
+    }
+
+    fn alu(&mut self, op: AluOp, dst: &Operand, src: &Operand) {
+        let size = size_of(dst);
+        let w = size == 8;
+        if op == AluOp::Test {
+            return self.test(dst, src);
+        }
+        let (base, ext) = alu_code(op);
+        match (dst, src) {
+            (_, Operand::Imm(val)) => {
+                if size == 1 {
+                    if matches!(dst, Operand::Reg(Gpr::Rax, _)) {
+                        self.bytes.push(base + 4);
+                    } else {
+                        self.modrm(&[0x80], false, ext, false, dst, 1);
+                    }
+                    self.imm(*val, 1);
+                } else if fits_i8(*val) {
+                    self.modrm(&[0x83], w, ext, false, dst, 1);
+                    self.imm(*val, 1);
+                } else {
+                    if let Operand::Reg(Gpr::Rax, _) = dst {
+                        if w {
+                            self.bytes.push(0x48);
+                        }
+                        self.bytes.push(base + 5);
+                    } else {
+                        self.modrm(&[0x81], w, ext, false, dst, 4);
+                    }
+                    self.imm(*val, 4);
+                }
+            }
+            (_, Operand::Reg(src, _)) => {
+                let opcode = if size == 1 { base } else { base + 1 };
+                self.modrm(
+                    &[opcode],
+                    w,
+                    *src as u8,
+                    needs_rex(&Operand::Reg(*src, size)),
+                    dst,
+                    0,
+                );
+            }
+            (Operand::Reg(reg, _), Operand::Mem(..)) => {
+                let opcode = if size == 1 { base + 2 } else { base + 3 };
+                self.modrm(&[opcode], w, *reg as u8, needs_rex(dst), src, 0);
+            }
+            _ => unreachable!("不正な ALU 命令のオペランドです"),
+        }
+    }
+
+    fn test(&mut self, dst: &Operand, src: &Operand) {
+        let size = size_of(dst);
+        let w = size == 8;
+        match src {
+            Operand::Imm(val) => {
+                let imm_size = size.min(4);
+                if let Operand::Reg(Gpr::Rax, _) = dst {
+                    if w {
+                        self.bytes.push(0x48);
+                    }
+                    self.bytes.push(if size == 1 { 0xa8 } else { 0xa9 });
+                } else {
+                    let opcode = if size == 1 { 0xf6 } else { 0xf7 };
+                    self.modrm(&[opcode], w, 0, false, dst, imm_size);
+                }
+                self.imm(*val, imm_size);
+            }
+            Operand::Reg(src, _) => {
+                let opcode = if size == 1 { 0x84 } else { 0x85 };
+                self.modrm(
+                    &[opcode],
+                    w,
+                    *src as u8,
+                    needs_rex(&Operand::Reg(*src, size)),
+                    dst,
+                    0,
+                );
+            }
+            Operand::Mem(..) => unreachable!("test の右辺にメモリはとれません"),
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Mov(dst, src) => self.mov(dst, src),
+            Inst::Movsx(Operand::Reg(dst, size), src) => {
+                let reg = Operand::Reg(*dst, *size);
+                if size_of(src) == 4 {
+                    self.modrm(&[0x63], true, *dst as u8, false, src, 0);
+                } else {
+                    self.modrm(
+                        &[0x0f, 0xbe],
+                        *size == 8,
+                        *dst as u8,
+                        needs_rex(&reg),
+                        src,
+                        0,
+                    );
+                }
+            }
+            Inst::Movzx(Operand::Reg(dst, size), src) => {
+                self.modrm(&[0x0f, 0xb6], *size == 8, *dst as u8, false, src, 0);
+            }
+            Inst::Lea(dst, mem) => {
+                let mem = Operand::Mem(mem.clone(), 8);
+                self.modrm(&[0x8d], true, *dst as u8, false, &mem, 0);
+            }
+            Inst::Alu(op, dst, src) => self.alu(*op, dst, src),
+            Inst::Imul(Operand::Reg(dst, size), src) => {
+                self.modrm(&[0x0f, 0xaf], *size == 8, *dst as u8, false, src, 0);
+            }
+            Inst::Imul3(Operand::Reg(dst, size), src, val) => {
+                if fits_i8(*val) {
+                    self.modrm(&[0x6b], *size == 8, *dst as u8, false, src, 1);
+                    self.imm(*val, 1);
+                } else {
+                    self.modrm(&[0x69], *size == 8, *dst as u8, false, src, 4);
+                    self.imm(*val, 4);
+                }
+            }
+            Inst::Shift(op, dst, amount) => {
+                let size = size_of(dst);
+                let ext = shift_code(*op);
+                let byte = (size == 1) as u8;
+                match amount {
+                    Operand::Imm(1) => self.modrm(&[0xd1 - byte], size == 8, ext, false, dst, 0),
+                    Operand::Imm(val) => {
+                        self.modrm(&[0xc1 - byte], size == 8, ext, false, dst, 1);
+                        self.imm(*val, 1);
+                    }
+                    _ => self.modrm(&[0xd3 - byte], size == 8, ext, false, dst, 0),
+                }
+            }
+            Inst::Cqo => self.bytes.extend_from_slice(&[0x48, 0x99]),
+            Inst::Cdq => self.bytes.push(0x99),
+            Inst::Idiv(src) | Inst::Div(src) => {
+                let size = size_of(src);
+                let ext = if matches!(inst, Inst::Idiv(_)) { 7 } else { 6 };
+                let opcode = if size == 1 { 0xf6 } else { 0xf7 };
+                self.modrm(&[opcode], size == 8, ext, false, src, 0);
+            }
+            Inst::Set(cond, dst) => {
+                self.modrm(&[0x0f, 0x90 | cond_code(*cond)], false, 0, false, dst, 0)
+            }
+            Inst::Push(Operand::Reg(reg, _)) | Inst::Pop(Operand::Reg(reg, _)) => {
+                if *reg as u8 >= 8 {
+                    self.bytes.push(0x41);
+                }
+                let base = if matches!(inst, Inst::Push(_)) {
+                    0x50
+                } else {
+                    0x58
+                };
+                self.bytes.push(base + (*reg as u8 & 7));
+            }
+            Inst::Call(name) => self.rel32(&[0xe8], name),
+            Inst::Ret => self.bytes.push(0xc3),
+            _ => unreachable!("符号化できない命令です: {:?}", inst),
+        }
+    }
+}
+
+// 各項目の先頭のオフセットとラベルのオフセット
+fn layout(items: &[Item]) -> (Vec<usize>, HashMap<String, usize>) {
+    let mut offsets = Vec::new();
+    let mut labels = HashMap::new();
+    let mut offset = 0;
+    for item in items.iter() {
+        offsets.push(offset);
+        match item {
+            Item::Code(code) => offset += code.bytes.len(),
+            Item::Label(name) => {
+                labels.insert(name.clone(), offset);
+            }
+            Item::Jump { cond, long, .. } => offset += jump_size(*cond, *long),
+            Item::FarJump { cond, .. } => offset += jump_size(*cond, true),
+        }
+    }
+    (offsets, labels)
+}
+
+fn jump_size(cond: Option<Cond>, long: bool) -> usize {
+    match (cond, long) {
+        (_, false) => 2,
+        (None, true) => 5,
+        (Some(_), true) => 6,
+    }
+}
+
+pub fn assemble(module: &Module, funcs: &[Vec<Inst>]) -> Object {
+    // シンボルは GNU as と同じく最初に現れた順に並べる
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    let mut note = |name: &str| {
+        if !name.starts_with(".L") && seen.insert(name.to_string()) {
+            order.push(name.to_string());
+        }
+    };
+
+    let mut bss = Section::new(".bss", SectionKind::Bss);
+    let mut data_symbols = HashMap::new();
+    for global in module.globals.iter() {
+        note(&global.name);
+        data_symbols.insert(global.name.clone(), bss.size);
+        bss.size += global.size;
+    }
+
+    let mut rodata = Section::new(".rodata", SectionKind::Rodata);
+    let mut strings = HashMap::new();
+    for (i, lit) in module.strings.iter().enumerate() {
+        strings.insert(format!(".LC{}", i), rodata.data.len());
+        rodata.data.extend_from_slice(lit);
+        rodata.data.push(0);
+    }
+
+    let mut locals = HashSet::new();
+    let mut items = Vec::new();
+    let labels: HashSet<&str> = funcs
+        .iter()
+        .flatten()
+        .filter_map(|inst| match inst {
+            Inst::Label(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    for (func, insts) in module.functions.iter().zip(funcs) {
+        if func.is_static {
+            locals.insert(func.name.clone());
+        } else {
+            note(&func.name);
+        }
+        for inst in insts.iter() {
+            match inst {
+                Inst::Label(name) => {
+                    note(name);
+                    items.push(Item::Label(name.clone()));
+                }
+                Inst::Jmp(target) | Inst::Jcc(_, target) if labels.contains(target.as_str()) => {
+                    note(target);
+                    let cond = match inst {
+                        Inst::Jcc(cond, _) => Some(*cond),
+                        _ => None,
+                    };
+                    items.push(Item::Jump {
+                        cond,
+                        target: target.clone(),
+                        long: false,
+                    });
+                }
+                Inst::Jmp(target) | Inst::Jcc(_, target) => {
+                    let target = target.strip_suffix("@PLT").unwrap_or(target);
+                    note(target);
+                    let cond = match inst {
+                        Inst::Jcc(cond, _) => Some(*cond),
+                        _ => None,
+                    };
+                    items.push(Item::FarJump {
+                        cond,
+                        target: target.to_string(),
+                    });
+                }
+                _ => {
+                    let mut code = Code::default();
+                    code.inst(inst);
+                    for fixup in code.fixups.iter() {
+                        note(&fixup.target);
+                        // GNU as は GOT を使うと _GLOBAL_OFFSET_TABLE_ への参照を加える
+                        if elf::is_got(fixup.kind) {
+                            note("_GLOBAL_OFFSET_TABLE_");
+                        }
+                    }
+                    items.push(Item::Code(code));
+                }
+            }
+        }
+    }
+
+    // 短いジャンプが届かなくなったら長くし、変わらなくなるまで繰り返す
+    let (offsets, label_offsets) = loop {
+        let (offsets, labels) = layout(&items);
+        let mut changed = false;
+        for (item, &offset) in items.iter_mut().zip(offsets.iter()) {
+            if let Item::Jump {
+                target,
+                long: long @ false,
+                ..
+            } = item
+            {
+                if !fits_i8(labels[target.as_str()] as i64 - (offset + 2) as i64) {
+                    *long = true;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break (offsets, labels);
+        }
+    };
+
+    // シンボル表 (ローカルなシンボルを先に並べる)
+    let mut text = Section::new(".text", SectionKind::Text);
+    let mut symbols = Vec::new();
+    let mut index = HashMap::new();
+    // .rodata のセクションシンボルは文字列を参照するときだけ作る
+    let uses_strings = items.iter().any(|item| match item {
+        Item::Code(code) => code
+            .fixups
+            .iter()
+            .any(|fixup| strings.contains_key(&fixup.target)),
+        _ => false,
+    });
+    if uses_strings {
+        symbols.push(Symbol {
+            name: String::new(),
+            kind: SymbolKind::Section,
+            section: Some(RODATA),
+            value: 0,
+            global: false,
+            visibility: Visibility::Default,
+        });
+    }
+    let defined = |name: &str| -> (Option<usize>, u64) {
+        if let Some(&offset) = label_offsets.get(name) {
+            (Some(TEXT), offset as u64)
+        } else if let Some(&offset) = data_symbols.get(name) {
+            (Some(BSS), offset as u64)
+        } else {
+            (None, 0)
+        }
+    };
+    let visibility: HashMap<&str, Visibility> = module
+        .globals
+        .iter()
+        .map(|global| (global.name.as_str(), global.visibility))
+        .chain(
+            module
+                .functions
+                .iter()
+                .filter(|func| !func.is_static)
+                .map(|func| (func.name.as_str(), func.visibility)),
+        )
+        .collect();
+    for global in [false, true] {
+        for name in order.iter().filter(|name| locals.contains(*name) != global) {
+            let (section, value) = defined(name);
+            index.insert(name.clone(), symbols.len());
+            symbols.push(Symbol {
+                name: name.clone(),
+                kind: SymbolKind::NoType,
+                section,
+                value,
+                global,
+                visibility: visibility
+                    .get(name.as_str())
+                    .copied()
+                    .unwrap_or(Visibility::Default),
+            });
+        }
+    }
+
+    let mut far = Vec::new();
+    for (item, &offset) in items.iter().zip(offsets.iter()) {
+        match item {
+            Item::Label(_) => {}
+            Item::Jump { cond, target, long } => {
+                let size = jump_size(*cond, *long);
+                let disp = label_offsets[target.as_str()] as i64 - (offset + size) as i64;
+                match (cond, long) {
+                    (None, false) => text.data.push(0xeb),
+                    (Some(cond), false) => text.data.push(0x70 | cond_code(*cond)),
+                    (None, true) => text.data.push(0xe9),
+                    (Some(cond), true) => text
+                        .data
+                        .extend_from_slice(&[0x0f, 0x80 | cond_code(*cond)]),
+                }
+                text.data
+                    .extend_from_slice(&disp.to_le_bytes()[..if *long { 4 } else { 1 }]);
+            }
+            Item::FarJump { cond, target } => {
+                match cond {
+                    None => text.data.push(0xe9),
+                    Some(cond) => text
+                        .data
+                        .extend_from_slice(&[0x0f, 0x80 | cond_code(*cond)]),
+                }
+                far.push(Reloc {
+                    offset: text.data.len() as u64,
+                    symbol: index[target],
+                    kind: elf::R_X86_64_PLT32,
+                    addend: -4,
+                });
+                text.data.extend_from_slice(&[0; 4]);
+            }
+            Item::Code(code) => {
+                let mut bytes = code.bytes.clone();
+                for fixup in code.fixups.iter() {
+                    let place = offset + fixup.offset;
+                    if let Some(&string) = strings.get(&fixup.target) {
+                        // 文字列は .rodata のセクションシンボルからのオフセットで指す
+                        text.relocs.push(Reloc {
+                            offset: place as u64,
+                            symbol: 0,
+                            kind: fixup.kind,
+                            addend: fixup.addend + string as i64,
+                        });
+                    } else if let (elf::R_X86_64_PC32 | elf::R_X86_64_PLT32, true, Some(&target)) = (
+                        fixup.kind,
+                        locals.contains(&fixup.target),
+                        label_offsets.get(fixup.target.as_str()),
+                    ) {
+                        // 同じセクションのローカルなシンボルはここで解決できる
+                        let disp = target as i64 + fixup.addend - place as i64;
+                        bytes[fixup.offset..fixup.offset + 4]
+                            .copy_from_slice(&(disp as i32).to_le_bytes());
+                    } else {
+                        text.relocs.push(Reloc {
+                            offset: place as u64,
+                            symbol: index[&fixup.target],
+                            kind: fixup.kind,
+                            addend: fixup.addend,
+                        });
+                    }
+                }
+                text.data.extend_from_slice(&bytes);
+            }
+        }
+    }
+
+    text.relocs.extend(far);
+
+    let mut sections = vec![text, Section::new(".data", SectionKind::Data), bss];
+    // .rodata は文字列があるときだけ作る
+    if !module.strings.is_empty() {
+        sections.push(rodata);
+    }
+    Object { sections, symbols }
+}
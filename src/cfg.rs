@@ -0,0 +1,276 @@
+use crate::ir::{BlockId, Function, Inst, Operand, Terminator};
+use crate::ssa;
+use std::collections::HashMap;
+
+// 制御フローグラフの解析
+
+pub fn predecessors(func: &Function) -> Vec<Vec<BlockId>> {
+    let mut preds = vec![Vec::new(); func.blocks.len()];
+    for (i, block) in func.blocks.iter().enumerate() {
+        for succ in block.term.successors() {
+            if !preds[succ.0].contains(&BlockId(i)) {
+                preds[succ.0].push(BlockId(i));
+            }
+        }
+    }
+    preds
+}
+
+// 入口から到達できるブロックの逆後順
+pub fn reverse_postorder(func: &Function) -> Vec<BlockId> {
+    let mut visited = vec![false; func.blocks.len()];
+    let mut order = Vec::new();
+    // (ブロック, 次に調べる後続の番号) のスタックで深さ優先探索する
+    let mut stack = vec![(BlockId(0), 0)];
+    visited[0] = true;
+    while let Some((block, i)) = stack.pop() {
+        let succs = func.blocks[block.0].term.successors();
+        if i < succs.len() {
+            stack.push((block, i + 1));
+            let succ = succs[i];
+            if !visited[succ.0] {
+                visited[succ.0] = true;
+                stack.push((succ, 0));
+            }
+        } else {
+            order.push(block);
+        }
+    }
+    order.reverse();
+    order
+}
+
+// 到達不能なブロックを取り除き、ブロックの番号を詰める
+pub fn remove_unreachable_blocks(func: &mut Function) -> bool {
+    let rpo = reverse_postorder(func);
+    if rpo.len() == func.blocks.len() {
+        return false;
+    }
+    let mut reachable = vec![false; func.blocks.len()];
+    for block in rpo.iter() {
+        reachable[block.0] = true;
+    }
+
+    let mut new_ids = vec![None; func.blocks.len()];
+    let mut blocks = Vec::new();
+    for (i, block) in std::mem::take(&mut func.blocks).into_iter().enumerate() {
+        if reachable[i] {
+            new_ids[i] = Some(BlockId(blocks.len()));
+            blocks.push(block);
+        }
+    }
+    for block in blocks.iter_mut() {
+        for succ in block.term.successors_mut() {
+            *succ = new_ids[succ.0].unwrap();
+        }
+        for inst in block.insts.iter_mut() {
+            if let Inst::Phi { incoming, .. } = inst {
+                incoming.retain(|(pred, _)| reachable[pred.0]);
+                for (pred, _) in incoming.iter_mut() {
+                    *pred = new_ids[pred.0].unwrap();
+                }
+            }
+        }
+    }
+    func.blocks = blocks;
+    true
+}
+
+// 支配木 (Cooper, Harvey, Kennedy の反復法で求める)
+pub struct DomTree {
+    idom: Vec<Option<BlockId>>, // 到達不能なブロックは None
+    children: Vec<Vec<BlockId>>,
+}
+
+impl DomTree {
+    pub fn new(func: &Function) -> Self {
+        let n = func.blocks.len();
+        let rpo = reverse_postorder(func);
+        let preds = predecessors(func);
+        let mut rpo_index = vec![usize::MAX; n];
+        for (i, block) in rpo.iter().enumerate() {
+            rpo_index[block.0] = i;
+        }
+
+        let mut idom: Vec<Option<BlockId>> = vec![None; n];
+        idom[0] = Some(BlockId(0));
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for pred in preds[block.0].iter() {
+                    if idom[pred.0].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *pred,
+                        Some(other) => intersect(&idom, &rpo_index, *pred, other),
+                    });
+                }
+                if new_idom.is_some() && idom[block.0] != new_idom {
+                    idom[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children = vec![Vec::new(); n];
+        for block in rpo.iter().skip(1) {
+            children[idom[block.0].unwrap().0].push(*block);
+        }
+        DomTree { idom, children }
+    }
+
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.idom[block.0].is_some()
+    }
+
+    pub fn children(&self, block: BlockId) -> &[BlockId] {
+        &self.children[block.0]
+    }
+
+    // a が b を支配するか
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        if !self.is_reachable(b) {
+            return true;
+        }
+        let mut block = b;
+        loop {
+            if block == a {
+                return true;
+            }
+            if block.0 == 0 {
+                return false;
+            }
+            block = self.idom[block.0].unwrap();
+        }
+    }
+
+    // 支配辺境
+    pub fn frontiers(&self, func: &Function) -> Vec<Vec<BlockId>> {
+        let preds = predecessors(func);
+        let mut df = vec![Vec::new(); func.blocks.len()];
+        for (i, preds) in preds.iter().enumerate() {
+            if preds.len() < 2 || !self.is_reachable(BlockId(i)) {
+                continue;
+            }
+            let idom = self.idom[i].unwrap();
+            for pred in preds.iter() {
+                if !self.is_reachable(*pred) {
+                    continue;
+                }
+                let mut runner = *pred;
+                while runner != idom {
+                    if !df[runner.0].contains(&BlockId(i)) {
+                        df[runner.0].push(BlockId(i));
+                    }
+                    runner = self.idom[runner.0].unwrap();
+                }
+            }
+        }
+        df
+    }
+}
+
+fn intersect(idom: &[Option<BlockId>], rpo_index: &[usize], a: BlockId, b: BlockId) -> BlockId {
+    let (mut a, mut b) = (a, b);
+    while a != b {
+        while rpo_index[a.0] > rpo_index[b.0] {
+            a = idom[a.0].unwrap();
+        }
+        while rpo_index[b.0] > rpo_index[a.0] {
+            b = idom[b.0].unwrap();
+        }
+    }
+    a
+}
+
+// 定数の条件による分岐を畳み込み、到達不能なブロックを取り除き、一本道のブロックをつなげる
+pub fn simplify_cfg(func: &mut Function) {
+    loop {
+        let mut changed = fold_branches(func);
+        changed |= remove_unreachable_blocks(func);
+        changed |= merge_blocks(func);
+        if !changed {
+            break;
+        }
+    }
+    ssa::remove_trivial_phis(func);
+}
+
+// 条件が定数か、両方の行き先が同じ分岐を無条件のジャンプにする
+fn fold_branches(func: &mut Function) -> bool {
+    let mut changed = false;
+    for i in 0..func.blocks.len() {
+        let (target, dropped) = match func.blocks[i].term {
+            Terminator::Branch { then, els, .. } if then == els => (then, None),
+            Terminator::Branch {
+                cond: Operand::Imm(cond),
+                then,
+                els,
+            } => {
+                if cond != 0 {
+                    (then, Some(els))
+                } else {
+                    (els, Some(then))
+                }
+            }
+            _ => continue,
+        };
+        func.blocks[i].term = Terminator::Jump(target);
+        // 通らなくなった辺の phi の入力を取り除く
+        if let Some(dropped) = dropped {
+            for inst in func.blocks[dropped.0].insts.iter_mut() {
+                if let Inst::Phi { incoming, .. } = inst {
+                    incoming.retain(|(pred, _)| *pred != BlockId(i));
+                }
+            }
+        }
+        changed = true;
+    }
+    changed
+}
+
+// 唯一の後続の唯一の先行ブロックなら、後続の命令を末尾につなげる
+// つなげた後続は到達不能になるので、次の remove_unreachable_blocks で取り除かれる
+fn merge_blocks(func: &mut Function) -> bool {
+    let mut changed = false;
+    for i in 0..func.blocks.len() {
+        while let Terminator::Jump(succ) = func.blocks[i].term {
+            let preds = predecessors(func);
+            if succ.0 == 0 || succ.0 == i || preds[succ.0] != [BlockId(i)] {
+                break;
+            }
+            // 先行ブロックが一つなので phi は入力の値そのもの
+            let mut replace = HashMap::new();
+            let mut insts = Vec::new();
+            for inst in std::mem::take(&mut func.blocks[succ.0].insts) {
+                match inst {
+                    Inst::Phi { dst, incoming } => {
+                        replace.insert(dst, incoming[0].1);
+                    }
+                    _ => insts.push(inst),
+                }
+            }
+            let term = std::mem::replace(&mut func.blocks[succ.0].term, Terminator::Unreachable);
+            // 後続の後続にある phi は、つなげた先のブロックから来ることになる
+            for next in term.successors() {
+                for inst in func.blocks[next.0].insts.iter_mut() {
+                    if let Inst::Phi { incoming, .. } = inst {
+                        for (pred, _) in incoming.iter_mut() {
+                            if *pred == succ {
+                                *pred = BlockId(i);
+                            }
+                        }
+                    }
+                }
+            }
+            func.blocks[i].insts.extend(insts);
+            func.blocks[i].term = term;
+            ssa::replace_uses(func, &replace);
+            changed = true;
+        }
+    }
+    changed
+}
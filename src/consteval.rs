@@ -0,0 +1,65 @@
+use crate::layout::DataLayout;
+use crate::parser::{get_type_size, BinaryOpKind, ComparisonOpKind, Node, NodeKind};
+
+// C の整数定数式を評価する
+// 型検査済みの式を受け取り、int の範囲に収まる値を返す
+pub fn eval(node: &Node, layout: DataLayout) -> Result<i64, String> {
+    match &node.kind {
+        NodeKind::Num(val) if node.ty.is_unsigned_int() => Ok(*val as u32 as i64),
+        NodeKind::Num(val) => Ok(*val as i64),
+        NodeKind::Sizeof if is_constant(node) => {
+            Ok(get_type_size(&node.lhs.as_ref().unwrap().ty, layout) as i64)
+        }
+        NodeKind::BinaryOp(op) => {
+            let lhs = eval(node.lhs.as_ref().unwrap(), layout)?;
+            let rhs = eval(node.rhs.as_ref().unwrap(), layout)?;
+            let val = match op {
+                BinaryOpKind::Add => lhs + rhs,
+                BinaryOpKind::Sub => lhs - rhs,
+                BinaryOpKind::Mul => lhs * rhs,
+                BinaryOpKind::Div => {
+                    if rhs == 0 {
+                        return Err("整数定数式でゼロ除算が発生しました".to_string());
+                    }
+                    lhs / rhs
+                }
+            };
+            check_overflow(val)
+        }
+        NodeKind::Comparison(op) => {
+            let lhs = eval(node.lhs.as_ref().unwrap(), layout)?;
+            let rhs = eval(node.rhs.as_ref().unwrap(), layout)?;
+            let val = match op {
+                ComparisonOpKind::Eq => lhs == rhs,
+                ComparisonOpKind::Nq => lhs != rhs,
+                ComparisonOpKind::Lt => lhs < rhs,
+                ComparisonOpKind::Le => lhs <= rhs,
+                ComparisonOpKind::Gt => lhs > rhs,
+                ComparisonOpKind::Ge => lhs >= rhs,
+            };
+            Ok(val as i64)
+        }
+        _ => Err("整数定数式ではありません".to_string()),
+    }
+}
+
+// 整数定数式の形をしているか (値の範囲は検査しない)
+// 可変長配列の sizeof は実行時に評価される
+pub fn is_constant(node: &Node) -> bool {
+    match &node.kind {
+        NodeKind::Num(_) => true,
+        NodeKind::Sizeof => !node.lhs.as_ref().unwrap().ty.is_vla(),
+        NodeKind::BinaryOp(_) | NodeKind::Comparison(_) => {
+            is_constant(node.lhs.as_ref().unwrap()) && is_constant(node.rhs.as_ref().unwrap())
+        }
+        _ => false,
+    }
+}
+
+// 演算結果が int に収まるか検査する
+fn check_overflow(val: i64) -> Result<i64, String> {
+    if val < i32::MIN as i64 || val > i32::MAX as i64 {
+        return Err("整数定数式がオーバーフローしました".to_string());
+    }
+    Ok(val)
+}
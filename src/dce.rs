@@ -0,0 +1,199 @@
+use crate::alias::AliasInfo;
+use crate::ir::{Function, Inst, Module, Operand, Reg, SlotId, Terminator, Ty};
+use crate::pass::PassContext;
+use crate::ssa;
+use std::collections::{HashMap, HashSet};
+
+// 不要な命令の削除
+// 副作用がなく結果が使われない命令と、読み出されることのないストアを取り除く
+pub fn dce(func: &mut Function, ctx: &PassContext) {
+    remove_dead_stores(func, ctx.strict_aliasing);
+
+    // 副作用のある命令と終端命令から使われている値をたどる
+    let mut defs = HashMap::new();
+    let mut worklist = Vec::new();
+    for block in func.blocks.iter() {
+        for inst in block.insts.iter() {
+            if let Some(dst) = inst.dst() {
+                defs.insert(dst, inst);
+            }
+            if has_side_effects(inst) {
+                worklist.extend(inst.operands());
+            }
+        }
+        worklist.extend(block.term.operands());
+    }
+    let mut live = HashSet::new();
+    while let Some(op) = worklist.pop() {
+        let Operand::Reg(reg) = op else {
+            continue;
+        };
+        if live.insert(reg) {
+            if let Some(inst) = defs.get(&reg) {
+                worklist.extend(inst.operands());
+            }
+        }
+    }
+
+    for block in func.blocks.iter_mut() {
+        block.insts.retain(|inst| {
+            has_side_effects(inst) || inst.dst().is_some_and(|dst| live.contains(&dst))
+        });
+    }
+    remove_unused_slots(func);
+}
+
+fn has_side_effects(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Store { .. }
+            | Inst::Call { .. }
+            | Inst::Alloca { .. }
+            | Inst::StackSave { .. }
+            | Inst::StackRestore { .. }
+    )
+}
+
+// 後から読まれることのないストアを取り除く
+fn remove_dead_stores(func: &mut Function, strict_aliasing: bool) {
+    // 書き込まれるだけで、読み出されずアドレスも他に使われないスロット
+    let mut addrs: HashMap<Reg, SlotId> = HashMap::new();
+    for inst in func.blocks.iter().flat_map(|block| block.insts.iter()) {
+        if let Inst::LocalAddr { dst, slot } = inst {
+            addrs.insert(*dst, *slot);
+        }
+    }
+    let mut read: HashSet<SlotId> = HashSet::new();
+    for block in func.blocks.iter() {
+        for inst in block.insts.iter() {
+            let ops = match inst {
+                Inst::Store { val, .. } => vec![*val],
+                _ => inst.operands(),
+            };
+            for op in ops {
+                if let Some(slot) = reg_of(op).and_then(|reg| addrs.get(&reg)) {
+                    read.insert(*slot);
+                }
+            }
+        }
+        for op in block.term.operands() {
+            if let Some(slot) = reg_of(op).and_then(|reg| addrs.get(&reg)) {
+                read.insert(*slot);
+            }
+        }
+    }
+    let dead = |addr: Operand| {
+        reg_of(addr)
+            .and_then(|reg| addrs.get(&reg))
+            .is_some_and(|slot| !read.contains(slot))
+    };
+
+    let alias = AliasInfo::new(func, strict_aliasing);
+    for block in func.blocks.iter_mut() {
+        let mut insts: Vec<Inst> = Vec::new();
+        for inst in std::mem::take(&mut block.insts) {
+            if let Inst::Store { addr, ty, .. } = inst {
+                if dead(addr) {
+                    continue;
+                }
+                // 同じ場所への上書きまでに読み出しがなければ、前のストアは不要
+                if let Some(prev) = insts
+                    .iter()
+                    .rposition(|prev| !is_transparent(prev, addr, ty, &alias))
+                {
+                    if matches!(insts[prev], Inst::Store { addr: a, ty: t, .. } if a == addr && t == ty)
+                    {
+                        insts.remove(prev);
+                    }
+                }
+            }
+            insts.push(inst);
+        }
+        // 関数の外から見えない場所へのストアは、戻るまでに読まれなければ不要
+        if matches!(block.term, Terminator::Ret(_)) {
+            let mut i = insts.len();
+            while i > 0 {
+                i -= 1;
+                if let Inst::Store { addr, ty, .. } = insts[i] {
+                    if alias.is_local(addr)
+                        && insts[i + 1..]
+                            .iter()
+                            .all(|next| is_transparent(next, addr, ty, &alias))
+                    {
+                        insts.remove(i);
+                    }
+                }
+            }
+        }
+        block.insts = insts;
+    }
+}
+
+// addr へのストアをまたいで取り除いてよい命令か (addr を読まず、addr に書かない)
+fn is_transparent(inst: &Inst, addr: Operand, ty: Ty, alias: &AliasInfo) -> bool {
+    match inst {
+        Inst::Store { addr: a, .. } => *a != addr,
+        Inst::Load { addr: a, ty: t, .. } => !alias.may_alias(*a, *t, addr, ty),
+        Inst::Call { .. } => !alias.call_may_read(addr),
+        Inst::Alloca { .. } | Inst::StackSave { .. } | Inst::StackRestore { .. } => false,
+        _ => true,
+    }
+}
+
+fn reg_of(op: Operand) -> Option<Reg> {
+    match op {
+        Operand::Reg(reg) => Some(reg),
+        Operand::Imm(_) => None,
+    }
+}
+
+// どこからもアドレスを取られないスロットを取り除く
+fn remove_unused_slots(func: &mut Function) {
+    let mut used = HashSet::new();
+    for inst in func.blocks.iter().flat_map(|block| block.insts.iter()) {
+        if let Inst::LocalAddr { slot, .. } = inst {
+            used.insert(*slot);
+        }
+    }
+    let unused = (0..func.slots.len())
+        .map(SlotId)
+        .filter(|slot| !used.contains(slot))
+        .collect();
+    ssa::remove_slots(func, &unused);
+}
+
+// どの外部結合の関数からも呼ばれない static 関数を取り除く
+pub fn global_dce(module: &mut Module) {
+    let index: HashMap<String, usize> = module
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(i, func)| (func.name.clone(), i))
+        .collect();
+    let mut live = vec![false; module.functions.len()];
+    let mut worklist: Vec<usize> = (0..module.functions.len())
+        .filter(|&i| !module.functions[i].is_static)
+        .collect();
+    while let Some(i) = worklist.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        for inst in module.functions[i]
+            .blocks
+            .iter()
+            .flat_map(|block| block.insts.iter())
+        {
+            if let Inst::Call { name, .. } = inst {
+                if let Some(&callee) = index.get(name) {
+                    worklist.push(callee);
+                }
+            }
+        }
+    }
+    let mut i = 0;
+    module.functions.retain(|_| {
+        i += 1;
+        live[i - 1]
+    });
+}
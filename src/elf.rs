@@ -0,0 +1,522 @@
+use crate::ir::Visibility;
+use std::collections::HashMap;
+
+// ELF64 (x86-64) の再配置可能オブジェクトファイル
+// セクションとシンボル、再配置を持ち、GNU as と同じ順にセクションを並べて書き出す
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+pub const R_X86_64_GOTPCREL: u32 = 9;
+pub const R_X86_64_32: u32 = 10;
+pub const R_X86_64_32S: u32 = 11;
+pub const R_X86_64_GOTPCRELX: u32 = 41;
+pub const R_X86_64_REX_GOTPCRELX: u32 = 42;
+
+// GOT を経由してシンボルのアドレスを読む再配置
+pub fn is_got(kind: u32) -> bool {
+    matches!(
+        kind,
+        R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX
+    )
+}
+
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHN_LORESERVE: usize = 0xff00;
+const SHN_ABS: usize = 0xfff1;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+const STV_HIDDEN: u8 = 2;
+const STV_PROTECTED: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Text,
+    Data,
+    Bss,
+    Rodata,
+}
+
+pub struct Section {
+    pub name: String,
+    pub kind: SectionKind,
+    pub data: Vec<u8>, // .bss では空
+    pub size: usize,   // .bss の大きさ (それ以外は data の長さ)
+    pub align: usize,
+    pub relocs: Vec<Reloc>,
+}
+
+impl Section {
+    pub fn new(name: &str, kind: SectionKind) -> Self {
+        Section {
+            name: name.to_string(),
+            kind,
+            data: Vec::new(),
+            size: 0,
+            align: 1,
+            relocs: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self.kind {
+            SectionKind::Bss => self.size,
+            _ => self.data.len(),
+        }
+    }
+
+    fn flags(&self) -> u64 {
+        match self.kind {
+            SectionKind::Text => SHF_ALLOC | SHF_EXECINSTR,
+            SectionKind::Data | SectionKind::Bss => SHF_ALLOC | SHF_WRITE,
+            SectionKind::Rodata => SHF_ALLOC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    NoType,
+    Section,
+}
+
+pub struct Symbol {
+    pub name: String, // セクションシンボルは空
+    pub kind: SymbolKind,
+    pub section: Option<usize>, // 定義されているセクション (未定義なら None)
+    pub value: u64,
+    pub global: bool,
+    pub visibility: Visibility,
+}
+
+// 再配置 (セクションの offset に、シンボルの値 + addend を kind の形で書き込む)
+pub struct Reloc {
+    pub offset: u64,
+    pub symbol: usize,
+    pub kind: u32,
+    pub addend: i64,
+}
+
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>, // ローカルなシンボルを先に並べる
+}
+
+// 文字列表 (先頭は空文字列) を作り、それぞれの名前のオフセットを返す
+// GNU as と同じく、ほかの名前の末尾と一致する名前はその部分を共有する
+fn strtab(names: &[&str]) -> (Vec<u8>, Vec<u32>) {
+    // 共有先は、その名前で終わる (共有されない) 名前のうち、逆から読んで辞書順で最小のもの
+    let rev = |name: &str| name.bytes().rev().collect::<Vec<u8>>();
+    let shared = |name: &str| {
+        names
+            .iter()
+            .filter(|other| other.len() > name.len() && other.ends_with(name))
+            .filter(|other| {
+                !names
+                    .iter()
+                    .any(|longer| longer.len() > other.len() && longer.ends_with(**other))
+            })
+            .min_by_key(|other| rev(other))
+            .copied()
+    };
+    let mut data = vec![0];
+    let mut placed: HashMap<&str, u32> = HashMap::new();
+    for &name in names.iter() {
+        if name.is_empty() || placed.contains_key(name) || shared(name).is_some() {
+            continue;
+        }
+        placed.insert(name, data.len() as u32);
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+    }
+    let offsets = names
+        .iter()
+        .map(|&name| match shared(name) {
+            _ if name.is_empty() => 0,
+            Some(other) => placed[other] + (other.len() - name.len()) as u32,
+            None => placed[name],
+        })
+        .collect();
+    (data, offsets)
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl Object {
+    // ファイルの中の並びも GNU as と同じく、セクションの中身、シンボル表、文字列表、
+    // 再配置、セクション名の表の順にする
+    pub fn encode(&self) -> Vec<u8> {
+        assert!(
+            self.symbols.windows(2).all(|w| !w[0].global || w[1].global),
+            "ローカルなシンボルはグローバルなシンボルより前に並べなければなりません"
+        );
+        let mut out = vec![0; 64];
+
+        // セクションの番号 (再配置のセクションはそれぞれのセクションの直後に置く)
+        let mut index = Vec::new();
+        let mut next = 1;
+        for section in self.sections.iter() {
+            index.push(next);
+            next += if section.relocs.is_empty() { 1 } else { 2 };
+        }
+        let symtab_index = next;
+        let mut headers: Vec<SectionHeader> =
+            (0..symtab_index + 3).map(|_| Default::default()).collect();
+
+        // セクション名の表
+        let mut names = vec![
+            ".symtab".to_string(),
+            ".strtab".to_string(),
+            ".shstrtab".to_string(),
+        ];
+        let mut name_index = Vec::new();
+        for section in self.sections.iter() {
+            name_index.push(names.len());
+            names.push(section.name.clone());
+            if !section.relocs.is_empty() {
+                names.push(format!(".rela{}", section.name));
+            }
+        }
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        let (shstrtab, name_offsets) = strtab(&names);
+        for (i, offset) in name_offsets[..3].iter().enumerate() {
+            headers[symtab_index + i].name = *offset;
+        }
+        for (i, section) in self.sections.iter().enumerate() {
+            headers[index[i]].name = name_offsets[name_index[i]];
+            if !section.relocs.is_empty() {
+                headers[index[i] + 1].name = name_offsets[name_index[i] + 1];
+            }
+        }
+
+        for (i, section) in self.sections.iter().enumerate() {
+            let header = &mut headers[index[i]];
+            header.kind = match section.kind {
+                SectionKind::Bss => SHT_NOBITS,
+                _ => SHT_PROGBITS,
+            };
+            header.flags = section.flags();
+            header.offset = out.len() as u64;
+            header.size = section.size() as u64;
+            header.align = section.align as u64;
+            out.extend_from_slice(&section.data);
+        }
+
+        // シンボル表 (先頭は空のシンボル)
+        let names: Vec<&str> = self
+            .symbols
+            .iter()
+            .map(|symbol| symbol.name.as_str())
+            .collect();
+        let (strtab, symbol_names) = strtab(&names);
+        align(&mut out, 8);
+        let offset = out.len() as u64;
+        out.extend_from_slice(&[0; 24]);
+        for (symbol, name) in self.symbols.iter().zip(symbol_names) {
+            out.extend_from_slice(&name.to_le_bytes());
+            let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+            let kind = match symbol.kind {
+                SymbolKind::NoType => STT_NOTYPE,
+                SymbolKind::Section => STT_SECTION,
+            };
+            out.push((bind << 4) | kind);
+            out.push(match symbol.visibility {
+                Visibility::Default => 0,
+                Visibility::Hidden => STV_HIDDEN,
+                Visibility::Protected => STV_PROTECTED,
+            });
+            let shndx = symbol.section.map_or(0, |section| index[section] as u16);
+            out.extend_from_slice(&shndx.to_le_bytes());
+            out.extend_from_slice(&symbol.value.to_le_bytes());
+            out.extend_from_slice(&0u64.to_le_bytes());
+        }
+        let locals = self.symbols.iter().filter(|symbol| !symbol.global).count();
+        let header = &mut headers[symtab_index];
+        header.kind = SHT_SYMTAB;
+        header.offset = offset;
+        header.size = (self.symbols.len() as u64 + 1) * 24;
+        header.link = symtab_index as u32 + 1;
+        header.info = locals as u32 + 1;
+        header.align = 8;
+        header.entsize = 24;
+
+        let header = &mut headers[symtab_index + 1];
+        header.kind = SHT_STRTAB;
+        header.offset = out.len() as u64;
+        header.size = strtab.len() as u64;
+        header.align = 1;
+        out.extend_from_slice(&strtab);
+
+        for (i, section) in self.sections.iter().enumerate() {
+            if section.relocs.is_empty() {
+                continue;
+            }
+            align(&mut out, 8);
+            let header = &mut headers[index[i] + 1];
+            header.kind = SHT_RELA;
+            header.flags = SHF_INFO_LINK;
+            header.offset = out.len() as u64;
+            header.size = section.relocs.len() as u64 * 24;
+            header.link = symtab_index as u32;
+            header.info = index[i] as u32;
+            header.align = 8;
+            header.entsize = 24;
+            for reloc in section.relocs.iter() {
+                out.extend_from_slice(&reloc.offset.to_le_bytes());
+                let info = ((reloc.symbol as u64 + 1) << 32) | reloc.kind as u64;
+                out.extend_from_slice(&info.to_le_bytes());
+                out.extend_from_slice(&reloc.addend.to_le_bytes());
+            }
+        }
+
+        let header = &mut headers[symtab_index + 2];
+        header.kind = SHT_STRTAB;
+        header.offset = out.len() as u64;
+        header.size = shstrtab.len() as u64;
+        header.align = 1;
+        out.extend_from_slice(&shstrtab);
+
+        align(&mut out, 8);
+        let shoff = out.len() as u64;
+        for header in headers.iter() {
+            out.extend_from_slice(&header.name.to_le_bytes());
+            out.extend_from_slice(&header.kind.to_le_bytes());
+            out.extend_from_slice(&header.flags.to_le_bytes());
+            out.extend_from_slice(&0u64.to_le_bytes()); // addr
+            out.extend_from_slice(&header.offset.to_le_bytes());
+            out.extend_from_slice(&header.size.to_le_bytes());
+            out.extend_from_slice(&header.link.to_le_bytes());
+            out.extend_from_slice(&header.info.to_le_bytes());
+            out.extend_from_slice(&header.align.to_le_bytes());
+            out.extend_from_slice(&header.entsize.to_le_bytes());
+        }
+
+        let header = file_header(ET_REL, 0, 0, shoff, headers.len());
+        out[..64].copy_from_slice(&header);
+        out
+    }
+}
+
+// ELF ヘッダ (プログラムヘッダはヘッダの直後に置く)
+pub fn file_header(kind: u16, entry: u64, phnum: usize, shoff: u64, shnum: usize) -> [u8; 64] {
+    let mut header = Vec::new();
+    header.extend_from_slice(b"\x7fELF");
+    header.extend_from_slice(&[2, 1, 1, 0]); // 64 ビット、リトルエンディアン、バージョン 1、System V
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&kind.to_le_bytes());
+    header.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&entry.to_le_bytes());
+    let phoff: u64 = if phnum > 0 { 64 } else { 0 };
+    header.extend_from_slice(&phoff.to_le_bytes());
+    header.extend_from_slice(&shoff.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes()); // フラグ
+    header.extend_from_slice(&64u16.to_le_bytes());
+    let phentsize: u16 = if phnum > 0 { 56 } else { 0 };
+    header.extend_from_slice(&phentsize.to_le_bytes());
+    header.extend_from_slice(&(phnum as u16).to_le_bytes());
+    header.extend_from_slice(&64u16.to_le_bytes());
+    header.extend_from_slice(&(shnum as u16).to_le_bytes());
+    header.extend_from_slice(&(shnum.saturating_sub(1) as u16).to_le_bytes());
+    header.try_into().unwrap()
+}
+
+fn align(out: &mut Vec<u8>, align: usize) {
+    out.resize(out.len().next_multiple_of(align), 0);
+}
+
+// ファイルの中の値を読む (範囲外なら壊れたファイル)
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], String> {
+    offset
+        .checked_add(N)
+        .and_then(|end| bytes.get(offset..end))
+        .map(|slice| slice.try_into().unwrap())
+        .ok_or_else(|| "オブジェクトファイルが壊れています".to_string())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<usize, String> {
+    Ok(u16::from_le_bytes(read(bytes, offset)?) as usize)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<usize, String> {
+    Ok(u32::from_le_bytes(read(bytes, offset)?) as usize)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(read(bytes, offset)?))
+}
+
+fn slice(bytes: &[u8], offset: usize, size: usize) -> Result<&[u8], String> {
+    offset
+        .checked_add(size)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or_else(|| "オブジェクトファイルが壊れています".to_string())
+}
+
+fn name_at(strtab: &[u8], offset: usize) -> Result<String, String> {
+    let name = strtab
+        .get(offset..)
+        .and_then(|rest| rest.split(|&b| b == 0).next())
+        .ok_or_else(|| "オブジェクトファイルが壊れています".to_string())?;
+    Ok(String::from_utf8_lossy(name).into_owned())
+}
+
+struct RawSection {
+    name: usize,
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+    align: usize,
+}
+
+impl Object {
+    // x86-64 の再配置可能オブジェクトファイルを読む
+    // メモリに置かれるセクションとその再配置、シンボル表だけを取り出す
+    pub fn parse(bytes: &[u8]) -> Result<Object, String> {
+        if slice(bytes, 0, 4)? != b"\x7fELF" || slice(bytes, 4, 2)? != [2, 1] {
+            return Err("64 ビットのリトルエンディアンの ELF ファイルではありません".to_string());
+        }
+        if u16_at(bytes, 16)? != 1 || u16_at(bytes, 18)? != 62 {
+            return Err("x86-64 の再配置可能オブジェクトファイルではありません".to_string());
+        }
+        let shoff = u64_at(bytes, 40)? as usize;
+        let shnum = u16_at(bytes, 60)?;
+        let shstrndx = u16_at(bytes, 62)?;
+        let raw = (0..shnum)
+            .map(|i| {
+                let header = shoff + i * 64;
+                Ok(RawSection {
+                    name: u32_at(bytes, header)?,
+                    kind: u32_at(bytes, header + 4)? as u32,
+                    flags: u64_at(bytes, header + 8)?,
+                    offset: u64_at(bytes, header + 24)? as usize,
+                    size: u64_at(bytes, header + 32)? as usize,
+                    link: u32_at(bytes, header + 40)?,
+                    info: u32_at(bytes, header + 44)?,
+                    align: u64_at(bytes, header + 48)? as usize,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let shstrtab = raw
+            .get(shstrndx)
+            .map(|section| slice(bytes, section.offset, section.size))
+            .transpose()?
+            .unwrap_or_default();
+
+        // メモリに置かれるセクション (ファイルの中の番号から読んだセクションの番号へ)
+        let mut sections = Vec::new();
+        let mut index = HashMap::new();
+        for (i, section) in raw.iter().enumerate() {
+            if section.flags & SHF_ALLOC == 0 {
+                continue;
+            }
+            let kind = if section.flags & SHF_EXECINSTR != 0 {
+                SectionKind::Text
+            } else if section.kind == SHT_NOBITS {
+                SectionKind::Bss
+            } else if section.flags & SHF_WRITE != 0 {
+                SectionKind::Data
+            } else {
+                SectionKind::Rodata
+            };
+            let mut new = Section::new(&name_at(shstrtab, section.name)?, kind);
+            new.align = section.align.max(1);
+            if kind == SectionKind::Bss {
+                new.size = section.size;
+            } else {
+                new.data = slice(bytes, section.offset, section.size)?.to_vec();
+            }
+            index.insert(i, sections.len());
+            sections.push(new);
+        }
+
+        // シンボル表 (読んだシンボルの番号はファイルの中の番号より 1 小さい)
+        let mut symbols = Vec::new();
+        if let Some(symtab) = raw.iter().find(|section| section.kind == SHT_SYMTAB) {
+            let strtab = raw
+                .get(symtab.link)
+                .ok_or_else(|| "オブジェクトファイルが壊れています".to_string())?;
+            let strtab = slice(bytes, strtab.offset, strtab.size)?;
+            for i in 1..symtab.size / 24 {
+                let entry = symtab.offset + i * 24;
+                let name = name_at(strtab, u32_at(bytes, entry)?)?;
+                let [info, other] = read::<2>(bytes, entry + 4)?;
+                let shndx = u16_at(bytes, entry + 6)?;
+                let global = info >> 4 != STB_LOCAL;
+                if global && shndx >= SHN_LORESERVE {
+                    let what = if shndx == SHN_ABS { "絶対" } else { "共通" };
+                    return Err(format!("{}シンボルには対応していません: {}", what, name));
+                }
+                symbols.push(Symbol {
+                    name,
+                    kind: if info & 0xf == STT_SECTION {
+                        SymbolKind::Section
+                    } else {
+                        SymbolKind::NoType
+                    },
+                    section: index.get(&shndx).copied(),
+                    value: u64_at(bytes, entry + 8)?,
+                    global,
+                    // internal は hidden と同じに扱う
+                    visibility: match other & 3 {
+                        0 => Visibility::Default,
+                        STV_PROTECTED => Visibility::Protected,
+                        _ => Visibility::Hidden,
+                    },
+                });
+            }
+        }
+
+        // メモリに置かれるセクションへの再配置
+        for section in raw.iter().filter(|section| section.kind == SHT_RELA) {
+            let Some(&target) = index.get(&section.info) else {
+                continue;
+            };
+            for i in 0..section.size / 24 {
+                let entry = section.offset + i * 24;
+                let info = u64_at(bytes, entry + 8)?;
+                let symbol = (info >> 32) as usize;
+                if symbol == 0 || symbol > symbols.len() {
+                    return Err("オブジェクトファイルが壊れています".to_string());
+                }
+                sections[target].relocs.push(Reloc {
+                    offset: u64_at(bytes, entry)?,
+                    symbol: symbol - 1,
+                    kind: info as u32,
+                    addend: u64_at(bytes, entry + 16)? as i64,
+                });
+            }
+        }
+        Ok(Object { sections, symbols })
+    }
+}
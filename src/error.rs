@@ -1,4 +1,4 @@
-pub fn error(message: &str) {
+pub fn error(message: &str) -> ! {
     eprintln!("{}", message);
     std::process::exit(1);
 }
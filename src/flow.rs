@@ -0,0 +1,192 @@
+use crate::consteval;
+use crate::layout::DataLayout;
+use crate::parser::{Node, NodeKind, UnaryOpKind};
+use crate::warning::{self, Warning};
+use std::collections::HashSet;
+
+// 文が必ず return で終わるか
+pub fn always_returns(node: &Node, layout: DataLayout) -> bool {
+    match &node.kind {
+        NodeKind::Return => true,
+        NodeKind::Block(stmts) => stmts.iter().any(|stmt| always_returns(stmt, layout)),
+        NodeKind::If => {
+            let rhs = node.rhs.as_ref().unwrap();
+            rhs.kind == NodeKind::Else
+                && always_returns(rhs.lhs.as_ref().unwrap(), layout)
+                && always_returns(rhs.rhs.as_ref().unwrap(), layout)
+        }
+        // break がないので、条件が常に真のループからは抜けられない
+        NodeKind::While => is_always_true(node.lhs.as_ref().unwrap(), layout),
+        NodeKind::For => is_always_true(node.rhs.as_ref().unwrap().lhs.as_ref().unwrap(), layout),
+        _ => false,
+    }
+}
+
+// return の後などに置かれて実行されることのない文を検出する
+pub fn check_unreachable(node: &Node, layout: DataLayout) {
+    let NodeKind::Block(stmts) = &node.kind else {
+        if let Some(lhs) = &node.lhs {
+            check_unreachable(lhs, layout);
+        }
+        if let Some(rhs) = &node.rhs {
+            check_unreachable(rhs, layout);
+        }
+        return;
+    };
+    let mut returned = false;
+    for stmt in stmts {
+        // 可変長配列の解放は意味解析で追加したもの
+        if matches!(stmt.kind, NodeKind::StackSave(_) | NodeKind::StackRestore(_)) {
+            continue;
+        }
+        if returned {
+            warning::warn(Warning::UnreachableCode, stmt.line, "到達できないコードです");
+            return;
+        }
+        check_unreachable(stmt, layout);
+        returned = always_returns(stmt, layout);
+    }
+}
+
+fn is_always_true(cond: &Node, layout: DataLayout) -> bool {
+    matches!(consteval::eval(cond, layout), Ok(val) if val != 0)
+}
+
+// 初期化済みのローカル変数 (オフセットで識別する)。None は到達不能を表す
+type Initialized = Option<HashSet<usize>>;
+
+fn meet(a: Initialized, b: Initialized) -> Initialized {
+    match (a, b) {
+        (None, x) | (x, None) => x,
+        (Some(a), Some(b)) => Some(a.intersection(&b).copied().collect()),
+    }
+}
+
+// 初期化されていない可能性のあるローカル変数の読み出しを検出する
+pub fn check_uninitialized(params: &[Node], body: &Node) {
+    let mut checker = UninitChecker {
+        warned: HashSet::new(),
+    };
+    // 引数は呼び出し時に初期化されている
+    let params = params.iter().filter_map(|param| match &param.kind {
+        NodeKind::LVar(lvar) => Some(lvar.offset),
+        _ => None,
+    });
+    let mut state = Some(params.collect());
+    checker.visit(body, &mut state);
+}
+
+struct UninitChecker {
+    warned: HashSet<usize>,
+}
+
+impl UninitChecker {
+    fn visit(&mut self, node: &Node, state: &mut Initialized) {
+        match &node.kind {
+            NodeKind::LVar(lvar) => {
+                // 配列と構造体は要素単位で初期化されるので追跡しない
+                if lvar.ty.is_aggregate() {
+                    return;
+                }
+                if let Some(init) = state {
+                    if !init.contains(&lvar.offset) && self.warned.insert(lvar.offset) {
+                        warning::warn(
+                            Warning::Uninitialized,
+                            node.line,
+                            &format!(
+                                "変数 '{}' が初期化されずに使われている可能性があります",
+                                lvar.name
+                            ),
+                        );
+                    }
+                }
+            }
+            NodeKind::Assign => {
+                let lhs = node.lhs.as_ref().unwrap();
+                self.visit(node.rhs.as_ref().unwrap(), state);
+                match &lhs.kind {
+                    NodeKind::LVar(lvar) => {
+                        if let Some(init) = state {
+                            init.insert(lvar.offset);
+                        }
+                    }
+                    _ => self.visit_lval(lhs, state),
+                }
+            }
+            NodeKind::UnaryOp(UnaryOpKind::Ref) => {
+                let lhs = node.lhs.as_ref().unwrap();
+                match &lhs.kind {
+                    // アドレスを取られた変数はポインタ経由で初期化されうる
+                    NodeKind::LVar(lvar) => {
+                        if let Some(init) = state {
+                            init.insert(lvar.offset);
+                        }
+                    }
+                    _ => self.visit_lval(lhs, state),
+                }
+            }
+            NodeKind::Fncall(_, args) => {
+                for arg in args {
+                    self.visit(arg, state);
+                }
+            }
+            NodeKind::Block(stmts) => {
+                for stmt in stmts {
+                    self.visit(stmt, state);
+                }
+            }
+            NodeKind::Return => {
+                if let Some(lhs) = node.lhs.as_ref() {
+                    self.visit(lhs, state);
+                }
+                *state = None;
+            }
+            NodeKind::If => {
+                self.visit(node.lhs.as_ref().unwrap(), state);
+                let rhs = node.rhs.as_ref().unwrap();
+                let mut then_state = state.clone();
+                if rhs.kind == NodeKind::Else {
+                    let mut else_state = state.clone();
+                    self.visit(rhs.lhs.as_ref().unwrap(), &mut then_state);
+                    self.visit(rhs.rhs.as_ref().unwrap(), &mut else_state);
+                    *state = meet(then_state, else_state);
+                } else {
+                    self.visit(rhs, &mut then_state);
+                    *state = meet(then_state, state.take());
+                }
+            }
+            // ループ本体は一度も実行されないかもしれないので、ループ後の状態は条件式の評価後と同じ
+            NodeKind::While => {
+                self.visit(node.lhs.as_ref().unwrap(), state);
+                let mut body_state = state.clone();
+                self.visit(node.rhs.as_ref().unwrap(), &mut body_state);
+            }
+            NodeKind::For => {
+                let cond = node.rhs.as_ref().unwrap();
+                let inc = cond.rhs.as_ref().unwrap();
+                self.visit(node.lhs.as_ref().unwrap(), state);
+                self.visit(cond.lhs.as_ref().unwrap(), state);
+                let mut body_state = state.clone();
+                self.visit(inc.rhs.as_ref().unwrap(), &mut body_state);
+                self.visit(inc.lhs.as_ref().unwrap(), &mut body_state);
+            }
+            _ => {
+                if let Some(lhs) = &node.lhs {
+                    self.visit(lhs, state);
+                }
+                if let Some(rhs) = &node.rhs {
+                    self.visit(rhs, state);
+                }
+            }
+        }
+    }
+
+    // 左辺値として使われる式 (値は読まない)
+    fn visit_lval(&mut self, node: &Node, state: &mut Initialized) {
+        match &node.kind {
+            NodeKind::UnaryOp(UnaryOpKind::Deref) => self.visit(node.lhs.as_ref().unwrap(), state),
+            NodeKind::LVar(_) | NodeKind::GVar(_) => {}
+            _ => self.visit(node, state),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::ir::{BinOp, CastKind, CmpOp, Function, Inst, Operand, Reg};
+use crate::ssa;
+use std::collections::HashMap;
+
+// 定数畳み込みと代数的な簡約
+// 定数だけの式を計算し、x + 0 や x * 1 のような恒等式を簡約する
+// 2 のべき乗による乗算と除算はシフトに置き換える
+pub fn fold(func: &mut Function) {
+    loop {
+        let negs = negations(func);
+        let mut replace = HashMap::new();
+        let mut changed = false;
+        for i in 0..func.blocks.len() {
+            let insts = std::mem::take(&mut func.blocks[i].insts);
+            let mut folded = Vec::new();
+            for mut inst in insts {
+                for op in inst.operands_mut() {
+                    *op = ssa::resolve(&replace, *op);
+                }
+                match simplify(func, &negs, &inst) {
+                    Some(Simplified::Value(val)) => {
+                        replace.insert(inst.dst().unwrap(), val);
+                        changed = true;
+                    }
+                    Some(Simplified::Insts(insts)) => {
+                        folded.extend(insts);
+                        changed = true;
+                    }
+                    None => folded.push(inst),
+                }
+            }
+            func.blocks[i].insts = folded;
+        }
+        if !changed {
+            return;
+        }
+        ssa::replace_uses(func, &replace);
+        ssa::remove_trivial_phis(func);
+    }
+}
+
+enum Simplified {
+    Value(Operand),   // 命令を取り除いて値に置き換える
+    Insts(Vec<Inst>), // 命令を書き換える
+}
+
+// 0 - x で定義された仮想レジスタと x
+fn negations(func: &Function) -> HashMap<Reg, Operand> {
+    let mut negs = HashMap::new();
+    for block in func.blocks.iter() {
+        for inst in block.insts.iter() {
+            if let Inst::Bin {
+                op: BinOp::Sub,
+                dst,
+                lhs: Operand::Imm(0),
+                rhs,
+            } = inst
+            {
+                negs.insert(*dst, *rhs);
+            }
+        }
+    }
+    negs
+}
+
+// size バイトの幅で符号拡張した値
+fn signed(val: i64, size: usize) -> i64 {
+    match size {
+        1 => val as i8 as i64,
+        4 => val as i32 as i64,
+        _ => val,
+    }
+}
+
+// size バイトの幅でゼロ拡張した値
+fn unsigned(val: i64, size: usize) -> u64 {
+    match size {
+        1 => val as u8 as u64,
+        4 => val as u32 as u64,
+        _ => val as u64,
+    }
+}
+
+// 2 のべき乗ならその指数
+fn log2(val: i64, size: usize) -> Option<i64> {
+    let val = unsigned(val, size);
+    val.is_power_of_two().then(|| val.trailing_zeros() as i64)
+}
+
+fn eval_bin(op: BinOp, size: usize, a: i64, b: i64) -> Option<i64> {
+    let bits = size as u32 * 8;
+    let amount = unsigned(b, size) as u32 & (bits - 1);
+    let val = match op {
+        BinOp::Add => a.wrapping_add(b),
+        BinOp::Sub => a.wrapping_sub(b),
+        BinOp::Mul => a.wrapping_mul(b),
+        // 0 で割る式は実行時に任せる
+        BinOp::SDiv if signed(b, size) == 0 => return None,
+        BinOp::SDiv => signed(a, size).wrapping_div(signed(b, size)),
+        BinOp::UDiv if unsigned(b, size) == 0 => return None,
+        BinOp::UDiv => (unsigned(a, size) / unsigned(b, size)) as i64,
+        BinOp::And => a & b,
+        BinOp::Or => a | b,
+        BinOp::Shl => a.wrapping_shl(amount),
+        BinOp::LShr => (unsigned(a, size) >> amount) as i64,
+        BinOp::AShr => signed(a, size) >> amount,
+    };
+    Some(signed(val, size))
+}
+
+pub fn eval_cmp(op: CmpOp, size: usize, a: i64, b: i64) -> bool {
+    let (sa, sb) = (signed(a, size), signed(b, size));
+    let (ua, ub) = (unsigned(a, size), unsigned(b, size));
+    match op {
+        CmpOp::Eq => sa == sb,
+        CmpOp::Ne => sa != sb,
+        CmpOp::Slt => sa < sb,
+        CmpOp::Sle => sa <= sb,
+        CmpOp::Sgt => sa > sb,
+        CmpOp::Sge => sa >= sb,
+        CmpOp::Ult => ua < ub,
+        CmpOp::Ule => ua <= ub,
+        CmpOp::Ugt => ua > ub,
+        CmpOp::Uge => ua >= ub,
+    }
+}
+
+fn simplify(func: &mut Function, negs: &HashMap<Reg, Operand>, inst: &Inst) -> Option<Simplified> {
+    match *inst {
+        Inst::Copy { src, .. } => Some(Simplified::Value(src)),
+        Inst::Bin { op, dst, lhs, rhs } => simplify_bin(func, negs, op, dst, lhs, rhs),
+        Inst::Cmp {
+            op,
+            ty,
+            lhs: Operand::Imm(a),
+            rhs: Operand::Imm(b),
+            ..
+        } => Some(Simplified::Value(Operand::Imm(
+            eval_cmp(op, ty.size(func.layout), a, b) as i64,
+        ))),
+        // 同じ値どうしの比較
+        Inst::Cmp { op, lhs, rhs, .. } if lhs == rhs => {
+            let val = matches!(
+                op,
+                CmpOp::Eq | CmpOp::Sle | CmpOp::Sge | CmpOp::Ule | CmpOp::Uge
+            );
+            Some(Simplified::Value(Operand::Imm(val as i64)))
+        }
+        Inst::Cast {
+            dst,
+            src: Operand::Imm(val),
+            kind,
+        } => {
+            let val = match kind {
+                CastKind::Sext(from) => signed(val, from.size(func.layout)),
+                CastKind::Zext(from) => unsigned(val, from.size(func.layout)) as i64,
+                CastKind::Trunc => val,
+            };
+            Some(Simplified::Value(Operand::Imm(signed(
+                val,
+                func.reg_ty(dst).size(func.layout),
+            ))))
+        }
+        _ => None,
+    }
+}
+
+fn simplify_bin(
+    func: &mut Function,
+    negs: &HashMap<Reg, Operand>,
+    op: BinOp,
+    dst: Reg,
+    lhs: Operand,
+    rhs: Operand,
+) -> Option<Simplified> {
+    let ty = func.reg_ty(dst);
+    let size = ty.size(func.layout);
+    let value = |val: Operand| Some(Simplified::Value(val));
+    let bin = |op, lhs, rhs| Some(Simplified::Insts(vec![Inst::Bin { op, dst, lhs, rhs }]));
+
+    let (a, b) = match (lhs, rhs) {
+        (Operand::Imm(a), Operand::Imm(b)) => {
+            return eval_bin(op, size, a, b).and_then(|val| value(Operand::Imm(val)));
+        }
+        // 可換な演算は定数を右辺に寄せる
+        (Operand::Imm(_), Operand::Reg(_))
+            if matches!(op, BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or) =>
+        {
+            return bin(op, rhs, lhs);
+        }
+        (Operand::Imm(a), _) => (Some(signed(a, size)), None),
+        (_, Operand::Imm(b)) => (None, Some(signed(b, size))),
+        _ => (None, None),
+    };
+
+    match (op, a, b) {
+        (BinOp::Add | BinOp::Sub | BinOp::Or, _, Some(0)) => value(lhs),
+        (BinOp::Shl | BinOp::LShr | BinOp::AShr, _, Some(0)) => value(lhs),
+        (BinOp::Mul | BinOp::SDiv | BinOp::UDiv, _, Some(1)) => value(lhs),
+        (BinOp::And, _, Some(-1)) => value(lhs),
+        (BinOp::Mul | BinOp::And, _, Some(0)) => value(Operand::Imm(0)),
+        (BinOp::Or, _, Some(-1)) => value(Operand::Imm(-1)),
+        (BinOp::Shl | BinOp::LShr | BinOp::AShr, Some(0), _) => value(Operand::Imm(0)),
+        (BinOp::Sub, _, _) if lhs == rhs => value(Operand::Imm(0)),
+        // -(-x) は x
+        (BinOp::Sub, Some(0), _) => match rhs {
+            Operand::Reg(reg) => negs.get(&reg).and_then(|x| value(*x)),
+            Operand::Imm(_) => None,
+        },
+        (BinOp::Mul, _, Some(b)) => {
+            log2(b, size).and_then(|k| bin(BinOp::Shl, lhs, Operand::Imm(k)))
+        }
+        (BinOp::UDiv, _, Some(b)) => {
+            log2(b, size).and_then(|k| bin(BinOp::LShr, lhs, Operand::Imm(k)))
+        }
+        (BinOp::SDiv, _, Some(b)) if b > 1 => {
+            let k = log2(b, size)?;
+            // 負の数を 0 の方向に丸めるため、算術シフトの前に 2^k - 1 を足す
+            let bits = size as i64 * 8;
+            let sign = func.new_reg(ty);
+            let bias = func.new_reg(ty);
+            let sum = func.new_reg(ty);
+            Some(Simplified::Insts(vec![
+                Inst::Bin {
+                    op: BinOp::AShr,
+                    dst: sign,
+                    lhs,
+                    rhs: Operand::Imm(bits - 1),
+                },
+                Inst::Bin {
+                    op: BinOp::LShr,
+                    dst: bias,
+                    lhs: Operand::Reg(sign),
+                    rhs: Operand::Imm(bits - k),
+                },
+                Inst::Bin {
+                    op: BinOp::Add,
+                    dst: sum,
+                    lhs,
+                    rhs: Operand::Reg(bias),
+                },
+                Inst::Bin {
+                    op: BinOp::AShr,
+                    dst,
+                    lhs: Operand::Reg(sum),
+                    rhs: Operand::Imm(k),
+                },
+            ]))
+        }
+        _ => None,
+    }
+}
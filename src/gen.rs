@@ -1,345 +1,646 @@
+use crate::asm::{self, AluOp, Cond, Gpr, Mem, ShiftOp, Syntax};
+use crate::assembler;
 use crate::error;
-use crate::parser::get_type_size;
-use crate::parser::{BinaryOpKind, ComparisonOpKind, Node, NodeKind, TypeKind, UnaryOpKind};
-
-fn gen_lval(node: &Node, id: &mut i32) {
-    match &node.kind {
-        NodeKind::LVar(lvar) => {
-            println!("  mov rax, rbp");
-            println!("  sub rax, {}", lvar.offset);
-            println!("  push rax");
+use crate::ir::{
+    BinOp, BlockId, CastKind, CmpOp, Function, Inst, Module, Operand, Reg, Terminator, Ty,
+    Visibility,
+};
+use crate::jit;
+use crate::linker;
+use crate::options::{Options, RelocModel};
+use crate::peephole;
+use crate::regalloc::{self, Allocation};
+use crate::tailcall;
+use std::collections::HashSet;
+use std::io::Write;
+
+const ARG_REGS64: [Gpr; 6] = [Gpr::Rdi, Gpr::Rsi, Gpr::Rdx, Gpr::Rcx, Gpr::R8, Gpr::R9];
+
+// 割り当てに使う物理レジスタ
+// rax, rcx, rdx と引数レジスタは命令の選択で一時的に使うので割り当てない
+#[derive(Clone, Copy)]
+struct PhysReg {
+    reg: Gpr,
+    callee_saved: bool,
+}
+
+const REGS: [PhysReg; 7] = [
+    PhysReg {
+        reg: Gpr::R10,
+        callee_saved: false,
+    },
+    PhysReg {
+        reg: Gpr::R11,
+        callee_saved: false,
+    },
+    PhysReg {
+        reg: Gpr::Rbx,
+        callee_saved: true,
+    },
+    PhysReg {
+        reg: Gpr::R12,
+        callee_saved: true,
+    },
+    PhysReg {
+        reg: Gpr::R13,
+        callee_saved: true,
+    },
+    PhysReg {
+        reg: Gpr::R14,
+        callee_saved: true,
+    },
+    PhysReg {
+        reg: Gpr::R15,
+        callee_saved: true,
+    },
+];
+
+// 中間表現から x86-64 のアセンブリ (-c ではオブジェクトファイル、-static では実行ファイル) を出力する
+// --run ではアセンブルしたものをその場で実行する
+pub fn gen(module: &Module, opts: &Options) {
+    let linkage = Linkage::new(module, opts.reloc_model);
+    let funcs: Vec<Vec<asm::Inst>> = module
+        .functions
+        .iter()
+        .map(|func| {
+            let mut insts = Emitter::new(func, &linkage, opts.optimize_sibling_calls).function();
+            if opts.opt_level >= 1 {
+                peephole::optimize(&mut insts);
+            }
+            insts
+        })
+        .collect();
+    if opts.emit_obj || opts.static_link || opts.run {
+        let obj = assembler::assemble(module, &funcs);
+        if opts.run {
+            match jit::run(&obj, &opts.run_args) {
+                Ok(status) => std::process::exit(status),
+                Err(msg) => error::error(&msg),
+            }
+        }
+        let out = if opts.static_link {
+            linker::link(obj, &opts.objects).unwrap_or_else(|msg| error::error(&msg))
+        } else {
+            obj.encode()
+        };
+        std::io::stdout().write_all(&out).expect("出力に失敗しました");
+        return;
+    }
+
+    if opts.asm_syntax == Syntax::Intel {
+        println!("  .intel_syntax noprefix");
+    }
+
+    if !module.strings.is_empty() {
+        println!("  .section .rodata");
+    }
+    for (i, lit) in module.strings.iter().enumerate() {
+        println!(".LC{}:", i);
+        println!("  .string \"{}\"", escape(lit));
+    }
+
+    for global in module.globals.iter() {
+        println!("  .bss");
+        println!("  .global {}", global.name);
+        if global.visibility != Visibility::Default {
+            println!("  .{} {}", global.visibility.name(), global.name);
         }
-        // デリファレンスの場合は右辺値を生成
-        // genを呼んでアドレスをraxに詰める
-        NodeKind::UnaryOp(op) => {
-            if *op == UnaryOpKind::Deref {
-                gen(node.lhs.as_ref().unwrap(), id);
+        println!("{}:", global.name);
+        println!("  .zero {}\n", global.size); // 初期化はサポートしてないので0埋め
+    }
+
+    for (func, insts) in module.functions.iter().zip(funcs.iter()) {
+        println!("  .text");
+        if !func.is_static {
+            println!("  .global {}", func.name);
+            if func.visibility != Visibility::Default {
+                println!("  .{} {}", func.visibility.name(), func.name);
             }
         }
-        NodeKind::GVar(gvar) => {
-            println!("  lea rax, {}[rip]", gvar.name);
-            println!("  push rax");
+        for inst in insts.iter() {
+            println!("{}", inst.display(opts.asm_syntax, func.layout));
         }
-        _ => {
-            error::error("代入の左辺値が変数ではありません");
+    }
+}
+
+// シンボルのアドレスの求め方と呼び出し方
+// -fPIC では、ほかのモジュールの定義で置き換えられるシンボルを GOT と PLT を経由して参照する
+struct Linkage<'a> {
+    reloc_model: RelocModel,
+    local: HashSet<&'a str>, // 置き換えられない (このモジュールの定義を直接参照できる) シンボル
+}
+
+impl<'a> Linkage<'a> {
+    fn new(module: &'a Module, reloc_model: RelocModel) -> Self {
+        // protected な変数は実行ファイルにコピーされることがあるので GOT を経由する
+        let globals = module
+            .globals
+            .iter()
+            .filter(|global| global.visibility == Visibility::Hidden)
+            .map(|global| global.name.as_str());
+        let functions = module
+            .functions
+            .iter()
+            .filter(|func| func.is_static || func.visibility != Visibility::Default)
+            .map(|func| func.name.as_str());
+        Linkage {
+            reloc_model,
+            local: globals.chain(functions).collect(),
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        name.starts_with(".L") || self.local.contains(name)
+    }
+
+    // 呼び出し先やジャンプ先の名前
+    fn callee(&self, name: &str) -> String {
+        if self.reloc_model == RelocModel::Pic && !self.is_local(name) {
+            format!("{}@PLT", name)
+        } else {
+            name.to_string()
         }
     }
 }
 
-pub fn gen(node: &Node, id: &mut i32) {
-    match &node.kind {
-        NodeKind::Num(val) => {
-            println!("  push {}", val);
-            return;
+// 仮想レジスタはレジスタ割り当ての結果に従って物理レジスタかスピル領域に置く
+// rax, rdi, rcx, rdx は命令の選択で一時的に使う
+struct Emitter<'a> {
+    func: &'a Function,
+    linkage: &'a Linkage<'a>,
+    alloc: Allocation,
+    slot_offsets: Vec<usize>,         // rbp からのオフセット
+    spill_base: usize,                // スピル領域の始まり (スロットの終わり)
+    saved_offsets: Vec<(Gpr, usize)>, // (callee-saved レジスタ, 退避先のオフセット)
+    stack_size: usize,
+    sibling_calls: bool, // 末尾呼び出しをジャンプにする
+    out: Vec<asm::Inst>,
+}
+
+// 64 ビットの命令の即値は符号拡張した 32 ビットに収まらなければならない
+fn fits_imm(op: Operand, size: usize) -> bool {
+    match op {
+        Operand::Imm(val) => size < 8 || i32::try_from(val).is_ok(),
+        Operand::Reg(_) => true,
+    }
+}
+
+fn reg(reg: Gpr, size: usize) -> asm::Operand {
+    asm::Operand::Reg(reg, size)
+}
+
+// rbp からのオフセットにあるスタック上の領域
+fn frame(offset: usize, size: usize) -> asm::Operand {
+    asm::Operand::Mem(Mem::Base(Gpr::Rbp, -(offset as i64)), size)
+}
+
+impl<'a> Emitter<'a> {
+    fn new(func: &'a Function, linkage: &'a Linkage<'a>, sibling_calls: bool) -> Self {
+        let alloc = regalloc::allocate(func, &REGS.map(|preg| preg.callee_saved));
+        let mut offset = 0;
+        let mut slot_offsets = Vec::new();
+        // スロットは宣言の順に 8 バイト境界から並べる
+        for slot in func.slots.iter() {
+            offset = (offset + slot.size).next_multiple_of(slot.align.max(8));
+            slot_offsets.push(offset);
         }
-        NodeKind::LVarDef(_) => {}
-        NodeKind::LVar(_lvar) => {
-            gen_lval(node, id);
-            println!("  pop rax");
-            if node.ty.kind == TypeKind::Char {
-                println!("  movzx rax, BYTE PTR [rax]");
-            } else {
-                println!("  mov rax, [rax]");
-            }
-            println!("  push rax");
-            return;
+        let spill_base = offset;
+        offset = alloc.spill_end(spill_base, 8);
+        let mut saved_offsets = Vec::new();
+        for preg in alloc.callee_saved.iter() {
+            offset = offset.next_multiple_of(8) + 8;
+            saved_offsets.push((REGS[*preg].reg, offset));
         }
-        NodeKind::GVarDef(gvar) => {
-            println!("  .bss");
-            println!("  .global {}", gvar.name);
-            println!("{}:", gvar.name);
-            println!("  .zero {}\n", get_type_size(&gvar.ty)); // 初期化はサポートしてないので0埋め
-            return;
+        Emitter {
+            func,
+            linkage,
+            alloc,
+            slot_offsets,
+            spill_base,
+            saved_offsets,
+            stack_size: offset.next_multiple_of(16),
+            sibling_calls: sibling_calls && tailcall::may_reuse_frame(func),
+            out: Vec::new(),
         }
-        NodeKind::GVar(gvar) => {
-            gen_lval(node, id);
-            //　配列の場合は中身を参照しない
-            // なんでGVarのときだけこの処理が必要なのかはよくわかってない (アドレッシングモードの違い？)
-            if gvar.ty.kind == TypeKind::Arr {
-                return;
-            }
-            println!("  pop rax");
-            if node.ty.kind == TypeKind::Char {
-                println!("  movzx rax, BYTE PTR [rax]");
-            } else {
-                println!("  mov rax, [rax]");
-            }
-            println!("  push rax");
-            return;
+    }
+
+    fn emit(&mut self, inst: asm::Inst) {
+        self.out.push(inst);
+    }
+
+    fn label(&self, block: BlockId) -> String {
+        format!(".L.{}.{}", self.func.name, block)
+    }
+
+    fn size(&self, reg: Reg) -> usize {
+        self.func.reg_ty(reg).size(self.func.layout)
+    }
+
+    // 仮想レジスタが置かれている物理レジスタ
+    fn phys(&self, op: Operand) -> Option<Gpr> {
+        self.alloc.phys(op, &REGS).map(|preg| preg.reg)
+    }
+
+    // オペランドを命令に書ける形にする
+    fn operand(&self, op: Operand, size: usize) -> asm::Operand {
+        match op {
+            Operand::Reg(r) => match self.phys(op) {
+                Some(preg) => reg(preg, size),
+                None => frame(self.alloc.spill_offset(r, self.spill_base, 8), size),
+            },
+            Operand::Imm(val) => asm::Operand::Imm(match size {
+                1 => val as i8 as i64,
+                4 => val as i32 as i64,
+                _ => val,
+            }),
         }
-        NodeKind::Assign => {
-            gen_lval(node.lhs.as_ref().unwrap(), id);
-            gen(node.rhs.as_ref().unwrap(), id);
-
-            println!("  pop rdi");
-            println!("  pop rax");
-            if node.lhs.as_ref().unwrap().ty.kind == TypeKind::Char {
-                println!("  mov BYTE PTR [rax], dil");
-            } else {
-                println!("  mov [rax], rdi");
+    }
+
+    // 結果を書き込むレジスタ (物理レジスタになければ rax)
+    fn target(&self, dst: Reg) -> Gpr {
+        self.phys(Operand::Reg(dst)).unwrap_or(Gpr::Rax)
+    }
+
+    // オペランドをレジスタに読み込む
+    fn load(&mut self, dst: Gpr, op: Operand, size: usize) {
+        if self.phys(op) != Some(dst) {
+            self.emit(asm::Inst::Mov(reg(dst, size), self.operand(op, size)));
+        }
+    }
+
+    // 物理レジスタにあればそのレジスタを、なければ scratch に読み込んで返す
+    fn in_reg(&mut self, op: Operand, scratch: Gpr) -> Gpr {
+        match self.phys(op) {
+            Some(preg) => preg,
+            None => {
+                self.load(scratch, op, 8);
+                scratch
             }
-            println!("  push rdi\n");
-            return;
         }
-        NodeKind::Return => {
-            gen(node.lhs.as_ref().unwrap(), id);
-            println!("  pop rax");
-            println!("  mov rsp, rbp");
-            println!("  pop rbp");
-            println!("  ret\n");
-            return;
+    }
+
+    // 即値が命令に書けなければ scratch に読み込む
+    fn source(&mut self, op: Operand, size: usize, scratch: Gpr) -> asm::Operand {
+        if fits_imm(op, size) {
+            self.operand(op, size)
+        } else {
+            self.load(scratch, op, size);
+            reg(scratch, size)
         }
-        NodeKind::If => {
-            let local_id = *id;
-            *id += 1;
-            gen(node.lhs.as_ref().unwrap(), id); // cond
-            println!("  pop rax");
-            println!("  cmp rax, 0");
-            if node.rhs.as_ref().unwrap().kind == NodeKind::Else {
-                println!("  je .Lelse{}", local_id);
-                gen(node.rhs.as_ref().unwrap().lhs.as_ref().unwrap(), id); // then
-                println!("  jmp .Lend{}", local_id);
-                println!(".Lelse{}:", local_id);
-                gen(node.rhs.as_ref().unwrap().rhs.as_ref().unwrap(), id); // else
-                println!(".Lend{}:", local_id);
-            } else {
-                println!("  je .Lend{}", local_id);
-                gen(node.rhs.as_ref().unwrap(), id); // then
-                println!(".Lend{}:", local_id);
+    }
+
+    fn store(&mut self, dst: Reg, src: Gpr) {
+        if self.phys(Operand::Reg(dst)) != Some(src) {
+            self.emit(asm::Inst::Mov(
+                self.operand(Operand::Reg(dst), 8),
+                reg(src, 8),
+            ));
+        }
+    }
+
+    fn copy(&mut self, dst: Reg, src: Operand) {
+        if let Some(preg) = self.phys(Operand::Reg(dst)) {
+            self.load(preg, src, 8);
+        } else if self.phys(src).is_some() || (matches!(src, Operand::Imm(_)) && fits_imm(src, 8)) {
+            if src != Operand::Reg(dst) {
+                self.emit(asm::Inst::Mov(
+                    self.operand(Operand::Reg(dst), 8),
+                    self.operand(src, 8),
+                ));
             }
-            return;
+        } else if src != Operand::Reg(dst) {
+            self.load(Gpr::Rax, src, 8);
+            self.store(dst, Gpr::Rax);
         }
-        NodeKind::While => {
-            let local_id = *id;
-            *id += 1;
-            println!(".Lbegin{}:", local_id);
-            gen(node.lhs.as_ref().unwrap(), id); // cond
-            println!("  pop rax");
-            println!("  cmp rax, 0");
-            println!("  je .Lend{}", local_id);
-            gen(node.rhs.as_ref().unwrap(), id); // body
-            println!("  jmp .Lbegin{}", local_id);
-            println!(".Lend{}:", local_id);
+    }
+
+    fn function(mut self) -> Vec<asm::Inst> {
+        let func = self.func;
+        self.emit(asm::Inst::Label(func.name.clone()));
+
+        // prologue
+        self.emit(asm::Inst::Push(reg(Gpr::Rbp, 8)));
+        self.emit(asm::Inst::Mov(reg(Gpr::Rbp, 8), reg(Gpr::Rsp, 8)));
+        // フレームに何も置かない関数は rsp を動かさない
+        if self.stack_size > 0 {
+            self.emit(asm::Inst::Alu(
+                AluOp::Sub,
+                reg(Gpr::Rsp, 8),
+                asm::Operand::Imm(self.stack_size as i64),
+            ));
         }
-        NodeKind::For => {
-            let local_id = *id;
-            *id += 1;
-            gen(node.lhs.as_ref().unwrap(), id); // init
-            println!(".Lbegin{}:", local_id);
-            gen(node.rhs.as_ref().unwrap().lhs.as_ref().unwrap(), id); // cond
-            println!("  pop rax");
-            println!("  cmp rax, 0");
-            println!("  je .Lend{}", local_id);
-            gen(
-                node.rhs
-                    .as_ref()
-                    .unwrap()
-                    .rhs
-                    .as_ref()
-                    .unwrap()
-                    .rhs
-                    .as_ref()
-                    .unwrap(),
-                id,
-            ); // body
-            gen(
-                node.rhs
-                    .as_ref()
-                    .unwrap()
-                    .rhs
-                    .as_ref()
-                    .unwrap()
-                    .lhs
-                    .as_ref()
-                    .unwrap(),
-                id,
-            ); // inc
-            println!("  jmp .Lbegin{}", local_id);
-            println!(".Lend{}:", local_id);
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.emit(asm::Inst::Mov(frame(offset, 8), reg(preg, 8)));
         }
-        NodeKind::Block(stmts) => {
-            for stmt in stmts.iter() {
-                gen(stmt, id);
+        for (i, param) in func.params.iter().enumerate() {
+            if self.alloc.locs[param.0].is_some() {
+                self.store(*param, ARG_REGS64[i]);
             }
         }
-        NodeKind::Fncall(func, args) => {
-            const REGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
 
-            for (i, arg) in args.iter().enumerate() {
-                gen(arg, id);
-                println!("  pop {} # set {}-th argument", REGS[i], i);
+        for (i, block) in func.blocks.iter().enumerate() {
+            self.emit(asm::Inst::Label(self.label(BlockId(i))));
+            if let Some(Inst::Call { name, args, .. }) = tailcall::tail_call(func, block)
+                .filter(|_| self.sibling_calls)
+            {
+                for inst in block.insts[..block.insts.len() - 1].iter() {
+                    self.inst(inst);
+                }
+                self.sibling_call(name, args);
+                continue;
             }
-            println!("  mov rax, {}", args.len());
-
-            println!("  mov al, 0");
-
-            // rspの位置を調整
-            // r10に調整分を保存
-            println!("  mov r10, rsp");
-            println!("  sub r10, 8");
-            println!("  and r10, 15 # save offset to r10");
-            println!("  sub rsp, r10 # align rsp to be divisible by 16");
-            println!("  push r10 # save offset to stack");
-            println!("  call {}", func.name);
-            println!("  pop r10 # restore offset from stack");
-            println!("  add rsp, r10 # adjust stack pointer after call");
-
-            println!("  push rax # rax has return value after call");
+            for inst in block.insts.iter() {
+                self.inst(inst);
+            }
+            self.terminator(&block.term, BlockId(i + 1));
         }
-        NodeKind::Fndef(func, args) => {
-            const REGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+        self.out
+    }
 
-            println!("  .text");
-            println!("  .global {}", func.name);
-            println!("{}:", func.name);
-
-            // prologue
-            println!("  push rbp");
-            println!("  mov rbp, rsp # save base pointer");
-            println!(
-                "  sub rsp, {} # make spaces for local variables\n",
-                func.stack_size
-            );
-
-            // save arguments to local variables
-            for (i, arg) in args.iter().enumerate() {
-                let mut offset = 0;
-                match &arg.kind {
-                    NodeKind::LVar(lvar) => {
-                        offset = lvar.offset;
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => self.copy(*dst, *src),
+            // phi はコード生成の前にコピーに置き換えてある
+            Inst::Phi { .. } => unreachable!(),
+            Inst::Bin { op, dst, lhs, rhs } => self.bin(*op, *dst, *lhs, *rhs),
+            Inst::Cmp {
+                op,
+                ty,
+                dst,
+                lhs,
+                rhs,
+            } => {
+                let size = ty.size(self.func.layout);
+                let lhs = match self.phys(*lhs) {
+                    Some(preg) => reg(preg, size),
+                    None => {
+                        self.load(Gpr::Rax, *lhs, size);
+                        reg(Gpr::Rax, size)
                     }
-                    _ => {
-                        error::error("関数の引数が変数ではありません");
+                };
+                let rhs = self.source(*rhs, size, Gpr::Rdi);
+                self.emit(asm::Inst::Alu(AluOp::Cmp, lhs, rhs));
+                let cond = match op {
+                    CmpOp::Eq => Cond::E,
+                    CmpOp::Ne => Cond::Ne,
+                    CmpOp::Slt => Cond::L,
+                    CmpOp::Sle => Cond::Le,
+                    CmpOp::Sgt => Cond::G,
+                    CmpOp::Sge => Cond::Ge,
+                    CmpOp::Ult => Cond::B,
+                    CmpOp::Ule => Cond::Be,
+                    CmpOp::Ugt => Cond::A,
+                    CmpOp::Uge => Cond::Ae,
+                };
+                let target = self.target(*dst);
+                self.emit(asm::Inst::Set(cond, reg(Gpr::Rax, 1)));
+                self.emit(asm::Inst::Movzx(reg(target, 4), reg(Gpr::Rax, 1)));
+                self.store(*dst, target);
+            }
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                signed,
+            } => {
+                let addr = Mem::Base(self.in_reg(*addr, Gpr::Rax), 0);
+                let target = self.target(*dst);
+                self.emit(match (ty, signed) {
+                    (Ty::I8, true) => {
+                        asm::Inst::Movsx(reg(target, 4), asm::Operand::Mem(addr, 1))
+                    }
+                    (Ty::I8, false) => {
+                        asm::Inst::Movzx(reg(target, 4), asm::Operand::Mem(addr, 1))
+                    }
+                    (Ty::I32, _) => asm::Inst::Mov(reg(target, 4), asm::Operand::Mem(addr, 4)),
+                    _ => asm::Inst::Mov(reg(target, 8), asm::Operand::Mem(addr, 8)),
+                });
+                self.store(*dst, target);
+            }
+            Inst::Store { addr, val, ty } => {
+                let addr = Mem::Base(self.in_reg(*addr, Gpr::Rax), 0);
+                let size = ty.size(self.func.layout);
+                let val = if self.phys(*val).is_some()
+                    || (matches!(val, Operand::Imm(_)) && fits_imm(*val, size))
+                {
+                    self.operand(*val, size)
+                } else {
+                    self.load(Gpr::Rdi, *val, 8);
+                    reg(Gpr::Rdi, size)
+                };
+                self.emit(asm::Inst::Mov(asm::Operand::Mem(addr, size), val));
+            }
+            Inst::LocalAddr { dst, slot } => {
+                let target = self.target(*dst);
+                let offset = self.slot_offsets[slot.0] as i64;
+                self.emit(asm::Inst::Lea(target, Mem::Base(Gpr::Rbp, -offset)));
+                self.store(*dst, target);
+            }
+            Inst::GlobalAddr { dst, name } => {
+                let target = self.target(*dst);
+                self.emit(match self.linkage.reloc_model {
+                    RelocModel::Static => asm::Inst::Lea(target, Mem::Abs(name.clone())),
+                    RelocModel::Pic if !self.linkage.is_local(name) => {
+                        asm::Inst::Mov(reg(target, 8), asm::Operand::Mem(Mem::Got(name.clone()), 8))
                     }
+                    _ => asm::Inst::Lea(target, Mem::Rip(name.clone())),
+                });
+                self.store(*dst, target);
+            }
+            Inst::Call { dst, name, args } => {
+                self.args(args);
+                self.emit(asm::Inst::Call(self.linkage.callee(name)));
+                if let Some(dst) = dst {
+                    self.store(*dst, Gpr::Rax);
                 }
-                println!("  mov [rbp-{}], {} # push argument", offset, REGS[i]);
             }
+            Inst::Cast { dst, src, kind } => {
+                let src = self.in_reg(*src, Gpr::Rax);
+                let target = self.target(*dst);
+                let wide = self.size(*dst) == 8;
+                self.emit(match kind {
+                    CastKind::Sext(Ty::I8) => {
+                        asm::Inst::Movsx(reg(target, if wide { 8 } else { 4 }), reg(src, 1))
+                    }
+                    CastKind::Zext(Ty::I8) => asm::Inst::Movzx(reg(target, 4), reg(src, 1)),
+                    CastKind::Sext(_) => asm::Inst::Movsx(reg(target, 8), reg(src, 4)),
+                    CastKind::Zext(_) | CastKind::Trunc => {
+                        asm::Inst::Mov(reg(target, 4), reg(src, 4))
+                    }
+                });
+                self.store(*dst, target);
+            }
+            Inst::Alloca { dst, size } => {
+                self.load(Gpr::Rax, *size, 8);
+                let rax = reg(Gpr::Rax, 8);
+                self.emit(asm::Inst::Alu(AluOp::Add, rax.clone(), asm::Operand::Imm(15)));
+                self.emit(asm::Inst::Alu(AluOp::And, rax.clone(), asm::Operand::Imm(-16)));
+                self.emit(asm::Inst::Alu(AluOp::Sub, reg(Gpr::Rsp, 8), rax));
+                self.store(*dst, Gpr::Rsp);
+            }
+            Inst::StackSave { dst } => self.store(*dst, Gpr::Rsp),
+            Inst::StackRestore { src } => self.load(Gpr::Rsp, *src, 8),
+        }
+    }
 
-            gen(node.rhs.as_ref().unwrap(), id);
-            println!("  pop rax");
+    fn args(&mut self, args: &[Operand]) {
+        for (i, arg) in args.iter().enumerate() {
+            match arg {
+                // int の引数は符号拡張して渡す
+                Operand::Reg(r) if self.func.reg_ty(*r) == Ty::I32 => self.emit(
+                    asm::Inst::Movsx(reg(ARG_REGS64[i], 8), self.operand(*arg, 4)),
+                ),
+                _ => self.load(ARG_REGS64[i], *arg, 8),
+            }
+        }
+        // 可変長引数の関数のために、ベクタレジスタの引数の個数を 0 にする
+        self.emit(asm::Inst::Mov(reg(Gpr::Rax, 4), asm::Operand::Imm(0)));
+    }
+
+    // 呼び出した結果をそのまま返すなら、フレームを片付けてから呼び出し先へ飛ぶ
+    // 呼び出し先は呼び出し元の戻り先へ直接戻る
+    // 引数はすべてレジスタで渡すので、スタックに積む引数の領域は要らない
+    fn sibling_call(&mut self, name: &str, args: &[Operand]) {
+        self.args(args);
+        self.epilogue();
+        self.emit(asm::Inst::Jmp(self.linkage.callee(name)));
+    }
 
-            // epilogue
-            println!("\n  mov rsp, rbp # restore stack pointer");
-            println!("  pop rbp # discard base pointer");
-            println!("  ret");
-            return;
+    fn epilogue(&mut self) {
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.emit(asm::Inst::Mov(reg(preg, 8), frame(offset, 8)));
         }
-        NodeKind::UnaryOp(op) => match op {
-            UnaryOpKind::Ref => {
-                gen_lval(node.lhs.as_ref().unwrap(), id);
-                return;
-            }
-            UnaryOpKind::Deref => {
-                gen(node.lhs.as_ref().unwrap(), id);
-                if node.ty.kind == TypeKind::Arr {
-                    return;
-                }
-                println!("  pop rax");
-                if node.ty.kind == TypeKind::Char {
-                    println!("  movzx rax, BYTE PTR [rax]");
+        self.emit(asm::Inst::Mov(reg(Gpr::Rsp, 8), reg(Gpr::Rbp, 8)));
+        self.emit(asm::Inst::Pop(reg(Gpr::Rbp, 8)));
+    }
+
+    fn bin(&mut self, op: BinOp, dst: Reg, lhs: Operand, rhs: Operand) {
+        let size = self.size(dst);
+        match op {
+            BinOp::SDiv | BinOp::UDiv => {
+                self.load(Gpr::Rax, lhs, size);
+                // 除数に即値は書けない
+                let divisor = match rhs {
+                    Operand::Imm(_) => {
+                        self.load(Gpr::Rdi, rhs, size);
+                        reg(Gpr::Rdi, size)
+                    }
+                    Operand::Reg(_) => self.operand(rhs, size),
+                };
+                if op == BinOp::SDiv {
+                    self.emit(if size == 8 {
+                        asm::Inst::Cqo
+                    } else {
+                        asm::Inst::Cdq
+                    });
+                    self.emit(asm::Inst::Idiv(divisor));
                 } else {
-                    println!("  mov rax, [rax]");
+                    let edx = reg(Gpr::Rdx, 4);
+                    self.emit(asm::Inst::Alu(AluOp::Xor, edx.clone(), edx));
+                    self.emit(asm::Inst::Div(divisor));
                 }
-                println!("  push rax");
+                self.store(dst, Gpr::Rax);
                 return;
             }
-        },
-        NodeKind::Strlit(lit) => {
-            println!("  lea rax, .LC{}[rip]", lit.idx);
-            println!("  push rax");
-            return;
+            // シフト量は cl に置く
+            BinOp::Shl | BinOp::LShr | BinOp::AShr
+                if !matches!(rhs, Operand::Imm(_)) || !fits_imm(rhs, size) =>
+            {
+                self.load(Gpr::Rcx, rhs, 8);
+            }
+            _ => {}
         }
-        _ => {
-            gen(node.lhs.as_ref().unwrap(), id);
-            gen(node.rhs.as_ref().unwrap(), id);
-
-            println!("  pop rdi");
-            println!("  pop rax");
-
-            match &node.kind {
-                NodeKind::BinaryOp(op) => match op {
-                    BinaryOpKind::Add => {
-                        if matches!(node.ty.kind, TypeKind::Ptr | TypeKind::Arr) {
-                            if matches!(
-                                node.lhs.as_ref().unwrap().ty.kind,
-                                TypeKind::Ptr | TypeKind::Arr
-                            ) {
-                                println!(
-                                    "  imul rdi, {}",
-                                    get_type_size(&node.ty.ptr_to.as_ref().unwrap())
-                                );
-                            } else {
-                                println!(
-                                    "  imul rax, {}",
-                                    get_type_size(&node.ty.ptr_to.as_ref().unwrap())
-                                );
-                            }
-                        }
-
-                        println!("  add rax, rdi");
-                    }
-                    BinaryOpKind::Sub => {
-                        if matches!(node.ty.kind, TypeKind::Ptr | TypeKind::Arr) {
-                            if matches!(
-                                node.lhs.as_ref().unwrap().ty.kind,
-                                TypeKind::Ptr | TypeKind::Arr
-                            ) {
-                                println!(
-                                    "  imul rdi, {}",
-                                    get_type_size(&node.ty.ptr_to.as_ref().unwrap())
-                                );
-                            } else {
-                                println!(
-                                    "  imul rax, {}",
-                                    get_type_size(&node.ty.ptr_to.as_ref().unwrap())
-                                );
-                            }
-                        }
-
-                        println!("  sub rax, rdi");
-                    }
-                    BinaryOpKind::Mul => println!("  imul rax, rdi"),
-                    BinaryOpKind::Div => {
-                        println!("  cqo");
-                        println!("  idiv rdi");
-                    }
-                },
-                NodeKind::Comparison(op) => match op {
-                    ComparisonOpKind::Eq => {
-                        println!("  cmp rax, rdi");
-                        println!("  sete al");
-                        println!("  movzb rax, al");
-                    }
-                    ComparisonOpKind::Nq => {
-                        println!("  cmp rax, rdi");
-                        println!("  setne al");
-                        println!("  movzb rax, al");
-                    }
-                    ComparisonOpKind::Lt => {
-                        println!("  cmp rax, rdi");
-                        println!("  setl al");
-                        println!("  movzb rax, al");
-                    }
-                    ComparisonOpKind::Le => {
-                        println!("  cmp rax, rdi");
-                        println!("  setle al");
-                        println!("  movzb rax, al");
-                    }
-                    ComparisonOpKind::Gt => {
-                        println!("  cmp rax, rdi");
-                        println!("  setg al");
-                        println!("  movzb rax, al");
-                    }
-                    ComparisonOpKind::Ge => {
-                        println!("  cmp rax, rdi");
-                        println!("  setge al");
-                        println!("  movzb rax, al");
+
+        // 右辺と同じレジスタに結果を置くときは rax で計算する
+        let target = match self.phys(Operand::Reg(dst)) {
+            Some(preg) if self.phys(rhs) != Some(preg) => preg,
+            _ => Gpr::Rax,
+        };
+        let imm = match rhs {
+            Operand::Imm(val) if fits_imm(rhs, size) => Some(val),
+            _ => None,
+        };
+        let rhs = self.source(rhs, size, Gpr::Rdi);
+        self.load(target, lhs, size);
+        let name = reg(target, size);
+        self.emit(match op {
+            BinOp::Add => asm::Inst::Alu(AluOp::Add, name, rhs),
+            BinOp::Sub => asm::Inst::Alu(AluOp::Sub, name, rhs),
+            BinOp::Mul => match rhs {
+                asm::Operand::Imm(val) => asm::Inst::Imul3(name.clone(), name, val),
+                _ => asm::Inst::Imul(name, rhs),
+            },
+            BinOp::And => asm::Inst::Alu(AluOp::And, name, rhs),
+            BinOp::Or => asm::Inst::Alu(AluOp::Or, name, rhs),
+            BinOp::Shl | BinOp::LShr | BinOp::AShr => {
+                let op = match op {
+                    BinOp::Shl => ShiftOp::Shl,
+                    BinOp::LShr => ShiftOp::Shr,
+                    _ => ShiftOp::Sar,
+                };
+                let amount = match imm {
+                    Some(amount) => asm::Operand::Imm(amount & 63),
+                    None => reg(Gpr::Rcx, 1),
+                };
+                asm::Inst::Shift(op, name, amount)
+            }
+            BinOp::SDiv | BinOp::UDiv => unreachable!(),
+        });
+        self.store(dst, target);
+    }
+
+    fn terminator(&mut self, term: &Terminator, next: BlockId) {
+        match term {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    self.emit(asm::Inst::Jmp(self.label(*target)));
+                }
+            }
+            Terminator::Branch { cond, then, els } => {
+                let size = match cond {
+                    Operand::Reg(r) => self.size(*r),
+                    Operand::Imm(_) => 8,
+                };
+                let cond = match cond {
+                    Operand::Imm(_) => {
+                        self.load(Gpr::Rax, *cond, 8);
+                        reg(Gpr::Rax, 8)
                     }
-                },
-                _ => unreachable!(),
+                    Operand::Reg(_) => self.operand(*cond, size),
+                };
+                self.emit(asm::Inst::Alu(AluOp::Cmp, cond, asm::Operand::Imm(0)));
+                // 条件が成り立つ側が次のブロックなら、成り立たないときだけ飛ぶ
+                if *then == next {
+                    self.emit(asm::Inst::Jcc(Cond::E, self.label(*els)));
+                    return;
+                }
+                self.emit(asm::Inst::Jcc(Cond::Ne, self.label(*then)));
+                if *els != next {
+                    self.emit(asm::Inst::Jmp(self.label(*els)));
+                }
+            }
+            Terminator::Ret(val) => {
+                if let Some(val) = val {
+                    self.load(Gpr::Rax, *val, 8);
+                }
+                self.epilogue();
+                self.emit(asm::Inst::Ret);
             }
+            Terminator::Unreachable => {}
+        }
+    }
+}
 
-            println!("  push rax");
+// アセンブラの .string に渡せるようにバイト列をエスケープする
+pub fn escape(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for &b in bytes {
+        match b {
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            0x20..=0x7e => s.push(b as char),
+            _ => s.push_str(&format!("\\{:03o}", b)),
         }
     }
+    s
 }
@@ -0,0 +1,256 @@
+use crate::alias::AliasInfo;
+use crate::cfg::{self, DomTree};
+use crate::ir::{BinOp, BlockId, CastKind, CmpOp, Function, Inst, Operand, Reg, SlotId, Ty};
+use crate::pass::PassContext;
+use crate::ssa;
+use std::collections::HashMap;
+
+// 共通部分式の削除
+// 前に計算したのと同じ値になる式は計算し直さず、前の結果を使う
+// cse は基本ブロックの中だけを、gvn は支配木をたどって支配するブロックの結果も見る
+
+// 値の番号付けに使う式。オペランドは置き換えを済ませたもの
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Expr {
+    Bin(BinOp, Ty, Operand, Operand),
+    Cmp(CmpOp, Ty, Operand, Operand),
+    Cast(CastKind, Ty, Operand),
+    LocalAddr(SlotId),
+    GlobalAddr(String),
+    // 同じメモリの世代の間だけ同じ値を読む
+    Load {
+        addr: Operand,
+        ty: Ty,
+        signed: bool,
+        dst: Ty,
+        memory: usize,
+    },
+}
+
+struct Table {
+    exprs: HashMap<Expr, Operand>,
+    undo: Vec<(Expr, Option<Operand>)>, // スコープを抜けるときに戻す
+    replace: HashMap<Reg, Operand>,
+    memory: usize,      // 今のメモリの世代
+    generations: usize, // これまでに作った世代の数
+    alias: AliasInfo,
+}
+
+// 基本ブロックの中の共通部分式の削除
+pub fn cse(func: &mut Function, ctx: &PassContext) {
+    let mut table = Table::new(func, ctx.strict_aliasing);
+    for i in 0..func.blocks.len() {
+        let scope = table.undo.len();
+        table.new_memory();
+        table.number(func, BlockId(i));
+        table.restore(scope);
+    }
+    ssa::replace_uses(func, &table.replace);
+}
+
+// 支配木による大域的な値の番号付け
+pub fn gvn(func: &mut Function, ctx: &PassContext) {
+    let dom = DomTree::new(func);
+    let preds = cfg::predecessors(func);
+    let mut table = Table::new(func, ctx.strict_aliasing);
+    table.visit(func, &dom, &preds, BlockId(0));
+    ssa::replace_uses(func, &table.replace);
+}
+
+impl Table {
+    fn new(func: &Function, strict_aliasing: bool) -> Self {
+        Table {
+            exprs: HashMap::new(),
+            undo: Vec::new(),
+            replace: HashMap::new(),
+            memory: 0,
+            generations: 1,
+            alias: AliasInfo::new(func, strict_aliasing),
+        }
+    }
+
+    fn visit(
+        &mut self,
+        func: &mut Function,
+        dom: &DomTree,
+        preds: &[Vec<BlockId>],
+        block: BlockId,
+    ) {
+        let scope = self.undo.len();
+        let memory = self.memory;
+        // 合流するブロックには、他の経路でメモリを書き換えてから来るかもしれない
+        if preds[block.0].len() != 1 {
+            self.new_memory();
+        }
+        self.number(func, block);
+        for child in dom.children(block).to_vec() {
+            self.visit(func, dom, preds, child);
+        }
+        self.restore(scope);
+        self.memory = memory;
+    }
+
+    fn new_memory(&mut self) {
+        self.memory = self.generations;
+        self.generations += 1;
+    }
+
+    // 新しい世代に移り、inst が書き換えない場所から読んだ値だけを引き継ぐ
+    fn clobber(&mut self, inst: &Inst) {
+        let old = self.memory;
+        self.new_memory();
+        let kept: Vec<(Expr, Operand)> = self
+            .exprs
+            .iter()
+            .filter_map(|(expr, val)| match expr {
+                Expr::Load {
+                    addr,
+                    ty,
+                    signed,
+                    dst,
+                    memory,
+                } if *memory == old && !self.alias.clobbers(inst, *addr, *ty) => {
+                    let load = Expr::Load {
+                        addr: *addr,
+                        ty: *ty,
+                        signed: *signed,
+                        dst: *dst,
+                        memory: self.memory,
+                    };
+                    Some((load, *val))
+                }
+                _ => None,
+            })
+            .collect();
+        for (expr, val) in kept {
+            self.insert(expr, val);
+        }
+    }
+
+    fn insert(&mut self, expr: Expr, val: Operand) {
+        let old = self.exprs.insert(expr.clone(), val);
+        self.undo.push((expr, old));
+    }
+
+    fn restore(&mut self, scope: usize) {
+        while self.undo.len() > scope {
+            let (expr, old) = self.undo.pop().unwrap();
+            match old {
+                Some(val) => self.exprs.insert(expr, val),
+                None => self.exprs.remove(&expr),
+            };
+        }
+    }
+
+    // ブロックの命令を順に見て、前と同じ値になる命令を取り除く
+    fn number(&mut self, func: &mut Function, block: BlockId) {
+        let mut kept = Vec::new();
+        for mut inst in std::mem::take(&mut func.blocks[block.0].insts) {
+            for op in inst.operands_mut() {
+                *op = ssa::resolve(&self.replace, *op);
+            }
+            if let Some(expr) = self.expr(func, &inst) {
+                let dst = inst.dst().unwrap();
+                if let Some(val) = self.exprs.get(&expr) {
+                    self.replace.insert(dst, *val);
+                    continue;
+                }
+                self.insert(expr, Operand::Reg(dst));
+            }
+            if clobbers_memory(&inst) {
+                self.clobber(&inst);
+            }
+            // 書き込んだ値はそのまま読み出せる (拡張がいらない場合だけ)
+            if let Inst::Store { addr, val, ty } = inst {
+                let same_ty = match val {
+                    Operand::Reg(reg) => func.reg_ty(reg) == ty,
+                    Operand::Imm(_) => ty != Ty::I8,
+                };
+                if same_ty {
+                    let load = Expr::Load {
+                        addr,
+                        ty,
+                        signed: false,
+                        dst: ty,
+                        memory: self.memory,
+                    };
+                    self.insert(load, val);
+                }
+            }
+            kept.push(inst);
+        }
+        let block = &mut func.blocks[block.0];
+        block.insts = kept;
+        for op in block.term.operands_mut() {
+            *op = ssa::resolve(&self.replace, *op);
+        }
+    }
+
+    fn expr(&self, func: &Function, inst: &Inst) -> Option<Expr> {
+        Some(match inst {
+            Inst::Bin { op, dst, lhs, rhs } => {
+                let (lhs, rhs) = if is_commutative(*op) {
+                    order(*lhs, *rhs)
+                } else {
+                    (*lhs, *rhs)
+                };
+                Expr::Bin(*op, func.reg_ty(*dst), lhs, rhs)
+            }
+            Inst::Cmp {
+                op, ty, lhs, rhs, ..
+            } => {
+                let (lhs, rhs) = if matches!(op, CmpOp::Eq | CmpOp::Ne) {
+                    order(*lhs, *rhs)
+                } else {
+                    (*lhs, *rhs)
+                };
+                Expr::Cmp(*op, *ty, lhs, rhs)
+            }
+            Inst::Cast { dst, src, kind } => Expr::Cast(*kind, func.reg_ty(*dst), *src),
+            Inst::LocalAddr { slot, .. } => Expr::LocalAddr(*slot),
+            Inst::GlobalAddr { name, .. } => Expr::GlobalAddr(name.clone()),
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                signed,
+            } => {
+                let dst = func.reg_ty(*dst);
+                Expr::Load {
+                    addr: *addr,
+                    ty: *ty,
+                    // 拡張しないなら符号の有無は関係ない
+                    signed: *signed && dst != *ty,
+                    dst,
+                    memory: self.memory,
+                }
+            }
+            _ => return None,
+        })
+    }
+}
+
+// メモリの内容を書き換えるかもしれない命令
+fn clobbers_memory(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Store { .. } | Inst::Call { .. } | Inst::StackRestore { .. }
+    )
+}
+
+fn is_commutative(op: BinOp) -> bool {
+    matches!(op, BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or)
+}
+
+// 交換できる演算のオペランドを決まった順に並べる
+fn order(lhs: Operand, rhs: Operand) -> (Operand, Operand) {
+    let key = |op: Operand| match op {
+        Operand::Reg(reg) => (0, reg.0 as i64),
+        Operand::Imm(val) => (1, val),
+    };
+    if key(lhs) <= key(rhs) {
+        (lhs, rhs)
+    } else {
+        (rhs, lhs)
+    }
+}
@@ -0,0 +1,515 @@
+use crate::asm::{self, AluOp, Cond, Gpr, Mem, ShiftOp, Syntax};
+use crate::gen::escape;
+use crate::ir::{
+    BinOp, BlockId, CastKind, CmpOp, Function, Inst, Module, Operand, Reg, Terminator, Ty,
+};
+use crate::options::Options;
+use crate::regalloc::{self, Allocation};
+use crate::tailcall;
+
+// 割り当てに使う物理レジスタ (すべて callee-saved)
+// eax, ecx, edx は命令の選択で一時的に使うので割り当てない
+const REGS: [Gpr; 3] = [Gpr::Rbx, Gpr::Rsi, Gpr::Rdi];
+
+// 中間表現から i386 (cdecl) のアセンブリを出力する
+// 引数はすべてスタックに積み、呼び出し元が片付ける。戻り値は eax に置く
+pub fn gen(module: &Module, opts: &Options) {
+    if opts.asm_syntax == Syntax::Intel {
+        println!("  .intel_syntax noprefix");
+    }
+
+    for (i, lit) in module.strings.iter().enumerate() {
+        println!(".LC{}:", i);
+        println!("  .string \"{}\"", escape(lit));
+    }
+
+    for global in module.globals.iter() {
+        println!("  .bss");
+        println!("  .global {}", global.name);
+        println!("{}:", global.name);
+        println!("  .zero {}\n", global.size); // 初期化はサポートしてないので0埋め
+    }
+
+    for func in module.functions.iter() {
+        println!("  .text");
+        if !func.is_static {
+            println!("  .global {}", func.name);
+        }
+        // のぞき穴最適化は x86-64 の呼び出し規約を前提にしているので使わない
+        for inst in Emitter::new(func, opts.optimize_sibling_calls)
+            .function()
+            .iter()
+        {
+            println!("{}", inst.display(opts.asm_syntax, func.layout));
+        }
+    }
+}
+
+// 仮想レジスタはレジスタ割り当ての結果に従って物理レジスタかスピル領域に置く
+// eax, ecx, edx は命令の選択で一時的に使う
+struct Emitter<'a> {
+    func: &'a Function,
+    alloc: Allocation,
+    slot_offsets: Vec<usize>,         // ebp からのオフセット
+    spill_base: usize,                // スピル領域の始まり (スロットの終わり)
+    saved_offsets: Vec<(Gpr, usize)>, // (callee-saved レジスタ, 退避先のオフセット)
+    stack_size: usize,
+    sibling_calls: bool, // 末尾呼び出しをジャンプにする
+    out: Vec<asm::Inst>,
+}
+
+fn reg(reg: Gpr, size: usize) -> asm::Operand {
+    asm::Operand::Reg(reg, size)
+}
+
+// ebp からのオフセットにあるスタック上の領域
+fn frame(offset: usize, size: usize) -> asm::Operand {
+    asm::Operand::Mem(Mem::Base(Gpr::Rbp, -(offset as i64)), size)
+}
+
+// i 番目の引数が置かれている領域 (戻り先と退避した ebp の上)
+fn param(i: usize) -> asm::Operand {
+    asm::Operand::Mem(Mem::Base(Gpr::Rbp, 8 + 4 * i as i64), 4)
+}
+
+// 下位 8 ビットを名前で指せるレジスタか (esi と edi は指せない)
+fn has_byte(reg: Gpr) -> bool {
+    matches!(reg, Gpr::Rax | Gpr::Rcx | Gpr::Rdx | Gpr::Rbx)
+}
+
+impl<'a> Emitter<'a> {
+    fn new(func: &'a Function, sibling_calls: bool) -> Self {
+        let alloc = regalloc::allocate(func, &REGS.map(|_| true));
+        let mut offset = 0;
+        let mut slot_offsets = Vec::new();
+        // スロットは宣言の順に 4 バイト境界から並べる
+        for slot in func.slots.iter() {
+            offset = (offset + slot.size).next_multiple_of(slot.align.max(4));
+            slot_offsets.push(offset);
+        }
+        let spill_base = offset;
+        offset = alloc.spill_end(spill_base, 4);
+        let mut saved_offsets = Vec::new();
+        for preg in alloc.callee_saved.iter() {
+            offset = offset.next_multiple_of(4) + 4;
+            saved_offsets.push((REGS[*preg], offset));
+        }
+        Emitter {
+            func,
+            alloc,
+            slot_offsets,
+            spill_base,
+            saved_offsets,
+            // 戻り先と ebp を積んだ後で、呼び出すときに esp が 16 バイト境界に揃うようにする
+            stack_size: (offset + 8).next_multiple_of(16) - 8,
+            sibling_calls: sibling_calls && tailcall::may_reuse_frame(func),
+            out: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, inst: asm::Inst) {
+        self.out.push(inst);
+    }
+
+    fn label(&self, block: BlockId) -> String {
+        format!(".L.{}.{}", self.func.name, block)
+    }
+
+    fn size(&self, reg: Reg) -> usize {
+        self.func.reg_ty(reg).size(self.func.layout)
+    }
+
+    // 仮想レジスタが置かれている物理レジスタ
+    fn phys(&self, op: Operand) -> Option<Gpr> {
+        self.alloc.phys(op, &REGS)
+    }
+
+    // オペランドを命令に書ける形にする
+    fn operand(&self, op: Operand, size: usize) -> asm::Operand {
+        match op {
+            Operand::Reg(r) => match self.phys(op) {
+                Some(preg) => reg(preg, size),
+                None => frame(self.alloc.spill_offset(r, self.spill_base, 4), size),
+            },
+            Operand::Imm(val) => asm::Operand::Imm(match size {
+                1 => val as i8 as i64,
+                _ => val as i32 as i64,
+            }),
+        }
+    }
+
+    // 結果を書き込むレジスタ (物理レジスタになければ eax)
+    fn target(&self, dst: Reg) -> Gpr {
+        self.phys(Operand::Reg(dst)).unwrap_or(Gpr::Rax)
+    }
+
+    // オペランドをレジスタに読み込む
+    fn load(&mut self, dst: Gpr, op: Operand) {
+        if self.phys(op) != Some(dst) {
+            self.emit(asm::Inst::Mov(reg(dst, 4), self.operand(op, 4)));
+        }
+    }
+
+    // 物理レジスタにあればそのレジスタを、なければ scratch に読み込んで返す
+    fn in_reg(&mut self, op: Operand, scratch: Gpr) -> Gpr {
+        match self.phys(op) {
+            Some(preg) => preg,
+            None => {
+                self.load(scratch, op);
+                scratch
+            }
+        }
+    }
+
+    // 物理レジスタか即値ならそのまま、スピルしていれば scratch に読み込んで返す
+    // メモリには一度に一つのオペランドしか書けない
+    fn source(&mut self, op: Operand, size: usize, scratch: Gpr) -> asm::Operand {
+        match (op, self.phys(op)) {
+            (Operand::Imm(_), _) | (_, Some(_)) => self.operand(op, size),
+            _ => {
+                self.load(scratch, op);
+                reg(scratch, size)
+            }
+        }
+    }
+
+    fn store(&mut self, dst: Reg, src: Gpr) {
+        if self.phys(Operand::Reg(dst)) != Some(src) {
+            self.emit(asm::Inst::Mov(
+                self.operand(Operand::Reg(dst), 4),
+                reg(src, 4),
+            ));
+        }
+    }
+
+    fn copy(&mut self, dst: Reg, src: Operand) {
+        if src == Operand::Reg(dst) {
+            return;
+        }
+        match self.phys(Operand::Reg(dst)) {
+            Some(preg) => self.load(preg, src),
+            None => {
+                let src = self.source(src, 4, Gpr::Rax);
+                self.emit(asm::Inst::Mov(self.operand(Operand::Reg(dst), 4), src));
+            }
+        }
+    }
+
+    fn function(mut self) -> Vec<asm::Inst> {
+        let func = self.func;
+        self.emit(asm::Inst::Label(func.name.clone()));
+
+        // prologue
+        self.emit(asm::Inst::Push(reg(Gpr::Rbp, 4)));
+        self.emit(asm::Inst::Mov(reg(Gpr::Rbp, 4), reg(Gpr::Rsp, 4)));
+        self.emit(asm::Inst::Alu(
+            AluOp::Sub,
+            reg(Gpr::Rsp, 4),
+            asm::Operand::Imm(self.stack_size as i64),
+        ));
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.emit(asm::Inst::Mov(frame(offset, 4), reg(preg, 4)));
+        }
+        // スタックで受け取った引数を割り当てた場所に移す
+        for (i, param_reg) in func.params.iter().enumerate() {
+            if self.alloc.locs[param_reg.0].is_none() {
+                continue;
+            }
+            let target = self.target(*param_reg);
+            self.emit(asm::Inst::Mov(reg(target, 4), param(i)));
+            self.store(*param_reg, target);
+        }
+
+        for (i, block) in func.blocks.iter().enumerate() {
+            self.emit(asm::Inst::Label(self.label(BlockId(i))));
+            // 自分が受け取った引数の領域に収まる呼び出しだけをジャンプにする
+            if let Some(Inst::Call { name, args, .. }) = tailcall::tail_call(func, block)
+                .filter(|_| self.sibling_calls)
+                .filter(|call| matches!(call, Inst::Call { args, .. } if args.len() <= func.params.len()))
+            {
+                for inst in block.insts[..block.insts.len() - 1].iter() {
+                    self.inst(inst);
+                }
+                self.sibling_call(name, args);
+                continue;
+            }
+            for inst in block.insts.iter() {
+                self.inst(inst);
+            }
+            self.terminator(&block.term, BlockId(i + 1));
+        }
+        self.out
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => self.copy(*dst, *src),
+            // phi はコード生成の前にコピーに置き換えてある
+            Inst::Phi { .. } => unreachable!(),
+            Inst::Bin { op, dst, lhs, rhs } => self.bin(*op, *dst, *lhs, *rhs),
+            Inst::Cmp {
+                op,
+                ty,
+                dst,
+                lhs,
+                rhs,
+            } => {
+                let size = ty.size(self.func.layout);
+                let lhs = match self.phys(*lhs) {
+                    Some(preg) => reg(preg, size),
+                    None => {
+                        self.load(Gpr::Rax, *lhs);
+                        reg(Gpr::Rax, size)
+                    }
+                };
+                let rhs = self.source(*rhs, size, Gpr::Rcx);
+                self.emit(asm::Inst::Alu(AluOp::Cmp, lhs, rhs));
+                let cond = match op {
+                    CmpOp::Eq => Cond::E,
+                    CmpOp::Ne => Cond::Ne,
+                    CmpOp::Slt => Cond::L,
+                    CmpOp::Sle => Cond::Le,
+                    CmpOp::Sgt => Cond::G,
+                    CmpOp::Sge => Cond::Ge,
+                    CmpOp::Ult => Cond::B,
+                    CmpOp::Ule => Cond::Be,
+                    CmpOp::Ugt => Cond::A,
+                    CmpOp::Uge => Cond::Ae,
+                };
+                let target = self.target(*dst);
+                self.emit(asm::Inst::Set(cond, reg(Gpr::Rax, 1)));
+                self.emit(asm::Inst::Movzx(reg(target, 4), reg(Gpr::Rax, 1)));
+                self.store(*dst, target);
+            }
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                signed,
+            } => {
+                let addr = Mem::Base(self.in_reg(*addr, Gpr::Rax), 0);
+                let target = self.target(*dst);
+                self.emit(match (ty, signed) {
+                    (Ty::I8, true) => asm::Inst::Movsx(reg(target, 4), asm::Operand::Mem(addr, 1)),
+                    (Ty::I8, false) => asm::Inst::Movzx(reg(target, 4), asm::Operand::Mem(addr, 1)),
+                    _ => asm::Inst::Mov(reg(target, 4), asm::Operand::Mem(addr, 4)),
+                });
+                self.store(*dst, target);
+            }
+            Inst::Store { addr, val, ty } => {
+                let addr = Mem::Base(self.in_reg(*addr, Gpr::Rax), 0);
+                let size = ty.size(self.func.layout);
+                let val = match (val, self.phys(*val)) {
+                    (Operand::Imm(_), _) => self.operand(*val, size),
+                    (_, Some(preg)) if size > 1 || has_byte(preg) => reg(preg, size),
+                    _ => {
+                        self.load(Gpr::Rcx, *val);
+                        reg(Gpr::Rcx, size)
+                    }
+                };
+                self.emit(asm::Inst::Mov(asm::Operand::Mem(addr, size), val));
+            }
+            Inst::LocalAddr { dst, slot } => {
+                let target = self.target(*dst);
+                let offset = self.slot_offsets[slot.0] as i64;
+                self.emit(asm::Inst::Lea(target, Mem::Base(Gpr::Rbp, -offset)));
+                self.store(*dst, target);
+            }
+            Inst::GlobalAddr { dst, name } => {
+                let target = self.target(*dst);
+                self.emit(asm::Inst::Lea(target, Mem::Abs(name.clone())));
+                self.store(*dst, target);
+            }
+            Inst::Call { dst, name, args } => {
+                // 引数の領域は 16 バイト境界に揃えて確保する
+                let size = (args.len() * 4).next_multiple_of(16) as i64;
+                if size > 0 {
+                    self.emit(asm::Inst::Alu(
+                        AluOp::Sub,
+                        reg(Gpr::Rsp, 4),
+                        asm::Operand::Imm(size),
+                    ));
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    let val = self.source(*arg, 4, Gpr::Rax);
+                    let slot = asm::Operand::Mem(Mem::Base(Gpr::Rsp, 4 * i as i64), 4);
+                    self.emit(asm::Inst::Mov(slot, val));
+                }
+                self.emit(asm::Inst::Call(name.clone()));
+                if size > 0 {
+                    self.emit(asm::Inst::Alu(
+                        AluOp::Add,
+                        reg(Gpr::Rsp, 4),
+                        asm::Operand::Imm(size),
+                    ));
+                }
+                if let Some(dst) = dst {
+                    self.store(*dst, Gpr::Rax);
+                }
+            }
+            Inst::Cast { dst, src, kind } => {
+                // esi と edi は下位 8 ビットを指せないので eax に読み込む
+                let src = match self.phys(*src) {
+                    Some(preg) if has_byte(preg) => preg,
+                    _ => {
+                        self.load(Gpr::Rax, *src);
+                        Gpr::Rax
+                    }
+                };
+                let target = self.target(*dst);
+                self.emit(match kind {
+                    CastKind::Sext(Ty::I8) => asm::Inst::Movsx(reg(target, 4), reg(src, 1)),
+                    CastKind::Zext(Ty::I8) => asm::Inst::Movzx(reg(target, 4), reg(src, 1)),
+                    // 32 ビットどうしの変換と切り詰めはコピーになる
+                    _ => asm::Inst::Mov(reg(target, 4), reg(src, 4)),
+                });
+                self.store(*dst, target);
+            }
+            Inst::Alloca { dst, size } => {
+                self.load(Gpr::Rax, *size);
+                let eax = reg(Gpr::Rax, 4);
+                self.emit(asm::Inst::Alu(
+                    AluOp::Add,
+                    eax.clone(),
+                    asm::Operand::Imm(15),
+                ));
+                self.emit(asm::Inst::Alu(
+                    AluOp::And,
+                    eax.clone(),
+                    asm::Operand::Imm(-16),
+                ));
+                self.emit(asm::Inst::Alu(AluOp::Sub, reg(Gpr::Rsp, 4), eax));
+                self.store(*dst, Gpr::Rsp);
+            }
+            Inst::StackSave { dst } => self.store(*dst, Gpr::Rsp),
+            Inst::StackRestore { src } => self.load(Gpr::Rsp, *src),
+        }
+    }
+
+    // 呼び出した結果をそのまま返すなら、受け取った引数の領域を呼び出し先への引数で
+    // 上書きし、フレームを片付けてから呼び出し先へ飛ぶ
+    // 引数の領域は呼び出し元が片付けるので、自分の引数の個数以下でなければならない
+    fn sibling_call(&mut self, name: &str, args: &[Operand]) {
+        for (i, arg) in args.iter().enumerate() {
+            let val = self.source(*arg, 4, Gpr::Rax);
+            self.emit(asm::Inst::Mov(param(i), val));
+        }
+        self.epilogue();
+        self.emit(asm::Inst::Jmp(name.to_string()));
+    }
+
+    fn epilogue(&mut self) {
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.emit(asm::Inst::Mov(reg(preg, 4), frame(offset, 4)));
+        }
+        self.emit(asm::Inst::Mov(reg(Gpr::Rsp, 4), reg(Gpr::Rbp, 4)));
+        self.emit(asm::Inst::Pop(reg(Gpr::Rbp, 4)));
+    }
+
+    fn bin(&mut self, op: BinOp, dst: Reg, lhs: Operand, rhs: Operand) {
+        let size = self.size(dst);
+        match op {
+            BinOp::SDiv | BinOp::UDiv => {
+                self.load(Gpr::Rax, lhs);
+                // 除数に即値は書けない
+                let divisor = match rhs {
+                    Operand::Imm(_) => {
+                        self.load(Gpr::Rcx, rhs);
+                        reg(Gpr::Rcx, size)
+                    }
+                    Operand::Reg(_) => self.operand(rhs, size),
+                };
+                if op == BinOp::SDiv {
+                    self.emit(asm::Inst::Cdq);
+                    self.emit(asm::Inst::Idiv(divisor));
+                } else {
+                    let edx = reg(Gpr::Rdx, 4);
+                    self.emit(asm::Inst::Alu(AluOp::Xor, edx.clone(), edx));
+                    self.emit(asm::Inst::Div(divisor));
+                }
+                self.store(dst, Gpr::Rax);
+                return;
+            }
+            // シフト量は cl に置く
+            BinOp::Shl | BinOp::LShr | BinOp::AShr if !matches!(rhs, Operand::Imm(_)) => {
+                self.load(Gpr::Rcx, rhs);
+            }
+            _ => {}
+        }
+
+        // 右辺と同じレジスタに結果を置くときは eax で計算する
+        let target = match self.phys(Operand::Reg(dst)) {
+            Some(preg) if self.phys(rhs) != Some(preg) => preg,
+            _ => Gpr::Rax,
+        };
+        let rhs = self.source(rhs, size, Gpr::Rcx);
+        self.load(target, lhs);
+        let name = reg(target, size);
+        self.emit(match op {
+            BinOp::Add => asm::Inst::Alu(AluOp::Add, name, rhs),
+            BinOp::Sub => asm::Inst::Alu(AluOp::Sub, name, rhs),
+            BinOp::Mul => match rhs {
+                asm::Operand::Imm(val) => asm::Inst::Imul3(name.clone(), name, val),
+                _ => asm::Inst::Imul(name, rhs),
+            },
+            BinOp::And => asm::Inst::Alu(AluOp::And, name, rhs),
+            BinOp::Or => asm::Inst::Alu(AluOp::Or, name, rhs),
+            BinOp::Shl | BinOp::LShr | BinOp::AShr => {
+                let op = match op {
+                    BinOp::Shl => ShiftOp::Shl,
+                    BinOp::LShr => ShiftOp::Shr,
+                    _ => ShiftOp::Sar,
+                };
+                let amount = match rhs {
+                    asm::Operand::Imm(amount) => asm::Operand::Imm(amount & 31),
+                    _ => reg(Gpr::Rcx, 1),
+                };
+                asm::Inst::Shift(op, name, amount)
+            }
+            BinOp::SDiv | BinOp::UDiv => unreachable!(),
+        });
+        self.store(dst, target);
+    }
+
+    fn terminator(&mut self, term: &Terminator, next: BlockId) {
+        match term {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    self.emit(asm::Inst::Jmp(self.label(*target)));
+                }
+            }
+            Terminator::Branch { cond, then, els } => {
+                let size = match cond {
+                    Operand::Reg(r) => self.size(*r),
+                    Operand::Imm(_) => 4,
+                };
+                let cond = match (cond, self.phys(*cond)) {
+                    (_, Some(preg)) if size > 1 || has_byte(preg) => reg(preg, size),
+                    (Operand::Reg(_), None) => self.operand(*cond, size),
+                    _ => {
+                        self.load(Gpr::Rax, *cond);
+                        reg(Gpr::Rax, size)
+                    }
+                };
+                self.emit(asm::Inst::Alu(AluOp::Cmp, cond, asm::Operand::Imm(0)));
+                // 条件が成り立つ側が次のブロックなら、成り立たないときだけ飛ぶ
+                if *then == next {
+                    self.emit(asm::Inst::Jcc(Cond::E, self.label(*els)));
+                    return;
+                }
+                self.emit(asm::Inst::Jcc(Cond::Ne, self.label(*then)));
+                if *els != next {
+                    self.emit(asm::Inst::Jmp(self.label(*els)));
+                }
+            }
+            Terminator::Ret(val) => {
+                if let Some(val) = val {
+                    self.load(Gpr::Rax, *val);
+                }
+                self.epilogue();
+                self.emit(asm::Inst::Ret);
+            }
+            Terminator::Unreachable => {}
+        }
+    }
+}
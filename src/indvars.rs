@@ -0,0 +1,271 @@
+use crate::cfg::{self, DomTree};
+use crate::ir::{BinOp, BlockId, CastKind, Function, Inst, Operand, Reg, Ty};
+use crate::loops::{self, Loop};
+use std::collections::{HashMap, HashSet};
+
+// 帰納変数の強度削減
+// 繰り返しごとに一定の値だけ増える変数 (基本帰納変数) の定数倍で求まる値を、
+// 乗算の代わりに毎回加算して更新する新しい帰納変数で置き換える
+// 配列の添字に要素の大きさを掛ける計算がポインタの加算になる
+
+// base + scale * iv + offset で求まる値
+#[derive(Clone, Copy)]
+struct Affine {
+    iv: Reg,
+    scale: i64,
+    base: Option<Reg>, // ループ不変な値
+    offset: i64,
+    scaled: bool, // 乗算を含む
+}
+
+pub fn indvars(func: &mut Function) {
+    loops::insert_preheaders(func);
+    let dom = DomTree::new(func);
+    for lp in loops::find_loops(func, &dom) {
+        reduce(func, &lp);
+    }
+}
+
+fn reduce(func: &mut Function, lp: &Loop) {
+    let (Some(pre), Some(latch)) = (lp.preheader(func), lp.latch()) else {
+        return;
+    };
+    let defs = lp.defs(func);
+    let blocks: Vec<BlockId> = cfg::reverse_postorder(func)
+        .into_iter()
+        .filter(|block| lp.contains(*block))
+        .collect();
+
+    let ivs = loops::induction_variables(func, lp);
+    let mut affine: HashMap<Reg, Affine> = HashMap::new();
+    for iv in ivs.keys() {
+        affine.insert(
+            *iv,
+            Affine {
+                iv: *iv,
+                scale: 1,
+                base: None,
+                offset: 0,
+                scaled: false,
+            },
+        );
+    }
+    if affine.is_empty() {
+        return;
+    }
+    for block in blocks.iter() {
+        for inst in func.blocks[block.0].insts.iter() {
+            if let Some(val) = derive(&affine, &defs, inst) {
+                affine.insert(inst.dst().unwrap(), val);
+            }
+        }
+    }
+
+    // 乗算を含む値のうち、帰納変数の計算以外に使われるものを置き換える
+    let mut candidates = Vec::new();
+    let mut seen = HashSet::new();
+    for block in blocks.iter() {
+        let block = &func.blocks[block.0];
+        let uses = block
+            .insts
+            .iter()
+            .filter(|inst| !inst.dst().is_some_and(|dst| affine.contains_key(&dst)))
+            .flat_map(|inst| inst.operands())
+            .chain(block.term.operands());
+        for op in uses {
+            let Operand::Reg(reg) = op else {
+                continue;
+            };
+            if affine
+                .get(&reg)
+                .is_some_and(|val| val.scaled && val.scale != 0)
+                && seen.insert(reg)
+            {
+                candidates.push(reg);
+            }
+        }
+    }
+
+    for reg in candidates {
+        let val = affine[&reg];
+        let (init, step) = ivs[&val.iv];
+        let Some(start) = start_value(func, pre, init, reg, val) else {
+            continue;
+        };
+        let ty = func.reg_ty(reg);
+        let step = step.wrapping_mul(val.scale);
+        let phi = func.new_reg(ty);
+        let next = func.new_reg(ty);
+        func.blocks[lp.header.0].insts.insert(
+            0,
+            Inst::Phi {
+                dst: phi,
+                incoming: vec![(pre, start), (latch, Operand::Reg(next))],
+            },
+        );
+        func.blocks[latch.0].insts.push(Inst::Bin {
+            op: BinOp::Add,
+            dst: next,
+            lhs: Operand::Reg(phi),
+            rhs: imm(step, ty.size(func.layout)),
+        });
+        for block in lp.blocks.iter() {
+            let block = &mut func.blocks[block.0];
+            let ops = block
+                .insts
+                .iter_mut()
+                .filter(|inst| inst.dst() != Some(next))
+                .flat_map(|inst| inst.operands_mut())
+                .chain(block.term.operands_mut());
+            for op in ops {
+                if *op == Operand::Reg(reg) {
+                    *op = Operand::Reg(phi);
+                }
+            }
+        }
+    }
+}
+
+// 帰納変数から求まる値なら、その形
+// 符号付き整数の溢れは未定義なので、符号拡張をまたいでも形は変わらないとみなす
+fn derive(affine: &HashMap<Reg, Affine>, defs: &HashSet<Reg>, inst: &Inst) -> Option<Affine> {
+    let get = |op: Operand| match op {
+        Operand::Reg(reg) => affine.get(&reg).copied(),
+        Operand::Imm(_) => None,
+    };
+    match *inst {
+        Inst::Cast {
+            src,
+            kind: CastKind::Sext(_),
+            ..
+        } => get(src),
+        Inst::Bin {
+            op: BinOp::Add,
+            lhs,
+            rhs,
+            ..
+        } => {
+            let (val, other) = match (get(lhs), get(rhs)) {
+                (Some(val), None) => (val, rhs),
+                (None, Some(val)) => (val, lhs),
+                // a[i][i] のように同じ帰納変数から求まる値どうしの和
+                (Some(a), Some(b)) if a.iv == b.iv && (a.base.is_none() || b.base.is_none()) => {
+                    return Some(Affine {
+                        iv: a.iv,
+                        scale: a.scale.wrapping_add(b.scale),
+                        base: a.base.or(b.base),
+                        offset: a.offset.wrapping_add(b.offset),
+                        scaled: a.scaled || b.scaled,
+                    });
+                }
+                _ => return None,
+            };
+            match other {
+                Operand::Imm(k) => Some(Affine {
+                    offset: val.offset.wrapping_add(k),
+                    ..val
+                }),
+                Operand::Reg(reg) if val.base.is_none() && !defs.contains(&reg) => Some(Affine {
+                    base: Some(reg),
+                    ..val
+                }),
+                Operand::Reg(_) => None,
+            }
+        }
+        Inst::Bin {
+            op: BinOp::Sub,
+            lhs,
+            rhs: Operand::Imm(k),
+            ..
+        } => get(lhs).map(|val| Affine {
+            offset: val.offset.wrapping_sub(k),
+            ..val
+        }),
+        Inst::Bin {
+            op: BinOp::Mul,
+            lhs,
+            rhs: Operand::Imm(k),
+            ..
+        } => scale(get(lhs)?, k),
+        Inst::Bin {
+            op: BinOp::Shl,
+            lhs,
+            rhs: Operand::Imm(k),
+            ..
+        } if (0..32).contains(&k) => scale(get(lhs)?, 1 << k),
+        _ => None,
+    }
+}
+
+fn scale(val: Affine, k: i64) -> Option<Affine> {
+    // (base + x) * k は base * k を別に計算しなければならないので扱わない
+    if val.base.is_some() {
+        return None;
+    }
+    Some(Affine {
+        scale: val.scale.wrapping_mul(k),
+        offset: val.offset.wrapping_mul(k),
+        scaled: true,
+        ..val
+    })
+}
+
+// プリヘッダで新しい帰納変数の初期値を計算する
+fn start_value(
+    func: &mut Function,
+    pre: BlockId,
+    init: Operand,
+    reg: Reg,
+    val: Affine,
+) -> Option<Operand> {
+    let ty = func.reg_ty(reg);
+    // ポインタはループ不変なポインタに整数を足した形のものだけ扱う
+    let index_ty = match (ty, val.base) {
+        (Ty::Ptr, Some(_)) => Ty::IPtr,
+        (Ty::Ptr, None) => return None,
+        _ => ty,
+    };
+    let iv_ty = func.reg_ty(val.iv);
+    let mut insts = Vec::new();
+    let mut start = init;
+    if iv_ty.size(func.layout) < index_ty.size(func.layout) {
+        let dst = func.new_reg(index_ty);
+        insts.push(Inst::Cast {
+            dst,
+            src: start,
+            kind: CastKind::Sext(iv_ty),
+        });
+        start = Operand::Reg(dst);
+    }
+    for (op, rhs) in [(BinOp::Mul, val.scale), (BinOp::Add, val.offset)] {
+        let dst = func.new_reg(index_ty);
+        insts.push(Inst::Bin {
+            op,
+            dst,
+            lhs: start,
+            rhs: imm(rhs, index_ty.size(func.layout)),
+        });
+        start = Operand::Reg(dst);
+    }
+    if let Some(base) = val.base {
+        let dst = func.new_reg(ty);
+        insts.push(Inst::Bin {
+            op: BinOp::Add,
+            dst,
+            lhs: Operand::Reg(base),
+            rhs: start,
+        });
+        start = Operand::Reg(dst);
+    }
+    func.blocks[pre.0].insts.extend(insts);
+    Some(start)
+}
+
+// size バイトの幅で符号拡張した即値
+fn imm(val: i64, size: usize) -> Operand {
+    match size {
+        1 => Operand::Imm(val as i8 as i64),
+        4 => Operand::Imm(val as i32 as i64),
+        _ => Operand::Imm(val),
+    }
+}
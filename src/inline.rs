@@ -0,0 +1,283 @@
+use crate::cfg;
+use crate::ir::{BlockId, Function, Inline, Inst, Module, Operand, SlotId, Terminator, Visibility};
+use crate::pass::PassContext;
+use crate::ssa;
+use std::collections::HashMap;
+
+// 同じ翻訳単位で定義された関数のインライン展開
+// 呼び出し先の命令数から定数の引数の分を割り引いたコストがしきい値以下なら展開する
+
+const THRESHOLD: usize = 20; // 指定のない関数
+const HINT_THRESHOLD: usize = 60; // inline を指定した関数
+const CONST_ARG_BONUS: usize = 5; // 定数の引数は畳み込みで命令が減ることを見込む
+const MAX_CALLER_SIZE: usize = 2000; // 呼び出し元がこれより大きくなったら展開しない
+
+pub fn inline(module: &mut Module, ctx: &PassContext) {
+    let index: HashMap<String, usize> = module
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(i, func)| (func.name.clone(), i))
+        .collect();
+    let callees: Vec<Vec<usize>> = module
+        .functions
+        .iter()
+        .map(|func| {
+            calls(func)
+                .filter_map(|(name, _)| index.get(name).copied())
+                .collect()
+        })
+        .collect();
+    let recursive = recursive_functions(&callees);
+    // 呼び出し箇所が一つだけの static 関数は、展開すれば本体が要らなくなる
+    let mut sites = vec![0; module.functions.len()];
+    for callee in callees.iter().flatten() {
+        sites[*callee] += 1;
+    }
+
+    // 呼び出し先から順に処理して、展開済みの本体を呼び出し元に展開する
+    for caller in bottom_up_order(&callees) {
+        let mut b = 0;
+        while b < module.functions[caller].blocks.len() {
+            let func = &module.functions[caller];
+            let site = func.blocks[b]
+                .insts
+                .iter()
+                .enumerate()
+                .find_map(|(i, inst)| {
+                    let Inst::Call { name, args, .. } = inst else {
+                        return None;
+                    };
+                    let callee = *index.get(name)?;
+                    let single = sites[callee] == 1 && module.functions[callee].is_static;
+                    (!recursive[callee]
+                        && size(func) < MAX_CALLER_SIZE
+                        && can_inline(func, &module.functions[callee], args)
+                        && should_inline(&module.functions[callee], args, single, ctx))
+                    .then_some((i, callee))
+                });
+            match site {
+                Some((i, callee)) => {
+                    let callee = module.functions[callee].clone();
+                    inline_call(&mut module.functions[caller], &callee, BlockId(b), i);
+                }
+                None => b += 1,
+            }
+        }
+    }
+}
+
+fn calls(func: &Function) -> impl Iterator<Item = (&String, &Vec<Operand>)> {
+    func.blocks
+        .iter()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(|inst| match inst {
+            Inst::Call { name, args, .. } => Some((name, args)),
+            _ => None,
+        })
+}
+
+fn size(func: &Function) -> usize {
+    func.blocks.iter().map(|block| block.insts.len() + 1).sum()
+}
+
+// 自分自身を呼び出しうる関数
+fn recursive_functions(callees: &[Vec<usize>]) -> Vec<bool> {
+    (0..callees.len())
+        .map(|start| {
+            let mut visited = vec![false; callees.len()];
+            let mut stack = callees[start].clone();
+            while let Some(func) = stack.pop() {
+                if func == start {
+                    return true;
+                }
+                if !visited[func] {
+                    visited[func] = true;
+                    stack.extend(callees[func].iter().copied());
+                }
+            }
+            false
+        })
+        .collect()
+}
+
+// 呼び出しグラフの後順 (呼び出し先が先に来る)
+fn bottom_up_order(callees: &[Vec<usize>]) -> Vec<usize> {
+    let mut visited = vec![false; callees.len()];
+    let mut order = Vec::new();
+    for root in 0..callees.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some((func, i)) = stack.pop() {
+            if i < callees[func].len() {
+                stack.push((func, i + 1));
+                let callee = callees[func][i];
+                if !visited[callee] {
+                    visited[callee] = true;
+                    stack.push((callee, 0));
+                }
+            } else {
+                order.push(func);
+            }
+        }
+    }
+    order
+}
+
+// 展開できる形の呼び出しか
+fn can_inline(caller: &Function, callee: &Function, args: &[Operand]) -> bool {
+    if args.len() != callee.params.len() {
+        return false;
+    }
+    // 引数の型が違う呼び出しは変換が要るので展開しない
+    let types_match = args
+        .iter()
+        .zip(callee.params.iter())
+        .all(|(arg, param)| match arg {
+            Operand::Reg(reg) => caller.reg_ty(*reg) == callee.reg_ty(*param),
+            Operand::Imm(_) => true,
+        });
+    // スタックを伸ばす関数を展開すると、ループの中で呼ばれたときに解放されなくなる
+    let grows_stack = callee
+        .blocks
+        .iter()
+        .flat_map(|block| block.insts.iter())
+        .any(|inst| matches!(inst, Inst::Alloca { .. } | Inst::StackSave { .. }));
+    // 入口のブロックに戻る辺があると、呼び出し元から入る辺を phi に足せない
+    let entry_has_preds = !cfg::predecessors(callee)[0].is_empty();
+    types_match && !grows_stack && !entry_has_preds
+}
+
+fn should_inline(
+    callee: &Function,
+    args: &[Operand],
+    single_site: bool,
+    ctx: &PassContext,
+) -> bool {
+    // 置き換えられうる関数は、inline の指定がなければ本体が呼ばれるものとは限らない
+    let interposable =
+        ctx.interposition && !callee.is_static && callee.visibility == Visibility::Default;
+    let threshold = match callee.inline {
+        Inline::Never => return false,
+        Inline::Always => return true,
+        Inline::Default if interposable => return false,
+        Inline::Hint => HINT_THRESHOLD,
+        Inline::Default => THRESHOLD,
+    };
+    let consts = args
+        .iter()
+        .filter(|arg| matches!(arg, Operand::Imm(_)))
+        .count();
+    single_site || size(callee).saturating_sub(consts * CONST_ARG_BONUS) <= threshold
+}
+
+// block の i 番目の命令の呼び出しを callee の本体で置き換える
+fn inline_call(caller: &mut Function, callee: &Function, block: BlockId, i: usize) {
+    let rest = caller.blocks[block.0].insts.split_off(i + 1);
+    let Some(Inst::Call { dst, args, .. }) = caller.blocks[block.0].insts.pop() else {
+        unreachable!();
+    };
+
+    // 呼び出しの後の命令は新しいブロックに移す
+    let cont = caller.new_block();
+    caller.blocks[cont.0].insts = rest;
+    let term = std::mem::replace(&mut caller.blocks[block.0].term, Terminator::Unreachable);
+    for succ in term.successors() {
+        for inst in caller.blocks[succ.0].insts.iter_mut() {
+            if let Inst::Phi { incoming, .. } = inst {
+                for (pred, _) in incoming.iter_mut() {
+                    if *pred == block {
+                        *pred = cont;
+                    }
+                }
+            }
+        }
+    }
+    caller.blocks[cont.0].term = term;
+
+    // 呼び出し先の仮想レジスタ、スロット、ブロックを呼び出し元に作り直す
+    let mut regs = Vec::new();
+    for (reg, ty) in callee.regs.iter().enumerate() {
+        let param = callee.params.iter().position(|param| param.0 == reg);
+        regs.push(match param {
+            Some(index) => args[index],
+            None => Operand::Reg(caller.new_reg(*ty)),
+        });
+    }
+    let slots: Vec<SlotId> = callee
+        .slots
+        .iter()
+        .map(|slot| caller.new_slot(slot.size, slot.align))
+        .collect();
+    // 到達できないブロックは複製しない (phi の入力からも除く)
+    let mut blocks: Vec<Option<BlockId>> = vec![None; callee.blocks.len()];
+    for id in cfg::reverse_postorder(callee) {
+        blocks[id.0] = Some(caller.new_block());
+    }
+
+    let mut rets = Vec::new();
+    for (src, block) in callee.blocks.iter().enumerate() {
+        let Some(copy) = blocks[src] else {
+            continue;
+        };
+        let mut insts = block.insts.clone();
+        for inst in insts.iter_mut() {
+            if let Some(dst) = inst.dst_mut() {
+                let Operand::Reg(reg) = regs[dst.0] else {
+                    unreachable!();
+                };
+                *dst = reg;
+            }
+            for op in inst.operands_mut() {
+                if let Operand::Reg(reg) = op {
+                    *op = regs[reg.0];
+                }
+            }
+            match inst {
+                Inst::LocalAddr { slot, .. } => *slot = slots[slot.0],
+                Inst::Phi { incoming, .. } => {
+                    incoming.retain(|(pred, _)| blocks[pred.0].is_some());
+                    for (pred, _) in incoming.iter_mut() {
+                        *pred = blocks[pred.0].unwrap();
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut term = block.term.clone();
+        for op in term.operands_mut() {
+            if let Operand::Reg(reg) = op {
+                *op = regs[reg.0];
+            }
+        }
+        for succ in term.successors_mut() {
+            *succ = blocks[succ.0].unwrap();
+        }
+        if let Terminator::Ret(val) = term {
+            rets.push((copy, val.unwrap_or(Operand::Imm(0))));
+            term = Terminator::Jump(cont);
+        }
+        caller.blocks[copy.0].insts = insts;
+        caller.blocks[copy.0].term = term;
+    }
+    caller.blocks[block.0].term = Terminator::Jump(blocks[0].unwrap());
+
+    // 戻り値は return した位置ごとの値の phi になる
+    let Some(dst) = dst else {
+        return;
+    };
+    match rets.as_slice() {
+        [] => ssa::replace_uses(caller, &HashMap::from([(dst, Operand::Imm(0))])),
+        [(_, val)] => ssa::replace_uses(caller, &HashMap::from([(dst, *val)])),
+        _ => caller.blocks[cont.0].insts.insert(
+            0,
+            Inst::Phi {
+                dst,
+                incoming: rets,
+            },
+        ),
+    }
+}
@@ -0,0 +1,590 @@
+use crate::layout::DataLayout;
+use std::fmt;
+
+// 構文木とアセンブリの間の中間表現
+// 関数は基本ブロックの列で、各ブロックは三番地コードの命令と終端命令からなる
+// 値は仮想レジスタに入り、ローカル変数はスタックスロットに置いて明示的に読み書きする
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Reg(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SlotId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ty {
+    I8,
+    I32,
+    IPtr, // ポインタと同じ幅の整数 (-m32 では 4 バイト)
+    Ptr,
+}
+
+impl Ty {
+    pub fn size(self, layout: DataLayout) -> usize {
+        match self {
+            Ty::I8 => 1,
+            Ty::I32 => 4,
+            Ty::IPtr | Ty::Ptr => layout.ptr_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    SDiv,
+    UDiv,
+    And,
+    Or,
+    Shl,
+    LShr,
+    AShr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Slt,
+    Sle,
+    Sgt,
+    Sge,
+    Ult,
+    Ule,
+    Ugt,
+    Uge,
+}
+
+// 変換元の型から変換先 (dst の型) への変換
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CastKind {
+    Sext(Ty),
+    Zext(Ty),
+    Trunc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Copy {
+        dst: Reg,
+        src: Operand,
+    },
+    // 基本ブロックの先頭にだけ置ける。直前に通ったブロックに応じた値を選ぶ
+    Phi {
+        dst: Reg,
+        incoming: Vec<(BlockId, Operand)>,
+    },
+    Bin {
+        op: BinOp,
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    // ty はオペランドの型。結果は i32 の 0 か 1
+    Cmp {
+        op: CmpOp,
+        ty: Ty,
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    // ty はメモリ上の型。dst より狭い場合は signed に従って拡張する
+    Load {
+        dst: Reg,
+        addr: Operand,
+        ty: Ty,
+        signed: bool,
+    },
+    Store {
+        addr: Operand,
+        val: Operand,
+        ty: Ty,
+    },
+    LocalAddr {
+        dst: Reg,
+        slot: SlotId,
+    },
+    GlobalAddr {
+        dst: Reg,
+        name: String,
+    },
+    Call {
+        dst: Option<Reg>,
+        name: String,
+        args: Vec<Operand>,
+    },
+    Cast {
+        dst: Reg,
+        src: Operand,
+        kind: CastKind,
+    },
+    // size バイトをスタックに確保する (関数から戻るまで有効)
+    Alloca {
+        dst: Reg,
+        size: Operand,
+    },
+    StackSave {
+        dst: Reg,
+    },
+    StackRestore {
+        src: Operand,
+    },
+}
+
+impl Inst {
+    // 命令が値を書き込む仮想レジスタ
+    pub fn dst(&self) -> Option<Reg> {
+        self.clone().dst_mut().copied()
+    }
+
+    pub fn dst_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            Inst::Copy { dst, .. }
+            | Inst::Phi { dst, .. }
+            | Inst::Bin { dst, .. }
+            | Inst::Cmp { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::LocalAddr { dst, .. }
+            | Inst::GlobalAddr { dst, .. }
+            | Inst::Cast { dst, .. }
+            | Inst::Alloca { dst, .. }
+            | Inst::StackSave { dst } => Some(dst),
+            Inst::Call { dst, .. } => dst.as_mut(),
+            Inst::Store { .. } | Inst::StackRestore { .. } => None,
+        }
+    }
+
+    // 命令が読むオペランド
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Cast { src, .. } | Inst::StackRestore { src } => {
+                vec![src]
+            }
+            Inst::Phi { incoming, .. } => incoming.iter_mut().map(|(_, val)| val).collect(),
+            Inst::Bin { lhs, rhs, .. } | Inst::Cmp { lhs, rhs, .. } => vec![lhs, rhs],
+            Inst::Load { addr, .. } => vec![addr],
+            Inst::Store { addr, val, .. } => vec![addr, val],
+            Inst::Call { args, .. } => args.iter_mut().collect(),
+            Inst::Alloca { size, .. } => vec![size],
+            Inst::LocalAddr { .. } | Inst::GlobalAddr { .. } | Inst::StackSave { .. } => vec![],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        self.clone()
+            .operands_mut()
+            .into_iter()
+            .map(|op| *op)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        cond: Operand,
+        then: BlockId,
+        els: BlockId,
+    },
+    Ret(Option<Operand>),
+    Unreachable,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then, els, .. } => vec![*then, *els],
+            Terminator::Ret(_) | Terminator::Unreachable => vec![],
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch { then, els, .. } => vec![then, els],
+            Terminator::Ret(_) | Terminator::Unreachable => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Branch { cond, .. } => vec![cond],
+            Terminator::Ret(Some(val)) => vec![val],
+            _ => vec![],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        self.clone()
+            .operands_mut()
+            .into_iter()
+            .map(|op| *op)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slot {
+    pub size: usize,
+    pub align: usize,
+}
+
+// インライン展開についての指定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inline {
+    Default,
+    Hint,   // inline
+    Always, // __attribute__((always_inline))
+    Never,  // __attribute__((noinline))
+}
+
+// シンボルの可視性 (共有ライブラリの外から見えるか、置き換えられるか)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Default,
+    Hidden,    // ほかのモジュールからは見えない
+    Protected, // 見えるが、このモジュールの中の参照は置き換えられない
+}
+
+impl Visibility {
+    pub fn name(self) -> &'static str {
+        match self {
+            Visibility::Default => "default",
+            Visibility::Hidden => "hidden",
+            Visibility::Protected => "protected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Reg>,
+    pub ret: Option<Ty>,
+    pub regs: Vec<Ty>, // 仮想レジスタの型
+    pub slots: Vec<Slot>,
+    pub blocks: Vec<Block>, // 先頭が入口のブロック
+    pub is_static: bool,    // 内部結合の関数
+    pub inline: Inline,
+    pub visibility: Visibility,
+    pub layout: DataLayout, // iptr や ptr の幅を決めるターゲットのデータレイアウト
+}
+
+impl Function {
+    pub fn new(name: &str, ret: Option<Ty>, layout: DataLayout) -> Self {
+        Function {
+            name: name.to_string(),
+            params: Vec::new(),
+            ret,
+            regs: Vec::new(),
+            slots: Vec::new(),
+            blocks: Vec::new(),
+            is_static: false,
+            inline: Inline::Default,
+            visibility: Visibility::Default,
+            layout,
+        }
+    }
+
+    pub fn new_reg(&mut self, ty: Ty) -> Reg {
+        self.regs.push(ty);
+        Reg(self.regs.len() - 1)
+    }
+
+    pub fn new_slot(&mut self, size: usize, align: usize) -> SlotId {
+        self.slots.push(Slot { size, align });
+        SlotId(self.slots.len() - 1)
+    }
+
+    pub fn new_block(&mut self) -> BlockId {
+        self.blocks.push(Block {
+            insts: Vec::new(),
+            term: Terminator::Unreachable,
+        });
+        BlockId(self.blocks.len() - 1)
+    }
+
+    pub fn reg_ty(&self, reg: Reg) -> Ty {
+        self.regs[reg.0]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub name: String,
+    pub size: usize,
+    pub visibility: Visibility,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub globals: Vec<Global>,
+    pub strings: Vec<Vec<u8>>, // .LC<番号> として参照する
+    pub functions: Vec<Function>,
+}
+
+// --emit-ir で出力するテキスト形式
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for SlotId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Ty::I8 => "i8",
+            Ty::I32 => "i32",
+            Ty::IPtr => "iptr",
+            Ty::Ptr => "ptr",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "{}", reg),
+            Operand::Imm(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::SDiv => "sdiv",
+            BinOp::UDiv => "udiv",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Shl => "shl",
+            BinOp::LShr => "lshr",
+            BinOp::AShr => "ashr",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CmpOp::Eq => "eq",
+            CmpOp::Ne => "ne",
+            CmpOp::Slt => "slt",
+            CmpOp::Sle => "sle",
+            CmpOp::Sgt => "sgt",
+            CmpOp::Sge => "sge",
+            CmpOp::Ult => "ult",
+            CmpOp::Ule => "ule",
+            CmpOp::Ugt => "ugt",
+            CmpOp::Uge => "uge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn fmt_args(args: &[Operand]) -> String {
+    args.iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Function {
+    fn fmt_inst(&self, f: &mut fmt::Formatter, inst: &Inst) -> fmt::Result {
+        let def =
+            |f: &mut fmt::Formatter, dst: &Reg| write!(f, "  {}: {} = ", dst, self.reg_ty(*dst));
+        match inst {
+            Inst::Copy { dst, src } => {
+                def(f, dst)?;
+                writeln!(f, "copy {}", src)
+            }
+            Inst::Phi { dst, incoming } => {
+                def(f, dst)?;
+                let incoming = incoming
+                    .iter()
+                    .map(|(block, val)| format!("[{}, {}]", val, block))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                writeln!(f, "phi {}", incoming)
+            }
+            Inst::Bin { op, dst, lhs, rhs } => {
+                def(f, dst)?;
+                writeln!(f, "{} {}, {}", op, lhs, rhs)
+            }
+            Inst::Cmp {
+                op,
+                ty,
+                dst,
+                lhs,
+                rhs,
+            } => {
+                def(f, dst)?;
+                writeln!(f, "cmp {} {} {}, {}", op, ty, lhs, rhs)
+            }
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                signed,
+            } => {
+                def(f, dst)?;
+                let op = if *signed { "load" } else { "loadu" };
+                writeln!(f, "{} {} {}", op, ty, addr)
+            }
+            Inst::Store { addr, val, ty } => writeln!(f, "  store {} {}, {}", ty, addr, val),
+            Inst::LocalAddr { dst, slot } => {
+                def(f, dst)?;
+                writeln!(f, "local {}", slot)
+            }
+            Inst::GlobalAddr { dst, name } => {
+                def(f, dst)?;
+                writeln!(f, "global @{}", name)
+            }
+            Inst::Call { dst, name, args } => {
+                if let Some(dst) = dst {
+                    def(f, dst)?;
+                } else {
+                    write!(f, "  ")?;
+                }
+                writeln!(f, "call @{}({})", name, fmt_args(args))
+            }
+            Inst::Cast { dst, src, kind } => {
+                def(f, dst)?;
+                match kind {
+                    CastKind::Sext(from) => writeln!(f, "sext {} {}", from, src),
+                    CastKind::Zext(from) => writeln!(f, "zext {} {}", from, src),
+                    CastKind::Trunc => writeln!(f, "trunc {}", src),
+                }
+            }
+            Inst::Alloca { dst, size } => {
+                def(f, dst)?;
+                writeln!(f, "alloca {}", size)
+            }
+            Inst::StackSave { dst } => {
+                def(f, dst)?;
+                writeln!(f, "stacksave")
+            }
+            Inst::StackRestore { src } => writeln!(f, "  stackrestore {}", src),
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jmp {}", target),
+            Terminator::Branch { cond, then, els } => write!(f, "br {}, {}, {}", cond, then, els),
+            Terminator::Ret(Some(val)) => write!(f, "ret {}", val),
+            Terminator::Ret(None) => write!(f, "ret"),
+            Terminator::Unreachable => write!(f, "unreachable"),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params = self
+            .params
+            .iter()
+            .map(|param| format!("{}: {}", param, self.reg_ty(*param)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let ret = match self.ret {
+            Some(ty) => ty.to_string(),
+            None => "void".to_string(),
+        };
+        if self.is_static {
+            write!(f, "static ")?;
+        }
+        if self.visibility != Visibility::Default {
+            write!(f, "{} ", self.visibility.name())?;
+        }
+        match self.inline {
+            Inline::Default => {}
+            Inline::Hint => write!(f, "inline ")?,
+            Inline::Always => write!(f, "always_inline ")?,
+            Inline::Never => write!(f, "noinline ")?,
+        }
+        writeln!(f, "function {}({}) -> {} {{", self.name, params, ret)?;
+        for (i, slot) in self.slots.iter().enumerate() {
+            writeln!(
+                f,
+                "  {}: size {}, align {}",
+                SlotId(i),
+                slot.size,
+                slot.align
+            )?;
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "{}:", BlockId(i))?;
+            for inst in block.insts.iter() {
+                self.fmt_inst(f, inst)?;
+            }
+            writeln!(f, "  {}", block.term)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for global in self.globals.iter() {
+            if global.visibility != Visibility::Default {
+                write!(f, "{} ", global.visibility.name())?;
+            }
+            writeln!(f, "global @{}: size {}", global.name, global.size)?;
+        }
+        for (i, s) in self.strings.iter().enumerate() {
+            writeln!(
+                f,
+                "string @.LC{}: \"{}\"",
+                i,
+                String::from_utf8_lossy(s).escape_default()
+            )?;
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 || !self.globals.is_empty() || !self.strings.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
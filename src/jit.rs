@@ -0,0 +1,179 @@
+use crate::elf::{self, Object, SectionKind};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void, CString};
+
+// --run: 機械語をメモリに置いてその場で main を呼ぶ
+// 定義のない関数は dlsym で libc などから探し、そのアドレスへ飛ぶスタブを経由して呼ぶ
+// (mmap した領域から共有ライブラリまでは 32 ビットの相対アドレスで届くとは限らない)
+// GOT を経由する参照のために、データの前に GOT を置く
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    static environ: *const *const c_char;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+const MAP_32BIT: c_int = 0x40;
+const RTLD_DEFAULT: *mut c_void = std::ptr::null_mut();
+
+const PAGE: usize = 0x1000;
+const STUB_SIZE: usize = 16; // jmp [rip+0] と 8 バイトのアドレス
+
+type MainFn = extern "C" fn(c_int, *const *const c_char, *const *const c_char) -> c_int;
+
+// 共有ライブラリのシンボルのアドレス
+fn lookup(name: &str) -> Result<u64, String> {
+    let cname = CString::new(name).map_err(|_| format!("不正なシンボル名です: {}", name))?;
+    let address = unsafe { dlsym(RTLD_DEFAULT, cname.as_ptr()) };
+    if address.is_null() {
+        return Err(format!("未定義のシンボルです: {}", name));
+    }
+    Ok(address as u64)
+}
+
+// オブジェクトを読み込んで main を呼び、その戻り値を返す
+// argv[0] は gcc が出力する実行ファイルの既定の名前にする
+pub fn run(obj: &Object, args: &[String]) -> Result<i32, String> {
+    // 実行可能な部分 (コードとスタブ) を先に置き、データはページ境界から置く
+    let mut offsets = vec![0; obj.sections.len()];
+    let mut offset: usize = 0;
+    for (i, section) in obj.sections.iter().enumerate() {
+        if section.kind == SectionKind::Text {
+            offset = offset.next_multiple_of(section.align);
+            offsets[i] = offset;
+            offset += section.size();
+        }
+    }
+    let relocs = || {
+        obj.sections
+            .iter()
+            .flat_map(|section| section.relocs.iter())
+    };
+    // スタブは呼び出す関数、GOT の項目はアドレスを読むシンボルごとに作る
+    let mut stubs = HashMap::new();
+    let mut slots = HashMap::new();
+    offset = offset.next_multiple_of(STUB_SIZE);
+    for reloc in relocs() {
+        let symbol = &obj.symbols[reloc.symbol];
+        if elf::is_got(reloc.kind) {
+            let next = slots.len();
+            slots.entry(reloc.symbol).or_insert(next);
+        } else if symbol.section.is_none() && !stubs.contains_key(symbol.name.as_str()) {
+            stubs.insert(symbol.name.as_str(), (offset, lookup(&symbol.name)?));
+            offset += STUB_SIZE;
+        }
+    }
+    let code_size = offset.next_multiple_of(PAGE);
+    let got = code_size;
+    offset = got + slots.len() * 8;
+    for (i, section) in obj.sections.iter().enumerate() {
+        if section.kind != SectionKind::Text {
+            offset = offset.next_multiple_of(section.align);
+            offsets[i] = offset;
+            offset += section.size();
+        }
+    }
+    let size = offset.next_multiple_of(PAGE).max(PAGE);
+
+    // 絶対アドレス (-fno-pic) で参照するなら下位 2GB に置く
+    let absolute = relocs().any(|reloc| matches!(reloc.kind, elf::R_X86_64_32 | elf::R_X86_64_32S));
+    let flags = if absolute { MAP_32BIT } else { 0 };
+    let base = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            size,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS | flags,
+            -1,
+            0,
+        )
+    };
+    if base as isize == -1 {
+        return Err("実行するためのメモリを確保できません".to_string());
+    }
+    // 匿名のマッピングは 0 で埋められているので、.bss はそのままでよい
+    let memory = unsafe { std::slice::from_raw_parts_mut(base as *mut u8, size) };
+    let base = base as u64;
+    for (section, &offset) in obj.sections.iter().zip(offsets.iter()) {
+        memory[offset..offset + section.data.len()].copy_from_slice(&section.data);
+    }
+    for &(offset, address) in stubs.values() {
+        memory[offset..offset + 6].copy_from_slice(&[0xff, 0x25, 0, 0, 0, 0]);
+        memory[offset + 6..offset + 14].copy_from_slice(&address.to_le_bytes());
+    }
+
+    let address = |index: usize| {
+        let symbol = &obj.symbols[index];
+        match symbol.section {
+            Some(sec) => Ok(base + (offsets[sec] as u64) + symbol.value),
+            None => lookup(&symbol.name),
+        }
+    };
+    for (&index, &slot) in slots.iter() {
+        let place = got + slot * 8;
+        memory[place..place + 8].copy_from_slice(&address(index)?.to_le_bytes());
+    }
+
+    for (section, &offset) in obj.sections.iter().zip(offsets.iter()) {
+        for reloc in section.relocs.iter() {
+            let symbol = &obj.symbols[reloc.symbol];
+            let target = if elf::is_got(reloc.kind) {
+                base + (got + slots[&reloc.symbol] * 8) as u64
+            } else if symbol.section.is_none() {
+                base + stubs[symbol.name.as_str()].0 as u64
+            } else {
+                address(reloc.symbol)?
+            };
+            let value = target.wrapping_add_signed(reloc.addend);
+            let place = offset + reloc.offset as usize;
+            let pc = base + place as u64;
+            let bytes = match reloc.kind {
+                elf::R_X86_64_32 => u32::try_from(value).map(|v| v.to_le_bytes()),
+                elf::R_X86_64_32S => i32::try_from(value as i64).map(|v| v.to_le_bytes()),
+                _ if reloc.kind == elf::R_X86_64_PC32
+                    || reloc.kind == elf::R_X86_64_PLT32
+                    || elf::is_got(reloc.kind) =>
+                {
+                    i32::try_from(value.wrapping_sub(pc) as i64).map(|v| v.to_le_bytes())
+                }
+                kind => return Err(format!("対応していない再配置の種類です: {}", kind)),
+            }
+            .map_err(|_| format!("再配置の値が範囲に収まりません: {}", symbol.name))?;
+            memory[place..place + 4].copy_from_slice(&bytes);
+        }
+    }
+
+    // 書き込みと実行を同時には許さない
+    if unsafe { mprotect(base as *mut c_void, code_size, PROT_READ | PROT_EXEC) } != 0 {
+        return Err("コードを実行可能にできません".to_string());
+    }
+
+    let main = obj
+        .symbols
+        .iter()
+        .find(|symbol| symbol.global && symbol.name == "main")
+        .and_then(|symbol| Some(base + (offsets[symbol.section?] as u64) + symbol.value))
+        .ok_or_else(|| "main が定義されていません".to_string())?;
+
+    let args: Vec<CString> = std::iter::once("a.out")
+        .chain(args.iter().map(|arg| arg.as_str()))
+        .map(|arg| CString::new(arg).map_err(|_| format!("不正な引数です: {}", arg)))
+        .collect::<Result<_, _>>()?;
+    let mut argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(std::ptr::null());
+    let main: MainFn = unsafe { std::mem::transmute(main as usize) };
+    Ok(main(args.len() as c_int, argv.as_ptr(), unsafe { environ }))
+}
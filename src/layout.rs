@@ -0,0 +1,19 @@
+use crate::options::Target;
+
+// ターゲットごとのデータレイアウト
+// ターゲットによって変わるのはポインタの幅だけで、中間表現の iptr (アドレスの計算や
+// サイズに使う整数) もポインタと同じ幅になる。char と int はどのターゲットでも 1 と 4 バイト
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataLayout {
+    pub ptr_size: usize, // ポインタのバイト数
+}
+
+impl DataLayout {
+    pub fn new(target: Target) -> Self {
+        let ptr_size = match target {
+            Target::I386 | Target::Wasm32 => 4,
+            _ => 8,
+        };
+        DataLayout { ptr_size }
+    }
+}
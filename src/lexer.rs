@@ -20,18 +20,77 @@ pub struct Token {
     pub kind: TokenKind,
     pub val: Option<i32>,
     pub str: String,
+    pub line: usize,
 }
 
 fn is_alnum(c: char) -> bool {
     c.is_ascii_alphabetic() || c.is_ascii_digit() || c == '_'
 }
 
+// 文字列リテラルのエスケープシーケンスを展開してバイト列にする
+pub fn unescape(lit: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chars = lit.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        let Some(c) = chars.next() else {
+            break;
+        };
+        match c {
+            'a' => bytes.push(7),
+            'b' => bytes.push(8),
+            't' => bytes.push(9),
+            'n' => bytes.push(10),
+            'v' => bytes.push(11),
+            'f' => bytes.push(12),
+            'r' => bytes.push(13),
+            'e' => bytes.push(27),
+            '0'..='7' => {
+                let mut val = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(d) => {
+                            val = val * 8 + d;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                bytes.push(val as u8);
+            }
+            'x' => {
+                let mut val = 0;
+                while let Some(d) = chars.peek().and_then(|c| c.to_digit(16)) {
+                    val = val * 16 + d;
+                    chars.next();
+                }
+                bytes.push(val as u8);
+            }
+            _ => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    bytes
+}
+
 pub fn tokenize(input: &str) -> Vec<Token> {
     let mut tokens = Vec::new();
     let mut chars = input.chars().peekable();
+    let mut line = 1;
 
     while let Some(&c) = chars.peek() {
         if c.is_whitespace() {
+            if c == '\n' {
+                line += 1;
+            }
             chars.next();
             continue;
         }
@@ -57,6 +116,9 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                     chars.next();
                     break;
                 }
+                if c == '\n' {
+                    line += 1;
+                }
                 chars.next();
             }
             continue;
@@ -71,6 +133,12 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                 }
                 lit.push(c);
                 chars.next();
+                // エスケープされた文字は終端として扱わない
+                if c == '\\' {
+                    if let Some(escaped) = chars.next() {
+                        lit.push(escaped);
+                    }
+                }
             }
             chars.next();
 
@@ -78,15 +146,29 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                 kind: TokenKind::Strlit,
                 val: None,
                 str: lit,
+                line,
+            });
+            continue;
+        }
+
+        if c == '-' && chars.clone().nth(1) == Some('>') {
+            tokens.push(Token {
+                kind: TokenKind::Reserved,
+                val: None,
+                str: "->".to_string(),
+                line,
             });
+            chars.next();
+            chars.next();
             continue;
         }
 
-        if "+-*/();{},&[]".contains(c) {
+        if "+-*/();{},&[].:".contains(c) {
             tokens.push(Token {
                 kind: TokenKind::Reserved,
                 val: None,
                 str: c.to_string(),
+                line,
             });
             chars.next();
             continue;
@@ -108,23 +190,39 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                 kind: TokenKind::Reserved,
                 val: None,
                 str: op,
+                line,
             });
             continue;
         }
 
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             let mut num_str = String::new();
             while let Some(&c) = chars.peek() {
-                if !c.is_digit(10) {
+                if !c.is_ascii_digit() {
                     break;
                 }
                 num_str.push(c);
                 chars.next();
             }
+            // 接尾辞 u の付いた定数は unsigned int になる (long はないので、int に収まらない値は u が要る)
+            let unsigned = matches!(chars.peek(), Some('u' | 'U'));
+            let (max, ty) = if unsigned {
+                (u32::MAX as u64, "unsigned int")
+            } else {
+                (i32::MAX as u64, "int")
+            };
+            let val = num_str.parse::<u64>().ok().filter(|val| *val <= max);
+            let Some(val) = val else {
+                error::error(format!("整数定数が {} に収まりません: {}", ty, num_str).as_str());
+            };
+            if unsigned {
+                num_str.push(chars.next().unwrap());
+            }
             tokens.push(Token {
                 kind: TokenKind::Num,
-                val: Some(num_str.parse().unwrap()),
+                val: Some(val as u32 as i32),
                 str: num_str,
+                line,
             });
             continue;
         }
@@ -144,6 +242,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::Return,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -152,6 +251,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::For,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -160,6 +260,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::While,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -168,6 +269,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::If,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -176,14 +278,16 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::Else,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
-                "int" => {
+                "int" | "struct" | "signed" | "unsigned" | "void" | "static" | "inline" => {
                     tokens.push(Token {
                         kind: TokenKind::Reserved,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -192,6 +296,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::Reserved,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -200,6 +305,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::Sizeof,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -208,6 +314,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
                         kind: TokenKind::Ident,
                         val: None,
                         str: ident,
+                        line,
                     });
                     continue;
                 }
@@ -221,6 +328,7 @@ pub fn tokenize(input: &str) -> Vec<Token> {
         kind: TokenKind::Eof,
         val: None,
         str: String::new(),
+        line,
     });
 
     tokens
@@ -0,0 +1,91 @@
+use crate::alias::AliasInfo;
+use crate::cfg::{self, DomTree};
+use crate::ir::{BinOp, BlockId, Function, Inst, Operand, Terminator};
+use crate::loops::{self, Loop};
+use crate::pass::PassContext;
+
+// ループ不変式の移動
+// ループを何度回っても同じ値になる式を、ループの前のプリヘッダで一度だけ計算する
+pub fn licm(func: &mut Function, ctx: &PassContext) {
+    loops::insert_preheaders(func);
+    let dom = DomTree::new(func);
+    let rpo = cfg::reverse_postorder(func);
+    let alias = AliasInfo::new(func, ctx.strict_aliasing);
+    // 内側のループから順に移すので、外側のループのプリヘッダまで移っていく
+    for lp in loops::find_loops(func, &dom) {
+        let Some(pre) = lp.preheader(func) else {
+            continue;
+        };
+        let mut defs = lp.defs(func);
+        // ループ内でメモリに書き込む命令
+        let writes: Vec<Inst> = lp
+            .blocks
+            .iter()
+            .flat_map(|block| func.blocks[block.0].insts.iter())
+            .filter(|inst| {
+                matches!(
+                    inst,
+                    Inst::Store { .. } | Inst::Call { .. } | Inst::StackRestore { .. }
+                )
+            })
+            .cloned()
+            .collect();
+        let entered = always_entered(func, &lp);
+        for block in rpo.iter().filter(|block| lp.contains(**block)) {
+            let mut kept = Vec::new();
+            for inst in std::mem::take(&mut func.blocks[block.0].insts) {
+                let invariant = inst.operands().iter().all(|op| match op {
+                    Operand::Reg(reg) => !defs.contains(reg),
+                    Operand::Imm(_) => true,
+                });
+                // ループ内で書き換えられない場所からの読み出しは、ループに入ると必ず実行されるか
+                // 例外にならないとわかっていれば移せる
+                let hoistable = match &inst {
+                    Inst::Load { addr, ty, .. } => {
+                        (entered.contains(block) || alias.is_dereferenceable(func, *addr, *ty))
+                            && !writes.iter().any(|write| alias.clobbers(write, *addr, *ty))
+                    }
+                    _ => can_hoist(&inst),
+                };
+                if invariant && hoistable {
+                    defs.remove(&inst.dst().unwrap());
+                    func.blocks[pre.0].insts.push(inst);
+                } else {
+                    kept.push(inst);
+                }
+            }
+            func.blocks[block.0].insts = kept;
+        }
+    }
+}
+
+// ループが一度も回らなくても実行してよい、副作用のない命令か
+fn can_hoist(inst: &Inst) -> bool {
+    match inst {
+        // 0 や -1 で割ると例外になりうる
+        Inst::Bin {
+            op: BinOp::SDiv | BinOp::UDiv,
+            rhs,
+            ..
+        } => matches!(rhs, Operand::Imm(val) if *val != 0 && *val != -1),
+        Inst::Copy { .. }
+        | Inst::Bin { .. }
+        | Inst::Cmp { .. }
+        | Inst::Cast { .. }
+        | Inst::LocalAddr { .. }
+        | Inst::GlobalAddr { .. } => true,
+        _ => false,
+    }
+}
+
+// ヘッダから無条件のジャンプだけでたどれる、ループに入ると必ず実行されるブロック
+fn always_entered(func: &Function, lp: &Loop) -> Vec<BlockId> {
+    let mut blocks = vec![lp.header];
+    while let Terminator::Jump(next) = func.blocks[blocks.last().unwrap().0].term {
+        if !lp.contains(next) || blocks.contains(&next) {
+            break;
+        }
+        blocks.push(next);
+    }
+    blocks
+}
@@ -0,0 +1,266 @@
+use crate::elf::{self, Object, Reloc, Section, SectionKind, Symbol, SymbolKind};
+use crate::ir::Visibility;
+use std::collections::HashMap;
+use std::fs;
+
+// 静的リンカ
+// オブジェクトファイルのシンボルを解決して再配置を適用し、_start から main を呼んでその戻り値で
+// 終了する実行ファイルを作る。libc はリンクしないので、定義のない関数は呼べない
+
+const BASE: u64 = 0x400000;
+const PAGE: usize = 0x1000;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+// main を呼んで、戻り値を終了ステータスにして exit システムコールを呼ぶ
+fn start() -> Object {
+    let mut text = Section::new(".text", SectionKind::Text);
+    text.data = vec![
+        0x31, 0xed, // xor ebp, ebp
+        0x48, 0x8b, 0x3c, 0x24, // mov rdi, [rsp] (argc)
+        0x48, 0x8d, 0x74, 0x24, 0x08, // lea rsi, [rsp+8] (argv)
+        0x48, 0x8d, 0x54, 0xfe, 0x08, // lea rdx, [rsi+rdi*8+8] (envp)
+        0x48, 0x83, 0xe4, 0xf0, // and rsp, -16
+        0xe8, 0, 0, 0, 0, // call main
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 0x3c, 0, 0, 0, // mov eax, 60 (exit)
+        0x0f, 0x05, // syscall
+    ];
+    text.relocs.push(Reloc {
+        offset: 21,
+        symbol: 1,
+        kind: elf::R_X86_64_PLT32,
+        addend: -4,
+    });
+    let symbol = |name: &str, section| Symbol {
+        name: name.to_string(),
+        kind: SymbolKind::NoType,
+        section,
+        value: 0,
+        global: true,
+        visibility: Visibility::Default,
+    };
+    Object {
+        sections: vec![text],
+        symbols: vec![symbol("_start", Some(0)), symbol("main", None)],
+    }
+}
+
+// コンパイルしたオブジェクトとファイルから読んだオブジェクトをリンクする
+pub fn link(main: Object, paths: &[String]) -> Result<Vec<u8>, String> {
+    let mut objects = vec![start(), main];
+    for path in paths {
+        let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        objects.push(Object::parse(&bytes).map_err(|msg| format!("{}: {}", path, msg))?);
+    }
+    let (got, slots) = got(&objects)?;
+    objects.push(got);
+    link_objects(&objects, &slots)
+}
+
+// GOT を経由して参照するシンボルごとに 8 バイトの項目を持つオブジェクトと、各シンボルの項目の番号
+// 項目にはシンボルのアドレスを R_X86_64_64 の再配置で書き込む
+fn got(objects: &[Object]) -> Result<(Object, HashMap<String, usize>), String> {
+    let mut section = Section::new(".got", SectionKind::Data);
+    section.align = 8;
+    let mut symbols = Vec::new();
+    let mut slots = HashMap::new();
+    for obj in objects.iter() {
+        for reloc in obj
+            .sections
+            .iter()
+            .flat_map(|sec| sec.relocs.iter())
+            .filter(|reloc| elf::is_got(reloc.kind))
+        {
+            let symbol = &obj.symbols[reloc.symbol];
+            if !symbol.global {
+                return Err(format!(
+                    "ローカルなシンボルを GOT から参照することには対応していません: {}",
+                    symbol.name
+                ));
+            }
+            if slots.contains_key(&symbol.name) {
+                continue;
+            }
+            slots.insert(symbol.name.clone(), symbols.len());
+            section.relocs.push(Reloc {
+                offset: section.data.len() as u64,
+                symbol: symbols.len(),
+                kind: elf::R_X86_64_64,
+                addend: 0,
+            });
+            section.data.extend_from_slice(&[0; 8]);
+            symbols.push(Symbol {
+                name: symbol.name.clone(),
+                kind: SymbolKind::NoType,
+                section: None,
+                value: 0,
+                global: true,
+                visibility: Visibility::Default,
+            });
+        }
+    }
+    let got = Object {
+        sections: vec![section],
+        symbols,
+    };
+    Ok((got, slots))
+}
+
+// セグメントに並べるセクションの種類 (読み出し専用、実行可能、書き込み可能の順)
+const SEGMENTS: [(&[SectionKind], u32); 3] = [
+    (&[SectionKind::Rodata], PF_R),
+    (&[SectionKind::Text], PF_R | PF_X),
+    (&[SectionKind::Data, SectionKind::Bss], PF_R | PF_W),
+];
+
+struct Segment {
+    offset: usize,
+    filesz: usize,
+    memsz: usize,
+    flags: u32,
+}
+
+// GOT は最後のオブジェクトにある
+fn link_objects(objects: &[Object], slots: &HashMap<String, usize>) -> Result<Vec<u8>, String> {
+    let sections = || {
+        objects.iter().enumerate().flat_map(|(i, obj)| {
+            obj.sections
+                .iter()
+                .enumerate()
+                .map(move |(j, sec)| (i, j, sec))
+        })
+    };
+
+    // 書き込み可能なセクションがなければそのセグメントは作らない
+    let writable = sections().any(|(_, _, sec)| {
+        matches!(sec.kind, SectionKind::Data | SectionKind::Bss) && sec.size() > 0
+    });
+    let phnum: usize = if writable { 4 } else { 3 };
+
+    // セクションのファイルの中のオフセット (アドレスは BASE を足したもの)
+    // 読み出し専用のセグメントはヘッダから始め、ほかのセグメントはページ境界から始める
+    let mut offsets: Vec<Vec<usize>> = objects
+        .iter()
+        .map(|obj| vec![0; obj.sections.len()])
+        .collect();
+    let mut segments = Vec::new();
+    let mut offset = 64 + 56 * phnum;
+    for (i, (kinds, flags)) in SEGMENTS.iter().enumerate() {
+        if *flags & PF_W != 0 && !writable {
+            continue;
+        }
+        let start = if i == 0 {
+            0
+        } else {
+            offset.next_multiple_of(PAGE)
+        };
+        offset = offset.max(start);
+        let mut filesz = offset - start;
+        for kind in kinds.iter() {
+            for (obj, sec, section) in sections().filter(|(_, _, sec)| sec.kind == *kind) {
+                offset = offset.next_multiple_of(section.align);
+                offsets[obj][sec] = offset;
+                offset += section.size();
+                if section.kind != SectionKind::Bss {
+                    filesz = offset - start;
+                }
+            }
+        }
+        segments.push(Segment {
+            offset: start,
+            filesz,
+            memsz: offset - start,
+            flags: *flags,
+        });
+        // .bss はファイルに含めない
+        offset = start + filesz;
+    }
+
+    // グローバルなシンボルのアドレス
+    let mut globals = HashMap::new();
+    for (i, obj) in objects.iter().enumerate() {
+        for symbol in obj.symbols.iter().filter(|symbol| symbol.global) {
+            if let Some(sec) = symbol.section {
+                let address = BASE + (offsets[i][sec] as u64) + symbol.value;
+                if globals.insert(symbol.name.as_str(), address).is_some() {
+                    return Err(format!(
+                        "シンボルが重複して定義されています: {}",
+                        symbol.name
+                    ));
+                }
+            }
+        }
+    }
+    let address = |obj: usize, symbol: &Symbol| match symbol.section {
+        Some(sec) => Ok(BASE + offsets[obj][sec] as u64 + symbol.value),
+        None => globals
+            .get(symbol.name.as_str())
+            .copied()
+            .ok_or_else(|| format!("未定義のシンボルです: {}", symbol.name)),
+    };
+
+    let got = BASE + offsets[objects.len() - 1][0] as u64;
+
+    let mut out = vec![0; offset];
+    for (obj, sec, section) in sections() {
+        // .bss はファイルの外 (out の終わりより後ろ) に置かれ、書き込む中身もない
+        if section.kind == SectionKind::Bss {
+            continue;
+        }
+        let offset = offsets[obj][sec];
+        out[offset..offset + section.data.len()].copy_from_slice(&section.data);
+        for reloc in section.relocs.iter() {
+            let symbol = &objects[obj].symbols[reloc.symbol];
+            let target = if elf::is_got(reloc.kind) {
+                got + 8 * slots[&symbol.name] as u64
+            } else {
+                address(obj, symbol)?
+            };
+            let value = target.wrapping_add_signed(reloc.addend);
+            let place = offset + reloc.offset as usize;
+            let pc = BASE + place as u64;
+            let bytes = match reloc.kind {
+                elf::R_X86_64_64 => Ok(value.to_le_bytes().to_vec()),
+                elf::R_X86_64_PC32
+                | elf::R_X86_64_PLT32
+                | elf::R_X86_64_GOTPCREL
+                | elf::R_X86_64_GOTPCRELX
+                | elf::R_X86_64_REX_GOTPCRELX => {
+                    i32::try_from(value.wrapping_sub(pc) as i64).map(|v| v.to_le_bytes().to_vec())
+                }
+                elf::R_X86_64_32 => u32::try_from(value).map(|v| v.to_le_bytes().to_vec()),
+                elf::R_X86_64_32S => i32::try_from(value as i64).map(|v| v.to_le_bytes().to_vec()),
+                kind => return Err(format!("対応していない再配置の種類です: {}", kind)),
+            }
+            .map_err(|_| format!("再配置の値が範囲に収まりません: {}", symbol.name))?;
+            out[place..place + bytes.len()].copy_from_slice(&bytes);
+        }
+    }
+
+    let entry = globals["_start"];
+    out[..64].copy_from_slice(&elf::file_header(elf::ET_EXEC, entry, phnum, 0, 0));
+    let mut phdrs = Vec::new();
+    for segment in segments.iter() {
+        phdrs.extend_from_slice(&PT_LOAD.to_le_bytes());
+        phdrs.extend_from_slice(&segment.flags.to_le_bytes());
+        phdrs.extend_from_slice(&(segment.offset as u64).to_le_bytes());
+        let address = BASE + segment.offset as u64;
+        phdrs.extend_from_slice(&address.to_le_bytes());
+        phdrs.extend_from_slice(&address.to_le_bytes());
+        phdrs.extend_from_slice(&(segment.filesz as u64).to_le_bytes());
+        phdrs.extend_from_slice(&(segment.memsz as u64).to_le_bytes());
+        phdrs.extend_from_slice(&(PAGE as u64).to_le_bytes());
+    }
+    // スタックを実行不可にする
+    phdrs.extend_from_slice(&PT_GNU_STACK.to_le_bytes());
+    phdrs.extend_from_slice(&(PF_R | PF_W).to_le_bytes());
+    phdrs.extend_from_slice(&[0; 40]);
+    phdrs.extend_from_slice(&16u64.to_le_bytes());
+    out[64..64 + phdrs.len()].copy_from_slice(&phdrs);
+    Ok(out)
+}
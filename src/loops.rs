@@ -0,0 +1,360 @@
+use crate::cfg::{self, DomTree};
+use crate::ir::{BinOp, BlockId, Function, Inst, Operand, Reg, Terminator};
+use std::collections::{HashMap, HashSet};
+
+// ループの検出とループを変形するパスで共通に使う処理
+
+// 自然ループ (戻り辺の行き先が支配するブロックの集まり)
+pub struct Loop {
+    pub header: BlockId,
+    pub latches: Vec<BlockId>, // ヘッダに戻る辺を持つブロック
+    pub blocks: Vec<BlockId>,  // 番号の順に並べたループ内のブロック
+}
+
+impl Loop {
+    pub fn contains(&self, block: BlockId) -> bool {
+        self.blocks.binary_search(&block).is_ok()
+    }
+
+    // 戻り辺が一つだけならそのブロック
+    pub fn latch(&self) -> Option<BlockId> {
+        match self.latches.as_slice() {
+            [latch] => Some(*latch),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self, func: &Function) -> usize {
+        self.blocks
+            .iter()
+            .map(|block| func.blocks[block.0].insts.len() + 1)
+            .sum()
+    }
+
+    // ループの外にあり、ループ内のブロックから分岐してくるブロック
+    pub fn exits(&self, func: &Function) -> Vec<BlockId> {
+        let mut exits = Vec::new();
+        for block in self.blocks.iter() {
+            for succ in func.blocks[block.0].term.successors() {
+                if !self.contains(succ) && !exits.contains(&succ) {
+                    exits.push(succ);
+                }
+            }
+        }
+        exits
+    }
+
+    // ループの外からヘッダに入る唯一のブロックで、ヘッダにしか分岐しないもの
+    pub fn preheader(&self, func: &Function) -> Option<BlockId> {
+        let preds = cfg::predecessors(func);
+        match self.outside_preds(&preds).as_slice() {
+            [pred] if func.blocks[pred.0].term.successors() == [self.header] => Some(*pred),
+            _ => None,
+        }
+    }
+
+    fn outside_preds(&self, preds: &[Vec<BlockId>]) -> Vec<BlockId> {
+        preds[self.header.0]
+            .iter()
+            .copied()
+            .filter(|pred| !self.contains(*pred))
+            .collect()
+    }
+
+    // ループ内で定義される仮想レジスタ
+    pub fn defs(&self, func: &Function) -> HashSet<Reg> {
+        self.blocks
+            .iter()
+            .flat_map(|block| func.blocks[block.0].insts.iter())
+            .filter_map(|inst| inst.dst())
+            .collect()
+    }
+
+    // 他のループを含まないか
+    pub fn is_innermost(&self, loops: &[Loop]) -> bool {
+        loops
+            .iter()
+            .all(|other| other.header == self.header || !self.contains(other.header))
+    }
+}
+
+// 内側のループが先に来るように並べた自然ループ
+pub fn find_loops(func: &Function, dom: &DomTree) -> Vec<Loop> {
+    let preds = cfg::predecessors(func);
+    let mut latches: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+    for (i, block) in func.blocks.iter().enumerate() {
+        if !dom.is_reachable(BlockId(i)) {
+            continue;
+        }
+        for succ in block.term.successors() {
+            if dom.dominates(succ, BlockId(i)) {
+                latches.entry(succ).or_default().push(BlockId(i));
+            }
+        }
+    }
+
+    let mut loops: Vec<Loop> = latches
+        .into_iter()
+        .map(|(header, latches)| {
+            // 戻り辺の元から先行ブロックをヘッダまでさかのぼる
+            let mut blocks = vec![header];
+            let mut stack = latches.clone();
+            while let Some(block) = stack.pop() {
+                if blocks.contains(&block) {
+                    continue;
+                }
+                blocks.push(block);
+                stack.extend(
+                    preds[block.0]
+                        .iter()
+                        .filter(|pred| dom.is_reachable(**pred)),
+                );
+            }
+            blocks.sort();
+            Loop {
+                header,
+                latches,
+                blocks,
+            }
+        })
+        .collect();
+    loops.sort_by_key(|lp| (lp.blocks.len(), lp.header));
+    loops
+}
+
+// すべてのループにプリヘッダを作る
+// ループの外からヘッダに入る辺を新しいブロックに集め、ヘッダの phi の入力もそこで合流させる
+pub fn insert_preheaders(func: &mut Function) {
+    loop {
+        let dom = DomTree::new(func);
+        let loops = find_loops(func, &dom);
+        let preds = cfg::predecessors(func);
+        // 入口のブロックがヘッダならループの外からは入れない
+        let Some(lp) = loops
+            .iter()
+            .find(|lp| lp.preheader(func).is_none() && !lp.outside_preds(&preds).is_empty())
+        else {
+            return;
+        };
+        let outside = lp.outside_preds(&preds);
+        let header = lp.header;
+
+        let pre = func.new_block();
+        func.blocks[pre.0].term = Terminator::Jump(header);
+        for pred in outside.iter() {
+            for succ in func.blocks[pred.0].term.successors_mut() {
+                if *succ == header {
+                    *succ = pre;
+                }
+            }
+        }
+        let mut merged = Vec::new();
+        for inst in func.blocks[header.0].insts.iter_mut() {
+            let Inst::Phi { dst, incoming } = inst else {
+                continue;
+            };
+            let (from_outside, mut rest): (Vec<_>, Vec<_>) = incoming
+                .drain(..)
+                .partition(|(pred, _)| outside.contains(pred));
+            merged.push((*dst, from_outside));
+            *incoming = std::mem::take(&mut rest);
+        }
+        for (dst, from_outside) in merged {
+            let val = match from_outside.as_slice() {
+                [(_, val)] => *val,
+                _ => {
+                    let phi = func.new_reg(func.reg_ty(dst));
+                    func.blocks[pre.0].insts.push(Inst::Phi {
+                        dst: phi,
+                        incoming: from_outside,
+                    });
+                    Operand::Reg(phi)
+                }
+            };
+            for inst in func.blocks[header.0].insts.iter_mut() {
+                if let Inst::Phi { dst: d, incoming } = inst {
+                    if *d == dst {
+                        incoming.push((pre, val));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// ループ内で定義した値をループの外で使うときは、出口のブロックの phi を経由させる (LCSSA 形式)
+// ループを複製したり出口を付け替えたりしても、外側の使用を書き換えずに済む
+// 書き換えられない使い方があれば false を返す
+pub fn form_lcssa(func: &mut Function, lp: &Loop) -> bool {
+    let mut lcssa = Lcssa {
+        dom: DomTree::new(func),
+        preds: cfg::predecessors(func),
+        exits: lp.exits(func),
+        def_blocks: HashMap::new(),
+        phis: HashMap::new(),
+    };
+    for block in lp.blocks.iter() {
+        for inst in func.blocks[block.0].insts.iter() {
+            if let Some(dst) = inst.dst() {
+                lcssa.def_blocks.insert(dst, *block);
+            }
+        }
+    }
+
+    for i in 0..func.blocks.len() {
+        let block = BlockId(i);
+        if lp.contains(block) || !lcssa.dom.is_reachable(block) {
+            continue;
+        }
+        let mut insts = std::mem::take(&mut func.blocks[i].insts);
+        let mut term = func.blocks[i].term.clone();
+        let mut uses: Vec<(BlockId, &mut Operand)> = Vec::new();
+        for inst in insts.iter_mut() {
+            match inst {
+                Inst::Phi { incoming, .. } => {
+                    uses.extend(incoming.iter_mut().map(|(pred, val)| (*pred, val)))
+                }
+                _ => uses.extend(inst.operands_mut().into_iter().map(|op| (block, op))),
+            }
+        }
+        uses.extend(term.operands_mut().into_iter().map(|op| (block, op)));
+        let mut ok = true;
+        for (at, op) in uses {
+            let Operand::Reg(reg) = *op else {
+                continue;
+            };
+            // ループ内のブロックから来る phi の入力はすでに出口で受け取っている
+            if !lcssa.def_blocks.contains_key(&reg) || lp.contains(at) {
+                continue;
+            }
+            match lcssa.phi_for(func, lp, reg, at) {
+                Some(phi) => *op = phi,
+                None => ok = false,
+            }
+        }
+        // 出口のブロック自身に phi を足していることがある
+        insts.splice(0..0, func.blocks[i].insts.drain(..));
+        func.blocks[i].insts = insts;
+        func.blocks[i].term = term;
+        if !ok {
+            return false;
+        }
+    }
+    true
+}
+
+struct Lcssa {
+    dom: DomTree,
+    preds: Vec<Vec<BlockId>>,
+    exits: Vec<BlockId>,
+    def_blocks: HashMap<Reg, BlockId>,
+    phis: HashMap<(Reg, BlockId), Reg>, // (値, 出口) ごとに置いた phi
+}
+
+impl Lcssa {
+    // at で使う reg の代わりになる出口の phi
+    fn phi_for(
+        &mut self,
+        func: &mut Function,
+        lp: &Loop,
+        reg: Reg,
+        at: BlockId,
+    ) -> Option<Operand> {
+        let exit = *self
+            .exits
+            .iter()
+            .find(|exit| self.dom.dominates(**exit, at))?;
+        if let Some(phi) = self.phis.get(&(reg, exit)) {
+            return Some(Operand::Reg(*phi));
+        }
+        // 出口のすべての先行ブロックで値が定義済みでなければならない
+        let def = self.def_blocks[&reg];
+        let preds = &self.preds[exit.0];
+        if !preds
+            .iter()
+            .all(|pred| lp.contains(*pred) && self.dom.dominates(def, *pred))
+        {
+            return None;
+        }
+        let phi = func.new_reg(func.reg_ty(reg));
+        func.blocks[exit.0].insts.insert(
+            0,
+            Inst::Phi {
+                dst: phi,
+                incoming: preds
+                    .iter()
+                    .map(|pred| (*pred, Operand::Reg(reg)))
+                    .collect(),
+            },
+        );
+        self.phis.insert((reg, exit), phi);
+        Some(Operand::Reg(phi))
+    }
+}
+
+// 基本帰納変数 (ヘッダの phi のうち、末尾で定数を足した値が戻ってくるもの) とその初期値と増分
+pub fn induction_variables(func: &Function, lp: &Loop) -> HashMap<Reg, (Operand, i64)> {
+    let (Some(pre), Some(latch)) = (lp.preheader(func), lp.latch()) else {
+        return HashMap::new();
+    };
+    let mut defs = HashMap::new();
+    for block in lp.blocks.iter() {
+        for inst in func.blocks[block.0].insts.iter() {
+            if let Some(dst) = inst.dst() {
+                defs.insert(dst, inst);
+            }
+        }
+    }
+    let mut ivs = HashMap::new();
+    for inst in func.blocks[lp.header.0].insts.iter() {
+        let Inst::Phi { dst, incoming } = inst else {
+            continue;
+        };
+        let (init, next) = match incoming.as_slice() {
+            [(p, init), (l, Operand::Reg(next))] | [(l, Operand::Reg(next)), (p, init)]
+                if *p == pre && *l == latch =>
+            {
+                (*init, next)
+            }
+            _ => continue,
+        };
+        let step = match defs.get(next) {
+            Some(Inst::Bin {
+                op: BinOp::Add,
+                lhs: Operand::Reg(iv),
+                rhs: Operand::Imm(step),
+                ..
+            }) if iv == dst => *step,
+            Some(Inst::Bin {
+                op: BinOp::Sub,
+                lhs: Operand::Reg(iv),
+                rhs: Operand::Imm(step),
+                ..
+            }) if iv == dst => step.wrapping_neg(),
+            _ => continue,
+        };
+        ivs.insert(*dst, (init, step));
+    }
+    ivs
+}
+
+// ブロック内の phi の先行ブロック from を to に付け替える
+pub fn rename_pred(func: &mut Function, block: BlockId, from: BlockId, to: BlockId) {
+    for inst in func.blocks[block.0].insts.iter_mut() {
+        if let Inst::Phi { incoming, .. } = inst {
+            for (pred, _) in incoming.iter_mut() {
+                if *pred == from {
+                    *pred = to;
+                }
+            }
+        }
+    }
+}
+
+// 仮想レジスタを対応表に従って置き換える
+pub fn remap(map: &HashMap<Reg, Operand>, op: Operand) -> Operand {
+    match op {
+        Operand::Reg(reg) => map.get(&reg).copied().unwrap_or(op),
+        Operand::Imm(_) => op,
+    }
+}
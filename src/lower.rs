@@ -0,0 +1,743 @@
+use crate::ir::{
+    self, BinOp, BlockId, CastKind, CmpOp, Inst, Module, Operand, Reg, SlotId, Terminator, Ty,
+    Visibility,
+};
+use crate::layout::DataLayout;
+use crate::parser::{get_type_align, get_type_size};
+use crate::parser::{
+    BinaryOpKind, ComparisonOpKind, Member, Node, NodeKind, Type, TypeKind, UnaryOpKind,
+};
+use std::collections::HashMap;
+
+// 型検査済みの構文木を中間表現に変換する
+// 可視性の指定がない定義は visibility (-fvisibility) にする
+pub fn lower(
+    program: &[Node],
+    strings: &[Vec<u8>],
+    visibility: Visibility,
+    layout: DataLayout,
+) -> Module {
+    let mut module = Module {
+        globals: Vec::new(),
+        strings: strings.to_vec(),
+        functions: Vec::new(),
+    };
+    for node in program {
+        match &node.kind {
+            NodeKind::GVarDef(gvar) => module.globals.push(ir::Global {
+                name: gvar.name.clone(),
+                size: get_type_size(&gvar.ty, layout),
+                visibility: gvar.visibility.unwrap_or(visibility),
+            }),
+            NodeKind::Fndef(func, _) => {
+                let mut lowered = Lowerer::function(node, strings, layout);
+                // static な関数はもともとほかのモジュールから見えない
+                if !func.is_static {
+                    lowered.visibility = func.visibility.unwrap_or(visibility);
+                }
+                module.functions.push(lowered);
+            }
+            _ => {}
+        }
+    }
+    module
+}
+
+// 値を仮想レジスタに置くときの型 (char は int に昇格させる)
+fn reg_ty(ty: &Type) -> Ty {
+    match ty.kind {
+        TypeKind::Char | TypeKind::Int | TypeKind::Void => Ty::I32,
+        _ => Ty::Ptr,
+    }
+}
+
+// メモリ上の型と、読み込むときに符号拡張するか
+fn mem_ty(ty: &Type) -> (Ty, bool) {
+    match ty.kind {
+        TypeKind::Char => (Ty::I8, !ty.is_unsigned),
+        TypeKind::Int => (Ty::I32, true),
+        _ => (Ty::Ptr, true),
+    }
+}
+
+struct Lowerer<'a> {
+    func: ir::Function,
+    cur: BlockId,
+    slots: HashMap<usize, SlotId>, // 意味解析で割り当てたオフセットからスロットへの対応
+    saved: HashMap<usize, Reg>,    // ブロックの入口で保存したスタックポインタ
+    ret_ty: Type,
+    strings: &'a [Vec<u8>],
+}
+
+impl<'a> Lowerer<'a> {
+    fn function(node: &Node, strings: &'a [Vec<u8>], layout: DataLayout) -> ir::Function {
+        let NodeKind::Fndef(func, params) = &node.kind else {
+            unreachable!();
+        };
+        let ret = match func.ty.kind {
+            TypeKind::Void => None,
+            _ => Some(reg_ty(&func.ty)),
+        };
+        let mut lowerer = Lowerer {
+            func: ir::Function::new(&func.name, ret, layout),
+            cur: BlockId(0),
+            slots: HashMap::new(),
+            saved: HashMap::new(),
+            ret_ty: func.ty.clone(),
+            strings,
+        };
+        lowerer.func.is_static = func.is_static;
+        lowerer.func.inline = func.inline;
+        lowerer.cur = lowerer.func.new_block();
+
+        // 引数はレジスタで受け取り、変数のスロットに保存する
+        let lvars: Vec<_> = params
+            .iter()
+            .map(|param| match &param.kind {
+                NodeKind::LVar(lvar) => lvar,
+                _ => unreachable!(),
+            })
+            .collect();
+        for lvar in lvars.iter() {
+            let reg = lowerer.func.new_reg(reg_ty(&lvar.ty));
+            lowerer.func.params.push(reg);
+        }
+        for (lvar, reg) in lvars.iter().zip(lowerer.func.params.clone()) {
+            let addr = lowerer.local_addr(lvar.offset, &lvar.ty);
+            let (ty, _) = mem_ty(&lvar.ty);
+            lowerer.emit(Inst::Store {
+                addr,
+                val: Operand::Reg(reg),
+                ty,
+            });
+        }
+
+        // 最後の式文の値を返して関数の終端に到達する
+        let last = lowerer.stmt(node.rhs.as_ref().unwrap());
+        let val = ret.map(|ty| lowerer.coerce(last.unwrap_or(Operand::Imm(0)), ty, false));
+        lowerer.terminate(Terminator::Ret(val));
+        lowerer.func
+    }
+
+    fn ptr_size(&self) -> usize {
+        self.func.layout.ptr_size
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.func.blocks[self.cur.0].insts.push(inst);
+    }
+
+    fn terminate(&mut self, term: Terminator) {
+        self.func.blocks[self.cur.0].term = term;
+    }
+
+    fn switch_to(&mut self, block: BlockId) {
+        self.cur = block;
+    }
+
+    fn ty_of(&self, op: Operand) -> Ty {
+        match op {
+            Operand::Reg(reg) => self.func.reg_ty(reg),
+            Operand::Imm(_) => Ty::I32,
+        }
+    }
+
+    fn bin(&mut self, op: BinOp, ty: Ty, lhs: Operand, rhs: Operand) -> Operand {
+        let dst = self.func.new_reg(ty);
+        self.emit(Inst::Bin { op, dst, lhs, rhs });
+        Operand::Reg(dst)
+    }
+
+    fn load(&mut self, addr: Operand, ty: &Type) -> Operand {
+        let (mem, signed) = mem_ty(ty);
+        let dst = self.func.new_reg(reg_ty(ty));
+        self.emit(Inst::Load {
+            dst,
+            addr,
+            ty: mem,
+            signed,
+        });
+        Operand::Reg(dst)
+    }
+
+    fn cast(&mut self, src: Operand, to: Ty, kind: CastKind) -> Operand {
+        let dst = self.func.new_reg(to);
+        self.emit(Inst::Cast { dst, src, kind });
+        Operand::Reg(dst)
+    }
+
+    // レジスタの型を変換する。即値はそのまま変換後の値にする
+    fn coerce(&mut self, val: Operand, to: Ty, unsigned: bool) -> Operand {
+        let from = self.ty_of(val);
+        if from.size(self.func.layout) == to.size(self.func.layout) {
+            return val;
+        }
+        if let Operand::Imm(imm) = val {
+            return match to {
+                Ty::I32 => Operand::Imm(imm as i32 as i64),
+                _ if unsigned => Operand::Imm(imm as u32 as i64),
+                _ => Operand::Imm(imm),
+            };
+        }
+        let kind = if to.size(self.func.layout) < from.size(self.func.layout) {
+            CastKind::Trunc
+        } else if unsigned {
+            CastKind::Zext(from)
+        } else {
+            CastKind::Sext(from)
+        };
+        self.cast(val, to, kind)
+    }
+
+    // char に格納した値を読み直したときの値にする
+    fn narrow(&mut self, val: Operand, ty: &Type) -> Operand {
+        if ty.kind != TypeKind::Char {
+            return val;
+        }
+        match val {
+            Operand::Imm(imm) if ty.is_unsigned => Operand::Imm(imm as u8 as i64),
+            Operand::Imm(imm) => Operand::Imm(imm as i8 as i64),
+            Operand::Reg(_) if ty.is_unsigned => self.cast(val, Ty::I32, CastKind::Zext(Ty::I8)),
+            Operand::Reg(_) => self.cast(val, Ty::I32, CastKind::Sext(Ty::I8)),
+        }
+    }
+
+    fn slot(&mut self, offset: usize, size: usize, align: usize) -> SlotId {
+        if let Some(slot) = self.slots.get(&offset) {
+            return *slot;
+        }
+        let slot = self.func.new_slot(size, align);
+        self.slots.insert(offset, slot);
+        slot
+    }
+
+    fn slot_addr(&mut self, slot: SlotId) -> Operand {
+        let dst = self.func.new_reg(Ty::Ptr);
+        self.emit(Inst::LocalAddr { dst, slot });
+        Operand::Reg(dst)
+    }
+
+    // ローカル変数のスロット。可変長配列はポインタを保持するスロットになる
+    fn declare(&mut self, offset: usize, ty: &Type) -> SlotId {
+        if ty.is_vla() {
+            self.slot(offset, self.ptr_size(), self.ptr_size())
+        } else {
+            self.slot(
+                offset,
+                get_type_size(ty, self.func.layout),
+                get_type_align(ty, self.func.layout),
+            )
+        }
+    }
+
+    fn local_addr(&mut self, offset: usize, ty: &Type) -> Operand {
+        let slot = self.declare(offset, ty);
+        self.slot_addr(slot)
+    }
+
+    // 可変長配列のバイト数を保持するスロットの値を読む
+    fn vla_size(&mut self, ty: &Type) -> Operand {
+        let slot = self.slot(ty.vla_size.unwrap(), self.ptr_size(), self.ptr_size());
+        let addr = self.slot_addr(slot);
+        let dst = self.func.new_reg(Ty::IPtr);
+        self.emit(Inst::Load {
+            dst,
+            addr,
+            ty: Ty::IPtr,
+            signed: true,
+        });
+        Operand::Reg(dst)
+    }
+
+    // 可変長配列のバイト数を内側の次元から順に計算してスロットに保存する
+    fn compute_vla_size(&mut self, ty: &Type) {
+        let elem = ty.ptr_to.as_ref().unwrap();
+        if elem.is_vla() {
+            self.compute_vla_size(elem);
+        }
+        let len = match &ty.arr_len {
+            Some(len) => {
+                let val = self.expr(len);
+                self.coerce(val, Ty::IPtr, len.ty.is_unsigned_int())
+            }
+            None => Operand::Imm(ty.arr_size as i64),
+        };
+        let size = self.scale(len, elem);
+        let slot = self.slot(ty.vla_size.unwrap(), self.ptr_size(), self.ptr_size());
+        let addr = self.slot_addr(slot);
+        self.emit(Inst::Store {
+            addr,
+            val: size,
+            ty: Ty::IPtr,
+        });
+    }
+
+    // i64 の値に要素のサイズを掛ける
+    fn scale(&mut self, val: Operand, elem: &Type) -> Operand {
+        let size = match elem.vla_size {
+            Some(_) => self.vla_size(elem),
+            None => Operand::Imm(get_type_size(elem, self.func.layout) as i64),
+        };
+        match (val, size) {
+            (_, Operand::Imm(1)) => val,
+            (Operand::Imm(a), Operand::Imm(b)) => Operand::Imm(a * b),
+            _ => self.bin(BinOp::Mul, Ty::IPtr, val, size),
+        }
+    }
+
+    // 文を変換し、式文ならその値を返す
+    fn stmt(&mut self, node: &Node) -> Option<Operand> {
+        match &node.kind {
+            NodeKind::Block(stmts) => {
+                let mut last = None;
+                for stmt in stmts {
+                    if let Some(val) = self.stmt(stmt) {
+                        last = Some(val);
+                    }
+                }
+                last
+            }
+            NodeKind::LVarDef(lvar) => {
+                // 可変長配列は lhs で領域を確保する
+                if let Some(alloca) = &node.lhs {
+                    let ptr = self.expr(alloca);
+                    let addr = self.local_addr(lvar.offset, &lvar.ty);
+                    self.emit(Inst::Store {
+                        addr,
+                        val: ptr,
+                        ty: Ty::Ptr,
+                    });
+                } else {
+                    // スロットは宣言の順に並べる
+                    self.declare(lvar.offset, &lvar.ty);
+                }
+                None
+            }
+            NodeKind::TypeDecl(_) => None,
+            NodeKind::StackSave(id) => {
+                let dst = self.func.new_reg(Ty::Ptr);
+                self.emit(Inst::StackSave { dst });
+                self.saved.insert(*id, dst);
+                None
+            }
+            NodeKind::StackRestore(id) => {
+                let src = Operand::Reg(self.saved[id]);
+                self.emit(Inst::StackRestore { src });
+                None
+            }
+            NodeKind::Return => {
+                // sema で値の有無は戻り値の型と合っていることを確かめてある
+                let val = match (node.lhs.as_ref(), self.func.ret) {
+                    (Some(lhs), Some(ty)) => {
+                        let val = self.expr(lhs);
+                        let val = self.coerce(val, ty, lhs.ty.is_unsigned_int());
+                        let ret_ty = self.ret_ty.clone();
+                        Some(self.narrow(val, &ret_ty))
+                    }
+                    _ => None,
+                };
+                self.terminate(Terminator::Ret(val));
+                // return の後の文は到達不能なブロックに置く
+                self.cur = self.func.new_block();
+                None
+            }
+            NodeKind::If => {
+                let cond = self.expr(node.lhs.as_ref().unwrap());
+                let rhs = node.rhs.as_ref().unwrap();
+                let then = self.func.new_block();
+                let end = self.func.new_block();
+                if rhs.kind == NodeKind::Else {
+                    let els = self.func.new_block();
+                    self.terminate(Terminator::Branch { cond, then, els });
+                    self.switch_to(then);
+                    self.stmt(rhs.lhs.as_ref().unwrap());
+                    self.terminate(Terminator::Jump(end));
+                    self.switch_to(els);
+                    self.stmt(rhs.rhs.as_ref().unwrap());
+                } else {
+                    self.terminate(Terminator::Branch {
+                        cond,
+                        then,
+                        els: end,
+                    });
+                    self.switch_to(then);
+                    self.stmt(rhs);
+                }
+                self.terminate(Terminator::Jump(end));
+                self.switch_to(end);
+                None
+            }
+            NodeKind::While => {
+                self.lower_loop(node.lhs.as_ref().unwrap(), node.rhs.as_ref().unwrap(), None);
+                None
+            }
+            NodeKind::For => {
+                let cond = node.rhs.as_ref().unwrap();
+                let inc = cond.rhs.as_ref().unwrap();
+                self.stmt(node.lhs.as_ref().unwrap());
+                self.lower_loop(
+                    cond.lhs.as_ref().unwrap(),
+                    inc.rhs.as_ref().unwrap(),
+                    inc.lhs.as_deref(),
+                );
+                None
+            }
+            _ => Some(self.expr(node)),
+        }
+    }
+
+    fn lower_loop(&mut self, cond: &Node, body: &Node, inc: Option<&Node>) {
+        let head = self.func.new_block();
+        let body_block = self.func.new_block();
+        let end = self.func.new_block();
+        self.terminate(Terminator::Jump(head));
+        self.switch_to(head);
+        let cond = self.expr(cond);
+        self.terminate(Terminator::Branch {
+            cond,
+            then: body_block,
+            els: end,
+        });
+        self.switch_to(body_block);
+        self.stmt(body);
+        if let Some(inc) = inc {
+            self.stmt(inc);
+        }
+        self.terminate(Terminator::Jump(head));
+        self.switch_to(end);
+    }
+}
+
+impl<'a> Lowerer<'a> {
+    // 左辺値のアドレスを求める
+    fn addr(&mut self, node: &Node) -> Operand {
+        match &node.kind {
+            // 可変長配列は確保した領域へのポインタを保持している
+            NodeKind::LVar(lvar) if lvar.ty.is_vla() => {
+                let addr = self.local_addr(lvar.offset, &lvar.ty);
+                let dst = self.func.new_reg(Ty::Ptr);
+                self.emit(Inst::Load {
+                    dst,
+                    addr,
+                    ty: Ty::Ptr,
+                    signed: true,
+                });
+                Operand::Reg(dst)
+            }
+            NodeKind::LVar(lvar) => self.local_addr(lvar.offset, &lvar.ty),
+            NodeKind::GVar(gvar) => {
+                let dst = self.func.new_reg(Ty::Ptr);
+                self.emit(Inst::GlobalAddr {
+                    dst,
+                    name: gvar.name.clone(),
+                });
+                Operand::Reg(dst)
+            }
+            NodeKind::UnaryOp(UnaryOpKind::Deref) => self.expr(node.lhs.as_ref().unwrap()),
+            NodeKind::Member(member) => {
+                let base = self.addr(node.lhs.as_ref().unwrap());
+                if member.offset == 0 {
+                    return base;
+                }
+                self.bin(
+                    BinOp::Add,
+                    Ty::Ptr,
+                    base,
+                    Operand::Imm(member.offset as i64),
+                )
+            }
+            // 左辺値であることは意味解析で検査済み
+            _ => unreachable!(),
+        }
+    }
+
+    // 左辺値の値を読む。配列と構造体はアドレスをそのまま値とする
+    fn load_lval(&mut self, node: &Node) -> Operand {
+        let addr = self.addr(node);
+        if node.ty.is_aggregate() {
+            return addr;
+        }
+        self.load(addr, &node.ty)
+    }
+
+    fn expr(&mut self, node: &Node) -> Operand {
+        match &node.kind {
+            NodeKind::Num(val) => Operand::Imm(*val as i64),
+            NodeKind::Strlit(lit) => {
+                let dst = self.func.new_reg(Ty::Ptr);
+                self.emit(Inst::GlobalAddr {
+                    dst,
+                    name: format!(".LC{}", lit.idx),
+                });
+                Operand::Reg(dst)
+            }
+            NodeKind::LVar(_) | NodeKind::GVar(_) => self.load_lval(node),
+            NodeKind::Member(member) if member.bit_field.is_some() => {
+                let addr = self.addr(node);
+                self.load_bit_field(addr, member)
+            }
+            NodeKind::Member(_) => self.load_lval(node),
+            NodeKind::UnaryOp(UnaryOpKind::Ref) => self.addr(node.lhs.as_ref().unwrap()),
+            NodeKind::UnaryOp(UnaryOpKind::Deref) => self.load_lval(node),
+            NodeKind::Sizeof => {
+                // 定数のサイズは意味解析で畳み込まれているので、ここに来るのは可変長配列だけ
+                let lhs = node.lhs.as_ref().unwrap();
+                if matches!(lhs.kind, NodeKind::TypeDecl(_)) {
+                    self.compute_vla_size(&lhs.ty);
+                }
+                let size = self.vla_size(&lhs.ty);
+                self.coerce(size, Ty::I32, false)
+            }
+            NodeKind::Alloca => {
+                let lhs = node.lhs.as_ref().unwrap();
+                let size = self.expr(lhs);
+                let size = self.coerce(size, Ty::IPtr, lhs.ty.is_unsigned_int());
+                let dst = self.func.new_reg(Ty::Ptr);
+                self.emit(Inst::Alloca { dst, size });
+                Operand::Reg(dst)
+            }
+            NodeKind::Assign => self.assign(node),
+            NodeKind::Fncall(func, args) => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect();
+                let dst = match func.ty.kind {
+                    TypeKind::Void => None,
+                    _ => Some(self.func.new_reg(reg_ty(&func.ty))),
+                };
+                self.emit(Inst::Call {
+                    dst,
+                    name: func.name.clone(),
+                    args,
+                });
+                dst.map_or(Operand::Imm(0), Operand::Reg)
+            }
+            NodeKind::BinaryOp(op) => self.binary(op, node),
+            NodeKind::Comparison(op) => self.comparison(op, node),
+            _ => unreachable!(),
+        }
+    }
+
+    fn assign(&mut self, node: &Node) -> Operand {
+        let lhs = node.lhs.as_ref().unwrap();
+        let rhs = node.rhs.as_ref().unwrap();
+
+        // 文字列リテラルで char 配列を初期化する (残りは0埋め)
+        if let NodeKind::Strlit(lit) = &rhs.kind {
+            if lhs.ty.kind == TypeKind::Arr {
+                let addr = self.addr(lhs);
+                let strings = self.strings;
+                let bytes = &strings[lit.idx];
+                for i in 0..lhs.ty.arr_size {
+                    let byte = bytes.get(i).copied().unwrap_or(0);
+                    let dst = self.bin(BinOp::Add, Ty::Ptr, addr, Operand::Imm(i as i64));
+                    self.emit(Inst::Store {
+                        addr: dst,
+                        val: Operand::Imm(byte as i8 as i64),
+                        ty: Ty::I8,
+                    });
+                }
+                return addr;
+            }
+        }
+
+        let addr = self.addr(lhs);
+        let val = self.expr(rhs);
+        if let NodeKind::Member(member) = &lhs.kind {
+            if member.bit_field.is_some() {
+                return self.store_bit_field(addr, val, member);
+            }
+        }
+        if lhs.ty.kind == TypeKind::Struct {
+            self.copy(addr, val, get_type_size(&lhs.ty, self.func.layout));
+            return addr;
+        }
+        let val = self.coerce(val, reg_ty(&lhs.ty), rhs.ty.is_unsigned_int());
+        let (ty, _) = mem_ty(&lhs.ty);
+        self.emit(Inst::Store { addr, val, ty });
+        self.narrow(val, &lhs.ty)
+    }
+
+    // src から dst に size バイトをコピーする
+    fn copy(&mut self, dst: Operand, src: Operand, size: usize) {
+        let mut offset = 0;
+        for ty in [Ty::IPtr, Ty::I32, Ty::I8] {
+            while offset + ty.size(self.func.layout) <= size {
+                let from = self.offset(src, offset);
+                let to = self.offset(dst, offset);
+                let val = self
+                    .func
+                    .new_reg(if ty == Ty::IPtr { Ty::IPtr } else { Ty::I32 });
+                self.emit(Inst::Load {
+                    dst: val,
+                    addr: from,
+                    ty,
+                    signed: true,
+                });
+                self.emit(Inst::Store {
+                    addr: to,
+                    val: Operand::Reg(val),
+                    ty,
+                });
+                offset += ty.size(self.func.layout);
+            }
+        }
+    }
+
+    fn offset(&mut self, addr: Operand, offset: usize) -> Operand {
+        if offset == 0 {
+            return addr;
+        }
+        self.bin(BinOp::Add, Ty::Ptr, addr, Operand::Imm(offset as i64))
+    }
+
+    // 格納単位の値の下位 width ビットを取り出して符号拡張またはゼロ拡張する
+    fn extend_bit_field(&mut self, val: Operand, member: &Member, shift: usize) -> Operand {
+        let width = member.bit_field.as_ref().unwrap().width;
+        let val = self.bin(
+            BinOp::Shl,
+            Ty::I32,
+            val,
+            Operand::Imm((32 - shift - width) as i64),
+        );
+        let op = if member.ty.is_unsigned {
+            BinOp::LShr
+        } else {
+            BinOp::AShr
+        };
+        self.bin(op, Ty::I32, val, Operand::Imm((32 - width) as i64))
+    }
+
+    fn load_bit_field(&mut self, addr: Operand, member: &Member) -> Operand {
+        let unit = self.load_unit(addr, member);
+        let bit_offset = member.bit_field.as_ref().unwrap().bit_offset;
+        self.extend_bit_field(unit, member, bit_offset)
+    }
+
+    // ビットフィールドの格納単位をゼロ拡張して読む
+    fn load_unit(&mut self, addr: Operand, member: &Member) -> Operand {
+        let (ty, _) = mem_ty(&member.ty);
+        let dst = self.func.new_reg(Ty::I32);
+        self.emit(Inst::Load {
+            dst,
+            addr,
+            ty,
+            signed: false,
+        });
+        Operand::Reg(dst)
+    }
+
+    // 格納単位のビットフィールドに値を書き込み、書き込んだ値を返す
+    fn store_bit_field(&mut self, addr: Operand, val: Operand, member: &Member) -> Operand {
+        let bf = member.bit_field.as_ref().unwrap();
+        let mask = ((1u64 << bf.width) - 1) as u32;
+        let val = self.coerce(val, Ty::I32, false);
+        let bits = self.bin(BinOp::And, Ty::I32, val, Operand::Imm(mask as i32 as i64));
+        let bits = self.bin(
+            BinOp::Shl,
+            Ty::I32,
+            bits,
+            Operand::Imm(bf.bit_offset as i64),
+        );
+        let unit = self.load_unit(addr, member);
+        let keep = !(mask << bf.bit_offset);
+        let unit = self.bin(BinOp::And, Ty::I32, unit, Operand::Imm(keep as i32 as i64));
+        let unit = self.bin(BinOp::Or, Ty::I32, unit, bits);
+        let (ty, _) = mem_ty(&member.ty);
+        self.emit(Inst::Store {
+            addr,
+            val: unit,
+            ty,
+        });
+        self.extend_bit_field(val, member, 0)
+    }
+
+    // 整数をポインタ演算用に i64 に広げて要素のサイズを掛ける
+    fn index(&mut self, node: &Node, elem: &Type) -> Operand {
+        let val = self.expr(node);
+        let val = self.coerce(val, Ty::IPtr, node.ty.is_unsigned_int());
+        self.scale(val, elem)
+    }
+
+    fn binary(&mut self, op: &BinaryOpKind, node: &Node) -> Operand {
+        let lhs = node.lhs.as_ref().unwrap();
+        let rhs = node.rhs.as_ref().unwrap();
+        let unsigned = node.ty.is_unsigned_int();
+        match op {
+            BinaryOpKind::Add if node.ty.kind == TypeKind::Ptr => {
+                let elem = node.ty.ptr_to.as_ref().unwrap();
+                let (ptr, idx) = if lhs.ty.is_pointer() {
+                    (lhs, rhs)
+                } else {
+                    (rhs, lhs)
+                };
+                let ptr_val = self.expr(ptr);
+                let offset = self.index(idx, elem);
+                self.bin(BinOp::Add, Ty::Ptr, ptr_val, offset)
+            }
+            BinaryOpKind::Sub if node.ty.kind == TypeKind::Ptr => {
+                let elem = node.ty.ptr_to.as_ref().unwrap();
+                let ptr_val = self.expr(lhs);
+                let offset = self.index(rhs, elem);
+                self.bin(BinOp::Sub, Ty::Ptr, ptr_val, offset)
+            }
+            // ポインタ同士の差は要素数で返す
+            BinaryOpKind::Sub if lhs.ty.is_pointer() => {
+                let a = self.expr(lhs);
+                let b = self.expr(rhs);
+                let diff = self.bin(BinOp::Sub, Ty::IPtr, a, b);
+                let elems = self.scale(Operand::Imm(1), lhs.ty.ptr_to.as_ref().unwrap());
+                let diff = if elems == Operand::Imm(1) {
+                    diff
+                } else {
+                    self.bin(BinOp::SDiv, Ty::IPtr, diff, elems)
+                };
+                self.coerce(diff, Ty::I32, false)
+            }
+            _ => {
+                let a = self.expr(lhs);
+                let b = self.expr(rhs);
+                let op = match op {
+                    BinaryOpKind::Add => BinOp::Add,
+                    BinaryOpKind::Sub => BinOp::Sub,
+                    BinaryOpKind::Mul => BinOp::Mul,
+                    BinaryOpKind::Div if unsigned => BinOp::UDiv,
+                    BinaryOpKind::Div => BinOp::SDiv,
+                };
+                self.bin(op, Ty::I32, a, b)
+            }
+        }
+    }
+
+    fn comparison(&mut self, op: &ComparisonOpKind, node: &Node) -> Operand {
+        let lhs = node.lhs.as_ref().unwrap();
+        let rhs = node.rhs.as_ref().unwrap();
+        // ポインタとの比較は符号なし、unsigned int を含む比較も符号なしで行う
+        let is_ptr = lhs.ty.is_pointer() || rhs.ty.is_pointer();
+        let unsigned = is_ptr || lhs.ty.is_unsigned_int() || rhs.ty.is_unsigned_int();
+        let ty = if is_ptr { Ty::Ptr } else { Ty::I32 };
+        let a = self.expr(lhs);
+        let a = self.coerce(a, ty, lhs.ty.is_unsigned_int());
+        let b = self.expr(rhs);
+        let b = self.coerce(b, ty, rhs.ty.is_unsigned_int());
+        let op = match (op, unsigned) {
+            (ComparisonOpKind::Eq, _) => CmpOp::Eq,
+            (ComparisonOpKind::Nq, _) => CmpOp::Ne,
+            (ComparisonOpKind::Lt, false) => CmpOp::Slt,
+            (ComparisonOpKind::Le, false) => CmpOp::Sle,
+            (ComparisonOpKind::Gt, false) => CmpOp::Sgt,
+            (ComparisonOpKind::Ge, false) => CmpOp::Sge,
+            (ComparisonOpKind::Lt, true) => CmpOp::Ult,
+            (ComparisonOpKind::Le, true) => CmpOp::Ule,
+            (ComparisonOpKind::Gt, true) => CmpOp::Ugt,
+            (ComparisonOpKind::Ge, true) => CmpOp::Uge,
+        };
+        let dst = self.func.new_reg(Ty::I32);
+        self.emit(Inst::Cmp {
+            op,
+            ty,
+            dst,
+            lhs: a,
+            rhs: b,
+        });
+        Operand::Reg(dst)
+    }
+}
@@ -1,37 +1,83 @@
+mod aarch64;
+mod alias;
+mod asm;
+mod assembler;
+mod cfg;
+mod consteval;
+mod dce;
+mod elf;
 mod error;
+mod flow;
+mod fold;
 mod gen;
+mod gvn;
+mod i386;
+mod indvars;
+mod inline;
+mod ir;
+mod jit;
+mod layout;
 mod lexer;
+mod licm;
+mod linker;
+mod loops;
+mod lower;
+mod options;
 mod parser;
+mod pass;
+mod peephole;
+mod regalloc;
+mod riscv64;
+mod rotate;
+mod sema;
+mod ssa;
+mod tailcall;
+mod unroll;
+mod verify;
+mod wasm;
+mod wasm32;
+mod warning;
 
-use crate::parser::NodeKind;
 use std::env;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        error::error("引数の個数が正しくありません");
-    }
-
-    let input = &args[1];
-    let tokens = lexer::tokenize(input);
-    let mut parser = parser::Parser::new(tokens);
-    let code = parser.program().expect("構文解析に失敗しました");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = match options::parse(&args) {
+        Ok(opts) => opts,
+        Err(msg) => error::error(&msg),
+    };
+    warning::init(&opts.warnings);
 
-    println!("  .intel_syntax noprefix");
+    let layout = layout::DataLayout::new(opts.target);
 
-    // define all string literals
-    // println!("  .rodata"); // read-only data section
-    for (i, lit) in parser.str_literals.iter().enumerate() {
-        println!(".LC{}:", i);
-        println!("  .string \"{}\"", lit);
+    let tokens = lexer::tokenize(&opts.input);
+    let mut parser = parser::Parser::new(tokens);
+    let mut code = parser.program().expect("構文解析に失敗しました");
+    if let Err(msg) = sema::analyze(&mut code, layout) {
+        error::error(&msg);
     }
+    warning::check_werror();
 
-    let mut id = 0;
-    for (i, node) in code.iter().enumerate() {
-        gen::gen(node, &mut id);
-        if matches!(node.kind, NodeKind::GVarDef(_)) || i == code.len() - 1 {
-            continue;
-        }
-        println!("  pop rax");
+    let mut module = lower::lower(&code, &parser.str_literals, opts.visibility, layout);
+    let passes = match pass::PassManager::new(&opts) {
+        Ok(passes) => passes,
+        Err(msg) => error::error(&msg),
+    };
+    if let Err(msg) = passes.run(&mut module) {
+        error::error(&msg);
+    }
+    if opts.emit_ir {
+        print!("{}", module);
+        return;
+    }
+    for func in module.functions.iter_mut() {
+        ssa::destruct(func);
+    }
+    match opts.target {
+        options::Target::X86_64 => gen::gen(&module, &opts),
+        options::Target::Aarch64 => aarch64::gen(&module, &opts),
+        options::Target::Riscv64 => riscv64::gen(&module, &opts),
+        options::Target::Wasm32 => wasm32::gen(&module, &opts),
+        options::Target::I386 => i386::gen(&module, &opts),
     }
 }
@@ -0,0 +1,204 @@
+use crate::asm::Syntax;
+use crate::ir::Visibility;
+use crate::warning;
+
+// 出力するコードのターゲット
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    X86_64,
+    Aarch64,
+    Riscv64,
+    Wasm32,
+    I386,
+}
+
+// シンボルのアドレスの求め方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocModel {
+    Static, // -fno-pic: 絶対アドレス (下位 2GB に置く実行ファイル)
+    Pie,    // -fPIE: RIP 相対 (実行ファイルのシンボルは置き換えられない)
+    Pic,    // -fPIC: 置き換えられるシンボルは GOT と PLT を経由する (共有ライブラリ)
+}
+
+// コマンドラインオプション
+pub struct Options {
+    pub input: String,
+    pub warnings: warning::Config,
+    pub emit_ir: bool, // アセンブリの代わりに中間表現を出力する
+    pub emit_wasm: bool, // wasm32 でテキスト形式の代わりにバイナリ形式のモジュールを出力する
+    pub emit_obj: bool,  // アセンブリの代わりに ELF の再配置可能オブジェクトファイルを出力する
+    pub static_link: bool, // 内蔵のリンカで静的リンクした実行ファイルを出力する
+    pub objects: Vec<String>, // 一緒にリンクするオブジェクトファイル
+    pub run: bool,            // コンパイルした main をその場で実行する
+    pub run_args: Vec<String>, // --run で main に渡す引数 (ソースより後ろの引数)
+    pub opt_level: usize,
+    pub passes: Option<Vec<String>>, // 最適化レベルの代わりに実行するパスの列
+    pub verify_ir: bool,             // パスを実行するたびに中間表現を検査する
+    pub dump_before: Vec<String>,    // 実行前の中間表現を出力するパス ("all" はすべて)
+    pub dump_after: Vec<String>,
+    pub unroll_loops: bool, // 最適化のパイプラインにループの展開を加える
+    pub optimize_sibling_calls: bool, // 末尾呼び出しをジャンプにする (-O2 以上で有効)
+    pub strict_aliasing: bool, // 型に基づく別名解析を使う (-O2 以上で有効)
+    pub target: Target,
+    pub asm_syntax: Syntax, // x86 のアセンブリの記法
+    pub reloc_model: RelocModel,
+    pub visibility: Visibility, // 指定のない定義の可視性
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut input = None;
+    let mut warnings = warning::Config::default();
+    let mut emit_ir = false;
+    let mut emit_wasm = false;
+    let mut emit_obj = false;
+    let mut static_link = false;
+    let mut objects = Vec::new();
+    let mut run = false;
+    let mut run_args = Vec::new();
+    let mut opt_level = 0;
+    let mut passes = None;
+    let mut verify_ir = false;
+    let mut dump_before = Vec::new();
+    let mut dump_after = Vec::new();
+    let mut unroll_loops = false;
+    let mut sibling_calls = None;
+    let mut strict_aliasing = None;
+    let mut target = Target::X86_64;
+    let mut asm_syntax = Syntax::Intel;
+    let mut reloc_model = RelocModel::Pie;
+    let mut visibility = Visibility::Default;
+
+    for arg in args {
+        if run && input.is_some() {
+            run_args.push(arg.clone());
+        } else if arg == "--emit-ir" {
+            emit_ir = true;
+        } else if arg == "--emit-wasm" {
+            emit_wasm = true;
+        } else if arg == "-c" {
+            emit_obj = true;
+        } else if arg == "--run" {
+            run = true;
+        } else if arg == "-static" {
+            static_link = true;
+        } else if arg == "--verify-ir" {
+            verify_ir = true;
+        } else if let Some(list) = arg.strip_prefix("--passes=") {
+            passes = Some(split_list(list));
+        } else if let Some(list) = arg.strip_prefix("--dump-ir-before=") {
+            dump_before.extend(split_list(list));
+        } else if let Some(list) = arg.strip_prefix("--dump-ir-after=") {
+            dump_after.extend(split_list(list));
+        } else if arg == "-funroll-loops" {
+            unroll_loops = true;
+        } else if arg == "-fno-unroll-loops" {
+            unroll_loops = false;
+        } else if arg == "-foptimize-sibling-calls" {
+            sibling_calls = Some(true);
+        } else if arg == "-fno-optimize-sibling-calls" {
+            sibling_calls = Some(false);
+        } else if arg == "-fstrict-aliasing" {
+            strict_aliasing = Some(true);
+        } else if arg == "-fno-strict-aliasing" {
+            strict_aliasing = Some(false);
+        } else if arg == "-fPIC" || arg == "-fpic" {
+            reloc_model = RelocModel::Pic;
+        } else if arg == "-fPIE" || arg == "-fpie" {
+            reloc_model = RelocModel::Pie;
+        } else if ["-fno-pic", "-fno-PIC", "-fno-pie", "-fno-PIE"].contains(&arg.as_str()) {
+            reloc_model = RelocModel::Static;
+        } else if let Some(name) = arg.strip_prefix("-fvisibility=") {
+            visibility = match name {
+                "default" => Visibility::Default,
+                "hidden" => Visibility::Hidden,
+                "protected" => Visibility::Protected,
+                _ => return Err(format!("不明な可視性です: {}", name)),
+            };
+        } else if let Some(triple) = arg.strip_prefix("--target=") {
+            target = match triple {
+                "x86_64" | "x86_64-linux-gnu" => Target::X86_64,
+                "aarch64" | "aarch64-linux-gnu" => Target::Aarch64,
+                "riscv64" | "riscv64-linux-gnu" => Target::Riscv64,
+                "wasm32" | "wasm32-unknown-unknown" => Target::Wasm32,
+                "i386" | "i386-linux-gnu" | "i686" | "i686-linux-gnu" => Target::I386,
+                _ => return Err(format!("不明なターゲットです: {}", triple)),
+            };
+        } else if let Some(syntax) = arg.strip_prefix("-masm=") {
+            asm_syntax = match syntax {
+                "intel" => Syntax::Intel,
+                "att" => Syntax::Att,
+                _ => return Err(format!("不明なアセンブリの記法です: {}", syntax)),
+            };
+        } else if arg == "-m32" {
+            target = Target::I386;
+        } else if arg == "-m64" {
+            target = Target::X86_64;
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            opt_level = match level {
+                "0" => 0,
+                "" | "1" => 1,
+                "2" => 2,
+                _ => return Err(format!("不明な最適化レベルです: {}", arg)),
+            };
+        } else if arg.starts_with("-W") {
+            warnings.parse_option(arg)?;
+        } else if arg.starts_with('-') {
+            return Err(format!("不明なオプションです: {}", arg));
+        } else if arg.ends_with(".o") {
+            objects.push(arg.clone());
+        } else if input.is_none() {
+            input = Some(arg.clone());
+        } else {
+            return Err("引数の個数が正しくありません".to_string());
+        }
+    }
+
+    let Some(input) = input else {
+        return Err("引数の個数が正しくありません".to_string());
+    };
+    if emit_obj && target != Target::X86_64 {
+        return Err("-c は x86-64 のターゲットでしか使えません".to_string());
+    }
+    if static_link && (emit_obj || target != Target::X86_64) {
+        return Err("-static は x86-64 のターゲットで、-c と一緒には使えません".to_string());
+    }
+    if run && (emit_obj || static_link || target != Target::X86_64) {
+        return Err("--run は x86-64 のターゲットで、-c や -static と一緒には使えません".to_string());
+    }
+    if reloc_model != RelocModel::Pie && target != Target::X86_64 {
+        return Err("-fPIC と -fno-pic は x86-64 のターゲットでしか使えません".to_string());
+    }
+    if !objects.is_empty() && !static_link {
+        return Err("オブジェクトファイルは -static でしかリンクできません".to_string());
+    }
+    Ok(Options {
+        input,
+        warnings,
+        emit_ir,
+        emit_wasm,
+        emit_obj,
+        static_link,
+        objects,
+        run,
+        run_args,
+        opt_level,
+        passes,
+        verify_ir,
+        dump_before,
+        dump_after,
+        unroll_loops,
+        optimize_sibling_calls: sibling_calls.unwrap_or(opt_level >= 2),
+        strict_aliasing: strict_aliasing.unwrap_or(opt_level >= 2),
+        target,
+        asm_syntax,
+        reloc_model,
+        visibility,
+    })
+}
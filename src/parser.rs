@@ -1,5 +1,8 @@
 use crate::error;
-use crate::lexer::{Token, TokenKind};
+use crate::ir::{Inline, Visibility};
+use crate::layout::DataLayout;
+use crate::lexer::{self, Token, TokenKind};
+use crate::warning::{self, Warning};
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -32,6 +35,8 @@ pub enum TypeKind {
     Ptr,
     Arr,
     Char,
+    Struct,
+    Void,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -39,6 +44,115 @@ pub struct Type {
     pub kind: TypeKind,
     pub ptr_to: Option<Box<Type>>,
     pub arr_size: usize,
+    pub arr_len: Option<Box<Node>>, // 配列の要素数の式 (意味解析で arr_size に評価される)
+    pub is_unsigned: bool,
+    pub members: Vec<Member>, // 構造体のメンバ (不完全型のときは空)
+    pub struct_id: usize,     // 構造体の識別子 (同じ定義を指す型は同じ値を持つ)
+    pub size: usize,          // 構造体のサイズ (意味解析でレイアウトが決まる)
+    pub align: usize,         // 構造体のアラインメント
+    pub vla_size: Option<usize>, // 可変長配列のバイト数を実行時に保持するスロットのオフセット
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub name: Option<String>, // 無名のビットフィールドは None
+    pub ty: Type,
+    pub offset: usize, // ビットフィールドの場合は格納単位の先頭のオフセット
+    pub bit_field: Option<BitField>,
+    pub width: Option<Box<Node>>, // ビットフィールドの幅の式 (意味解析で bit_field に評価される)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitField {
+    pub width: usize,
+    pub bit_offset: usize, // 格納単位の中でのビット位置
+}
+
+impl Type {
+    fn new(kind: TypeKind) -> Self {
+        Type {
+            kind,
+            ptr_to: None,
+            arr_size: 1,
+            arr_len: None,
+            is_unsigned: false,
+            members: Vec::new(),
+            struct_id: 0,
+            size: 0,
+            align: 1,
+            vla_size: None,
+        }
+    }
+
+    pub fn int() -> Self {
+        Type::new(TypeKind::Int)
+    }
+
+    pub fn char() -> Self {
+        Type::new(TypeKind::Char)
+    }
+
+    pub fn void() -> Self {
+        Type::new(TypeKind::Void)
+    }
+
+    pub fn pointer_to(base: Type) -> Self {
+        Type {
+            ptr_to: Some(Box::new(base)),
+            ..Type::new(TypeKind::Ptr)
+        }
+    }
+
+    pub fn array_of(base: Type, len: usize) -> Self {
+        Type {
+            ptr_to: Some(Box::new(base)),
+            arr_size: len,
+            ..Type::new(TypeKind::Arr)
+        }
+    }
+
+    pub fn struct_of(id: usize, members: Vec<Member>) -> Self {
+        Type {
+            members,
+            struct_id: id,
+            ..Type::new(TypeKind::Struct)
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self.kind, TypeKind::Int | TypeKind::Char)
+    }
+
+    // 配列は式の中ではポインタとして扱う
+    pub fn is_pointer(&self) -> bool {
+        matches!(self.kind, TypeKind::Ptr | TypeKind::Arr)
+    }
+
+    // 値がアドレスで表される型
+    pub fn is_aggregate(&self) -> bool {
+        matches!(self.kind, TypeKind::Arr | TypeKind::Struct)
+    }
+
+    pub fn is_unsigned_int(&self) -> bool {
+        self.kind == TypeKind::Int && self.is_unsigned
+    }
+
+    // サイズが実行時に決まる配列 (要素が可変長配列のものを含む)
+    pub fn is_vla(&self) -> bool {
+        self.vla_size.is_some()
+    }
+}
+
+impl Member {
+    fn named(name: String) -> Self {
+        Member {
+            name: Some(name),
+            ty: Type::int(),
+            offset: 0,
+            bit_field: None,
+            width: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -52,6 +166,7 @@ pub struct LVar {
 pub struct GVar {
     pub name: String,
     pub ty: Type,
+    pub visibility: Option<Visibility>, // 指定がなければ -fvisibility に従う
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -59,8 +174,12 @@ pub struct Function {
     pub name: String,
     pub stack_size: usize,
     pub ty: Type,
+    pub is_static: bool, // 内部結合の関数
+    pub inline: Inline,
+    pub visibility: Option<Visibility>,
 }
 
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Strlit {
     pub idx: usize,
@@ -74,6 +193,7 @@ pub enum NodeKind {
     Comparison(ComparisonOpKind), // Comparison operations: ==, !=, <, <=, >, >=
     Num(i32),                     // Numeric literals
     Strlit(Strlit),               // String literals
+    Var(String),                  // Variable reference (resolved by sema)
     LVar(LVar),                   // Local variable
     LVarDef(LVar),                // Local variable definition
     GVar(GVar),                   // Global variable
@@ -87,6 +207,12 @@ pub enum NodeKind {
     While,                        // While
     If,                           // If
     Else,                         // Else
+    Sizeof,                       // Sizeof (folded into Num by sema)
+    Member(Member),               // Struct member access (resolved by sema)
+    TypeDecl(Type),               // Declaration without a declarator (struct definition)
+    Alloca,                       // Stack allocation of lhs bytes
+    StackSave(usize),             // Save the stack pointer on block entry (id shared with the restore)
+    StackRestore(usize),          // Release VLAs on block exit
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -95,175 +221,84 @@ pub struct Node {
     pub lhs: Option<Box<Node>>,
     pub rhs: Option<Box<Node>>,
     pub ty: Type,
+    pub line: usize,
 }
 
 pub struct Parser {
     tokens: Vec<Token>,
     pos: usize,
-    fn_idx: usize,
-    pub locals: Vec<HashMap<String, LVar>>, // function name -> local variables
-    pub globals: HashMap<String, GVar>,
-    pub functions: Vec<Function>,
-    pub str_literals: Vec<String>,
-    stack_size: usize,
+    pub str_literals: Vec<Vec<u8>>,
+    tags: Vec<HashMap<String, Type>>, // 構造体タグのスコープ
+    struct_count: usize,
 }
 
-pub fn get_type_size(ty: &Type) -> usize {
+pub fn get_type_size(ty: &Type, layout: DataLayout) -> usize {
     match &ty.kind {
         TypeKind::Char => 1,
         TypeKind::Int => 4,
-        TypeKind::Ptr => 8,
-        TypeKind::Arr => ty.arr_size * get_type_size(ty.ptr_to.as_ref().unwrap()),
+        TypeKind::Ptr => layout.ptr_size,
+        TypeKind::Arr => ty.arr_size * get_type_size(ty.ptr_to.as_ref().unwrap(), layout),
+        TypeKind::Struct => ty.size,
+        TypeKind::Void => 1,
     }
 }
 
-// 左右の子に応じてノードの型を決定する
-fn create_new_node(kind: NodeKind, lhs: Option<Box<Node>>, rhs: Option<Box<Node>>) -> Node {
-    let ty = match &kind {
-        NodeKind::BinaryOp(op) => {
-            match op {
-                BinaryOpKind::Add | BinaryOpKind::Sub => {
-                    if let (Some(l), Some(r)) = (&lhs, &rhs) {
-                        match (&l.ty.kind, &r.ty.kind) {
-                            (TypeKind::Ptr, TypeKind::Int) | (TypeKind::Arr, TypeKind::Int) => {
-                                // ポインタ/配列 + 整数 の場合
-                                match &l.ty.ptr_to {
-                                    Some(ptr_to) if ptr_to.kind == TypeKind::Arr => {
-                                        // 多次元配列の場合、1次元分の型を進める
-                                        (**ptr_to).clone()
-                                    }
-                                    _ => l.ty.clone(),
-                                }
-                            }
-                            (TypeKind::Int, TypeKind::Ptr) | (TypeKind::Int, TypeKind::Arr) => {
-                                // 整数 + ポインタ/配列 の場合
-                                match &r.ty.ptr_to {
-                                    Some(ptr_to) if ptr_to.kind == TypeKind::Arr => {
-                                        // 多次元配列の場合、1次元分の型を進める
-                                        (**ptr_to).clone()
-                                    }
-                                    _ => r.ty.clone(),
-                                }
-                            }
-                            _ => Type {
-                                kind: TypeKind::Int,
-                                ptr_to: None,
-                                arr_size: 1,
-                            },
-                        }
-                    } else {
-                        Type {
-                            kind: TypeKind::Int,
-                            ptr_to: None,
-                            arr_size: 1,
-                        }
-                    }
-                }
-                BinaryOpKind::Mul | BinaryOpKind::Div => Type {
-                    kind: TypeKind::Int,
-                    ptr_to: None,
-                    arr_size: 1,
-                },
-            }
+pub fn get_type_align(ty: &Type, layout: DataLayout) -> usize {
+    match &ty.kind {
+        TypeKind::Arr => get_type_align(ty.ptr_to.as_ref().unwrap(), layout),
+        TypeKind::Struct => ty.align,
+        _ => get_type_size(ty, layout),
+    }
+}
+
+// System V ABI に従って構造体のメンバを配置する
+// ビットフィールドは宣言された型の格納単位をまたがないように詰めていく
+// メンバの型とビットフィールドの幅は評価済みであること
+pub fn layout_struct(ty: &mut Type, layout: DataLayout) -> Result<(), String> {
+    let mut bits: usize = 0; // 先頭からのビット位置
+    let mut align = 1;
+
+    for member in ty.members.iter_mut() {
+        let size = get_type_size(&member.ty, layout);
+        let member_align = get_type_align(&member.ty, layout);
+
+        let Some(bit_field) = member.bit_field.as_mut() else {
+            bits = bits.next_multiple_of(member_align * 8);
+            member.offset = bits / 8;
+            bits += size * 8;
+            align = align.max(member_align);
+            continue;
+        };
+
+        let unit_bits = size * 8;
+        if bit_field.width > unit_bits {
+            return Err(format!(
+                "ビットフィールドの幅 {} が型の幅 {} を超えています",
+                bit_field.width, unit_bits
+            ));
         }
-        NodeKind::UnaryOp(op) => match op {
-            UnaryOpKind::Ref => {
-                if let Some(l) = &lhs {
-                    Type {
-                        kind: TypeKind::Ptr,
-                        ptr_to: Some(Box::new(l.ty.clone())),
-                        arr_size: 1,
-                    }
-                } else {
-                    Type {
-                        kind: TypeKind::Ptr,
-                        ptr_to: None,
-                        arr_size: 1,
-                    }
-                }
-            }
-            UnaryOpKind::Deref => {
-                if let Some(l) = &lhs {
-                    match &l.ty.kind {
-                        TypeKind::Ptr | TypeKind::Arr => l.ty.ptr_to.as_ref().map_or(
-                            Type {
-                                kind: TypeKind::Int,
-                                ptr_to: None,
-                                arr_size: 1,
-                            },
-                            |ptr_to| (**ptr_to).clone(),
-                        ),
-                        _ => Type {
-                            kind: TypeKind::Int,
-                            ptr_to: None,
-                            arr_size: 1,
-                        },
-                    }
-                } else {
-                    Type {
-                        kind: TypeKind::Int,
-                        ptr_to: None,
-                        arr_size: 1,
-                    }
-                }
-            }
-        },
-        _ => match &kind {
-            NodeKind::Comparison(_) => Type {
-                kind: TypeKind::Int,
-                ptr_to: None,
-                arr_size: 1,
-            },
-            NodeKind::Num(_) => Type {
-                kind: TypeKind::Int,
-                ptr_to: None,
-                arr_size: 1,
-            },
-            NodeKind::LVar(lvar) => lvar.ty.clone(),
-            NodeKind::LVarDef(lvar) => lvar.ty.clone(),
-            NodeKind::GVar(gvar) | NodeKind::GVarDef(gvar) => gvar.ty.clone(),
-            NodeKind::Assign => {
-                if let Some(l) = &lhs {
-                    l.ty.clone()
-                } else {
-                    Type {
-                        kind: TypeKind::Int,
-                        ptr_to: None,
-                        arr_size: 1,
-                    }
-                }
-            }
-            NodeKind::Strlit(lit) => Type {
-                kind: TypeKind::Arr,
-                ptr_to: Some(Box::new(Type {
-                    kind: TypeKind::Char,
-                    ptr_to: None,
-                    arr_size: 1,
-                })),
-                arr_size: lit.len,
-            },
-            NodeKind::Return => Type {
-                kind: TypeKind::Int,
-                ptr_to: None,
-                arr_size: 1,
-            },
-            NodeKind::Block(_) => Type {
-                kind: TypeKind::Int,
-                ptr_to: None,
-                arr_size: 1,
-            },
-            NodeKind::Fncall(func, _) => func.ty.clone(),
-            NodeKind::Fndef(func, _) => func.ty.clone(),
-            NodeKind::For | NodeKind::While | NodeKind::If | NodeKind::Else => Type {
-                kind: TypeKind::Int,
-                ptr_to: None,
-                arr_size: 1,
-            },
-            _ => unreachable!(),
-        },
-    };
-
-    Node { kind, lhs, rhs, ty }
+        // 幅0のビットフィールドは次の格納単位まで位置を進める
+        if bit_field.width == 0 {
+            bits = bits.next_multiple_of(unit_bits);
+            member.offset = bits / 8;
+            bit_field.bit_offset = 0;
+            continue;
+        }
+        if bits / unit_bits != (bits + bit_field.width - 1) / unit_bits {
+            bits = bits.next_multiple_of(unit_bits);
+        }
+        member.offset = bits / unit_bits * size;
+        bit_field.bit_offset = bits % unit_bits;
+        bits += bit_field.width;
+        // 無名のビットフィールドは構造体のアラインメントに影響しない
+        if member.name.is_some() {
+            align = align.max(member_align);
+        }
+    }
+
+    ty.align = align;
+    ty.size = bits.div_ceil(8).next_multiple_of(align);
+    Ok(())
 }
 
 impl Parser {
@@ -271,50 +306,34 @@ impl Parser {
         Parser {
             tokens,
             pos: 0,
-            fn_idx: 0, // indicates the index of the current function
-            locals: Vec::new(),
-            globals: HashMap::new(),
-            functions: Vec::new(),
             str_literals: Vec::new(),
-            stack_size: 0,
+            tags: vec![HashMap::new()],
+            struct_count: 0,
         }
     }
 
-    fn create_lvar(&mut self, name: &str, ty: Type) -> LVar {
-        let offset = if self.stack_size == 0 {
-            8
-        } else {
-            self.stack_size
-        };
-        self.stack_size = offset + get_type_size(&ty).next_multiple_of(8);
-        let lvar = LVar {
-            name: name.to_string(),
-            offset: offset,
-            ty: ty.clone(),
-        };
-        self.locals[self.fn_idx].insert(name.to_string(), lvar.clone());
-        lvar
-    }
-
-    fn find_lvar(&self, name: &str) -> Option<&LVar> {
-        self.locals[self.fn_idx].get(name)
+    // ノードを生成する (型は意味解析で決定する)
+    fn new_node(&self, kind: NodeKind, lhs: Option<Box<Node>>, rhs: Option<Box<Node>>) -> Node {
+        Node {
+            kind,
+            lhs,
+            rhs,
+            ty: Type::int(),
+            line: self.tokens[self.pos.saturating_sub(1)].line,
+        }
     }
 
-    fn create_gvar(&mut self, name: &str, ty: Type) -> GVar {
-        let gvar = GVar {
+    // オフセットは意味解析でスコープに登録するときに決まる
+    fn new_lvar(&self, name: &str, ty: Type) -> LVar {
+        LVar {
             name: name.to_string(),
-            ty: ty.clone(),
-        };
-        self.globals.insert(name.to_string(), gvar.clone());
-        gvar
-    }
-
-    fn find_gvar(&self, name: &str) -> Option<&GVar> {
-        self.globals.get(name)
+            offset: 0,
+            ty,
+        }
     }
 
     fn consume(&mut self, op: &str) -> bool {
-        if self.tokens[self.pos].str != op.to_string() {
+        if self.tokens[self.pos].str != op {
             return false;
         }
         self.pos += 1;
@@ -343,6 +362,18 @@ impl Parser {
         Ok(val)
     }
 
+    fn expect_ident(&mut self) -> Result<String, String> {
+        if self.tokens[self.pos].kind != TokenKind::Ident {
+            return Err(format!(
+                "識別子が期待されますが、'{}'でした",
+                self.tokens[self.pos].str
+            ));
+        }
+        let name = self.tokens[self.pos].str.clone();
+        self.pos += 1;
+        Ok(name)
+    }
+
     fn at_eof(&self) -> bool {
         self.tokens[self.pos].kind == TokenKind::Eof
     }
@@ -351,7 +382,12 @@ impl Parser {
         let mut nodes = Vec::new();
         let mut ty;
         while !self.at_eof() {
+            let (is_static, inline, visibility) = self.specifiers()?;
             ty = self.ty()?;
+            if self.consume(";") {
+                nodes.push(self.new_node(NodeKind::TypeDecl(ty), None, None));
+                continue;
+            }
             if self.tokens[self.pos].kind != TokenKind::Ident {
                 error::error("変数名がありません");
             }
@@ -359,18 +395,77 @@ impl Parser {
             self.pos += 1;
 
             if self.tokens[self.pos].str == "(" {
-                self.locals.push(HashMap::new());
-                nodes.push(self.function(name, ty)?);
-                self.fn_idx += 1;
-                self.stack_size = 0;
+                nodes.push(self.function(name, ty, is_static, inline, visibility)?);
+            } else if is_static || inline != Inline::Default {
+                return Err("static と inline は関数の定義にしか指定できません".to_string());
+            } else {
+                nodes.push(self.global_decl(name, ty, visibility)?);
+            }
+        }
+        Ok(nodes)
+    }
+
+    // 定義の前に置く static, inline と __attribute__((...))
+    fn specifiers(&mut self) -> Result<(bool, Inline, Option<Visibility>), String> {
+        let mut is_static = false;
+        let mut inline = Inline::Default;
+        let mut visibility = None;
+        loop {
+            if self.consume("static") {
+                is_static = true;
+            } else if self.consume("inline") {
+                if inline == Inline::Default {
+                    inline = Inline::Hint;
+                }
+            } else if self.consume("__attribute__") {
+                self.expect("(")?;
+                self.expect("(")?;
+                loop {
+                    let attr = self.tokens[self.pos].str.clone();
+                    self.pos += 1;
+                    match attr.as_str() {
+                        "always_inline" => inline = Inline::Always,
+                        "noinline" => inline = Inline::Never,
+                        "visibility" => visibility = Some(self.visibility()?),
+                        _ => return Err(format!("不明な属性です: {}", attr)),
+                    }
+                    if !self.consume(",") {
+                        break;
+                    }
+                }
+                self.expect(")")?;
+                self.expect(")")?;
             } else {
-                nodes.push(self.global_decl(name, ty)?);
+                return Ok((is_static, inline, visibility));
             }
         }
-        return Ok(nodes);
     }
 
-    fn function(&mut self, name: String, ty: Type) -> Result<Node, String> {
+    // visibility("...") の引数
+    fn visibility(&mut self) -> Result<Visibility, String> {
+        self.expect("(")?;
+        if self.tokens[self.pos].kind != TokenKind::Strlit {
+            return Err("可視性は文字列で指定してください".to_string());
+        }
+        let name = self.tokens[self.pos].str.clone();
+        self.pos += 1;
+        self.expect(")")?;
+        match name.as_str() {
+            "default" => Ok(Visibility::Default),
+            "hidden" => Ok(Visibility::Hidden),
+            "protected" => Ok(Visibility::Protected),
+            _ => Err(format!("不明な可視性です: {}", name)),
+        }
+    }
+
+    fn function(
+        &mut self,
+        name: String,
+        ty: Type,
+        is_static: bool,
+        inline: Inline,
+        visibility: Option<Visibility>,
+    ) -> Result<Node, String> {
         self.expect("(")?;
 
         let mut params = Vec::new();
@@ -379,24 +474,18 @@ impl Parser {
             self.expect(")")?;
         }
 
-        if self.functions.iter().any(|x| x.name == name) {
-            return Err(format!("関数 '{}' はすでに定義されています", name));
-        }
-
         let body = self.stmt()?;
 
         let rhs = Some(Box::new(body));
         let func = Function {
-            name: name.clone(),
-            stack_size: self.stack_size,
-            ty: ty.clone(),
+            name,
+            stack_size: 0,
+            ty,
+            is_static,
+            inline,
+            visibility,
         };
-        self.functions.push(func.clone());
-        Ok(create_new_node(
-            NodeKind::Fndef(func.clone(), params.clone()),
-            None,
-            rhs,
-        ))
+        Ok(self.new_node(NodeKind::Fndef(func, params), None, rhs))
     }
 
     fn paramlist(&mut self) -> Result<Vec<Node>, String> {
@@ -404,9 +493,9 @@ impl Parser {
 
         loop {
             let ty = self.ty()?;
-            let lvar = self.create_lvar(&self.tokens[self.pos].str.clone(), ty.clone());
-            params.push(create_new_node(NodeKind::LVar(lvar), None, None));
-            self.pos += 1;
+            let name = self.expect_ident()?;
+            let lvar = self.new_lvar(&name, ty);
+            params.push(self.new_node(NodeKind::LVar(lvar), None, None));
             if !self.consume(",") {
                 break;
             }
@@ -414,55 +503,73 @@ impl Parser {
         Ok(params)
     }
 
-    fn global_decl(&mut self, name: String, mut ty: Type) -> Result<Node, String> {
-        let node;
+    fn global_decl(
+        &mut self,
+        name: String,
+        ty: Type,
+        visibility: Option<Visibility>,
+    ) -> Result<Node, String> {
+        let ty = self.array_suffix(ty)?;
+        let gvar = GVar {
+            name,
+            ty,
+            visibility,
+        };
+        let node = self.new_node(NodeKind::GVarDef(gvar), None, None);
+
+        self.expect(";")?;
+        Ok(node)
+    }
 
-        let mut nums = Vec::new();
+    // ("[" expr "]")* を読んで配列型を組み立てる
+    fn array_suffix(&mut self, mut ty: Type) -> Result<Type, String> {
+        let mut lens = Vec::new();
         while self.consume("[") {
-            nums.push(self.expect_number()?);
+            lens.push(self.expr()?);
             self.expect("]")?;
         }
 
-        for num in nums.iter().rev() {
-            ty.ptr_to = Some(Box::new(ty.clone()));
-            ty.kind = TypeKind::Arr;
-            ty.arr_size = *num as usize;
+        for len in lens.into_iter().rev() {
+            ty = Type::array_of(ty, 0);
+            ty.arr_len = Some(Box::new(len));
         }
-
-        let gvar = self.create_gvar(&name, ty.clone());
-        self.globals.insert(name.clone(), gvar.clone());
-
-        node = create_new_node(NodeKind::GVarDef(gvar.clone()), None, None);
-
-        self.expect(";")?;
-        Ok(node)
+        Ok(ty)
     }
 
     fn stmt(&mut self) -> Result<Node, String> {
         let node: Node;
         if self.consume("{") {
             let mut stmts = Vec::new();
+            self.tags.push(HashMap::new());
             loop {
                 if self.consume("}") {
                     break;
                 }
                 stmts.push(self.stmt()?);
             }
-            node = create_new_node(NodeKind::Block(stmts), None, None);
+            self.tags.pop();
+            node = self.new_node(NodeKind::Block(stmts), None, None);
         } else if self.consume("return") {
-            node = create_new_node(NodeKind::Return, Some(Box::new(self.expr()?)), None);
-            self.expect(";")?;
+            // 値のない return は lhs を持たない
+            let expr = if self.consume(";") {
+                None
+            } else {
+                let expr = self.expr()?;
+                self.expect(";")?;
+                Some(Box::new(expr))
+            };
+            node = self.new_node(NodeKind::Return, expr, None);
         } else if self.consume("for") {
-            let mut init = create_new_node(NodeKind::Num(0), None, None);
-            let mut cond = create_new_node(NodeKind::Num(1), None, None);
-            let mut inc = create_new_node(NodeKind::Num(0), None, None);
+            let mut init = self.new_node(NodeKind::Num(0), None, None);
+            let mut cond = self.new_node(NodeKind::Num(1), None, None);
+            let mut inc = self.new_node(NodeKind::Num(0), None, None);
             self.expect("(")?;
             if !self.consume(";") {
                 init = self.expr()?;
                 self.expect(";")?;
             }
             if !self.consume(";") {
-                cond = self.expr()?;
+                cond = self.cond_expr()?;
                 self.expect(";")?;
             }
             if !self.consume(")") {
@@ -470,33 +577,30 @@ impl Parser {
                 self.expect(")")?;
             }
             let lhs = Some(Box::new(init));
-            let rrhs = create_new_node(
-                NodeKind::For,
-                Some(Box::new(inc)),
-                Some(Box::new(self.stmt()?)),
-            );
-            let rhs = create_new_node(NodeKind::For, Some(Box::new(cond)), Some(Box::new(rrhs)));
-            node = create_new_node(NodeKind::For, lhs, Some(Box::new(rhs)));
+            let body = self.stmt()?;
+            let rrhs = self.new_node(NodeKind::For, Some(Box::new(inc)), Some(Box::new(body)));
+            let rhs = self.new_node(NodeKind::For, Some(Box::new(cond)), Some(Box::new(rrhs)));
+            node = self.new_node(NodeKind::For, lhs, Some(Box::new(rhs)));
         } else if self.consume("while") {
             self.expect("(")?;
-            let cond = self.expr()?;
+            let cond = self.cond_expr()?;
             self.expect(")")?;
             let rhs = Some(Box::new(self.stmt()?));
-            node = create_new_node(NodeKind::While, Some(Box::new(cond)), rhs);
+            node = self.new_node(NodeKind::While, Some(Box::new(cond)), rhs);
         } else if self.consume("if") {
             self.expect("(")?;
-            let cond = self.expr()?;
+            let cond = self.cond_expr()?;
             self.expect(")")?;
             let then = self.stmt()?;
             if self.consume("else") {
                 let els = self.stmt()?;
                 let rhs =
-                    create_new_node(NodeKind::Else, Some(Box::new(then)), Some(Box::new(els)));
-                node = create_new_node(NodeKind::If, Some(Box::new(cond)), Some(Box::new(rhs)));
+                    self.new_node(NodeKind::Else, Some(Box::new(then)), Some(Box::new(els)));
+                node = self.new_node(NodeKind::If, Some(Box::new(cond)), Some(Box::new(rhs)));
             } else {
-                node = create_new_node(NodeKind::If, Some(Box::new(cond)), Some(Box::new(then)));
+                node = self.new_node(NodeKind::If, Some(Box::new(cond)), Some(Box::new(then)));
             }
-        } else if self.tokens[self.pos].str == "int" || self.tokens[self.pos].str == "char" {
+        } else if self.is_typename() {
             node = self.decl()?;
             self.expect(";")?;
         } else {
@@ -506,57 +610,78 @@ impl Parser {
         Ok(node)
     }
 
-    fn decl(&mut self) -> Result<Node, String> {
-        let node;
-        let mut ty = self.ty()?;
-        let name = self.tokens[self.pos].str.clone();
-        self.pos += 1;
+    // 条件式を読む。括弧で囲まれていない代入は書き間違いの可能性があるので警告する
+    fn cond_expr(&mut self) -> Result<Node, String> {
+        let start = self.pos;
+        let node = self.expr()?;
+        if node.kind == NodeKind::Assign && !self.is_parenthesized(start) {
+            warning::warn(
+                Warning::Parentheses,
+                node.line,
+                "条件式で代入が使われています (意図したものなら括弧で囲んでください)",
+            );
+        }
+        Ok(node)
+    }
 
-        let mut nums = Vec::new();
-        while self.consume("[") {
-            nums.push(self.expect_number()?);
-            self.expect("]")?;
+    // start から直前のトークンまでが一組の括弧で囲まれているか
+    fn is_parenthesized(&self, start: usize) -> bool {
+        let mut depth = 0;
+        for i in start..self.pos {
+            let tok = &self.tokens[i];
+            if tok.kind != TokenKind::Reserved {
+                continue;
+            }
+            if tok.str == "(" {
+                depth += 1;
+            } else if tok.str == ")" {
+                depth -= 1;
+                if depth == 0 {
+                    return i == self.pos - 1 && self.tokens[start].str == "(";
+                }
+            }
         }
+        false
+    }
 
-        for num in nums.iter().rev() {
-            ty.ptr_to = Some(Box::new(ty.clone()));
-            ty.kind = TypeKind::Arr;
-            ty.arr_size = *num as usize;
+    // 初期化子付きの宣言は Assign(LVarDef, 初期化式) になる
+    fn decl(&mut self) -> Result<Node, String> {
+        let ty = self.ty()?;
+        if self.tokens[self.pos].str == ";" {
+            return Ok(self.new_node(NodeKind::TypeDecl(ty), None, None));
         }
+        let name = self.expect_ident()?;
+        let ty = self.array_suffix(ty)?;
 
-        let lvar = self.create_lvar(&name, ty.clone());
+        let lvar = self.new_lvar(&name, ty);
+        let def = self.new_node(NodeKind::LVarDef(lvar), None, None);
 
         if self.consume("=") {
-            let lhs = create_new_node(NodeKind::LVar(lvar.clone()), None, None);
-            node = create_new_node(
+            let init = self.expr()?;
+            Ok(self.new_node(
                 NodeKind::Assign,
-                Some(Box::new(lhs)),
-                Some(Box::new(self.expr()?)),
-            );
+                Some(Box::new(def)),
+                Some(Box::new(init)),
+            ))
         } else {
-            node = create_new_node(NodeKind::LVarDef(lvar.clone()), None, None);
+            Ok(def)
         }
-        Ok(node)
     }
 
     fn expr(&mut self) -> Result<Node, String> {
-        return self.assign();
+        self.assign()
     }
 
     fn assign(&mut self) -> Result<Node, String> {
         let mut node = self.equality()?;
 
         if self.consume("=") {
-            node = create_new_node(
-                NodeKind::Assign,
-                Some(Box::new(node)),
-                Some(Box::new(self.assign()?)),
-            );
+            let rhs = self.assign()?;
+            node = self.new_node(NodeKind::Assign, Some(Box::new(node)), Some(Box::new(rhs)));
         }
 
-        return Ok(node);
+        Ok(node)
     }
-
     fn equality(&mut self) -> Result<Node, String> {
         let mut node = self.relational()?;
 
@@ -564,11 +689,11 @@ impl Parser {
             if self.consume("==") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.relational()?));
-                node = create_new_node(NodeKind::Comparison(ComparisonOpKind::Eq), lhs, rhs);
+                node = self.new_node(NodeKind::Comparison(ComparisonOpKind::Eq), lhs, rhs);
             } else if self.consume("!=") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.relational()?));
-                node = create_new_node(NodeKind::Comparison(ComparisonOpKind::Nq), lhs, rhs);
+                node = self.new_node(NodeKind::Comparison(ComparisonOpKind::Nq), lhs, rhs);
             } else {
                 return Ok(node);
             }
@@ -582,19 +707,19 @@ impl Parser {
             if self.consume("<") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.add()?));
-                node = create_new_node(NodeKind::Comparison(ComparisonOpKind::Lt), lhs, rhs);
+                node = self.new_node(NodeKind::Comparison(ComparisonOpKind::Lt), lhs, rhs);
             } else if self.consume("<=") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.add()?));
-                node = create_new_node(NodeKind::Comparison(ComparisonOpKind::Le), lhs, rhs);
+                node = self.new_node(NodeKind::Comparison(ComparisonOpKind::Le), lhs, rhs);
             } else if self.consume(">") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.add()?));
-                node = create_new_node(NodeKind::Comparison(ComparisonOpKind::Gt), lhs, rhs);
+                node = self.new_node(NodeKind::Comparison(ComparisonOpKind::Gt), lhs, rhs);
             } else if self.consume(">=") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.add()?));
-                node = create_new_node(NodeKind::Comparison(ComparisonOpKind::Ge), lhs, rhs);
+                node = self.new_node(NodeKind::Comparison(ComparisonOpKind::Ge), lhs, rhs);
             } else {
                 return Ok(node);
             }
@@ -608,11 +733,11 @@ impl Parser {
             if self.consume("+") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.mul()?));
-                node = create_new_node(NodeKind::BinaryOp(BinaryOpKind::Add), lhs, rhs);
+                node = self.new_node(NodeKind::BinaryOp(BinaryOpKind::Add), lhs, rhs);
             } else if self.consume("-") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.mul()?));
-                node = create_new_node(NodeKind::BinaryOp(BinaryOpKind::Sub), lhs, rhs);
+                node = self.new_node(NodeKind::BinaryOp(BinaryOpKind::Sub), lhs, rhs);
             } else {
                 return Ok(node);
             }
@@ -626,11 +751,11 @@ impl Parser {
             if self.consume("*") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.unary()?));
-                node = create_new_node(NodeKind::BinaryOp(BinaryOpKind::Mul), lhs, rhs);
+                node = self.new_node(NodeKind::BinaryOp(BinaryOpKind::Mul), lhs, rhs);
             } else if self.consume("/") {
                 let lhs = Some(Box::new(node));
                 let rhs = Some(Box::new(self.unary()?));
-                node = create_new_node(NodeKind::BinaryOp(BinaryOpKind::Div), lhs, rhs);
+                node = self.new_node(NodeKind::BinaryOp(BinaryOpKind::Div), lhs, rhs);
             } else {
                 return Ok(node);
             }
@@ -639,143 +764,135 @@ impl Parser {
 
     fn unary(&mut self) -> Result<Node, String> {
         if self.consume("+") {
-            return self.primary();
+            self.postfix()
         } else if self.consume("-") {
             // 0 - x として扱う
-            let lhs = Some(Box::new(create_new_node(NodeKind::Num(0), None, None)));
-            let rhs = Some(Box::new(self.primary()?));
-            return Ok(create_new_node(
+            let lhs = Some(Box::new(self.new_node(NodeKind::Num(0), None, None)));
+            let rhs = Some(Box::new(self.postfix()?));
+            Ok(self.new_node(
                 NodeKind::BinaryOp(BinaryOpKind::Sub),
                 lhs,
                 rhs,
-            ));
+            ))
         } else if self.consume("&") {
             let lhs = Some(Box::new(self.unary()?));
-            return Ok(create_new_node(
+            Ok(self.new_node(
                 NodeKind::UnaryOp(UnaryOpKind::Ref),
                 lhs,
                 None,
-            ));
+            ))
         } else if self.consume("*") {
             let lhs = Some(Box::new(self.unary()?));
-            return Ok(create_new_node(
+            Ok(self.new_node(
                 NodeKind::UnaryOp(UnaryOpKind::Deref),
                 lhs,
                 None,
-            ));
+            ))
         } else if self.consume("sizeof") {
-            let node = self.unary()?;
+            // sizeof(型名) は型だけを持つノードとして扱う
+            if self.tokens[self.pos].str == "(" && self.is_typename_at(self.pos + 1) {
+                self.expect("(")?;
+                let ty = self.ty()?;
+                let ty = self.array_suffix(ty)?;
+                self.expect(")")?;
+                let lhs = Some(Box::new(self.new_node(NodeKind::TypeDecl(ty), None, None)));
+                return Ok(self.new_node(NodeKind::Sizeof, lhs, None));
+            }
+            let lhs = Some(Box::new(self.unary()?));
+            Ok(self.new_node(NodeKind::Sizeof, lhs, None))
+        } else {
+            self.postfix()
+        }
+    }
 
-            if node.ty.kind == TypeKind::Int {
-                return Ok(create_new_node(NodeKind::Num(4), None, None));
+    fn postfix(&mut self) -> Result<Node, String> {
+        let mut node = self.primary()?;
+
+        loop {
+            if self.consume("[") {
+                // a[3][4] -> *(*(a+3)+4)
+                let index = self.expr()?;
+                self.expect("]")?;
+                let add = self.new_node(
+                    NodeKind::BinaryOp(BinaryOpKind::Add),
+                    Some(Box::new(node)),
+                    Some(Box::new(index)),
+                );
+                node = self.new_node(
+                    NodeKind::UnaryOp(UnaryOpKind::Deref),
+                    Some(Box::new(add)),
+                    None,
+                );
+            } else if self.consume(".") {
+                let name = self.expect_ident()?;
+                node = self.new_node(
+                    NodeKind::Member(Member::named(name)),
+                    Some(Box::new(node)),
+                    None,
+                );
+            } else if self.consume("->") {
+                // p->a -> (*p).a
+                let name = self.expect_ident()?;
+                let deref = self.new_node(
+                    NodeKind::UnaryOp(UnaryOpKind::Deref),
+                    Some(Box::new(node)),
+                    None,
+                );
+                node = self.new_node(
+                    NodeKind::Member(Member::named(name)),
+                    Some(Box::new(deref)),
+                    None,
+                );
             } else {
-                return Ok(create_new_node(NodeKind::Num(8), None, None));
+                return Ok(node);
             }
-        } else {
-            return self.primary();
         }
     }
 
     fn primary(&mut self) -> Result<Node, String> {
         if self.tokens[self.pos].kind == TokenKind::Strlit {
-            self.str_literals.push(self.tokens[self.pos].str.clone());
+            let bytes = lexer::unescape(&self.tokens[self.pos].str);
             self.pos += 1;
 
             let lit = Strlit {
-                idx: self.str_literals.len() - 1,
-                len: self.str_literals[self.str_literals.len() - 1].len(),
+                idx: self.str_literals.len(),
+                len: bytes.len(),
             };
+            self.str_literals.push(bytes);
 
-            return Ok(create_new_node(NodeKind::Strlit(lit), None, None));
+            Ok(self.new_node(NodeKind::Strlit(lit), None, None))
         } else if self.consume("(") {
             let node = self.expr()?;
             self.expect(")")?;
             Ok(node)
         } else if self.tokens[self.pos].kind == TokenKind::Ident {
             let name = self.tokens[self.pos].str.clone();
-            let lvar = self.find_lvar(&name).cloned();
-            let gvar = self.find_gvar(&name).cloned();
-            let mut func = self.functions.iter().find(|&x| x.name == name).cloned();
-            if func.is_none() {
-                func = Some(Function {
-                    name: name.clone(),
-                    stack_size: 0,
-                    ty: Type {
-                        kind: TypeKind::Int,
-                        ptr_to: None,
-                        arr_size: 1,
-                    },
-                });
-            }
             self.pos += 1;
 
             if self.consume("(") {
+                // 戻り値の型は意味解析で関数定義から決まる
+                let func = Function {
+                    name,
+                    stack_size: 0,
+                    ty: Type::int(),
+                    is_static: false,
+                    inline: Inline::Default,
+                    visibility: None,
+                };
                 let mut args = Vec::new();
-                if self.consume(")") {
-                    return Ok(create_new_node(
-                        NodeKind::Fncall(func.unwrap(), args),
-                        None,
-                        None,
-                    ));
-                } else {
+                if !self.consume(")") {
                     args = self.arglist()?;
-                    return Ok(create_new_node(
-                        NodeKind::Fncall(func.unwrap(), args),
-                        None,
-                        None,
-                    ));
-                }
-            } else if self.consume("[") {
-                if lvar.is_none() && gvar.is_none() {
-                    return Err(format!("変数 '{}' が見つかりません", name));
-                }
-
-                let mut indices = Vec::new();
-                indices.push(self.expr()?);
-                self.expect("]")?;
-
-                while self.consume("[") {
-                    indices.push(self.expr()?);
-                    self.expect("]")?;
-                }
-
-                let mut node;
-                if lvar.is_some() {
-                    node = create_new_node(NodeKind::LVar(lvar.unwrap()), None, None);
-                } else {
-                    node = create_new_node(NodeKind::GVar(gvar.unwrap()), None, None);
-                }
-
-                // a[3][4] -> *(*(a+3)+4)
-                for index in indices.iter() {
-                    node = create_new_node(
-                        NodeKind::BinaryOp(BinaryOpKind::Add),
-                        Some(Box::new(node)),
-                        Some(Box::new(index.clone())),
-                    );
-                }
-                Ok(create_new_node(
-                    NodeKind::UnaryOp(UnaryOpKind::Deref),
-                    Some(Box::new(node)),
-                    None,
-                ))
-            } else {
-                if lvar.is_none() && gvar.is_none() {
-                    return Err(format!("変数 '{}' が見つかりません", name));
-                } else {
-                    if lvar.is_some() {
-                        return Ok(create_new_node(NodeKind::LVar(lvar.unwrap()), None, None));
-                    } else {
-                        return Ok(create_new_node(NodeKind::GVar(gvar.unwrap()), None, None));
-                    }
                 }
+                return Ok(self.new_node(NodeKind::Fncall(func, args), None, None));
             }
+
+            Ok(self.new_node(NodeKind::Var(name), None, None))
         } else {
-            Ok(create_new_node(
-                NodeKind::Num(self.expect_number()?),
-                None,
-                None,
-            ))
+            let unsigned = self.tokens[self.pos].str.ends_with(['u', 'U']);
+            let val = self.expect_number()?;
+            let mut node = self.new_node(NodeKind::Num(val), None, None);
+            node.ty.is_unsigned = unsigned;
+            Ok(node)
         }
     }
 
@@ -800,11 +917,7 @@ impl Parser {
 
         loop {
             if self.consume("*") {
-                ty = Type {
-                    kind: TypeKind::Ptr,
-                    ptr_to: Some(Box::new(ty)),
-                    arr_size: 1,
-                };
+                ty = Type::pointer_to(ty);
             } else {
                 break;
             }
@@ -813,24 +926,154 @@ impl Parser {
         Ok(ty)
     }
 
+    fn is_typename(&self) -> bool {
+        self.is_typename_at(self.pos)
+    }
+
+    fn is_typename_at(&self, pos: usize) -> bool {
+        let tok = &self.tokens[pos];
+        tok.kind == TokenKind::Reserved
+            && matches!(
+                tok.str.as_str(),
+                "int" | "char" | "struct" | "signed" | "unsigned" | "void"
+            )
+    }
+
     fn base_type(&mut self) -> Result<Type, String> {
-        if self.consume("int") {
-            Ok(Type {
-                kind: TypeKind::Int,
-                ptr_to: None,
-                arr_size: 1,
-            })
-        } else if self.consume("char") {
-            Ok(Type {
-                kind: TypeKind::Char,
-                ptr_to: None,
-                arr_size: 1,
-            })
+        if self.consume("struct") {
+            return self.struct_decl();
+        }
+        if self.consume("void") {
+            return Ok(Type::void());
+        }
+
+        let is_unsigned = self.consume("unsigned");
+        let is_signed = !is_unsigned && self.consume("signed");
+        let mut ty = if self.consume("char") {
+            Type::char()
+        } else if self.consume("int") || is_unsigned || is_signed {
+            Type::int()
         } else {
-            Err(format!(
+            return Err(format!(
                 "型名が期待されますが、{}でした",
                 self.tokens[self.pos].str
-            ))
+            ));
+        };
+        ty.is_unsigned = is_unsigned;
+        Ok(ty)
+    }
+
+    // struct_decl ::= "struct" ident? ("{" struct_member* "}")?
+    // メンバの配置は意味解析で決まる
+    fn struct_decl(&mut self) -> Result<Type, String> {
+        let tag = if self.tokens[self.pos].kind == TokenKind::Ident {
+            Some(self.expect_ident()?)
+        } else {
+            None
+        };
+
+        if !self.consume("{") {
+            let Some(tag) = tag else {
+                return Err("構造体のタグ名がありません".to_string());
+            };
+            if let Some(ty) = self.tags.iter().rev().find_map(|scope| scope.get(&tag)) {
+                return Ok(ty.clone());
+            }
+            // 未定義のタグは不完全型として登録する
+            let ty = Type::struct_of(self.new_struct_id(), Vec::new());
+            self.tags.last_mut().unwrap().insert(tag, ty.clone());
+            return Ok(ty);
+        }
+
+        // 同じスコープで前方宣言されたタグは同じ構造体を指す
+        let declared = tag
+            .as_ref()
+            .and_then(|tag| self.tags.last().unwrap().get(tag));
+        let id = match declared {
+            Some(ty) if ty.members.is_empty() => ty.struct_id,
+            Some(_) => return Err(format!("構造体 '{}' が再定義されています", tag.unwrap())),
+            None => self.new_struct_id(),
+        };
+        // メンバから自身を参照できるように、先に不完全型として登録しておく
+        if let Some(tag) = &tag {
+            let ty = Type::struct_of(id, Vec::new());
+            self.tags.last_mut().unwrap().insert(tag.clone(), ty);
+        }
+
+        let mut members = Vec::new();
+        while !self.consume("}") {
+            self.struct_member(&mut members)?;
+        }
+        if members.is_empty() {
+            return Err("メンバのない構造体は定義できません".to_string());
+        }
+
+        let ty = Type::struct_of(id, members);
+        if let Some(tag) = tag {
+            self.tags.last_mut().unwrap().insert(tag, ty.clone());
+        }
+        Ok(ty)
+    }
+
+    // struct_member ::= base_type declarator ("," declarator)* ";"
+    // declarator    ::= "*"* ident? ("[" expr "]")* (":" expr)?
+    fn struct_member(&mut self, members: &mut Vec<Member>) -> Result<(), String> {
+        let base = self.base_type()?;
+
+        loop {
+            let mut ty = base.clone();
+            while self.consume("*") {
+                ty = Type::pointer_to(ty);
+            }
+            let name = if self.tokens[self.pos].kind == TokenKind::Ident {
+                Some(self.expect_ident()?)
+            } else {
+                None
+            };
+            // フレキシブル配列メンバ: 要素数0の配列として配置する
+            let is_flexible = self.tokens[self.pos].str == "["
+                && self.tokens[self.pos + 1].str == "]";
+            if is_flexible {
+                self.pos += 2;
+            }
+            let mut ty = self.array_suffix(ty)?;
+            if is_flexible {
+                ty = Type::array_of(ty, 0);
+                if name.is_none() || self.tokens[self.pos].str != ";" {
+                    return Err("フレキシブル配列メンバの宣言が正しくありません".to_string());
+                }
+                if members.is_empty() || self.tokens[self.pos + 1].str != "}" {
+                    return Err(
+                        "フレキシブル配列メンバは構造体の最後のメンバでなければなりません"
+                            .to_string(),
+                    );
+                }
+            }
+            let width = if self.consume(":") {
+                Some(Box::new(self.expr()?))
+            } else {
+                None
+            };
+            if name.is_none() && width.is_none() {
+                return Err("メンバ名がありません".to_string());
+            }
+
+            members.push(Member {
+                name,
+                ty,
+                offset: 0,
+                bit_field: None,
+                width,
+            });
+            if !self.consume(",") {
+                break;
+            }
         }
+        self.expect(";")
+    }
+
+    fn new_struct_id(&mut self) -> usize {
+        self.struct_count += 1;
+        self.struct_count
     }
 }
@@ -0,0 +1,195 @@
+use crate::cfg;
+use crate::dce;
+use crate::fold;
+use crate::gvn;
+use crate::indvars;
+use crate::inline;
+use crate::ir::{Function, Module};
+use crate::licm;
+use crate::options::{Options, RelocModel};
+use crate::rotate;
+use crate::ssa;
+use crate::tailcall;
+use crate::unroll;
+use crate::verify;
+
+// 最適化パス
+#[derive(Clone, Copy)]
+struct Pass {
+    name: &'static str,
+    run: Run,
+}
+
+#[derive(Clone, Copy)]
+enum Run {
+    Function(fn(&mut Function, &PassContext)), // 関数ごとに実行する
+    Module(fn(&mut Module, &PassContext)),     // モジュール全体に対して実行する
+}
+
+// パスが参照するコンパイルの設定
+#[derive(Clone, Copy)]
+pub struct PassContext {
+    pub strict_aliasing: bool, // 型の違うメモリアクセスは別の場所に触れるとみなす
+    pub interposition: bool,   // 関数がほかのモジュールの同じ名前の定義に置き換えられうる (-fPIC)
+}
+
+const PASSES: &[Pass] = &[
+    Pass {
+        name: "mem2reg",
+        run: Run::Function(|func, _| ssa::mem2reg(func)),
+    },
+    Pass {
+        name: "fold",
+        run: Run::Function(|func, _| fold::fold(func)),
+    },
+    Pass {
+        name: "simplifycfg",
+        run: Run::Function(|func, _| cfg::simplify_cfg(func)),
+    },
+    Pass {
+        name: "dce",
+        run: Run::Function(dce::dce),
+    },
+    Pass {
+        name: "globaldce",
+        run: Run::Module(|module, _| dce::global_dce(module)),
+    },
+    Pass {
+        name: "inline",
+        run: Run::Module(inline::inline),
+    },
+    Pass {
+        name: "cse",
+        run: Run::Function(gvn::cse),
+    },
+    Pass {
+        name: "gvn",
+        run: Run::Function(gvn::gvn),
+    },
+    Pass {
+        name: "rotate",
+        run: Run::Function(|func, _| rotate::rotate(func)),
+    },
+    Pass {
+        name: "licm",
+        run: Run::Function(licm::licm),
+    },
+    Pass {
+        name: "indvars",
+        run: Run::Function(|func, _| indvars::indvars(func)),
+    },
+    Pass {
+        name: "unroll",
+        run: Run::Function(|func, _| unroll::unroll(func)),
+    },
+    Pass {
+        name: "tailcall",
+        run: Run::Function(|func, _| tailcall::tailcall(func)),
+    },
+];
+
+// 最適化レベルごとのパイプライン
+fn default_pipeline(opts: &Options) -> Vec<String> {
+    if opts.opt_level == 0 {
+        return Vec::new();
+    }
+    let mut passes = vec!["mem2reg", "fold", "simplifycfg", "dce"];
+    // 自己再帰がループになれば、インライン展開やループの最適化の対象になる
+    if opts.optimize_sibling_calls {
+        passes.push("tailcall");
+    }
+    passes.extend(["inline", "fold", "simplifycfg"]);
+    // -O2 では支配するブロックで計算した値も使う
+    passes.push(if opts.opt_level >= 2 { "gvn" } else { "cse" });
+    passes.extend(["rotate", "licm", "indvars"]);
+    if opts.unroll_loops {
+        passes.push("unroll");
+    }
+    passes.extend([
+        "fold",
+        "simplifycfg",
+        "fold",
+        "simplifycfg",
+        "dce",
+        "globaldce",
+    ]);
+    passes.iter().map(|name| name.to_string()).collect()
+}
+
+pub struct PassManager {
+    pipeline: Vec<Pass>,
+    ctx: PassContext,
+    verify: bool,
+    dump_before: Vec<String>,
+    dump_after: Vec<String>,
+}
+
+fn find_pass(name: &str) -> Result<Pass, String> {
+    PASSES
+        .iter()
+        .find(|pass| pass.name == name)
+        .copied()
+        .ok_or_else(|| format!("不明な最適化パスです: {}", name))
+}
+
+impl PassManager {
+    pub fn new(opts: &Options) -> Result<Self, String> {
+        let names = match &opts.passes {
+            Some(passes) => passes.clone(),
+            None => default_pipeline(opts),
+        };
+        let pipeline = names
+            .iter()
+            .map(|name| find_pass(name))
+            .collect::<Result<_, _>>()?;
+        for name in opts.dump_before.iter().chain(opts.dump_after.iter()) {
+            if name != "all" {
+                find_pass(name)?;
+            }
+        }
+        Ok(PassManager {
+            pipeline,
+            ctx: PassContext {
+                strict_aliasing: opts.strict_aliasing,
+                interposition: opts.reloc_model == RelocModel::Pic,
+            },
+            verify: opts.verify_ir,
+            dump_before: opts.dump_before.clone(),
+            dump_after: opts.dump_after.clone(),
+        })
+    }
+
+    pub fn run(&self, module: &mut Module) -> Result<(), String> {
+        if self.verify {
+            verify_module(module).map_err(|e| format!("中間表現が不正です: {}", e))?;
+        }
+        for pass in self.pipeline.iter() {
+            if matches(&self.dump_before, pass.name) {
+                eprint!("; *** {} の前 ***\n{}", pass.name, module);
+            }
+            match pass.run {
+                Run::Function(run) => module
+                    .functions
+                    .iter_mut()
+                    .for_each(|func| run(func, &self.ctx)),
+                Run::Module(run) => run(module, &self.ctx),
+            }
+            if matches(&self.dump_after, pass.name) {
+                eprint!("; *** {} の後 ***\n{}", pass.name, module);
+            }
+            if self.verify {
+                verify_module(module)
+                    .map_err(|e| format!("{} の後の中間表現が不正です: {}", pass.name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn matches(names: &[String], pass: &str) -> bool {
+    names.iter().any(|name| name == "all" || name == pass)
+}
+
+fn verify_module(module: &Module) -> Result<(), String> {
+    module.functions.iter().try_for_each(verify::verify)
+}
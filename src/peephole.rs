@@ -0,0 +1,465 @@
+use crate::asm::{AluOp, Cond, Gpr, Inst, Operand};
+use std::collections::{HashMap, HashSet};
+
+// のぞき穴最適化 (-O1 以上)
+// 出力する命令の列を短い窓で眺めて、同じ働きをするより短い列に置き換える
+// レジスタの生存情報を使って、結果が読まれない命令を取り除く
+
+// レジスタの集合 (Gpr の番号のビットと、フラグのビット)
+type Regs = u32;
+
+const FLAGS: Regs = 1 << 16;
+// rsp と rbp はスタックフレームを指すので常に生きているとみなす
+const FRAME: Regs = bit(Gpr::Rsp) | bit(Gpr::Rbp);
+const ARGS: Regs = bit(Gpr::Rdi)
+    | bit(Gpr::Rsi)
+    | bit(Gpr::Rdx)
+    | bit(Gpr::Rcx)
+    | bit(Gpr::R8)
+    | bit(Gpr::R9);
+const CALLER_SAVED: Regs = ARGS | bit(Gpr::Rax) | bit(Gpr::R10) | bit(Gpr::R11);
+const CALLEE_SAVED: Regs = FRAME
+    | bit(Gpr::Rbx)
+    | bit(Gpr::R12)
+    | bit(Gpr::R13)
+    | bit(Gpr::R14)
+    | bit(Gpr::R15);
+
+const fn bit(reg: Gpr) -> Regs {
+    1 << reg as u32
+}
+
+pub fn optimize(insts: &mut Vec<Inst>) {
+    loop {
+        let mut changed = thread_jumps(insts);
+        changed |= remove_unreachable(insts);
+        changed |= remove_unused_labels(insts);
+        changed |= combine(insts);
+        changed |= remove_dead(insts);
+        if !changed {
+            return;
+        }
+    }
+}
+
+// オペランドを読むときに使うレジスタ
+fn reads(op: &Operand) -> Regs {
+    op.reg().map_or(0, bit)
+}
+
+// 書き込み先のオペランドが読むレジスタ (メモリのアドレスの計算)
+fn dst_reads(op: &Operand) -> Regs {
+    match op {
+        Operand::Mem(..) => reads(op),
+        _ => 0,
+    }
+}
+
+fn dst_writes(op: &Operand) -> Regs {
+    match op {
+        Operand::Reg(reg, _) => bit(*reg),
+        _ => 0,
+    }
+}
+
+// 命令が読むレジスタと書き込むレジスタ
+fn effects(inst: &Inst) -> (Regs, Regs) {
+    match inst {
+        Inst::Label(_) | Inst::Jmp(_) => (0, 0),
+        Inst::Mov(dst, src) | Inst::Movsx(dst, src) | Inst::Movzx(dst, src) => {
+            (reads(src) | dst_reads(dst), dst_writes(dst))
+        }
+        Inst::Lea(dst, mem) => (mem.base().map_or(0, bit), bit(*dst)),
+        Inst::Alu(AluOp::Cmp | AluOp::Test, lhs, rhs) => (reads(lhs) | reads(rhs), FLAGS),
+        Inst::Alu(_, dst, src) | Inst::Imul(dst, src) | Inst::Shift(_, dst, src) => {
+            (reads(dst) | reads(src), dst_writes(dst) | FLAGS)
+        }
+        Inst::Imul3(dst, src, _) => (reads(src) | dst_reads(dst), dst_writes(dst) | FLAGS),
+        Inst::Cqo | Inst::Cdq => (bit(Gpr::Rax), bit(Gpr::Rdx)),
+        Inst::Idiv(src) | Inst::Div(src) => (
+            reads(src) | bit(Gpr::Rax) | bit(Gpr::Rdx),
+            bit(Gpr::Rax) | bit(Gpr::Rdx) | FLAGS,
+        ),
+        Inst::Set(_, dst) => (FLAGS | dst_reads(dst), dst_writes(dst)),
+        Inst::Jcc(..) => (FLAGS, 0),
+        Inst::Push(src) => (reads(src) | bit(Gpr::Rsp), bit(Gpr::Rsp)),
+        Inst::Pop(dst) => (
+            dst_reads(dst) | bit(Gpr::Rsp),
+            dst_writes(dst) | bit(Gpr::Rsp),
+        ),
+        // 可変長引数の関数には al でベクタレジスタの引数の個数を渡す
+        Inst::Call(_) => (ARGS | bit(Gpr::Rax) | FRAME, CALLER_SAVED | FLAGS),
+        Inst::Ret => (bit(Gpr::Rax) | CALLEE_SAVED, 0),
+    }
+}
+
+// 8 ビットのレジスタへの書き込みは残りのビットを変えないので、それより前の値を殺さない
+fn partial(inst: &Inst) -> Regs {
+    match inst {
+        Inst::Mov(dst, _)
+        | Inst::Movsx(dst, _)
+        | Inst::Movzx(dst, _)
+        | Inst::Alu(_, dst, _)
+        | Inst::Shift(_, dst, _)
+        | Inst::Set(_, dst)
+        | Inst::Pop(dst) => match dst {
+            Operand::Reg(reg, 1) => bit(*reg),
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+fn labels(insts: &[Inst]) -> HashMap<&str, usize> {
+    insts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, inst)| match inst {
+            Inst::Label(name) => Some((name.as_str(), i)),
+            _ => None,
+        })
+        .collect()
+}
+
+// 各命令の直後で生きているレジスタ
+fn liveness(insts: &[Inst]) -> Vec<Regs> {
+    let labels = labels(insts);
+    let n = insts.len();
+    // 関数の外に飛ぶ先や末尾から落ちた先では何が読まれるか分からない
+    let unknown = n;
+    let succs: Vec<Vec<usize>> = insts
+        .iter()
+        .enumerate()
+        .map(|(i, inst)| {
+            let target = |label: &String| labels.get(label.as_str()).copied().unwrap_or(unknown);
+            match inst {
+                Inst::Jmp(label) => vec![target(label)],
+                Inst::Jcc(_, label) => vec![i + 1, target(label)],
+                Inst::Ret => vec![],
+                _ => vec![i + 1],
+            }
+        })
+        .collect();
+    let effects: Vec<(Regs, Regs)> = insts
+        .iter()
+        .map(|inst| {
+            let (uses, defs) = effects(inst);
+            (uses, defs & !partial(inst))
+        })
+        .collect();
+
+    let mut live_in = vec![0; n + 1];
+    live_in[unknown] = Regs::MAX;
+    let mut live_out = vec![0; n];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..n).rev() {
+            let out = succs[i].iter().fold(FRAME, |live, succ| live | live_in[*succ]);
+            let (uses, kills) = effects[i];
+            let inn = uses | (out & !kills);
+            if out != live_out[i] || inn != live_in[i] {
+                live_out[i] = out;
+                live_in[i] = inn;
+                changed = true;
+            }
+        }
+    }
+    live_out
+}
+
+// ラベルの位置から最初の命令
+fn target<'a>(insts: &'a [Inst], labels: &HashMap<&str, usize>, label: &str) -> Option<&'a Inst> {
+    insts[labels.get(label)? + 1..]
+        .iter()
+        .find(|inst| !matches!(inst, Inst::Label(_)))
+}
+
+// jmp だけのブロックに飛ぶなら、その先に直接飛ぶ
+fn thread_jumps(insts: &mut [Inst]) -> bool {
+    let labels = labels(insts);
+    let mut threaded = Vec::new();
+    for (i, inst) in insts.iter().enumerate() {
+        let (Inst::Jmp(label) | Inst::Jcc(_, label)) = inst else {
+            continue;
+        };
+        let mut dest = label;
+        let mut seen = HashSet::new();
+        while let Some(Inst::Jmp(next)) = target(insts, &labels, dest) {
+            // 無限ループになっているジャンプの列はそのままにする
+            if !seen.insert(next) {
+                break;
+            }
+            dest = next;
+        }
+        if dest != label {
+            threaded.push((i, dest.clone()));
+        }
+    }
+    let changed = !threaded.is_empty();
+    for (i, dest) in threaded {
+        if let Inst::Jmp(label) | Inst::Jcc(_, label) = &mut insts[i] {
+            *label = dest;
+        }
+    }
+    changed
+}
+
+// jmp と ret の後ろで、ラベルまでの命令は実行されない
+fn remove_unreachable(insts: &mut Vec<Inst>) -> bool {
+    let len = insts.len();
+    let mut reachable = true;
+    insts.retain(|inst| {
+        if matches!(inst, Inst::Label(_)) {
+            reachable = true;
+        }
+        let keep = reachable;
+        if matches!(inst, Inst::Jmp(_) | Inst::Ret) {
+            reachable = false;
+        }
+        keep
+    });
+    insts.len() != len
+}
+
+// どこからも飛んでこないブロックのラベル (関数の名前は残す)
+fn remove_unused_labels(insts: &mut Vec<Inst>) -> bool {
+    let used: HashSet<String> = insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::Jmp(label) | Inst::Jcc(_, label) => Some(label.clone()),
+            _ => None,
+        })
+        .collect();
+    let len = insts.len();
+    insts.retain(|inst| match inst {
+        Inst::Label(name) => !name.starts_with(".L.") || used.contains(name),
+        _ => true,
+    });
+    insts.len() != len
+}
+
+// 隣り合う命令の組を置き換える
+fn combine(insts: &mut Vec<Inst>) -> bool {
+    let live = liveness(insts);
+    let old = std::mem::take(insts);
+    let mut changed = false;
+    let mut i = 0;
+    while i < old.len() {
+        match rewrite(&old[i..], &live[i..]) {
+            Some((consumed, new)) => {
+                insts.extend(new);
+                i += consumed;
+                changed = true;
+            }
+            None => {
+                insts.push(old[i].clone());
+                i += 1;
+            }
+        }
+    }
+    changed
+}
+
+// 書き込んだレジスタとフラグがどれも読まれず、ほかに副作用のない命令を取り除く
+fn remove_dead(insts: &mut Vec<Inst>) -> bool {
+    let live = liveness(insts);
+    let old = std::mem::take(insts);
+    let len = old.len();
+    insts.extend(old.into_iter().zip(live).filter_map(|(inst, live)| {
+        let pure = match &inst {
+            Inst::Mov(dst, _)
+            | Inst::Movsx(dst, _)
+            | Inst::Movzx(dst, _)
+            | Inst::Alu(_, dst, _)
+            | Inst::Imul(dst, _)
+            | Inst::Imul3(dst, _, _)
+            | Inst::Shift(_, dst, _)
+            | Inst::Set(_, dst) => !dst.is_mem(),
+            Inst::Lea(..) | Inst::Cqo | Inst::Cdq => true,
+            _ => false,
+        };
+        let defs = effects(&inst).1;
+        let dead = pure && defs != 0 && defs & (live | FRAME) == 0;
+        (!dead).then_some(inst)
+    }));
+    insts.len() != len
+}
+
+// 先頭の命令から始まる列を置き換えられるなら、置き換える命令の数と新しい命令
+fn rewrite(window: &[Inst], live: &[Regs]) -> Option<(usize, Vec<Inst>)> {
+    if let Some(fused) = fuse_branch(window, live) {
+        return Some(fused);
+    }
+    match window {
+        // 同じ 64 ビットのレジスタへの mov (32 ビットなら上位を 0 にする働きがある)
+        [Inst::Mov(Operand::Reg(dst, 8), Operand::Reg(src, 8)), ..] if dst == src => {
+            Some((1, vec![]))
+        }
+        // push と pop の組は mov になる
+        [Inst::Push(src), Inst::Pop(dst), ..] => {
+            if src == dst {
+                Some((2, vec![]))
+            } else if src.is_mem() && dst.is_mem() {
+                None
+            } else {
+                Some((2, vec![Inst::Mov(dst.clone(), src.clone())]))
+            }
+        }
+        // mov a, b の直後の mov b, a は何も変えない
+        [first @ Inst::Mov(a, b), Inst::Mov(c, d), ..]
+            if c == b
+                && d == a
+                && (b.is_mem() || matches!(b, Operand::Reg(_, 8)))
+                && !matches!(a, Operand::Reg(reg, _) if b.reg() == Some(*reg)) =>
+        {
+            Some((2, vec![first.clone()]))
+        }
+        // 次の命令へのジャンプ
+        [Inst::Jmp(label), rest @ ..] if falls_into(rest, label) => Some((1, vec![])),
+        // 条件分岐でジャンプを飛び越すなら、条件を反転してジャンプの先に飛ぶ
+        [Inst::Jcc(cond, over), Inst::Jmp(dest), rest @ ..] if falls_into(rest, over) => {
+            Some((2, vec![Inst::Jcc(cond.negate(), dest.clone())]))
+        }
+        // 0 との比較は test のほうが短い
+        [Inst::Alu(AluOp::Cmp, lhs @ Operand::Reg(..), Operand::Imm(0)), ..] => {
+            Some((1, vec![Inst::Alu(AluOp::Test, lhs.clone(), lhs.clone())]))
+        }
+        // レジスタに入れた値をすぐ一度だけ使うなら、元の値を直接使う
+        [Inst::Mov(Operand::Reg(reg, size), src), next, ..]
+            if src.reg() != Some(*reg)
+                && FRAME & bit(*reg) == 0
+                && live[1] & bit(*reg) == 0 =>
+        {
+            let next = forward(next, *reg, *size, src)?;
+            Some((2, vec![next]))
+        }
+        _ => None,
+    }
+}
+
+// setcc で作った真偽値を 0 と比べて分岐するなら、元の比較の結果で分岐する
+// 間にフラグにも真偽値にも触れない命令 (phi のコピーなど) があってもよい
+fn fuse_branch(window: &[Inst], live: &[Regs]) -> Option<(usize, Vec<Inst>)> {
+    let [set @ Inst::Set(cond, Operand::Reg(flag, 1)), ext @ Inst::Movzx(Operand::Reg(val, 4), Operand::Reg(src, 1)), rest @ ..] =
+        window
+    else {
+        return None;
+    };
+    if flag != src {
+        return None;
+    }
+    let touches = FLAGS | bit(*val);
+    let between = rest
+        .iter()
+        .take_while(|inst| {
+            let (uses, defs) = effects(inst);
+            !matches!(inst, Inst::Label(_)) && (uses | defs) & touches == 0
+        })
+        .count();
+    let [test, Inst::Jcc(jcc @ (Cond::E | Cond::Ne), label), ..] = &rest[between..] else {
+        return None;
+    };
+    let at = 2 + between + 1;
+    if zero_test(test) != Some(Operand::Reg(*val, 4)) || live[at] & touches != 0 {
+        return None;
+    }
+    let cond = if *jcc == Cond::Ne {
+        *cond
+    } else {
+        cond.negate()
+    };
+    let mut fused = vec![set.clone(), ext.clone()];
+    fused.extend(rest[..between].iter().cloned());
+    fused.push(Inst::Jcc(cond, label.clone()));
+    Some((at + 1, fused))
+}
+
+// 値が 0 かどうかを調べる命令なら、調べる値
+fn zero_test(inst: &Inst) -> Option<Operand> {
+    match inst {
+        Inst::Alu(AluOp::Cmp, lhs, Operand::Imm(0)) => Some(lhs.clone()),
+        Inst::Alu(AluOp::Test, lhs, rhs) if lhs == rhs => Some(lhs.clone()),
+        _ => None,
+    }
+}
+
+// ラベルの並びに label が含まれるか
+fn falls_into(rest: &[Inst], label: &str) -> bool {
+    rest.iter()
+        .map_while(|inst| match inst {
+            Inst::Label(name) => Some(name),
+            _ => None,
+        })
+        .any(|name| name == label)
+}
+
+// 64 ビットの命令に書ける即値か
+fn fits_imm(op: &Operand, size: usize) -> bool {
+    match op {
+        Operand::Imm(val) => size < 8 || i32::try_from(*val).is_ok(),
+        _ => true,
+    }
+}
+
+// reg に入れた val の下位 size バイト (リトルエンディアンなのでメモリは同じアドレス)
+fn narrow(val: &Operand, size: usize) -> Operand {
+    match val {
+        Operand::Reg(reg, _) => Operand::Reg(*reg, size),
+        Operand::Mem(mem, _) => Operand::Mem(mem.clone(), size),
+        Operand::Imm(imm) => Operand::Imm(match size {
+            1 => *imm as i8 as i64,
+            4 => *imm as i32 as i64,
+            _ => *imm,
+        }),
+    }
+}
+
+// inst が reg を読むオペランドを val に置き換えた命令
+// 置き換えた後も reg を読むか、命令に書けない形になるなら None
+fn forward(inst: &Inst, reg: Gpr, size: usize, val: &Operand) -> Option<Inst> {
+    // reg のうち書き込んだ範囲だけを読むなら、その部分の値
+    let subst = |op: &Operand| match op {
+        Operand::Reg(r, width) if *r == reg && *width <= size => Some(narrow(val, *width)),
+        _ => None,
+    };
+    let is_imm = matches!(val, Operand::Imm(_));
+    let new = match inst {
+        Inst::Mov(dst, src) => {
+            let val = subst(src)?;
+            let fits = match dst {
+                Operand::Reg(..) => true,
+                _ => fits_imm(&val, dst_size(dst)),
+            };
+            (fits && !(dst.is_mem() && val.is_mem())).then(|| Inst::Mov(dst.clone(), val))
+        }
+        Inst::Movsx(dst, src) if !is_imm => Some(Inst::Movsx(dst.clone(), subst(src)?)),
+        Inst::Movzx(dst, src) if !is_imm => Some(Inst::Movzx(dst.clone(), subst(src)?)),
+        Inst::Alu(op, dst, src) if subst(src).is_some() => {
+            let val = subst(src)?;
+            (fits_imm(&val, dst_size(dst)) && !(dst.is_mem() && val.is_mem()))
+                .then(|| Inst::Alu(*op, dst.clone(), val))
+        }
+        // cmp と test は左辺も読むだけ
+        Inst::Alu(op @ (AluOp::Cmp | AluOp::Test), lhs, rhs) if !is_imm => {
+            let val = subst(lhs)?;
+            (!(rhs.is_mem() && val.is_mem())).then(|| Inst::Alu(*op, val, rhs.clone()))
+        }
+        Inst::Imul(dst @ Operand::Reg(..), src) => match subst(src)? {
+            Operand::Imm(imm) => fits_imm(&Operand::Imm(imm), dst_size(dst))
+                .then(|| Inst::Imul3(dst.clone(), dst.clone(), imm)),
+            val => Some(Inst::Imul(dst.clone(), val)),
+        },
+        Inst::Idiv(src) if !is_imm => Some(Inst::Idiv(subst(src)?)),
+        Inst::Div(src) if !is_imm => Some(Inst::Div(subst(src)?)),
+        _ => None,
+    }?;
+    (effects(&new).0 & bit(reg) == 0).then_some(new)
+}
+
+fn dst_size(op: &Operand) -> usize {
+    match op {
+        Operand::Reg(_, size) | Operand::Mem(_, size) => *size,
+        Operand::Imm(_) => 8,
+    }
+}
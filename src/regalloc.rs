@@ -0,0 +1,334 @@
+use crate::ir::{Function, Inst, Operand, Reg};
+use std::collections::HashSet;
+
+// 線形走査法によるレジスタ割り当て (Poletto, Sarkar)
+// phi を取り除いた後の関数に対して行う
+// 使える物理レジスタはターゲットごとに、callee-saved かどうかの列で渡す
+// コピーで結ばれ、同時に生きていない仮想レジスタはまとめて 1 つの区間として割り当てる
+
+// 仮想レジスタの置き場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loc {
+    Reg(usize),   // 物理レジスタの番号 (callee_saved の添字)
+    Spill(usize), // スピル領域の番号
+}
+
+pub struct Allocation {
+    pub locs: Vec<Option<Loc>>, // 使われない仮想レジスタは None
+    pub spills: usize,
+    pub callee_saved: Vec<usize>, // 使った callee-saved レジスタの番号
+}
+
+impl Allocation {
+    // 仮想レジスタが置かれている物理レジスタ (regs は番号からターゲットのレジスタへの表)
+    pub fn phys<T: Copy>(&self, op: Operand, regs: &[T]) -> Option<T> {
+        match op {
+            Operand::Reg(reg) => match self.locs[reg.0] {
+                Some(Loc::Reg(preg)) => Some(regs[preg]),
+                _ => None,
+            },
+            Operand::Imm(_) => None,
+        }
+    }
+
+    // スピルした仮想レジスタのフレームポインタからのオフセット
+    // スピル領域は base (スロットの終わり) の後に size バイトずつ並べる
+    pub fn spill_offset(&self, reg: Reg, base: usize, size: usize) -> usize {
+        match self.locs[reg.0].expect("割り当てられていません") {
+            Loc::Spill(i) => base.next_multiple_of(size) + (i + 1) * size,
+            Loc::Reg(_) => unreachable!(),
+        }
+    }
+
+    // スピル領域の終わりのオフセット
+    pub fn spill_end(&self, base: usize, size: usize) -> usize {
+        base.next_multiple_of(size) + self.spills * size
+    }
+}
+
+// 生存区間 (命令の位置の閉区間)
+struct Interval {
+    reg: Reg,
+    start: usize,
+    end: usize,
+    across_call: bool, // 区間の途中で関数を呼び出すか
+}
+
+pub fn allocate(func: &Function, callee_saved: &[bool]) -> Allocation {
+    let live_in = live_in(func);
+    let rep = coalesce(func, &live_in);
+    let mut intervals = intervals(func, &live_in, &rep);
+    intervals.sort_by_key(|interval| (interval.start, interval.reg));
+
+    let mut locs = vec![None; func.regs.len()];
+    let mut spills = 0;
+    let mut free: Vec<bool> = vec![true; callee_saved.len()];
+    // 割り当て中の区間 (終わりの位置, 仮想レジスタ, 物理レジスタ)
+    let mut active: Vec<(usize, Reg, usize)> = Vec::new();
+
+    for interval in intervals.iter() {
+        // 終わった区間のレジスタを解放する
+        active.retain(|&(end, _, preg)| {
+            if end < interval.start {
+                free[preg] = true;
+                false
+            } else {
+                true
+            }
+        });
+
+        // 関数呼び出しをまたぐ値は callee-saved レジスタにしか置けない
+        let usable = |preg: usize| !interval.across_call || callee_saved[preg];
+        if let Some(preg) = (0..callee_saved.len()).find(|&preg| free[preg] && usable(preg)) {
+            free[preg] = false;
+            active.push((interval.end, interval.reg, preg));
+            locs[interval.reg.0] = Some(Loc::Reg(preg));
+            continue;
+        }
+
+        // 空きがなければ、最も遅くまで生きる区間をスピルする
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, &(_, _, preg))| usable(preg))
+            .max_by_key(|(_, &(end, _, _))| end)
+            .map(|(i, _)| i);
+        match victim {
+            Some(i) if active[i].0 > interval.end => {
+                let (_, reg, preg) = active[i];
+                locs[reg.0] = Some(Loc::Spill(spills));
+                spills += 1;
+                active[i] = (interval.end, interval.reg, preg);
+                locs[interval.reg.0] = Some(Loc::Reg(preg));
+            }
+            _ => {
+                locs[interval.reg.0] = Some(Loc::Spill(spills));
+                spills += 1;
+            }
+        }
+    }
+
+    // まとめた仮想レジスタには代表と同じ場所を割り当てる
+    for reg in 0..locs.len() {
+        locs[reg] = locs[rep[reg].0];
+    }
+
+    let mut used: Vec<usize> = locs
+        .iter()
+        .filter_map(|loc| match loc {
+            Some(Loc::Reg(preg)) if callee_saved[*preg] => Some(*preg),
+            _ => None,
+        })
+        .collect();
+    used.sort();
+    used.dedup();
+    Allocation {
+        locs,
+        spills,
+        callee_saved: used,
+    }
+}
+
+// ブロックの入口で生きている仮想レジスタ
+fn live_in(func: &Function) -> Vec<HashSet<Reg>> {
+    let n = func.blocks.len();
+    let mut uses = vec![HashSet::new(); n];
+    let mut defs = vec![HashSet::new(); n];
+    for (i, block) in func.blocks.iter().enumerate() {
+        for inst in block.insts.iter() {
+            for reg in inst.operands().into_iter().filter_map(as_reg) {
+                if !defs[i].contains(&reg) {
+                    uses[i].insert(reg);
+                }
+            }
+            if let Some(dst) = inst.dst() {
+                defs[i].insert(dst);
+            }
+        }
+        for reg in block.term.operands().into_iter().filter_map(as_reg) {
+            if !defs[i].contains(&reg) {
+                uses[i].insert(reg);
+            }
+        }
+    }
+
+    let mut live_in = uses.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..n).rev() {
+            for succ in func.blocks[i].term.successors() {
+                let added: Vec<Reg> = live_in[succ.0]
+                    .iter()
+                    .filter(|reg| !defs[i].contains(reg) && !live_in[i].contains(reg))
+                    .copied()
+                    .collect();
+                if !added.is_empty() {
+                    live_in[i].extend(added);
+                    changed = true;
+                }
+            }
+        }
+    }
+    live_in
+}
+
+fn as_reg(op: Operand) -> Option<Reg> {
+    match op {
+        Operand::Reg(reg) => Some(reg),
+        Operand::Imm(_) => None,
+    }
+}
+
+// コピー `dst = src` で結ばれた仮想レジスタを、干渉しない限り同じ組にまとめる
+// 組の代表の仮想レジスタを、仮想レジスタごとに返す
+fn coalesce(func: &Function, live_in: &[HashSet<Reg>]) -> Vec<Reg> {
+    let n = func.regs.len();
+    let mut copies = Vec::new();
+    let mut candidate = vec![false; n];
+    for block in func.blocks.iter() {
+        for inst in block.insts.iter() {
+            if let Inst::Copy {
+                dst,
+                src: Operand::Reg(src),
+            } = *inst
+            {
+                if dst != src && func.reg_ty(dst) == func.reg_ty(src) {
+                    copies.push((dst, src));
+                    candidate[dst.0] = true;
+                    candidate[src.0] = true;
+                }
+            }
+        }
+    }
+    if copies.is_empty() {
+        return (0..n).map(Reg).collect();
+    }
+
+    // 候補どうしの干渉を調べる
+    // 定義の直後に生きている値と干渉する (コピーの元は除く)
+    let mut interferes = HashSet::new();
+    let mut add = |a: Reg, b: Reg| {
+        if a != b && candidate[a.0] && candidate[b.0] {
+            interferes.insert((a, b));
+            interferes.insert((b, a));
+        }
+    };
+    // 引数は入口でいっせいに定義される
+    for (i, param) in func.params.iter().enumerate() {
+        for other in func.params[..i].iter().chain(live_in[0].iter()) {
+            add(*param, *other);
+        }
+    }
+    for block in func.blocks.iter() {
+        let mut live: HashSet<Reg> = HashSet::new();
+        for succ in block.term.successors() {
+            live.extend(live_in[succ.0].iter().copied());
+        }
+        live.extend(block.term.operands().into_iter().filter_map(as_reg));
+        for inst in block.insts.iter().rev() {
+            if let Some(dst) = inst.dst() {
+                let src = match inst {
+                    Inst::Copy { src, .. } => as_reg(*src),
+                    _ => None,
+                };
+                for reg in live.iter() {
+                    if Some(*reg) != src {
+                        add(dst, *reg);
+                    }
+                }
+                live.remove(&dst);
+            }
+            live.extend(inst.operands().into_iter().filter_map(as_reg));
+        }
+    }
+
+    // 組を合わせる (union-find)
+    let mut parent: Vec<usize> = (0..n).collect();
+    let mut members: Vec<Vec<Reg>> = (0..n).map(|i| vec![Reg(i)]).collect();
+    fn find(parent: &mut [usize], i: usize) -> usize {
+        if parent[i] != i {
+            parent[i] = find(parent, parent[i]);
+        }
+        parent[i]
+    }
+    for (dst, src) in copies {
+        let a = find(&mut parent, dst.0);
+        let b = find(&mut parent, src.0);
+        if a == b {
+            continue;
+        }
+        let conflict = members[a]
+            .iter()
+            .any(|x| members[b].iter().any(|y| interferes.contains(&(*x, *y))));
+        if conflict {
+            continue;
+        }
+        let moved = std::mem::take(&mut members[b]);
+        members[a].extend(moved);
+        parent[b] = a;
+    }
+    (0..n).map(|i| Reg(find(&mut parent, i))).collect()
+}
+
+// ブロックを並べた順に命令へ番号を振り、仮想レジスタの組ごとに生存区間を求める
+// 区間に穴は作らず、生きている範囲全体を覆う 1 つの区間にする
+fn intervals(func: &Function, live_in: &[HashSet<Reg>], rep: &[Reg]) -> Vec<Interval> {
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; func.regs.len()];
+    let mut extend = |reg: Reg, pos: usize| {
+        let range = ranges[rep[reg.0].0].get_or_insert((pos, pos));
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+    // 引数は位置 0 で定義される
+    for param in func.params.iter() {
+        extend(*param, 0);
+    }
+
+    let mut calls = Vec::new();
+    let mut pos = 1;
+    for (i, block) in func.blocks.iter().enumerate() {
+        // 入口で生きている値はブロックの先頭の命令より前の位置から始める
+        // (先頭の呼び出しと同じ位置にすると、呼び出しをまたぐことが分からない)
+        for reg in live_in[i].iter() {
+            extend(*reg, pos);
+        }
+        pos += 1;
+        for inst in block.insts.iter() {
+            for reg in inst.operands().into_iter().filter_map(as_reg) {
+                extend(reg, pos);
+            }
+            if let Some(dst) = inst.dst() {
+                extend(dst, pos);
+            }
+            if matches!(inst, Inst::Call { .. }) {
+                calls.push(pos);
+            }
+            pos += 1;
+        }
+        for reg in block.term.operands().into_iter().filter_map(as_reg) {
+            extend(reg, pos);
+        }
+        // 出口で生きている値はブロックの終わりまで延ばす
+        for succ in block.term.successors() {
+            for reg in live_in[succ.0].iter() {
+                extend(*reg, pos);
+            }
+        }
+        pos += 1;
+    }
+
+    ranges
+        .iter()
+        .enumerate()
+        .filter_map(|(i, range)| {
+            let (start, end) = (*range)?;
+            Some(Interval {
+                reg: Reg(i),
+                start,
+                end,
+                across_call: calls.iter().any(|&call| start < call && call < end),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,608 @@
+use crate::gen::escape;
+use crate::ir::{
+    BinOp, BlockId, CastKind, CmpOp, Function, Inst, Module, Operand, Reg, Terminator, Ty,
+};
+use crate::options::Options;
+use crate::regalloc::{self, Allocation};
+use crate::tailcall;
+
+// 中間表現から RISC-V (RV64GC) のアセンブリ (GNU アセンブラの記法) を出力する
+// 呼び出し規約は LP64D に従い、引数は a0-a7、戻り値は a0 に置く
+// 32 ビットの値は、ABI と同じくレジスタの中で 64 ビットに符号拡張した形に保つ
+// (こうしておけば比較は符号の有無によらず 64 ビットのままでよい)
+
+// 割り当てに使う物理レジスタ (t3-t6 は caller-saved、s1-s11 は callee-saved)
+// 引数レジスタと t0-t2 は命令の選択で一時的に使うので割り当てない
+const REGS: [u8; 15] = [28, 29, 30, 31, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+
+const ZERO: u8 = 0;
+const TMP: u8 = 5; // t0: 結果を一時的に置く
+const LHS: u8 = 6; // t1: 左辺やアドレスを一時的に置く
+const RHS: u8 = 7; // t2: 右辺や格納する値を一時的に置く
+const A0: u8 = 10;
+
+// 条件分岐の飛び先は ±4KiB までなので、関数がこれより大きくなりうるときは
+// 逆の条件で j (±1MiB) を飛び越える形にする
+const BRANCH_RANGE: usize = 4096;
+
+// ABI での名前
+fn reg(reg: u8) -> &'static str {
+    const NAMES: [&str; 32] = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+        "t5", "t6",
+    ];
+    NAMES[reg as usize]
+}
+
+// I 形式の命令の即値は 12 ビットの符号付き整数
+fn fits_imm12(val: i64) -> bool {
+    (-2048..2048).contains(&val)
+}
+
+// 即値をオペランドの大きさで解釈した値
+fn imm(op: Operand, size: usize) -> Option<i64> {
+    match op {
+        Operand::Imm(val) if size == 8 => Some(val),
+        Operand::Imm(val) => Some(val as i32 as i64),
+        Operand::Reg(_) => None,
+    }
+}
+
+pub fn gen(module: &Module, opts: &Options) {
+    // アドレスは auipc による pc 相対で求める
+    println!("  .option nopic");
+
+    for (i, lit) in module.strings.iter().enumerate() {
+        println!(".LC{}:", i);
+        println!("  .string \"{}\"", escape(lit));
+    }
+
+    for global in module.globals.iter() {
+        println!("  .bss");
+        println!("  .global {}", global.name);
+        println!("{}:", global.name);
+        println!("  .zero {}\n", global.size);
+    }
+
+    for func in module.functions.iter() {
+        println!("  .text");
+        // 圧縮命令があるので命令は 2 バイト境界に置く
+        println!("  .p2align 1");
+        if !func.is_static {
+            println!("  .global {}", func.name);
+        }
+        for line in Emitter::new(func, opts.optimize_sibling_calls).function() {
+            println!("{}", line);
+        }
+    }
+}
+
+// 仮想レジスタはレジスタ割り当ての結果に従って物理レジスタかスピル領域に置く
+// s0 (フレームポインタ) の直下に ra と呼び出し元の s0 を置き、その下にローカル変数とスピル領域を並べる
+struct Emitter<'a> {
+    func: &'a Function,
+    alloc: Allocation,
+    slot_offsets: Vec<usize>,        // s0 からのオフセット
+    spill_base: usize,               // スピル領域の始まり (スロットの終わり)
+    saved_offsets: Vec<(u8, usize)>, // (callee-saved レジスタ, 退避先のオフセット)
+    stack_size: usize,
+    sibling_calls: bool,  // 末尾呼び出しをジャンプにする
+    branches: Vec<usize>, // 条件分岐の命令の out での位置
+    out: Vec<String>,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(func: &'a Function, sibling_calls: bool) -> Self {
+        let callee_saved = REGS.map(|preg| preg == 9 || (18..=27).contains(&preg));
+        let alloc = regalloc::allocate(func, &callee_saved);
+        let mut offset = 16;
+        let mut slot_offsets = Vec::new();
+        for slot in func.slots.iter() {
+            offset = (offset + slot.size).next_multiple_of(slot.align.max(8));
+            slot_offsets.push(offset);
+        }
+        let spill_base = offset;
+        offset = alloc.spill_end(spill_base, 8);
+        let mut saved_offsets = Vec::new();
+        for preg in alloc.callee_saved.iter() {
+            offset = offset.next_multiple_of(8) + 8;
+            saved_offsets.push((REGS[*preg], offset));
+        }
+        Emitter {
+            func,
+            alloc,
+            slot_offsets,
+            spill_base,
+            saved_offsets,
+            stack_size: offset.next_multiple_of(16),
+            sibling_calls: sibling_calls && tailcall::may_reuse_frame(func),
+            branches: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        self.out.push(format!("  {}", line));
+    }
+
+    fn label(&self, block: BlockId) -> String {
+        format!(".L.{}.{}", self.func.name, block)
+    }
+
+    fn size(&self, reg: Reg) -> usize {
+        self.func.reg_ty(reg).size(self.func.layout)
+    }
+
+    // 仮想レジスタが置かれている物理レジスタ
+    fn phys(&self, op: Operand) -> Option<u8> {
+        self.alloc.phys(op, &REGS)
+    }
+
+    fn spill_offset(&self, reg: Reg) -> usize {
+        self.alloc.spill_offset(reg, self.spill_base, 8)
+    }
+
+    // 即値をレジスタに置く
+    // 32 ビットに収まれば lui と addiw で、それより大きければ上位を作ってから
+    // 12 ビットずつシフトして addi で下位を足す
+    fn mov_imm(&mut self, dst: u8, val: i64) {
+        let name = reg(dst);
+        if fits_imm12(val) {
+            self.emit(format!("li {}, {}", name, val));
+            return;
+        }
+        // 下位 12 ビットは符号付きで足されるので、上位はその分を繰り上げる
+        let lo = (val << 52) >> 52;
+        if val == val as i32 as i64 {
+            let hi = (val.wrapping_sub(lo) >> 12) & 0xfffff;
+            self.emit(format!("lui {}, {}", name, hi));
+            if lo != 0 {
+                self.emit(format!("addiw {}, {}, {}", name, name, lo));
+            }
+            return;
+        }
+        let mut hi = val.wrapping_sub(lo) >> 12;
+        let mut shift = 12;
+        while hi & 1 == 0 {
+            hi >>= 1;
+            shift += 1;
+        }
+        self.mov_imm(dst, hi);
+        self.emit(format!("slli {}, {}, {}", name, name, shift));
+        if lo != 0 {
+            self.emit(format!("addi {}, {}, {}", name, name, lo));
+        }
+    }
+
+    // s0 から offset だけ下の領域を読む。遠ければ dst をアドレスの計算に使う
+    fn frame_load(&mut self, dst: u8, offset: usize) {
+        if fits_imm12(-(offset as i64)) {
+            self.emit(format!("ld {}, -{}(s0)", reg(dst), offset));
+        } else {
+            self.mov_imm(dst, -(offset as i64));
+            self.emit(format!("add {}, s0, {}", reg(dst), reg(dst)));
+            self.emit(format!("ld {}, 0({})", reg(dst), reg(dst)));
+        }
+    }
+
+    // s0 から offset だけ下の領域に書く。遠ければ t2 をアドレスの計算に使う
+    fn frame_store(&mut self, src: u8, offset: usize) {
+        if fits_imm12(-(offset as i64)) {
+            self.emit(format!("sd {}, -{}(s0)", reg(src), offset));
+        } else {
+            self.mov_imm(RHS, -(offset as i64));
+            self.emit(format!("add {}, s0, {}", reg(RHS), reg(RHS)));
+            self.emit(format!("sd {}, 0({})", reg(src), reg(RHS)));
+        }
+    }
+
+    // オペランドをレジスタに読み込む
+    fn load(&mut self, dst: u8, op: Operand, size: usize) {
+        match op {
+            Operand::Imm(_) => self.mov_imm(dst, imm(op, size).unwrap()),
+            Operand::Reg(r) => match self.phys(op) {
+                Some(preg) if preg == dst => {}
+                Some(preg) => self.emit(format!("mv {}, {}", reg(dst), reg(preg))),
+                None => self.frame_load(dst, self.spill_offset(r)),
+            },
+        }
+    }
+
+    // 物理レジスタにあればそのレジスタを、なければ scratch に読み込んで返す
+    // 即値の 0 は zero レジスタを使う
+    fn in_reg(&mut self, op: Operand, scratch: u8, size: usize) -> u8 {
+        if op == Operand::Imm(0) {
+            return ZERO;
+        }
+        match self.phys(op) {
+            Some(preg) => preg,
+            None => {
+                self.load(scratch, op, size);
+                scratch
+            }
+        }
+    }
+
+    // 結果を書き込むレジスタ (物理レジスタになければ t0)
+    fn target(&self, dst: Reg) -> u8 {
+        self.phys(Operand::Reg(dst)).unwrap_or(TMP)
+    }
+
+    fn store(&mut self, dst: Reg, src: u8) {
+        match self.phys(Operand::Reg(dst)) {
+            Some(preg) if preg == src => {}
+            Some(preg) => self.emit(format!("mv {}, {}", reg(preg), reg(src))),
+            None => self.frame_store(src, self.spill_offset(dst)),
+        }
+    }
+
+    fn function(mut self) -> Vec<String> {
+        let func = self.func;
+        self.out.push(format!("{}:", func.name));
+
+        // prologue
+        self.emit("addi sp, sp, -16".to_string());
+        self.emit("sd ra, 8(sp)".to_string());
+        self.emit("sd s0, 0(sp)".to_string());
+        self.emit("addi s0, sp, 16".to_string());
+        self.sub_sp(self.stack_size - 16);
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.frame_store(preg, offset);
+        }
+        for (i, param) in func.params.iter().enumerate() {
+            if self.alloc.locs[param.0].is_some() {
+                self.store(*param, A0 + i as u8);
+            }
+        }
+
+        for (i, block) in func.blocks.iter().enumerate() {
+            self.out.push(format!("{}:", self.label(BlockId(i))));
+            if let Some(Inst::Call { name, args, .. }) =
+                tailcall::tail_call(func, block).filter(|_| self.sibling_calls)
+            {
+                for inst in block.insts[..block.insts.len() - 1].iter() {
+                    self.inst(inst);
+                }
+                self.sibling_call(name, args);
+                continue;
+            }
+            for inst in block.insts.iter() {
+                self.inst(inst);
+            }
+            self.terminator(&block.term, BlockId(i + 1));
+        }
+        self.relax_branches();
+        self.out
+    }
+
+    // 関数が条件分岐の届く範囲より大きくなりうるなら、条件分岐を
+    // 逆の条件で j を飛び越える形に書き換える
+    // 一行は疑似命令を展開しても 8 バイト以下なので、行数から大きさを見積もる
+    fn relax_branches(&mut self) {
+        if self.out.len() * 8 < BRANCH_RANGE {
+            return;
+        }
+        for (n, i) in std::mem::take(&mut self.branches)
+            .into_iter()
+            .enumerate()
+            .rev()
+        {
+            let line = self.out[i].trim().to_string();
+            let (op, rest) = line.split_once(' ').unwrap();
+            let (cond, target) = rest.split_once(", ").unwrap();
+            let inverse = if op == "beqz" { "bnez" } else { "beqz" };
+            let skip = format!(".L.{}.far{}", self.func.name, n);
+            self.out.splice(
+                i..=i,
+                [
+                    format!("  {} {}, {}", inverse, cond, skip),
+                    format!("  j {}", target),
+                    format!("{}:", skip),
+                ],
+            );
+        }
+    }
+
+    fn sub_sp(&mut self, size: usize) {
+        if size == 0 {
+            return;
+        }
+        if fits_imm12(-(size as i64)) {
+            self.emit(format!("addi sp, sp, -{}", size));
+        } else {
+            self.mov_imm(LHS, size as i64);
+            self.emit(format!("sub sp, sp, {}", reg(LHS)));
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => match self.phys(Operand::Reg(*dst)) {
+                Some(preg) => self.load(preg, *src, self.size(*dst)),
+                None => {
+                    let src = self.in_reg(*src, TMP, self.size(*dst));
+                    self.store(*dst, src);
+                }
+            },
+            // phi はコード生成の前にコピーに置き換えてある
+            Inst::Phi { .. } => unreachable!(),
+            Inst::Bin { op, dst, lhs, rhs } => self.bin(*op, *dst, *lhs, *rhs),
+            Inst::Cmp {
+                op,
+                ty,
+                dst,
+                lhs,
+                rhs,
+            } => self.cmp(*op, ty.size(self.func.layout), *dst, *lhs, *rhs),
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                signed,
+            } => {
+                let addr = self.in_reg(*addr, LHS, 8);
+                let target = self.target(*dst);
+                let op = match (ty, signed) {
+                    (Ty::I8, true) => "lb",
+                    (Ty::I8, false) => "lbu",
+                    // 符号なしの値も 32 ビットのままなら符号拡張した形にする
+                    (Ty::I32, false) if self.size(*dst) == 8 => "lwu",
+                    (Ty::I32, _) => "lw",
+                    _ => "ld",
+                };
+                self.emit(format!("{} {}, 0({})", op, reg(target), reg(addr)));
+                self.store(*dst, target);
+            }
+            Inst::Store { addr, val, ty } => {
+                let addr = self.in_reg(*addr, LHS, 8);
+                let val = self.in_reg(*val, RHS, ty.size(self.func.layout));
+                let op = match ty {
+                    Ty::I8 => "sb",
+                    Ty::I32 => "sw",
+                    _ => "sd",
+                };
+                self.emit(format!("{} {}, 0({})", op, reg(val), reg(addr)));
+            }
+            Inst::LocalAddr { dst, slot } => {
+                let target = self.target(*dst);
+                let offset = -(self.slot_offsets[slot.0] as i64);
+                if fits_imm12(offset) {
+                    self.emit(format!("addi {}, s0, {}", reg(target), offset));
+                } else {
+                    self.mov_imm(target, offset);
+                    self.emit(format!("add {}, s0, {}", reg(target), reg(target)));
+                }
+                self.store(*dst, target);
+            }
+            Inst::GlobalAddr { dst, name } => {
+                let target = self.target(*dst);
+                self.emit(format!("lla {}, {}", reg(target), name));
+                self.store(*dst, target);
+            }
+            Inst::Call { dst, name, args } => {
+                self.args(args);
+                self.emit(format!("call {}", name));
+                if let Some(dst) = dst {
+                    self.store(*dst, A0);
+                }
+            }
+            Inst::Cast { dst, src, kind } => {
+                let src = reg(self.in_reg(*src, LHS, 8));
+                let target = self.target(*dst);
+                let name = reg(target);
+                match kind {
+                    CastKind::Sext(Ty::I8) => {
+                        self.emit(format!("slli {}, {}, 56", name, src));
+                        self.emit(format!("srai {}, {}, 56", name, name));
+                    }
+                    CastKind::Zext(Ty::I8) => self.emit(format!("andi {}, {}, 255", name, src)),
+                    CastKind::Zext(_) => {
+                        self.emit(format!("slli {}, {}, 32", name, src));
+                        self.emit(format!("srli {}, {}, 32", name, name));
+                    }
+                    CastKind::Sext(_) | CastKind::Trunc if self.size(*dst) >= 4 => {
+                        self.emit(format!("sext.w {}, {}", name, src))
+                    }
+                    // char の上位のビットは拡張するときに捨てる
+                    CastKind::Sext(_) | CastKind::Trunc => {
+                        self.emit(format!("mv {}, {}", name, src))
+                    }
+                }
+                self.store(*dst, target);
+            }
+            Inst::Alloca { dst, size } => {
+                let tmp = reg(LHS);
+                self.load(LHS, *size, 8);
+                self.emit(format!("addi {}, {}, 15", tmp, tmp));
+                self.emit(format!("andi {}, {}, -16", tmp, tmp));
+                self.emit(format!("sub sp, sp, {}", tmp));
+                let target = self.target(*dst);
+                self.emit(format!("mv {}, sp", reg(target)));
+                self.store(*dst, target);
+            }
+            Inst::StackSave { dst } => {
+                let target = self.target(*dst);
+                self.emit(format!("mv {}, sp", reg(target)));
+                self.store(*dst, target);
+            }
+            Inst::StackRestore { src } => {
+                let src = self.in_reg(*src, LHS, 8);
+                self.emit(format!("mv sp, {}", reg(src)));
+            }
+        }
+    }
+
+    fn args(&mut self, args: &[Operand]) {
+        for (i, arg) in args.iter().enumerate() {
+            let size = match arg {
+                Operand::Reg(r) => self.size(*r),
+                Operand::Imm(_) => 4,
+            };
+            self.load(A0 + i as u8, *arg, size);
+        }
+    }
+
+    // 呼び出した結果をそのまま返すなら、フレームを片付けてから呼び出し先へ飛ぶ
+    // 戻り先は ra に戻してあるので、呼び出し先は呼び出し元の戻り先へ直接戻る
+    fn sibling_call(&mut self, name: &str, args: &[Operand]) {
+        self.args(args);
+        self.epilogue();
+        self.emit(format!("tail {}", name));
+    }
+
+    fn epilogue(&mut self) {
+        for (preg, offset) in self.saved_offsets.clone() {
+            self.frame_load(preg, offset);
+        }
+        self.emit("addi sp, s0, -16".to_string());
+        self.emit("ld ra, 8(sp)".to_string());
+        self.emit("ld s0, 0(sp)".to_string());
+        self.emit("addi sp, sp, 16".to_string());
+    }
+
+    // 比較の結果は slt / sltu と、0 との比較 (seqz / snez) で求める
+    fn cmp(&mut self, op: CmpOp, size: usize, dst: Reg, lhs: Operand, rhs: Operand) {
+        let lhs = reg(self.in_reg(lhs, LHS, size));
+        let target = self.target(dst);
+        let name = reg(target);
+        let rhs_imm = imm(rhs, size).filter(|val| fits_imm12(*val));
+        match op {
+            CmpOp::Eq | CmpOp::Ne => {
+                let set = if op == CmpOp::Eq { "seqz" } else { "snez" };
+                match rhs_imm {
+                    Some(0) => self.emit(format!("{} {}, {}", set, name, lhs)),
+                    Some(val) => {
+                        self.emit(format!("xori {}, {}, {}", name, lhs, val));
+                        self.emit(format!("{} {}, {}", set, name, name));
+                    }
+                    None => {
+                        let rhs = reg(self.in_reg(rhs, RHS, size));
+                        self.emit(format!("xor {}, {}, {}", name, lhs, rhs));
+                        self.emit(format!("{} {}, {}", set, name, name));
+                    }
+                }
+            }
+            _ => {
+                let signed = matches!(op, CmpOp::Slt | CmpOp::Sle | CmpOp::Sgt | CmpOp::Sge);
+                let slt = if signed { "slt" } else { "sltu" };
+                match (op, rhs_imm) {
+                    (CmpOp::Slt | CmpOp::Ult, Some(val)) => {
+                        self.emit(format!("{}i {}, {}, {}", slt, name, lhs, val))
+                    }
+                    (CmpOp::Sge | CmpOp::Uge, Some(val)) => {
+                        self.emit(format!("{}i {}, {}, {}", slt, name, lhs, val));
+                        self.emit(format!("xori {}, {}, 1", name, name));
+                    }
+                    _ => {
+                        let rhs = reg(self.in_reg(rhs, RHS, size));
+                        // a > b は b < a、a <= b は !(b < a)、a >= b は !(a < b)
+                        let (a, b) = match op {
+                            CmpOp::Slt | CmpOp::Ult | CmpOp::Sge | CmpOp::Uge => (lhs, rhs),
+                            _ => (rhs, lhs),
+                        };
+                        self.emit(format!("{} {}, {}, {}", slt, name, a, b));
+                        if matches!(op, CmpOp::Sle | CmpOp::Ule | CmpOp::Sge | CmpOp::Uge) {
+                            self.emit(format!("xori {}, {}, 1", name, name));
+                        }
+                    }
+                }
+            }
+        }
+        self.store(dst, target);
+    }
+
+    fn bin(&mut self, op: BinOp, dst: Reg, lhs: Operand, rhs: Operand) {
+        let size = self.size(dst);
+        let lhs = reg(self.in_reg(lhs, LHS, size));
+        let target = self.target(dst);
+        let name = reg(target);
+        // 32 ビットの演算は w の付いた命令で、結果を符号拡張する
+        let w = if size == 8 { "" } else { "w" };
+        let bits = if size == 8 { 64 } else { 32 };
+        match (op, imm(rhs, size)) {
+            (BinOp::Add, Some(val)) if fits_imm12(val) => {
+                self.emit(format!("addi{} {}, {}, {}", w, name, lhs, val))
+            }
+            (BinOp::Sub, Some(val)) if fits_imm12(-val) => {
+                self.emit(format!("addi{} {}, {}, {}", w, name, lhs, -val))
+            }
+            (BinOp::And | BinOp::Or, Some(val)) if fits_imm12(val) => {
+                let op = if op == BinOp::And { "andi" } else { "ori" };
+                self.emit(format!("{} {}, {}, {}", op, name, lhs, val));
+            }
+            (BinOp::Shl | BinOp::LShr | BinOp::AShr, Some(val)) => {
+                let op = match op {
+                    BinOp::Shl => "slli",
+                    BinOp::LShr => "srli",
+                    _ => "srai",
+                };
+                self.emit(format!(
+                    "{}{} {}, {}, {}",
+                    op,
+                    w,
+                    name,
+                    lhs,
+                    val & (bits - 1)
+                ));
+            }
+            _ => {
+                let rhs = reg(self.in_reg(rhs, RHS, size));
+                let (op, w) = match op {
+                    BinOp::Add => ("add", w),
+                    BinOp::Sub => ("sub", w),
+                    BinOp::Mul => ("mul", w),
+                    BinOp::SDiv => ("div", w),
+                    BinOp::UDiv => ("divu", w),
+                    // 符号拡張した値どうしの論理演算の結果は符号拡張した形のまま
+                    BinOp::And => ("and", ""),
+                    BinOp::Or => ("or", ""),
+                    BinOp::Shl => ("sll", w),
+                    BinOp::LShr => ("srl", w),
+                    BinOp::AShr => ("sra", w),
+                };
+                self.emit(format!("{}{} {}, {}, {}", op, w, name, lhs, rhs));
+            }
+        }
+        self.store(dst, target);
+    }
+
+    fn terminator(&mut self, term: &Terminator, next: BlockId) {
+        match term {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    self.emit(format!("j {}", self.label(*target)));
+                }
+            }
+            Terminator::Branch { cond, then, els } => {
+                let size = match cond {
+                    Operand::Reg(r) => self.size(*r),
+                    Operand::Imm(_) => 8,
+                };
+                let cond = self.in_reg(*cond, TMP, size);
+                // 条件が成り立つ側が次のブロックなら、成り立たないときだけ飛ぶ
+                self.branches.push(self.out.len());
+                if *then == next {
+                    self.emit(format!("beqz {}, {}", reg(cond), self.label(*els)));
+                    return;
+                }
+                self.emit(format!("bnez {}, {}", reg(cond), self.label(*then)));
+                if *els != next {
+                    self.emit(format!("j {}", self.label(*els)));
+                }
+            }
+            Terminator::Ret(val) => {
+                if let Some(val) = val {
+                    let size = match val {
+                        Operand::Reg(r) => self.size(*r),
+                        Operand::Imm(_) => 4,
+                    };
+                    self.load(A0, *val, size);
+                }
+                self.epilogue();
+                self.emit("ret".to_string());
+            }
+            Terminator::Unreachable => {}
+        }
+    }
+}
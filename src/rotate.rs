@@ -0,0 +1,153 @@
+use crate::cfg::DomTree;
+use crate::ir::{Function, Inst, Operand, Reg, Terminator};
+use crate::loops::{self, Loop};
+use std::collections::{HashMap, HashSet};
+
+// ループの回転
+// 先頭で条件を調べる while 型のループを、入口で一度だけ条件を調べてから末尾で調べる形に変える
+// 末尾から先頭へ戻るジャンプと先頭の条件分岐が一つの分岐になる
+
+const MAX_HEADER_SIZE: usize = 16; // 複製するヘッダの命令数の上限
+
+pub fn rotate(func: &mut Function) {
+    loops::insert_preheaders(func);
+    let mut tried = HashSet::new();
+    loop {
+        let dom = DomTree::new(func);
+        let Some(lp) = loops::find_loops(func, &dom)
+            .into_iter()
+            .find(|lp| !tried.contains(&lp.header))
+        else {
+            return;
+        };
+        tried.insert(lp.header);
+        rotate_loop(func, &lp);
+    }
+}
+
+fn rotate_loop(func: &mut Function, lp: &Loop) {
+    let header = lp.header;
+    let (Some(latch), Some(pre)) = (lp.latch(), lp.preheader(func)) else {
+        return;
+    };
+    if latch == header || func.blocks[latch.0].term != Terminator::Jump(header) {
+        return;
+    }
+    // ヘッダの分岐の一方がループの本体、もう一方が出口
+    let Terminator::Branch { then, els, .. } = func.blocks[header.0].term else {
+        return;
+    };
+    let (body, exit) = match (lp.contains(then), lp.contains(els)) {
+        (true, false) => (then, els),
+        (false, true) => (els, then),
+        _ => return,
+    };
+    let insts = &func.blocks[header.0].insts;
+    let size = insts
+        .iter()
+        .filter(|inst| !matches!(inst, Inst::Phi { .. }))
+        .count();
+    let touches_stack = insts.iter().any(|inst| {
+        matches!(
+            inst,
+            Inst::Alloca { .. } | Inst::StackSave { .. } | Inst::StackRestore { .. }
+        )
+    });
+    if size > MAX_HEADER_SIZE || touches_stack || !loops::form_lcssa(func, lp) {
+        return;
+    }
+
+    // ヘッダの phi は、入口では初期値、末尾では次の繰り返しの値になる
+    let mut entry_map = HashMap::new();
+    let mut latch_map = HashMap::new();
+    let mut phis = Vec::new();
+    let mut rest = Vec::new();
+    for inst in std::mem::take(&mut func.blocks[header.0].insts) {
+        match &inst {
+            Inst::Phi { dst, incoming } => {
+                for (pred, val) in incoming.iter() {
+                    if *pred == pre {
+                        entry_map.insert(*dst, *val);
+                    } else {
+                        latch_map.insert(*dst, *val);
+                    }
+                }
+                phis.push(inst);
+            }
+            _ => rest.push(inst),
+        }
+    }
+    let guard = func.new_block();
+    let entry_insts = copy_insts(func, &rest, &mut entry_map);
+    let latch_insts = copy_insts(func, &rest, &mut latch_map);
+
+    // ヘッダで計算していた値は、入口で計算した値と末尾で計算した値の phi になる
+    for inst in rest.iter() {
+        if let Some(dst) = inst.dst() {
+            phis.push(Inst::Phi {
+                dst,
+                incoming: vec![
+                    (guard, loops::remap(&entry_map, Operand::Reg(dst))),
+                    (latch, loops::remap(&latch_map, Operand::Reg(dst))),
+                ],
+            });
+        }
+    }
+    let branch = std::mem::replace(&mut func.blocks[header.0].term, Terminator::Jump(body));
+    func.blocks[header.0].insts = phis;
+    loops::rename_pred(func, header, pre, guard);
+
+    let rotated = |map: &HashMap<Reg, Operand>| {
+        let mut term = branch.clone();
+        for op in term.operands_mut() {
+            *op = loops::remap(map, *op);
+        }
+        for succ in term.successors_mut() {
+            if *succ == body {
+                *succ = header;
+            }
+        }
+        term
+    };
+    func.blocks[guard.0].insts = entry_insts;
+    func.blocks[guard.0].term = rotated(&entry_map);
+    func.blocks[latch.0].insts.extend(latch_insts);
+    func.blocks[latch.0].term = rotated(&latch_map);
+    for succ in func.blocks[pre.0].term.successors_mut() {
+        if *succ == header {
+            *succ = guard;
+        }
+    }
+
+    // 出口にはヘッダの代わりに入口の判定と末尾の判定から来る
+    for inst in func.blocks[exit.0].insts.iter_mut() {
+        let Inst::Phi { incoming, .. } = inst else {
+            continue;
+        };
+        let Some(i) = incoming.iter().position(|(pred, _)| *pred == header) else {
+            continue;
+        };
+        let (_, val) = incoming.remove(i);
+        incoming.push((guard, loops::remap(&entry_map, val)));
+        incoming.push((latch, loops::remap(&latch_map, val)));
+    }
+}
+
+// 命令を新しい仮想レジスタに定義し直して複製する
+fn copy_insts(func: &mut Function, insts: &[Inst], map: &mut HashMap<Reg, Operand>) -> Vec<Inst> {
+    insts
+        .iter()
+        .map(|inst| {
+            let mut inst = inst.clone();
+            for op in inst.operands_mut() {
+                *op = loops::remap(map, *op);
+            }
+            if let Some(dst) = inst.dst_mut() {
+                let reg = func.new_reg(func.reg_ty(*dst));
+                map.insert(*dst, Operand::Reg(reg));
+                *dst = reg;
+            }
+            inst
+        })
+        .collect()
+}
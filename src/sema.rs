@@ -0,0 +1,760 @@
+use crate::consteval;
+use crate::flow;
+use crate::layout::DataLayout;
+use crate::parser::{
+    get_type_size, layout_struct, BinaryOpKind, BitField, Function, GVar, LVar, Node, NodeKind,
+    Type, TypeKind, UnaryOpKind,
+};
+use crate::warning::{self, Warning};
+use std::collections::{HashMap, HashSet};
+
+// 引数はレジスタ渡しのみ対応
+const MAX_ARGS: usize = 6;
+
+// 関数のシグネチャ
+struct FuncSig {
+    ty: Type,
+    params: Vec<Type>,
+}
+
+// 構文木全体に対する名前解決と型検査
+// 変数参照の解決、型の計算、ローカル変数のスタック配置をここで行う
+struct Sema {
+    globals: HashMap<String, GVar>,
+    functions: HashMap<String, FuncSig>,
+    scopes: Vec<HashMap<String, LVar>>,
+    stack_size: usize,
+    ret_ty: Type,
+    declared: Vec<Declared>,       // 関数内で宣言されたローカル変数
+    referenced: HashSet<usize>,    // 参照されたローカル変数のオフセット
+    structs: HashMap<usize, Type>, // 配置の決まった構造体の定義
+    block_has_vla: bool,           // 現在のブロックで可変長配列が宣言されたか
+    layout: DataLayout,
+}
+
+// 未使用の警告のために記録しておく宣言
+struct Declared {
+    name: String,
+    offset: usize,
+    line: usize,
+    is_param: bool,
+}
+
+pub fn analyze(program: &mut [Node], layout: DataLayout) -> Result<(), String> {
+    let mut sema = Sema {
+        globals: HashMap::new(),
+        functions: HashMap::new(),
+        scopes: Vec::new(),
+        stack_size: 0,
+        ret_ty: Type::int(),
+        declared: Vec::new(),
+        referenced: HashSet::new(),
+        structs: HashMap::new(),
+        block_has_vla: false,
+        layout,
+    };
+
+    // 宣言の順序に依存しないように、先にトップレベルの名前をすべて登録する
+    for node in program.iter_mut() {
+        sema.declare_toplevel(node)?;
+    }
+    for node in program.iter_mut() {
+        sema.function(node)?;
+    }
+    Ok(())
+}
+
+fn is_lvalue(node: &Node) -> bool {
+    matches!(
+        node.kind,
+        NodeKind::LVar(_)
+            | NodeKind::GVar(_)
+            | NodeKind::UnaryOp(UnaryOpKind::Deref)
+            | NodeKind::Member(_)
+    )
+}
+
+fn is_bit_field(node: &Node) -> bool {
+    matches!(&node.kind, NodeKind::Member(member) if member.bit_field.is_some())
+}
+
+// 定義されていない構造体、void、またはそれを要素とする配列
+fn is_incomplete(ty: &Type) -> bool {
+    match ty.kind {
+        TypeKind::Struct => ty.members.is_empty(),
+        TypeKind::Void => true,
+        TypeKind::Arr => is_incomplete(ty.ptr_to.as_ref().unwrap()),
+        _ => false,
+    }
+}
+
+// 構造体は定義ごとに別の型として扱う。符号の違いは区別しない
+fn is_compatible(a: &Type, b: &Type) -> bool {
+    if a.kind != b.kind {
+        return false;
+    }
+    match a.kind {
+        TypeKind::Struct => a.struct_id == b.struct_id,
+        TypeKind::Ptr => is_compatible(a.ptr_to.as_ref().unwrap(), b.ptr_to.as_ref().unwrap()),
+        TypeKind::Arr => {
+            (a.arr_size == b.arr_size || a.is_vla() || b.is_vla())
+                && is_compatible(a.ptr_to.as_ref().unwrap(), b.ptr_to.as_ref().unwrap())
+        }
+        _ => true,
+    }
+}
+
+// 配列は先頭要素へのポインタに読み替えたときの指す先の型
+fn pointee(ty: &Type) -> Option<&Type> {
+    if ty.is_pointer() {
+        ty.ptr_to.as_deref()
+    } else {
+        None
+    }
+}
+
+fn is_null_pointer_constant(node: &Node) -> bool {
+    node.kind == NodeKind::Num(0)
+}
+
+// from の値を to 型の変数に代入できるか検査する
+fn check_assignable(to: &Type, from: &Node) -> Result<(), String> {
+    if from.ty.kind == TypeKind::Void {
+        return Err("void 型の値は使えません".to_string());
+    }
+    if to.kind == TypeKind::Struct || from.ty.kind == TypeKind::Struct {
+        if !is_compatible(to, &from.ty) {
+            return Err("互換性のない構造体型です".to_string());
+        }
+        return Ok(());
+    }
+    match (pointee(to), pointee(&from.ty)) {
+        (Some(to), Some(from)) => {
+            // void* は任意のオブジェクトへのポインタと相互に変換できる
+            let is_void = to.kind == TypeKind::Void || from.kind == TypeKind::Void;
+            if !is_void && !is_compatible(to, from) {
+                return Err("互換性のないポインタ型です".to_string());
+            }
+        }
+        (Some(_), None) => {
+            if !is_null_pointer_constant(from) {
+                warning::warn(
+                    Warning::IntConversion,
+                    from.line,
+                    "整数からポインタへの暗黙の変換です",
+                );
+            }
+        }
+        (None, Some(_)) => warning::warn(
+            Warning::IntConversion,
+            from.line,
+            "ポインタから整数への暗黙の変換です",
+        ),
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+impl Sema {
+    fn declare_toplevel(&mut self, node: &mut Node) -> Result<(), String> {
+        match &mut node.kind {
+            NodeKind::GVarDef(gvar) => {
+                if self.globals.contains_key(&gvar.name) || self.functions.contains_key(&gvar.name)
+                {
+                    return Err(format!("変数 '{}' はすでに定義されています", gvar.name));
+                }
+                self.resolve_type(&mut gvar.ty)?;
+                if is_incomplete(&gvar.ty) {
+                    return Err(format!("変数 '{}' の型が不完全です", gvar.name));
+                }
+                self.globals.insert(gvar.name.clone(), gvar.clone());
+            }
+            NodeKind::Fndef(func, params) => {
+                if self.functions.contains_key(&func.name) || self.globals.contains_key(&func.name)
+                {
+                    return Err(format!("関数 '{}' はすでに定義されています", func.name));
+                }
+                if params.len() > MAX_ARGS {
+                    return Err(format!(
+                        "関数 '{}' の引数が多すぎます (最大{}個)",
+                        func.name, MAX_ARGS
+                    ));
+                }
+                self.resolve_type(&mut func.ty)?;
+                if func.ty.kind == TypeKind::Struct {
+                    return Err(format!(
+                        "関数 '{}': 構造体を返す関数は未対応です",
+                        func.name
+                    ));
+                }
+                let sig = FuncSig {
+                    ty: func.ty.clone(),
+                    params: params.iter().map(param_type).collect(),
+                };
+                self.functions.insert(func.name.clone(), sig);
+            }
+            NodeKind::TypeDecl(ty) => self.resolve_type(ty)?,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, node: &mut Node) -> Result<(), String> {
+        let Node { kind, rhs, .. } = node;
+        let NodeKind::Fndef(func, params) = kind else {
+            return Ok(());
+        };
+
+        self.scopes = vec![HashMap::new()];
+        self.stack_size = 0;
+        self.ret_ty = func.ty.clone();
+        self.declared.clear();
+        self.referenced.clear();
+
+        for param in params.iter_mut() {
+            if let NodeKind::LVar(lvar) = &mut param.kind {
+                self.declare_lvar(lvar, param.line, true)?;
+                if lvar.ty.kind == TypeKind::Struct {
+                    return Err(format!("引数 '{}': 構造体の値渡しは未対応です", lvar.name));
+                }
+                param.ty = lvar.ty.clone();
+            }
+        }
+
+        // 引数と関数本体の最も外側のブロックは同じスコープに属する
+        let body = rhs.as_mut().unwrap();
+        match &mut body.kind {
+            NodeKind::Block(stmts) => {
+                for stmt in stmts.iter_mut() {
+                    self.visit(stmt)?;
+                }
+            }
+            _ => self.visit(body)?,
+        }
+
+        func.stack_size = self.stack_size.next_multiple_of(16);
+        self.scopes.clear();
+
+        self.warn_unused();
+        // main と void 関数は終端に到達しても問題ないものとして扱う
+        let returns_value = func.name != "main" && func.ty.kind != TypeKind::Void;
+        if returns_value && !flow::always_returns(body, self.layout) {
+            warning::warn(
+                Warning::ReturnType,
+                body.line,
+                &format!(
+                    "関数 '{}' が値を返さずに終了する可能性があります",
+                    func.name
+                ),
+            );
+        }
+        flow::check_uninitialized(params, body);
+        flow::check_unreachable(body, self.layout);
+        Ok(())
+    }
+
+    fn warn_unused(&self) {
+        for decl in self.declared.iter() {
+            if self.referenced.contains(&decl.offset) {
+                continue;
+            }
+            if decl.is_param {
+                warning::warn(
+                    Warning::UnusedParameter,
+                    decl.line,
+                    &format!("引数 '{}' は使われていません", decl.name),
+                );
+            } else {
+                warning::warn(
+                    Warning::UnusedVariable,
+                    decl.line,
+                    &format!("変数 '{}' は使われていません", decl.name),
+                );
+            }
+        }
+    }
+
+    // 配列の要素数の式を整数定数式として評価し、構造体の配置を決める
+    fn resolve_type(&mut self, ty: &mut Type) -> Result<(), String> {
+        if ty.kind == TypeKind::Struct {
+            return self.resolve_struct(ty);
+        }
+        if let Some(base) = ty.ptr_to.as_mut() {
+            self.resolve_type(base)?;
+        }
+        if ty.kind != TypeKind::Arr {
+            return Ok(());
+        }
+        let elem_is_vla = ty.ptr_to.as_ref().unwrap().is_vla();
+        let Some(mut len) = ty.arr_len.take() else {
+            if elem_is_vla {
+                ty.vla_size = Some(self.alloc_slot());
+            }
+            return Ok(());
+        };
+
+        self.visit(&mut len)?;
+        // 関数内では定数でない要素数の配列を可変長配列として扱う
+        if !consteval::is_constant(&len) && !self.scopes.is_empty() {
+            if !len.ty.is_integer() {
+                return Err("配列の要素数が整数ではありません".to_string());
+            }
+            ty.arr_len = Some(len);
+            ty.vla_size = Some(self.alloc_slot());
+            return Ok(());
+        }
+        let val = consteval::eval(&len, self.layout).map_err(|e| format!("配列の要素数: {}", e))?;
+        if val < 0 {
+            return Err("配列の要素数が負です".to_string());
+        }
+        ty.arr_size = val as usize;
+        if elem_is_vla {
+            ty.vla_size = Some(self.alloc_slot());
+        }
+        Ok(())
+    }
+
+    // コンパイラが内部で使う 8 バイトのスロットをスタックに確保する
+    fn alloc_slot(&mut self) -> usize {
+        self.stack_size += 8;
+        self.stack_size
+    }
+
+    fn resolve_struct(&mut self, ty: &mut Type) -> Result<(), String> {
+        // 定義済みの構造体 (不完全型として参照されたものを含む) は記録した定義を使う
+        if let Some(def) = self.structs.get(&ty.struct_id) {
+            *ty = def.clone();
+            return Ok(());
+        }
+        if ty.members.is_empty() {
+            return Ok(());
+        }
+
+        let mut names = HashSet::new();
+        for member in ty.members.iter_mut() {
+            self.resolve_type(&mut member.ty)?;
+            let name = member.name.clone().unwrap_or_default();
+            if member.name.is_some() && !names.insert(name.clone()) {
+                return Err(format!("メンバ '{}' が重複しています", name));
+            }
+            if is_incomplete(&member.ty) {
+                return Err(format!("メンバ '{}' の型が不完全です", name));
+            }
+            if member.ty.is_vla() {
+                return Err(format!("メンバ '{}' に可変長配列は使えません", name));
+            }
+
+            let Some(mut width) = member.width.take() else {
+                continue;
+            };
+            if !member.ty.is_integer() {
+                return Err(format!(
+                    "ビットフィールド '{}' の型が整数ではありません",
+                    name
+                ));
+            }
+            self.visit(&mut width)?;
+            let val = consteval::eval(&width, self.layout)
+                .map_err(|e| format!("ビットフィールドの幅: {}", e))?;
+            if val < 0 {
+                return Err(format!("ビットフィールド '{}' の幅が負です", name));
+            }
+            if val == 0 && member.name.is_some() {
+                return Err(format!("ビットフィールド '{}' の幅が0です", name));
+            }
+            member.bit_field = Some(BitField {
+                width: val as usize,
+                bit_offset: 0,
+            });
+        }
+
+        layout_struct(ty, self.layout)?;
+        self.structs.insert(ty.struct_id, ty.clone());
+        Ok(())
+    }
+
+    // 不完全型として参照された構造体を、その後の定義で置き換える
+    fn complete(&self, ty: &mut Type) {
+        if let Some(base) = ty.ptr_to.as_mut() {
+            self.complete(base);
+        }
+        if ty.kind == TypeKind::Struct && ty.members.is_empty() {
+            if let Some(def) = self.structs.get(&ty.struct_id) {
+                *ty = def.clone();
+            }
+        }
+    }
+
+    // ローカル変数を現在のスコープに登録し、スタック上のオフセットを割り当てる
+    fn declare_lvar(&mut self, lvar: &mut LVar, line: usize, is_param: bool) -> Result<(), String> {
+        if self.scopes.last().unwrap().contains_key(&lvar.name) {
+            return Err(format!("変数 '{}' はすでに定義されています", lvar.name));
+        }
+        self.resolve_type(&mut lvar.ty)?;
+        if is_incomplete(&lvar.ty) {
+            return Err(format!("変数 '{}' の型が不完全です", lvar.name));
+        }
+        // 可変長配列は実行時に確保した領域へのポインタを保持する
+        if lvar.ty.is_vla() {
+            self.stack_size += 8;
+            self.block_has_vla = true;
+        } else {
+            self.stack_size += get_type_size(&lvar.ty, self.layout);
+        }
+        self.stack_size = self.stack_size.next_multiple_of(8);
+        lvar.offset = self.stack_size;
+        let scope = self.scopes.last_mut().unwrap();
+        scope.insert(lvar.name.clone(), lvar.clone());
+        self.declared.push(Declared {
+            name: lvar.name.clone(),
+            offset: lvar.offset,
+            line,
+            is_param,
+        });
+        Ok(())
+    }
+
+    fn resolve_var(&mut self, name: &str) -> Result<NodeKind, String> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(lvar) = scope.get(name) {
+                self.referenced.insert(lvar.offset);
+                return Ok(NodeKind::LVar(lvar.clone()));
+            }
+        }
+        if let Some(gvar) = self.globals.get(name) {
+            return Ok(NodeKind::GVar(gvar.clone()));
+        }
+        Err(format!("変数 '{}' が見つかりません", name))
+    }
+
+    fn is_variable(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains_key(name)) || self.globals.contains_key(name)
+    }
+
+    fn fncall(&self, func: &mut Function, args: &[Node], line: usize) -> Result<(), String> {
+        if self.is_variable(&func.name) {
+            return Err(format!("'{}' は関数ではありません", func.name));
+        }
+        if args.len() > MAX_ARGS {
+            return Err(format!(
+                "関数 '{}' の引数が多すぎます (最大{}個)",
+                func.name, MAX_ARGS
+            ));
+        }
+
+        // 定義のない関数は int を返すものとして扱う
+        let Some(sig) = self.functions.get(&func.name) else {
+            warning::warn(
+                Warning::ImplicitFunctionDeclaration,
+                line,
+                &format!("関数 '{}' の暗黙的な宣言です", func.name),
+            );
+            func.ty = Type::int();
+            return Ok(());
+        };
+        if sig.params.len() != args.len() {
+            return Err(format!(
+                "関数 '{}' の引数の個数が一致しません (期待: {}, 実際: {})",
+                func.name,
+                sig.params.len(),
+                args.len()
+            ));
+        }
+        if args.iter().any(|arg| arg.ty.kind == TypeKind::Struct) {
+            return Err(format!(
+                "関数 '{}' の引数: 構造体の値渡しは未対応です",
+                func.name
+            ));
+        }
+        for (param, arg) in sig.params.iter().zip(args) {
+            check_assignable(param, arg)
+                .map_err(|e| format!("関数 '{}' の引数: {}", func.name, e))?;
+        }
+        func.ty = sig.ty.clone();
+        Ok(())
+    }
+
+    fn visit(&mut self, node: &mut Node) -> Result<(), String> {
+        match &mut node.kind {
+            NodeKind::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                let outer_has_vla = std::mem::replace(&mut self.block_has_vla, false);
+                for stmt in stmts.iter_mut() {
+                    self.visit(stmt)?;
+                }
+                // ブロックを抜けるときに可変長配列の領域を解放する
+                if self.block_has_vla {
+                    let save = self.alloc_slot();
+                    let line = node.line;
+                    stmts.insert(0, new_node(NodeKind::StackSave(save), line));
+                    stmts.push(new_node(NodeKind::StackRestore(save), line));
+                }
+                self.block_has_vla = outer_has_vla;
+                self.scopes.pop();
+                return Ok(());
+            }
+            NodeKind::LVarDef(lvar) => {
+                self.declare_lvar(lvar, node.line, false)?;
+                node.ty = lvar.ty.clone();
+                if node.ty.is_vla() {
+                    // int a[n]; -> a = __builtin_alloca(sizeof(int[n]))
+                    let line = node.line;
+                    let mut ty = new_node(NodeKind::TypeDecl(node.ty.clone()), line);
+                    ty.ty = node.ty.clone();
+                    let mut size = new_node(NodeKind::Sizeof, line);
+                    size.lhs = Some(Box::new(ty));
+                    let mut alloca = new_node(NodeKind::Alloca, line);
+                    alloca.lhs = Some(Box::new(size));
+                    node.lhs = Some(Box::new(alloca));
+                }
+                return Ok(());
+            }
+            NodeKind::Var(name) => {
+                let kind = self.resolve_var(name)?;
+                node.kind = kind;
+            }
+            NodeKind::Fncall(func, args) if func.name == "__builtin_alloca" => {
+                let [size] = args.as_mut_slice() else {
+                    return Err("__builtin_alloca の引数は1個です".to_string());
+                };
+                self.visit(size)?;
+                if !size.ty.is_integer() {
+                    return Err("__builtin_alloca の引数が整数ではありません".to_string());
+                }
+                let size = args.pop().unwrap();
+                node.kind = NodeKind::Alloca;
+                node.lhs = Some(Box::new(size));
+                node.ty = Type::pointer_to(Type::void());
+                return Ok(());
+            }
+            NodeKind::Fncall(func, args) => {
+                for arg in args.iter_mut() {
+                    self.visit(arg)?;
+                }
+                self.fncall(func, args, node.line)?;
+                node.ty = func.ty.clone();
+                self.complete(&mut node.ty);
+                return Ok(());
+            }
+            NodeKind::TypeDecl(ty) => {
+                self.resolve_type(ty)?;
+                node.ty = ty.clone();
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if let Some(lhs) = node.lhs.as_mut() {
+            self.visit(lhs)?;
+        }
+        if let Some(rhs) = node.rhs.as_mut() {
+            self.visit(rhs)?;
+        }
+
+        self.check(node)
+    }
+
+    // 子の型が決まった後に、ノード自身の型を決めて検査する
+    fn check(&mut self, node: &mut Node) -> Result<(), String> {
+        node.ty = match &node.kind {
+            // 接尾辞 u の付いた定数は構文解析で unsigned int になっている
+            NodeKind::Num(_) => node.ty.clone(),
+            NodeKind::Strlit(lit) => Type::array_of(Type::char(), lit.len + 1),
+            NodeKind::LVar(lvar) => lvar.ty.clone(),
+            NodeKind::GVar(gvar) => gvar.ty.clone(),
+            NodeKind::Sizeof => {
+                let ty = &node.lhs.as_ref().unwrap().ty;
+                if is_incomplete(ty) {
+                    return Err("不完全な型に sizeof は使えません".to_string());
+                }
+                // 可変長配列のサイズは実行時に求める
+                if ty.is_vla() {
+                    node.ty = Type::int();
+                    return Ok(());
+                }
+                let size = get_type_size(ty, self.layout);
+                node.kind = NodeKind::Num(size as i32);
+                node.lhs = None;
+                return Ok(());
+            }
+            NodeKind::BinaryOp(op) => {
+                let lhs = &node.lhs.as_ref().unwrap().ty;
+                let rhs = &node.rhs.as_ref().unwrap().ty;
+                binary_type(op, lhs, rhs)?
+            }
+            NodeKind::Comparison(_) => {
+                let lhs = &node.lhs.as_ref().unwrap().ty;
+                let rhs = &node.rhs.as_ref().unwrap().ty;
+                if lhs.kind == TypeKind::Struct || rhs.kind == TypeKind::Struct {
+                    return Err("構造体は比較できません".to_string());
+                }
+                Type::int()
+            }
+            NodeKind::UnaryOp(UnaryOpKind::Ref) => {
+                let lhs = node.lhs.as_ref().unwrap();
+                if !is_lvalue(lhs) {
+                    return Err("アドレスを取れるのは変数だけです".to_string());
+                }
+                if is_bit_field(lhs) {
+                    return Err("ビットフィールドのアドレスは取れません".to_string());
+                }
+                Type::pointer_to(lhs.ty.clone())
+            }
+            NodeKind::UnaryOp(UnaryOpKind::Deref) => {
+                match pointee(&node.lhs.as_ref().unwrap().ty) {
+                    Some(ty) if ty.kind == TypeKind::Void => {
+                        return Err("void* は参照外しできません".to_string());
+                    }
+                    Some(ty) => ty.clone(),
+                    None => return Err("ポインタでない値は参照外しできません".to_string()),
+                }
+            }
+            NodeKind::Member(_) => member(node)?,
+            NodeKind::Assign => self.assign(node)?,
+            NodeKind::Return => {
+                let is_void = self.ret_ty.kind == TypeKind::Void;
+                match node.lhs.as_ref() {
+                    Some(_) if is_void => {
+                        return Err("void 型の関数から値を返すことはできません".to_string());
+                    }
+                    Some(lhs) => {
+                        check_assignable(&self.ret_ty, lhs).map_err(|e| format!("戻り値: {}", e))?
+                    }
+                    None if !is_void => {
+                        return Err("値を返す関数の return に値がありません".to_string());
+                    }
+                    None => {}
+                }
+                Type::int()
+            }
+            _ => Type::int(),
+        };
+        self.complete(&mut node.ty);
+        Ok(())
+    }
+
+    fn assign(&self, node: &mut Node) -> Result<Type, String> {
+        let lhs = node.lhs.as_mut().unwrap();
+        let rhs = node.rhs.as_ref().unwrap();
+
+        // 宣言の初期化子は通常の代入として扱う
+        let is_init = if let NodeKind::LVarDef(lvar) = &lhs.kind {
+            lhs.kind = NodeKind::LVar(self.resolve_local(&lvar.name));
+            true
+        } else {
+            false
+        };
+
+        if !is_lvalue(lhs) {
+            return Err("代入の左辺値が変数ではありません".to_string());
+        }
+
+        if lhs.ty.kind == TypeKind::Arr {
+            // 配列に代入できるのは char 配列の文字列リテラルによる初期化だけ
+            let is_strlit_init = is_init
+                && !lhs.ty.is_vla()
+                && matches!(rhs.kind, NodeKind::Strlit(_))
+                && lhs.ty.ptr_to.as_ref().unwrap().kind == TypeKind::Char;
+            if !is_strlit_init {
+                return Err("配列には代入できません".to_string());
+            }
+        } else {
+            check_assignable(&lhs.ty, rhs)?;
+        }
+        Ok(lhs.ty.clone())
+    }
+
+    fn resolve_local(&self, name: &str) -> LVar {
+        self.scopes.last().unwrap().get(name).unwrap().clone()
+    }
+}
+
+// 構造体のメンバを名前で探し、ノードをオフセットの決まったメンバに置き換える
+fn member(node: &mut Node) -> Result<Type, String> {
+    let base = &node.lhs.as_ref().unwrap().ty;
+    let NodeKind::Member(member) = &node.kind else {
+        unreachable!();
+    };
+    let name = member.name.as_ref().unwrap();
+    if base.kind != TypeKind::Struct {
+        return Err(format!(
+            "構造体でない値のメンバ '{}' は参照できません",
+            name
+        ));
+    }
+    if base.members.is_empty() {
+        return Err(format!(
+            "不完全な構造体のメンバ '{}' は参照できません",
+            name
+        ));
+    }
+    let Some(found) = base.members.iter().find(|m| m.name.as_ref() == Some(name)) else {
+        return Err(format!("構造体にメンバ '{}' がありません", name));
+    };
+
+    // int より幅の狭い unsigned のビットフィールドは int に格上げされる
+    let ty = match &found.bit_field {
+        Some(bf) if found.ty.is_unsigned_int() && bf.width < 32 => Type::int(),
+        _ => found.ty.clone(),
+    };
+    node.kind = NodeKind::Member(found.clone());
+    Ok(ty)
+}
+
+// 算術変換: どちらかが unsigned int なら結果も unsigned int になる
+fn arith_type(lhs: &Type, rhs: &Type) -> Result<Type, String> {
+    if !lhs.is_integer() || !rhs.is_integer() {
+        return Err("算術演算のオペランドは整数でなければなりません".to_string());
+    }
+    let mut ty = Type::int();
+    ty.is_unsigned = lhs.is_unsigned_int() || rhs.is_unsigned_int();
+    Ok(ty)
+}
+
+fn pointer_arith(base: &Type) -> Result<Type, String> {
+    if is_incomplete(base) {
+        return Err("不完全な型へのポインタは演算できません".to_string());
+    }
+    Ok(Type::pointer_to(base.clone()))
+}
+
+fn binary_type(op: &BinaryOpKind, lhs: &Type, rhs: &Type) -> Result<Type, String> {
+    match op {
+        BinaryOpKind::Add => match (pointee(lhs), pointee(rhs)) {
+            (None, None) => arith_type(lhs, rhs),
+            (Some(base), None) | (None, Some(base)) => pointer_arith(base),
+            (Some(_), Some(_)) => Err("ポインタ同士は加算できません".to_string()),
+        },
+        BinaryOpKind::Sub => match (pointee(lhs), pointee(rhs)) {
+            (None, None) => arith_type(lhs, rhs),
+            (Some(base), None) => pointer_arith(base),
+            (Some(l), Some(r)) if is_compatible(l, r) => {
+                pointer_arith(l)?;
+                Ok(Type::int())
+            }
+            (Some(_), Some(_)) => Err("互換性のないポインタ同士の減算です".to_string()),
+            (None, Some(_)) => Err("整数からポインタは減算できません".to_string()),
+        },
+        BinaryOpKind::Mul | BinaryOpKind::Div => arith_type(lhs, rhs)
+            .map_err(|_| "乗除算のオペランドは整数でなければなりません".to_string()),
+    }
+}
+
+// 意味解析で挿入するノード
+fn new_node(kind: NodeKind, line: usize) -> Node {
+    Node {
+        kind,
+        lhs: None,
+        rhs: None,
+        ty: Type::int(),
+        line,
+    }
+}
+
+// 引数ノードの宣言された型
+fn param_type(param: &Node) -> Type {
+    match &param.kind {
+        NodeKind::LVar(lvar) => lvar.ty.clone(),
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,475 @@
+use crate::cfg::{self, DomTree};
+use crate::ir::{BlockId, CastKind, Function, Inst, Operand, Reg, SlotId, Terminator, Ty};
+use std::collections::{HashMap, HashSet};
+
+// スタックスロットに置かれたローカル変数を仮想レジスタに昇格させて SSA 形式にする
+// アドレスが読み書き以外に使われるスロット (配列、構造体、& を取られた変数) はそのまま残す
+pub fn mem2reg(func: &mut Function) {
+    cfg::remove_unreachable_blocks(func);
+    let slots = promotable_slots(func);
+    if slots.is_empty() {
+        return;
+    }
+    let addrs: HashMap<Reg, SlotId> = func
+        .blocks
+        .iter()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(|inst| match inst {
+            Inst::LocalAddr { dst, slot } if slots.contains_key(slot) => Some((*dst, *slot)),
+            _ => None,
+        })
+        .collect();
+
+    // 値が書き込まれるブロックの反復支配辺境に phi を置く
+    let dom = DomTree::new(func);
+    let frontiers = dom.frontiers(func);
+    let mut phis: HashMap<Reg, SlotId> = HashMap::new();
+    let mut ordered: Vec<_> = slots.iter().collect();
+    ordered.sort_by_key(|(slot, _)| **slot);
+    for (slot, ty) in ordered {
+        let mut has_phi = vec![false; func.blocks.len()];
+        let mut work: Vec<BlockId> = (0..func.blocks.len())
+            .map(BlockId)
+            .filter(|block| {
+                func.blocks[block.0].insts.iter().any(|inst| {
+                    matches!(inst, Inst::Store { addr: Operand::Reg(addr), .. }
+                        if addrs.get(addr) == Some(slot))
+                })
+            })
+            .collect();
+        while let Some(block) = work.pop() {
+            for frontier in frontiers[block.0].iter() {
+                if has_phi[frontier.0] {
+                    continue;
+                }
+                has_phi[frontier.0] = true;
+                let dst = func.new_reg(value_ty(*ty));
+                func.blocks[frontier.0].insts.insert(
+                    0,
+                    Inst::Phi {
+                        dst,
+                        incoming: Vec::new(),
+                    },
+                );
+                phis.insert(dst, *slot);
+                work.push(*frontier);
+            }
+        }
+    }
+
+    let mut renamer = Renamer {
+        addrs,
+        phis,
+        stacks: HashMap::new(),
+        replace: HashMap::new(),
+    };
+    renamer.rename(func, &dom, BlockId(0));
+
+    // 読み出しを置き換えた値を使う側に反映する
+    replace_uses(func, &renamer.replace);
+    remove_trivial_phis(func);
+    remove_slots(func, &slots.keys().copied().collect());
+}
+
+// スロットを取り除き、残りのスロットの番号を詰める
+pub fn remove_slots(func: &mut Function, removed: &HashSet<SlotId>) {
+    let mut new_ids = Vec::new();
+    let mut slots = Vec::new();
+    for (i, slot) in std::mem::take(&mut func.slots).into_iter().enumerate() {
+        if removed.contains(&SlotId(i)) {
+            new_ids.push(None);
+        } else {
+            new_ids.push(Some(SlotId(slots.len())));
+            slots.push(slot);
+        }
+    }
+    func.slots = slots;
+    for inst in func
+        .blocks
+        .iter_mut()
+        .flat_map(|block| block.insts.iter_mut())
+    {
+        if let Inst::LocalAddr { slot, .. } = inst {
+            *slot = new_ids[slot.0].unwrap();
+        }
+    }
+}
+
+// スロットに置いた値をレジスタに置くときの型
+fn value_ty(ty: Ty) -> Ty {
+    match ty {
+        Ty::I8 => Ty::I32,
+        _ => ty,
+    }
+}
+
+// 同じ型でだけ読み書きされ、アドレスが他に使われないスロットとその型
+fn promotable_slots(func: &Function) -> HashMap<SlotId, Ty> {
+    let mut addrs = HashMap::new();
+    for inst in func.blocks.iter().flat_map(|block| block.insts.iter()) {
+        if let Inst::LocalAddr { dst, slot } = inst {
+            addrs.insert(*dst, *slot);
+        }
+    }
+
+    let mut access: HashMap<SlotId, Option<Ty>> = HashMap::new();
+    let mut escaped = |slot: SlotId| {
+        access.insert(slot, None);
+    };
+    let mut accesses = Vec::new();
+    for block in func.blocks.iter() {
+        for inst in block.insts.iter() {
+            let (addr, ty, rest) = match inst {
+                Inst::Load { addr, ty, .. } => (Some(*addr), Some(*ty), vec![]),
+                Inst::Store { addr, val, ty } => (Some(*addr), Some(*ty), vec![*val]),
+                _ => (None, None, inst.operands()),
+            };
+            if let (Some(Operand::Reg(addr)), Some(ty)) = (addr, ty) {
+                if let Some(slot) = addrs.get(&addr) {
+                    accesses.push((*slot, ty));
+                }
+            }
+            for op in rest {
+                if let Operand::Reg(reg) = op {
+                    if let Some(slot) = addrs.get(&reg) {
+                        escaped(*slot);
+                    }
+                }
+            }
+        }
+        for op in block.term.operands() {
+            if let Operand::Reg(reg) = op {
+                if let Some(slot) = addrs.get(&reg) {
+                    escaped(*slot);
+                }
+            }
+        }
+    }
+    for (slot, ty) in accesses {
+        let size = func.slots[slot.0].size;
+        let entry = access.entry(slot).or_insert(Some(ty));
+        if *entry != Some(ty) || ty.size(func.layout) != size {
+            *entry = None;
+        }
+    }
+    // 一度も読み書きされないスロットも昇格させて取り除く
+    for slot in addrs.values() {
+        access.entry(*slot).or_insert(Some(Ty::IPtr));
+    }
+    access
+        .into_iter()
+        .filter_map(|(slot, ty)| ty.map(|ty| (slot, ty)))
+        .collect()
+}
+
+struct Renamer {
+    addrs: HashMap<Reg, SlotId>, // 昇格させるスロットのアドレスを持つレジスタ
+    phis: HashMap<Reg, SlotId>,  // 挿入した phi とそのスロット
+    stacks: HashMap<SlotId, Vec<Operand>>, // スロットの現在の値
+    replace: HashMap<Reg, Operand>, // 読み出しの結果を置き換える値
+}
+
+impl Renamer {
+    fn current(&self, slot: SlotId) -> Operand {
+        // 初期化されていない変数の値は不定なので 0 にする
+        self.stacks
+            .get(&slot)
+            .and_then(|stack| stack.last())
+            .copied()
+            .unwrap_or(Operand::Imm(0))
+    }
+
+    fn promoted(&self, addr: &Operand) -> Option<SlotId> {
+        match addr {
+            Operand::Reg(reg) => self.addrs.get(reg).copied(),
+            Operand::Imm(_) => None,
+        }
+    }
+
+    fn rename(&mut self, func: &mut Function, dom: &DomTree, block: BlockId) {
+        let mut pushed = Vec::new();
+        let insts = std::mem::take(&mut func.blocks[block.0].insts);
+        let mut kept = Vec::new();
+        for inst in insts {
+            match &inst {
+                Inst::Phi { dst, .. } if self.phis.contains_key(dst) => {
+                    let slot = self.phis[dst];
+                    self.stacks
+                        .entry(slot)
+                        .or_default()
+                        .push(Operand::Reg(*dst));
+                    pushed.push(slot);
+                }
+                Inst::LocalAddr { dst, .. } if self.addrs.contains_key(dst) => continue,
+                Inst::Load {
+                    dst,
+                    addr,
+                    ty,
+                    signed,
+                } => {
+                    if let Some(slot) = self.promoted(addr) {
+                        let val = self.current(slot);
+                        // char のスロットから読んだ値は下位8ビットを拡張したものになる
+                        if *ty == Ty::I8 {
+                            let kind = if *signed {
+                                CastKind::Sext(Ty::I8)
+                            } else {
+                                CastKind::Zext(Ty::I8)
+                            };
+                            kept.push(Inst::Cast {
+                                dst: *dst,
+                                src: val,
+                                kind,
+                            });
+                        } else {
+                            self.replace.insert(*dst, val);
+                        }
+                        continue;
+                    }
+                }
+                Inst::Store { addr, val, .. } => {
+                    if let Some(slot) = self.promoted(addr) {
+                        self.stacks.entry(slot).or_default().push(*val);
+                        pushed.push(slot);
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            kept.push(inst);
+        }
+        func.blocks[block.0].insts = kept;
+
+        let mut succs = func.blocks[block.0].term.successors();
+        succs.dedup();
+        for succ in succs {
+            let vals: Vec<_> = func.blocks[succ.0]
+                .insts
+                .iter()
+                .enumerate()
+                .filter_map(|(i, inst)| match inst {
+                    Inst::Phi { dst, .. } => {
+                        self.phis.get(dst).map(|slot| (i, self.current(*slot)))
+                    }
+                    _ => None,
+                })
+                .collect();
+            for (i, val) in vals {
+                if let Inst::Phi { incoming, .. } = &mut func.blocks[succ.0].insts[i] {
+                    incoming.push((block, val));
+                }
+            }
+        }
+
+        for child in dom.children(block).to_vec() {
+            self.rename(func, dom, child);
+        }
+        for slot in pushed {
+            self.stacks.get_mut(&slot).unwrap().pop();
+        }
+    }
+}
+
+// 置き換えの連鎖をたどる
+pub fn resolve(replace: &HashMap<Reg, Operand>, op: Operand) -> Operand {
+    let mut op = op;
+    while let Operand::Reg(reg) = op {
+        match replace.get(&reg) {
+            Some(next) => op = *next,
+            None => break,
+        }
+    }
+    op
+}
+
+// 自分自身を除いてただ一つの値しか受け取らない phi をその値に置き換える
+pub fn remove_trivial_phis(func: &mut Function) {
+    loop {
+        let mut replace = HashMap::new();
+        for block in func.blocks.iter() {
+            for inst in block.insts.iter() {
+                let Inst::Phi { dst, incoming } = inst else {
+                    continue;
+                };
+                let mut vals = incoming
+                    .iter()
+                    .map(|(_, val)| *val)
+                    .filter(|val| *val != Operand::Reg(*dst));
+                let Some(first) = vals.next() else {
+                    continue;
+                };
+                if vals.all(|val| val == first) {
+                    replace.insert(*dst, first);
+                }
+            }
+        }
+        if replace.is_empty() {
+            return;
+        }
+        for block in func.blocks.iter_mut() {
+            block
+                .insts
+                .retain(|inst| !matches!(inst, Inst::Phi { dst, .. } if replace.contains_key(dst)));
+        }
+        replace_uses(func, &replace);
+    }
+}
+
+// 仮想レジスタを使うところをすべて置き換える
+pub fn replace_uses(func: &mut Function, replace: &HashMap<Reg, Operand>) {
+    for block in func.blocks.iter_mut() {
+        for inst in block.insts.iter_mut() {
+            for op in inst.operands_mut() {
+                *op = resolve(replace, *op);
+            }
+        }
+        for op in block.term.operands_mut() {
+            *op = resolve(replace, *op);
+        }
+    }
+}
+
+// phi を先行ブロックでのコピーに置き換えて SSA 形式から戻す
+pub fn destruct(func: &mut Function) {
+    split_critical_edges(func);
+    for i in 0..func.blocks.len() {
+        let phis: Vec<_> = func.blocks[i]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Phi { dst, incoming } => Some((*dst, incoming.clone())),
+                _ => None,
+            })
+            .collect();
+        if phis.is_empty() {
+            continue;
+        }
+        func.blocks[i]
+            .insts
+            .retain(|inst| !matches!(inst, Inst::Phi { .. }));
+
+        let mut preds: Vec<BlockId> = phis[0].1.iter().map(|(pred, _)| *pred).collect();
+        preds.sort();
+        for pred in preds {
+            // phi は同時に値を受け取るので、一度一時レジスタに移してからコピーする
+            let mut temps = Vec::new();
+            for (dst, incoming) in phis.iter() {
+                let val = incoming.iter().find(|(p, _)| *p == pred).unwrap().1;
+                let temp = func.new_reg(func.reg_ty(*dst));
+                func.blocks[pred.0].insts.push(Inst::Copy {
+                    dst: temp,
+                    src: val,
+                });
+                temps.push((*dst, temp));
+            }
+            for (dst, temp) in temps {
+                func.blocks[pred.0].insts.push(Inst::Copy {
+                    dst,
+                    src: Operand::Reg(temp),
+                });
+            }
+        }
+    }
+}
+
+// 複数の後続を持つブロックから phi を持つブロックへの辺に空のブロックを挟む
+// もう一方の後続で phi の値が使われなければ、挟まずに分岐の前でコピーする
+fn split_critical_edges(func: &mut Function) {
+    for i in 0..func.blocks.len() {
+        let succs = func.blocks[i].term.successors();
+        if succs.len() < 2 || succs[0] == succs[1] {
+            continue;
+        }
+        let mut copied_in_pred = false;
+        for (j, &succ) in succs.iter().enumerate() {
+            let phis = phi_dsts(func, succ);
+            if phis.is_empty() {
+                continue;
+            }
+            if !copied_in_pred && !observes(func, BlockId(i), succ, succs[1 - j], &phis) {
+                copied_in_pred = true;
+                continue;
+            }
+            let mid = func.new_block();
+            func.blocks[mid.0].term = Terminator::Jump(succ);
+            for target in func.blocks[i].term.successors_mut() {
+                if *target == succ {
+                    *target = mid;
+                }
+            }
+            for inst in func.blocks[succ.0].insts.iter_mut() {
+                if let Inst::Phi { incoming, .. } = inst {
+                    for (pred, _) in incoming.iter_mut() {
+                        if *pred == BlockId(i) {
+                            *pred = mid;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn phi_dsts(func: &Function, block: BlockId) -> HashSet<Reg> {
+    func.blocks[block.0]
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::Phi { dst, .. } => Some(*dst),
+            _ => None,
+        })
+        .collect()
+}
+
+// pred の末尾で succ の phi に値を入れたとき、other へ進んだ先でその値が使われうるか
+fn observes(
+    func: &Function,
+    pred: BlockId,
+    succ: BlockId,
+    other: BlockId,
+    phis: &HashSet<Reg>,
+) -> bool {
+    let uses = |ops: Vec<Operand>| {
+        ops.iter()
+            .any(|op| matches!(op, Operand::Reg(reg) if phis.contains(reg)))
+    };
+    let incoming_from = |block: BlockId, from: BlockId| {
+        func.blocks[block.0]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Phi { incoming, .. } => incoming.iter().find(|(p, _)| *p == from),
+                _ => None,
+            })
+            .map(|(_, val)| *val)
+            .collect::<Vec<_>>()
+    };
+    // 分岐の条件と other の phi の入力は、コピーの後に読まれる
+    if uses(func.blocks[pred.0].term.operands()) || uses(incoming_from(other, pred)) {
+        return true;
+    }
+    // succ で定義し直されるまでに使われるか
+    let mut visited = HashSet::new();
+    let mut stack = vec![other];
+    while let Some(block) = stack.pop() {
+        if block == succ || !visited.insert(block) {
+            continue;
+        }
+        let b = &func.blocks[block.0];
+        let used = b
+            .insts
+            .iter()
+            .filter(|inst| !matches!(inst, Inst::Phi { .. }))
+            .any(|inst| uses(inst.operands()))
+            || uses(b.term.operands())
+            || b.term
+                .successors()
+                .into_iter()
+                .any(|next| uses(incoming_from(next, block)));
+        if used {
+            return true;
+        }
+        stack.extend(b.term.successors());
+    }
+    false
+}
@@ -0,0 +1,93 @@
+use crate::ir::{Block, BlockId, Function, Inst, Operand, Reg, Terminator};
+use crate::loops;
+use crate::ssa;
+use std::collections::HashMap;
+
+// 末尾の自己再帰呼び出しをループにする
+// 関数の本体の先頭に引数の phi を置き、再帰呼び出しの代わりに新しい引数を持ってそこへ飛ぶ
+pub fn tailcall(func: &mut Function) {
+    // スタック上の領域を指すポインタは呼び出し先に渡っているかもしれない
+    if !may_reuse_frame(func) {
+        return;
+    }
+    let is_tail = |func: &Function, block: &Block| {
+        matches!(tail_call(func, block), Some(Inst::Call { name, args, .. })
+            if *name == func.name && args.len() == func.params.len())
+    };
+    if !func.blocks.iter().any(|block| is_tail(func, block)) {
+        return;
+    }
+
+    // 入口のブロックの中身を新しいブロックに移し、入口からはそこへ飛ぶだけにする
+    let entry = BlockId(0);
+    let body = func.new_block();
+    func.blocks.swap(entry.0, body.0);
+    func.blocks[entry.0].term = Terminator::Jump(body);
+    for succ in func.blocks[body.0].term.successors() {
+        loops::rename_pred(func, succ, entry, body);
+    }
+
+    // 引数は本体の先頭の phi で受け取る
+    let params = func.params.clone();
+    let phis: Vec<Reg> = params
+        .iter()
+        .map(|param| func.new_reg(func.reg_ty(*param)))
+        .collect();
+    let replace: HashMap<Reg, Operand> = params
+        .iter()
+        .zip(phis.iter())
+        .map(|(param, phi)| (*param, Operand::Reg(*phi)))
+        .collect();
+    ssa::replace_uses(func, &replace);
+
+    let mut incoming: Vec<Vec<(BlockId, Operand)>> = params
+        .iter()
+        .map(|param| vec![(entry, Operand::Reg(*param))])
+        .collect();
+    let tails: Vec<BlockId> = (0..func.blocks.len())
+        .map(BlockId)
+        .filter(|block| is_tail(func, &func.blocks[block.0]))
+        .collect();
+    for block in tails {
+        let Some(Inst::Call { args, .. }) = func.blocks[block.0].insts.pop() else {
+            unreachable!();
+        };
+        for (incoming, arg) in incoming.iter_mut().zip(args) {
+            incoming.push((block, arg));
+        }
+        func.blocks[block.0].term = Terminator::Jump(body);
+    }
+    let phis = phis
+        .into_iter()
+        .zip(incoming)
+        .map(|(dst, incoming)| Inst::Phi { dst, incoming });
+    func.blocks[body.0].insts.splice(0..0, phis);
+}
+
+// 呼び出し元のスタックフレームをそのまま呼び出し先が使ってよいか
+pub fn may_reuse_frame(func: &Function) -> bool {
+    !func.blocks.iter().flat_map(|block| block.insts.iter()).any(|inst| {
+        matches!(
+            inst,
+            Inst::LocalAddr { .. } | Inst::Alloca { .. } | Inst::StackSave { .. }
+        )
+    })
+}
+
+// ブロックが関数を呼び出してその結果をそのまま返すなら、その呼び出し
+// void の関数では呼び出しの後に何もせず戻るブロックへ飛ぶこともある
+pub fn tail_call<'a>(func: &Function, block: &'a Block) -> Option<&'a Inst> {
+    let call @ Inst::Call { dst, .. } = block.insts.last()? else {
+        return None;
+    };
+    let returns = match block.term {
+        Terminator::Ret(None) => true,
+        Terminator::Ret(Some(Operand::Reg(val))) => *dst == Some(val),
+        Terminator::Jump(next) => {
+            let next = &func.blocks[next.0];
+            next.insts.is_empty() && next.term == Terminator::Ret(None)
+        }
+        _ => false,
+    };
+    returns.then_some(call)
+}
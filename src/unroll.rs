@@ -0,0 +1,255 @@
+use crate::cfg::DomTree;
+use crate::fold;
+use crate::ir::{BinOp, Block, BlockId, Function, Inst, Operand, Reg, Terminator};
+use crate::loops::{self, Loop};
+use std::collections::{HashMap, HashSet};
+
+// ループの展開 (-funroll-loops)
+// 最も内側のループの本体を複製して、一度の繰り返しで何回分かを実行する
+// 複製のそれぞれに終了の判定を残すので、回数の分からないループもそのまま展開できる
+// 回数が定数で少ないループは回数分だけ複製し、判定は定数畳み込みで消える
+
+const FACTOR: usize = 4; // 回数の分からないループを展開する数
+const MAX_FULL_UNROLL: usize = 16; // 完全に展開する回数の上限
+const MAX_UNROLLED_SIZE: usize = 200; // 展開した後のループの命令数の上限
+
+pub fn unroll(func: &mut Function) {
+    loops::insert_preheaders(func);
+    let mut done = HashSet::new();
+    loop {
+        let dom = DomTree::new(func);
+        let loops = loops::find_loops(func, &dom);
+        let Some(lp) = loops
+            .iter()
+            .find(|lp| !done.contains(&lp.header) && lp.is_innermost(&loops))
+        else {
+            return;
+        };
+        done.insert(lp.header);
+        if lp.latch().is_none() || lp.preheader(func).is_none() {
+            continue;
+        }
+        let size = lp.size(func);
+        let trips = trip_count(func, &dom, lp)
+            .filter(|(trips, _)| *trips <= MAX_FULL_UNROLL && trips * size <= MAX_UNROLLED_SIZE);
+        let count = match trips {
+            Some((trips, _)) => trips,
+            None if size * FACTOR <= MAX_UNROLLED_SIZE => FACTOR,
+            None => continue,
+        };
+        if count < 2 || !loops::form_lcssa(func, lp) {
+            continue;
+        }
+        // 抜ける向きは複製で後続が付け替えられる前の判定から求める
+        let exits_on_then = trips.map(|(_, exiting)| match func.blocks[exiting.0].term {
+            Terminator::Branch { then, .. } => !lp.contains(then),
+            _ => unreachable!(),
+        });
+        let last = unroll_loop(func, lp, count);
+        // 完全に展開したら、最後の複製の判定は必ずループを抜ける
+        if let (Some((_, exiting)), Some(exits_on_then)) = (trips, exits_on_then) {
+            let block = last[&exiting];
+            if let Terminator::Branch { cond, .. } = &mut func.blocks[block.0].term {
+                *cond = Operand::Imm(exits_on_then as i64);
+            }
+        }
+    }
+}
+
+// 定数の初期値から定数ずつ増える変数と定数の比較でループを抜けるとき、
+// ヘッダを通る回数と判定するブロック
+// 判定するブロックは毎回実行されなければならない
+fn trip_count(func: &Function, dom: &DomTree, lp: &Loop) -> Option<(usize, BlockId)> {
+    let latch = lp.latch()?;
+    let ivs = loops::induction_variables(func, lp);
+    let mut defs = HashMap::new();
+    for block in lp.blocks.iter() {
+        for inst in func.blocks[block.0].insts.iter() {
+            if let Some(dst) = inst.dst() {
+                defs.insert(dst, inst);
+            }
+        }
+    }
+    // 基本帰納変数に定数を足した値なら (帰納変数, 足す数)
+    let iv_plus = |op: Operand| -> Option<(Reg, i64)> {
+        let Operand::Reg(reg) = op else {
+            return None;
+        };
+        if ivs.contains_key(&reg) {
+            return Some((reg, 0));
+        }
+        match defs.get(&reg) {
+            Some(Inst::Bin {
+                op: BinOp::Add,
+                lhs: Operand::Reg(iv),
+                rhs: Operand::Imm(k),
+                ..
+            }) if ivs.contains_key(iv) => Some((*iv, *k)),
+            _ => None,
+        }
+    };
+
+    for block in lp.blocks.iter() {
+        let Terminator::Branch {
+            cond: Operand::Reg(cond),
+            then,
+            els,
+        } = func.blocks[block.0].term
+        else {
+            continue;
+        };
+        if lp.contains(then) == lp.contains(els) || !dom.dominates(*block, latch) {
+            continue;
+        }
+        let Some(Inst::Cmp {
+            op, ty, lhs, rhs, ..
+        }) = defs.get(&cond)
+        else {
+            continue;
+        };
+        // 帰納変数の側を左辺にそろえる
+        let (iv, k, bound, swapped) = match (iv_plus(*lhs), iv_plus(*rhs), *lhs, *rhs) {
+            (Some((iv, k)), None, _, Operand::Imm(bound)) => (iv, k, bound, false),
+            (None, Some((iv, k)), Operand::Imm(bound), _) => (iv, k, bound, true),
+            _ => continue,
+        };
+        let (Operand::Imm(init), step) = ivs[&iv] else {
+            continue;
+        };
+        for t in 0..=MAX_FULL_UNROLL as i64 {
+            let val = init.wrapping_add(step.wrapping_mul(t)).wrapping_add(k);
+            let (a, b) = if swapped { (bound, val) } else { (val, bound) };
+            let stays = fold::eval_cmp(*op, ty.size(func.layout), a, b) == lp.contains(then);
+            if !stays {
+                return Some((t as usize + 1, *block));
+            }
+        }
+    }
+    None
+}
+
+// ループの本体を count 回分に増やし、最後の複製のブロックの対応を返す
+// 複製の末尾は次の複製のヘッダに進み、最後の複製の末尾が元のヘッダに戻る
+fn unroll_loop(func: &mut Function, lp: &Loop, count: usize) -> HashMap<BlockId, BlockId> {
+    let header = lp.header;
+    let latch = lp.latch().unwrap();
+    let exits = lp.exits(func);
+    // ヘッダの phi が末尾から受け取る値
+    let carried: Vec<(Reg, Operand)> = func.blocks[header.0]
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::Phi { dst, incoming } => incoming
+                .iter()
+                .find(|(pred, _)| *pred == latch)
+                .map(|(_, val)| (*dst, *val)),
+            _ => None,
+        })
+        .collect();
+
+    // 元のブロックは末尾の分岐先を書き換えるので、書き換える前を複製する
+    let originals: HashMap<BlockId, Block> = lp
+        .blocks
+        .iter()
+        .map(|block| (*block, func.blocks[block.0].clone()))
+        .collect();
+    let mut prev_map: HashMap<Reg, Operand> = HashMap::new();
+    let mut prev_blocks: HashMap<BlockId, BlockId> = HashMap::new();
+    let mut prev_latch = latch;
+    for _ in 1..count {
+        let blocks: HashMap<BlockId, BlockId> = lp
+            .blocks
+            .iter()
+            .map(|block| (*block, func.new_block()))
+            .collect();
+        // 複製のヘッダの phi は、一つ前の複製の末尾の値になる
+        let mut map: HashMap<Reg, Operand> = carried
+            .iter()
+            .map(|(dst, val)| (*dst, loops::remap(&prev_map, *val)))
+            .collect();
+        for block in lp.blocks.iter() {
+            for inst in originals[block].insts.iter() {
+                let Some(dst) = inst.dst() else {
+                    continue;
+                };
+                if *block != header || !matches!(inst, Inst::Phi { .. }) {
+                    let reg = func.new_reg(func.reg_ty(dst));
+                    map.insert(dst, Operand::Reg(reg));
+                }
+            }
+        }
+
+        for block in lp.blocks.iter() {
+            let mut insts = Vec::new();
+            for inst in originals[block].insts.iter() {
+                if *block == header && matches!(inst, Inst::Phi { .. }) {
+                    continue;
+                }
+                let mut inst = inst.clone();
+                for op in inst.operands_mut() {
+                    *op = loops::remap(&map, *op);
+                }
+                if let Some(dst) = inst.dst_mut() {
+                    let Operand::Reg(reg) = map[dst] else {
+                        unreachable!();
+                    };
+                    *dst = reg;
+                }
+                if let Inst::Phi { incoming, .. } = &mut inst {
+                    for (pred, _) in incoming.iter_mut() {
+                        *pred = blocks[pred];
+                    }
+                }
+                insts.push(inst);
+            }
+            let mut term = originals[block].term.clone();
+            for op in term.operands_mut() {
+                *op = loops::remap(&map, *op);
+            }
+            for succ in term.successors_mut() {
+                if *succ != header && lp.contains(*succ) {
+                    *succ = blocks[succ];
+                }
+            }
+            let copy = &mut func.blocks[blocks[block].0];
+            copy.insts = insts;
+            copy.term = term;
+        }
+
+        for succ in func.blocks[prev_latch.0].term.successors_mut() {
+            if *succ == header {
+                *succ = blocks[&header];
+            }
+        }
+        // 出口へは複製したブロックからも来る
+        for exit in exits.iter() {
+            for inst in func.blocks[exit.0].insts.iter_mut() {
+                let Inst::Phi { incoming, .. } = inst else {
+                    continue;
+                };
+                let copied: Vec<(BlockId, Operand)> = incoming
+                    .iter()
+                    .filter(|(pred, _)| lp.contains(*pred))
+                    .map(|(pred, val)| (blocks[pred], loops::remap(&map, *val)))
+                    .collect();
+                incoming.extend(copied);
+            }
+        }
+        prev_map = map;
+        prev_latch = blocks[&latch];
+        prev_blocks = blocks;
+    }
+
+    for inst in func.blocks[header.0].insts.iter_mut() {
+        let Inst::Phi { incoming, .. } = inst else {
+            continue;
+        };
+        for (pred, val) in incoming.iter_mut() {
+            if *pred == latch {
+                *pred = prev_latch;
+                *val = loops::remap(&prev_map, *val);
+            }
+        }
+    }
+    prev_blocks
+}
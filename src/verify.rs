@@ -0,0 +1,139 @@
+use crate::cfg::{self, DomTree};
+use crate::ir::{BlockId, Function, Inst, Operand, Reg, Terminator};
+
+// 中間表現が正しい形をしているかを検査する
+// 仮想レジスタはただ一度だけ定義され、使う位置は定義に支配されていなければならない
+pub fn verify(func: &Function) -> Result<(), String> {
+    check(func).map_err(|e| format!("関数 '{}': {}", func.name, e))
+}
+
+// 定義の位置 (引数は入口のブロックの先頭より前)
+#[derive(Clone, Copy)]
+struct Def {
+    block: BlockId,
+    pos: Option<usize>,
+}
+
+fn check(func: &Function) -> Result<(), String> {
+    if func.blocks.is_empty() {
+        return Err("基本ブロックがありません".to_string());
+    }
+    let reg_count = func.regs.len();
+    let check_reg = |reg: Reg| {
+        if reg.0 >= reg_count {
+            return Err(format!("{} は存在しない仮想レジスタです", reg));
+        }
+        Ok(())
+    };
+
+    let mut defs: Vec<Option<Def>> = vec![None; reg_count];
+    for param in func.params.iter() {
+        check_reg(*param)?;
+        defs[param.0] = Some(Def {
+            block: BlockId(0),
+            pos: None,
+        });
+    }
+    for (i, block) in func.blocks.iter().enumerate() {
+        let mut in_phis = true;
+        for (pos, inst) in block.insts.iter().enumerate() {
+            if matches!(inst, Inst::Phi { .. }) {
+                if !in_phis {
+                    return Err(format!("bb{}: phi がブロックの先頭にありません", i));
+                }
+            } else {
+                in_phis = false;
+            }
+            if let Inst::LocalAddr { slot, .. } = inst {
+                if slot.0 >= func.slots.len() {
+                    return Err(format!("bb{}: {} は存在しないスロットです", i, slot));
+                }
+            }
+            let Some(dst) = inst.dst() else {
+                continue;
+            };
+            check_reg(dst)?;
+            if defs[dst.0].is_some() {
+                return Err(format!("{} が複数回定義されています", dst));
+            }
+            defs[dst.0] = Some(Def {
+                block: BlockId(i),
+                pos: Some(pos),
+            });
+        }
+        for succ in block.term.successors() {
+            if succ.0 >= func.blocks.len() {
+                return Err(format!(
+                    "bb{}: 存在しないブロック {} に分岐しています",
+                    i, succ
+                ));
+            }
+        }
+        match (&block.term, func.ret) {
+            (Terminator::Ret(Some(_)), None) => {
+                return Err(format!("bb{}: void 関数が値を返しています", i));
+            }
+            (Terminator::Ret(None), Some(_)) => {
+                return Err(format!("bb{}: 戻り値がありません", i));
+            }
+            _ => {}
+        }
+    }
+
+    let dom = DomTree::new(func);
+    let preds = cfg::predecessors(func);
+    // 使う位置が定義に支配されているか
+    let check_use = |op: Operand, block: BlockId, pos: usize| -> Result<(), String> {
+        let Operand::Reg(reg) = op else {
+            return Ok(());
+        };
+        check_reg(reg)?;
+        let Some(def) = defs[reg.0] else {
+            return Err(format!("{}: {} が定義されていません", block, reg));
+        };
+        let dominated = if def.block == block {
+            def.pos.is_none_or(|def_pos| def_pos < pos)
+        } else {
+            dom.dominates(def.block, block)
+        };
+        if !dominated {
+            return Err(format!("{}: {} が定義より前で使われています", block, reg));
+        }
+        Ok(())
+    };
+
+    for (i, block) in func.blocks.iter().enumerate() {
+        let id = BlockId(i);
+        if !dom.is_reachable(id) {
+            continue;
+        }
+        for (pos, inst) in block.insts.iter().enumerate() {
+            if let Inst::Phi { incoming, .. } = inst {
+                // phi の値は対応する先行ブロックの終わりで使われる
+                let mut from: Vec<BlockId> = incoming.iter().map(|(pred, _)| *pred).collect();
+                from.sort();
+                let mut expected: Vec<BlockId> = preds[i]
+                    .iter()
+                    .copied()
+                    .filter(|pred| dom.is_reachable(*pred))
+                    .collect();
+                expected.sort();
+                if from != expected {
+                    return Err(format!("{}: phi の入力が先行ブロックと一致しません", id));
+                }
+                for (pred, val) in incoming.iter() {
+                    let end = func.blocks[pred.0].insts.len();
+                    check_use(*val, *pred, end)?;
+                }
+                continue;
+            }
+            for op in inst.operands() {
+                check_use(op, id, pos)?;
+            }
+        }
+        for op in block.term.operands() {
+            check_use(op, id, block.insts.len())?;
+        }
+    }
+    Ok(())
+}
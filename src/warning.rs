@@ -0,0 +1,149 @@
+use crate::error;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Warning {
+    UnusedVariable,
+    UnusedParameter,
+    Uninitialized,
+    ReturnType,
+    ImplicitFunctionDeclaration,
+    Parentheses,
+    IntConversion,
+    UnreachableCode,
+}
+
+const ALL_WARNINGS: [Warning; 8] = [
+    Warning::UnusedVariable,
+    Warning::UnusedParameter,
+    Warning::Uninitialized,
+    Warning::ReturnType,
+    Warning::ImplicitFunctionDeclaration,
+    Warning::Parentheses,
+    Warning::IntConversion,
+    Warning::UnreachableCode,
+];
+
+impl Warning {
+    pub fn name(self) -> &'static str {
+        match self {
+            Warning::UnusedVariable => "unused-variable",
+            Warning::UnusedParameter => "unused-parameter",
+            Warning::Uninitialized => "uninitialized",
+            Warning::ReturnType => "return-type",
+            Warning::ImplicitFunctionDeclaration => "implicit-function-declaration",
+            Warning::Parentheses => "parentheses",
+            Warning::IntConversion => "int-conversion",
+            Warning::UnreachableCode => "unreachable-code",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Warning> {
+        ALL_WARNINGS.into_iter().find(|w| w.name() == name)
+    }
+
+    // オプションなしで有効な警告
+    fn enabled_by_default(self) -> bool {
+        matches!(
+            self,
+            Warning::ImplicitFunctionDeclaration | Warning::IntConversion
+        )
+    }
+
+    // -Wall で有効になる警告 (unreachable-code は個別に指定したときだけ有効)
+    fn enabled_by_wall(self) -> bool {
+        !matches!(self, Warning::UnusedParameter | Warning::UnreachableCode)
+    }
+
+    // -Wextra で有効になる警告
+    fn enabled_by_wextra(self) -> bool {
+        matches!(self, Warning::UnusedParameter)
+    }
+}
+
+// 警告に関するコマンドラインオプション
+// 個別の -W<name> / -Wno-<name> は -Wall / -Wextra よりも優先される
+#[derive(Debug, Default)]
+pub struct Config {
+    wall: bool,
+    wextra: bool,
+    werror: bool,
+    explicit: HashMap<Warning, bool>,
+}
+
+impl Config {
+    // -W で始まるオプションを解釈する
+    pub fn parse_option(&mut self, opt: &str) -> Result<(), String> {
+        let name = &opt[2..];
+        match name {
+            "all" => self.wall = true,
+            "extra" => self.wextra = true,
+            "error" => self.werror = true,
+            "no-error" => self.werror = false,
+            _ => {
+                let (name, enable) = match name.strip_prefix("no-") {
+                    Some(name) => (name, false),
+                    None => (name, true),
+                };
+                let Some(warning) = Warning::from_name(name) else {
+                    return Err(format!("不明な警告オプションです: {}", opt));
+                };
+                self.explicit.insert(warning, enable);
+            }
+        }
+        Ok(())
+    }
+
+    fn enabled(&self) -> HashSet<Warning> {
+        ALL_WARNINGS
+            .into_iter()
+            .filter(|&w| match self.explicit.get(&w) {
+                Some(&enable) => enable,
+                None => {
+                    w.enabled_by_default()
+                        || (self.wall && w.enabled_by_wall())
+                        || (self.wextra && w.enabled_by_wextra())
+                }
+            })
+            .collect()
+    }
+}
+
+struct State {
+    enabled: HashSet<Warning>,
+    werror: bool,
+}
+
+static STATE: OnceLock<State> = OnceLock::new();
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init(config: &Config) {
+    let state = State {
+        enabled: config.enabled(),
+        werror: config.werror,
+    };
+    let _ = STATE.set(state);
+}
+
+pub fn warn(warning: Warning, line: usize, message: &str) {
+    let Some(state) = STATE.get() else {
+        return;
+    };
+    if !state.enabled.contains(&warning) {
+        return;
+    }
+    eprintln!("{}行目: 警告: {} [-W{}]", line, message, warning.name());
+    COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+// -Werror のときに警告が出ていればコンパイルを失敗させる
+pub fn check_werror() {
+    let Some(state) = STATE.get() else {
+        return;
+    };
+    if state.werror && COUNT.load(Ordering::Relaxed) > 0 {
+        error::error("警告がエラーとして扱われました (-Werror)");
+    }
+}
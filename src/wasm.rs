@@ -0,0 +1,513 @@
+use std::fmt;
+
+// WebAssembly のモジュール
+// コード生成はモジュールをこの形で組み立て、テキスト形式 (.wat) かバイナリ形式 (.wasm) で出力する
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValType {
+    I32,
+}
+
+impl ValType {
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub result: Option<ValType>,
+}
+
+// 引数をスタックから取って結果を積む命令 (メモリの読み書きを含む)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    I32Eq,
+    I32Ne,
+    I32LtS,
+    I32LtU,
+    I32GtS,
+    I32GtU,
+    I32LeS,
+    I32LeU,
+    I32GeS,
+    I32GeU,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32DivS,
+    I32DivU,
+    I32And,
+    I32Or,
+    I32Shl,
+    I32ShrS,
+    I32ShrU,
+    I32Extend8S,
+    I32Load,
+    I32Load8S,
+    I32Load8U,
+    I32Store,
+    I32Store8,
+}
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::I32Eq => "i32.eq",
+            Op::I32Ne => "i32.ne",
+            Op::I32LtS => "i32.lt_s",
+            Op::I32LtU => "i32.lt_u",
+            Op::I32GtS => "i32.gt_s",
+            Op::I32GtU => "i32.gt_u",
+            Op::I32LeS => "i32.le_s",
+            Op::I32LeU => "i32.le_u",
+            Op::I32GeS => "i32.ge_s",
+            Op::I32GeU => "i32.ge_u",
+            Op::I32Add => "i32.add",
+            Op::I32Sub => "i32.sub",
+            Op::I32Mul => "i32.mul",
+            Op::I32DivS => "i32.div_s",
+            Op::I32DivU => "i32.div_u",
+            Op::I32And => "i32.and",
+            Op::I32Or => "i32.or",
+            Op::I32Shl => "i32.shl",
+            Op::I32ShrS => "i32.shr_s",
+            Op::I32ShrU => "i32.shr_u",
+            Op::I32Extend8S => "i32.extend8_s",
+            Op::I32Load => "i32.load",
+            Op::I32Load8S => "i32.load8_s",
+            Op::I32Load8U => "i32.load8_u",
+            Op::I32Store => "i32.store",
+            Op::I32Store8 => "i32.store8",
+        }
+    }
+
+    fn opcode(self) -> u8 {
+        match self {
+            Op::I32Eq => 0x46,
+            Op::I32Ne => 0x47,
+            Op::I32LtS => 0x48,
+            Op::I32LtU => 0x49,
+            Op::I32GtS => 0x4a,
+            Op::I32GtU => 0x4b,
+            Op::I32LeS => 0x4c,
+            Op::I32LeU => 0x4d,
+            Op::I32GeS => 0x4e,
+            Op::I32GeU => 0x4f,
+            Op::I32Add => 0x6a,
+            Op::I32Sub => 0x6b,
+            Op::I32Mul => 0x6c,
+            Op::I32DivS => 0x6d,
+            Op::I32DivU => 0x6e,
+            Op::I32And => 0x71,
+            Op::I32Or => 0x72,
+            Op::I32Shl => 0x74,
+            Op::I32ShrS => 0x75,
+            Op::I32ShrU => 0x76,
+            Op::I32Extend8S => 0xc0,
+            Op::I32Load => 0x28,
+            Op::I32Load8S => 0x2c,
+            Op::I32Load8U => 0x2d,
+            Op::I32Store => 0x36,
+            Op::I32Store8 => 0x3a,
+        }
+    }
+
+    // メモリを読み書きする命令なら、アクセスするバイト数の log2 (アラインメントの指定に使う)
+    fn align(self) -> Option<u32> {
+        match self {
+            Op::I32Load8S | Op::I32Load8U | Op::I32Store8 => Some(0),
+            Op::I32Load | Op::I32Store => Some(2),
+            _ => None,
+        }
+    }
+}
+
+// 構造化された制御命令のブロックは値を残さない
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instr {
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32), // 内側から数えた何番目のブロックから抜けるか
+    Return,
+    Unreachable,
+    Drop,
+    Call(u32),       // インポートを先頭に並べた関数の番号
+    ReturnCall(u32), // 末尾呼び出し (tail call の拡張)
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32Const(i32),
+    Op(Op),
+}
+
+// 関数のインポート (モジュール名は env)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    pub name: String,
+    pub id: String, // テキスト形式での名前 (同じ名前を違う型でインポートすることがある)
+    pub ty: FuncType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Func {
+    pub name: String,
+    pub ty: FuncType,
+    pub export: bool,
+    pub locals: Vec<ValType>,     // 引数以外のローカル変数
+    pub local_names: Vec<String>, // 引数を含むローカル変数のテキスト形式での名前
+    pub body: Vec<Instr>,
+}
+
+// グローバル変数はスタックポインタだけ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub imports: Vec<Import>,
+    pub funcs: Vec<Func>,
+    pub memory_pages: u32,
+    pub stack_pointer: i32,        // スタックポインタの初期値
+    pub data: Vec<(u32, Vec<u8>)>, // (アドレス, 内容)
+}
+
+const STACK_POINTER: &str = "__stack_pointer";
+
+impl Module {
+    fn func_id(&self, index: u32) -> &str {
+        let index = index as usize;
+        match self.imports.get(index) {
+            Some(import) => &import.id,
+            None => &self.funcs[index - self.imports.len()].name,
+        }
+    }
+
+    // バイナリ形式に符号化する
+    pub fn encode(&self) -> Vec<u8> {
+        let mut types = Vec::new();
+        let import_types: Vec<u32> = self
+            .imports
+            .iter()
+            .map(|import| type_index(&mut types, &import.ty))
+            .collect();
+        let func_types: Vec<u32> = self
+            .funcs
+            .iter()
+            .map(|func| type_index(&mut types, &func.ty))
+            .collect();
+
+        let mut out = b"\0asm".to_vec();
+        out.extend(1u32.to_le_bytes());
+
+        // type
+        let mut sec = Vec::new();
+        uleb(&mut sec, types.len() as u64);
+        for ty in types.iter() {
+            sec.push(0x60);
+            uleb(&mut sec, ty.params.len() as u64);
+            sec.extend(ty.params.iter().map(|param| param.code()));
+            uleb(&mut sec, ty.result.iter().count() as u64);
+            sec.extend(ty.result.iter().map(|result| result.code()));
+        }
+        section(&mut out, 1, sec);
+
+        // import
+        let mut sec = Vec::new();
+        uleb(&mut sec, self.imports.len() as u64);
+        for (import, ty) in self.imports.iter().zip(import_types) {
+            name(&mut sec, "env");
+            name(&mut sec, &import.name);
+            sec.push(0x00);
+            uleb(&mut sec, ty as u64);
+        }
+        section(&mut out, 2, sec);
+
+        // function
+        let mut sec = Vec::new();
+        uleb(&mut sec, func_types.len() as u64);
+        for ty in func_types {
+            uleb(&mut sec, ty as u64);
+        }
+        section(&mut out, 3, sec);
+
+        // memory
+        let mut sec = vec![1, 0x00];
+        uleb(&mut sec, self.memory_pages as u64);
+        section(&mut out, 5, sec);
+
+        // global
+        let mut sec = vec![1, ValType::I32.code(), 0x01, 0x41];
+        sleb(&mut sec, self.stack_pointer as i64);
+        sec.push(0x0b);
+        section(&mut out, 6, sec);
+
+        // export
+        let mut sec = Vec::new();
+        let exports: Vec<(usize, &Func)> = self
+            .funcs
+            .iter()
+            .enumerate()
+            .filter(|(_, func)| func.export)
+            .collect();
+        uleb(&mut sec, exports.len() as u64 + 1);
+        name(&mut sec, "memory");
+        sec.extend([0x02, 0x00]);
+        for (i, func) in exports {
+            name(&mut sec, &func.name);
+            sec.push(0x00);
+            uleb(&mut sec, (self.imports.len() + i) as u64);
+        }
+        section(&mut out, 7, sec);
+
+        // code
+        let mut sec = Vec::new();
+        uleb(&mut sec, self.funcs.len() as u64);
+        for func in self.funcs.iter() {
+            let mut code = Vec::new();
+            // 同じ型のローカル変数が続くところをまとめる
+            let mut runs: Vec<(u32, ValType)> = Vec::new();
+            for local in func.locals.iter() {
+                match runs.last_mut() {
+                    Some((count, ty)) if ty == local => *count += 1,
+                    _ => runs.push((1, *local)),
+                }
+            }
+            uleb(&mut code, runs.len() as u64);
+            for (count, ty) in runs {
+                uleb(&mut code, count as u64);
+                code.push(ty.code());
+            }
+            for instr in func.body.iter() {
+                instr.encode(&mut code);
+            }
+            code.push(0x0b);
+            uleb(&mut sec, code.len() as u64);
+            sec.extend(code);
+        }
+        section(&mut out, 10, sec);
+
+        // data
+        let mut sec = Vec::new();
+        uleb(&mut sec, self.data.len() as u64);
+        for (addr, bytes) in self.data.iter() {
+            sec.extend([0x00, 0x41]);
+            sleb(&mut sec, *addr as i64);
+            sec.push(0x0b);
+            uleb(&mut sec, bytes.len() as u64);
+            sec.extend(bytes);
+        }
+        section(&mut out, 11, sec);
+
+        out
+    }
+}
+
+impl Instr {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Instr::Block => out.extend([0x02, 0x40]),
+            Instr::Loop => out.extend([0x03, 0x40]),
+            Instr::If => out.extend([0x04, 0x40]),
+            Instr::Else => out.push(0x05),
+            Instr::End => out.push(0x0b),
+            Instr::Br(depth) => {
+                out.push(0x0c);
+                uleb(out, *depth as u64);
+            }
+            Instr::Return => out.push(0x0f),
+            Instr::Unreachable => out.push(0x00),
+            Instr::Drop => out.push(0x1a),
+            Instr::Call(index) | Instr::ReturnCall(index) => {
+                out.push(if matches!(self, Instr::Call(_)) {
+                    0x10
+                } else {
+                    0x12
+                });
+                uleb(out, *index as u64);
+            }
+            Instr::LocalGet(index) | Instr::LocalSet(index) => {
+                out.push(if matches!(self, Instr::LocalGet(_)) {
+                    0x20
+                } else {
+                    0x21
+                });
+                uleb(out, *index as u64);
+            }
+            Instr::GlobalGet(index) | Instr::GlobalSet(index) => {
+                out.push(if matches!(self, Instr::GlobalGet(_)) {
+                    0x23
+                } else {
+                    0x24
+                });
+                uleb(out, *index as u64);
+            }
+            Instr::I32Const(val) => {
+                out.push(0x41);
+                sleb(out, *val as i64);
+            }
+            Instr::Op(op) => {
+                out.push(op.opcode());
+                if let Some(align) = op.align() {
+                    uleb(out, align as u64);
+                    uleb(out, 0);
+                }
+            }
+        }
+    }
+}
+
+// 同じ型は一つにまとめて番号を振る
+fn type_index(types: &mut Vec<FuncType>, ty: &FuncType) -> u32 {
+    match types.iter().position(|other| other == ty) {
+        Some(i) => i as u32,
+        None => {
+            types.push(ty.clone());
+            types.len() as u32 - 1
+        }
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut val: i64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        // 残りが符号だけになったら終わり
+        if (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: Vec<u8>) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend(contents);
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValType::I32 => write!(f, "i32"),
+        }
+    }
+}
+
+impl fmt::Display for FuncType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for param in self.params.iter() {
+            write!(f, " (param {})", param)?;
+        }
+        if let Some(result) = self.result {
+            write!(f, " (result {})", result)?;
+        }
+        Ok(())
+    }
+}
+
+// テキスト形式で出力する
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "(module")?;
+        for import in self.imports.iter() {
+            writeln!(
+                f,
+                "  (import \"env\" \"{}\" (func ${}{}))",
+                import.name, import.id, import.ty
+            )?;
+        }
+        writeln!(f, "  (memory (export \"memory\") {})", self.memory_pages)?;
+        writeln!(
+            f,
+            "  (global ${} (mut i32) (i32.const {}))",
+            STACK_POINTER, self.stack_pointer
+        )?;
+        for (addr, bytes) in self.data.iter() {
+            write!(f, "  (data (i32.const {}) \"", addr)?;
+            for byte in bytes.iter() {
+                match byte {
+                    b'"' | b'\\' => write!(f, "\\{}", *byte as char)?,
+                    0x20..=0x7e => write!(f, "{}", *byte as char)?,
+                    _ => write!(f, "\\{:02x}", byte)?,
+                }
+            }
+            writeln!(f, "\")")?;
+        }
+        for func in self.funcs.iter() {
+            write!(f, "  (func ${}", func.name)?;
+            if func.export {
+                write!(f, " (export \"{}\")", func.name)?;
+            }
+            for (param, name) in func.ty.params.iter().zip(func.local_names.iter()) {
+                write!(f, " (param ${} {})", name, param)?;
+            }
+            if let Some(result) = func.ty.result {
+                write!(f, " (result {})", result)?;
+            }
+            writeln!(f)?;
+            let names = &func.local_names[func.ty.params.len()..];
+            for (local, name) in func.locals.iter().zip(names) {
+                writeln!(f, "    (local ${} {})", name, local)?;
+            }
+            let mut depth = 2;
+            for instr in func.body.iter() {
+                if matches!(instr, Instr::Else | Instr::End) {
+                    depth -= 1;
+                }
+                write!(f, "{}", "  ".repeat(depth))?;
+                match instr {
+                    Instr::Block => write!(f, "block")?,
+                    Instr::Loop => write!(f, "loop")?,
+                    Instr::If => write!(f, "if")?,
+                    Instr::Else => write!(f, "else")?,
+                    Instr::End => write!(f, "end")?,
+                    Instr::Br(label) => write!(f, "br {}", label)?,
+                    Instr::Return => write!(f, "return")?,
+                    Instr::Unreachable => write!(f, "unreachable")?,
+                    Instr::Drop => write!(f, "drop")?,
+                    Instr::Call(index) => write!(f, "call ${}", self.func_id(*index))?,
+                    Instr::ReturnCall(index) => write!(f, "return_call ${}", self.func_id(*index))?,
+                    Instr::LocalGet(index) => {
+                        write!(f, "local.get ${}", func.local_names[*index as usize])?
+                    }
+                    Instr::LocalSet(index) => {
+                        write!(f, "local.set ${}", func.local_names[*index as usize])?
+                    }
+                    Instr::GlobalGet(_) => write!(f, "global.get ${}", STACK_POINTER)?,
+                    Instr::GlobalSet(_) => write!(f, "global.set ${}", STACK_POINTER)?,
+                    Instr::I32Const(val) => write!(f, "i32.const {}", val)?,
+                    Instr::Op(op) => write!(f, "{}", op.name())?,
+                }
+                writeln!(f)?;
+                if matches!(instr, Instr::Block | Instr::Loop | Instr::If | Instr::Else) {
+                    depth += 1;
+                }
+            }
+            writeln!(f, "  )")?;
+        }
+        writeln!(f, ")")
+    }
+}
@@ -0,0 +1,605 @@
+use crate::cfg::{self, DomTree};
+use crate::ir::{
+    self, BinOp, BlockId, CastKind, CmpOp, Function, Inst, Operand, Reg, Terminator, Ty,
+};
+use crate::options::Options;
+use crate::tailcall;
+use crate::wasm::{Func, FuncType, Import, Instr, Module, Op, ValType};
+use std::collections::HashMap;
+use std::io::Write;
+
+// 中間表現から WebAssembly のモジュールを作る
+// 仮想レジスタは wasm のローカル変数に、アドレスをとるローカル変数とグローバル変数は
+// 線形メモリに置く。ポインタは 4 バイト (ILP32) で、線形メモリの i32 のアドレスをそのまま使う
+// 制御フローは支配木をたどって block / loop / if の入れ子に組み直す
+// (Ramsey, "Beyond Relooper: Recursive Translation of Unstructured Control Flow to Structured Control Flow")
+
+const DATA_BASE: usize = 16; // 0 番地はヌルポインタとして使わない
+const STACK_SIZE: usize = 1 << 20;
+const PAGE_SIZE: usize = 1 << 16;
+const SP: u32 = 0; // スタックポインタのグローバル変数
+
+// 値はポインタも含めてすべて i32 で扱う
+fn val_type(_: Ty) -> ValType {
+    ValType::I32
+}
+
+pub fn gen(module: &ir::Module, opts: &Options) {
+    let module = build(module, opts.optimize_sibling_calls);
+    if opts.emit_wasm {
+        std::io::stdout()
+            .write_all(&module.encode())
+            .expect("出力に失敗しました");
+    } else {
+        print!("{}", module);
+    }
+}
+
+fn build(module: &ir::Module, sibling_calls: bool) -> Module {
+    // 文字列リテラルとグローバル変数を先頭から並べ、その上をスタックにする
+    let mut addrs = HashMap::new();
+    let mut data = Vec::new();
+    let mut addr = DATA_BASE;
+    for (i, lit) in module.strings.iter().enumerate() {
+        addrs.insert(format!(".LC{}", i), addr);
+        let mut bytes = lit.clone();
+        bytes.push(0);
+        data.push((addr as u32, bytes));
+        addr += lit.len() + 1;
+    }
+    // 領域は 0 で初期化されている
+    for global in module.globals.iter() {
+        addr = addr.next_multiple_of(8);
+        addrs.insert(global.name.clone(), addr);
+        addr += global.size;
+    }
+    let top = (addr.next_multiple_of(16) + STACK_SIZE).next_multiple_of(PAGE_SIZE);
+
+    // 定義されていない関数はインポートする。型は呼び出しの引数と結果から決める
+    let mut signatures: HashMap<&str, FuncType> = HashMap::new();
+    for func in module.functions.iter() {
+        let ty = FuncType {
+            params: func
+                .params
+                .iter()
+                .map(|p| val_type(func.reg_ty(*p)))
+                .collect(),
+            result: func.ret.map(val_type),
+        };
+        signatures.insert(&func.name, ty);
+    }
+    let mut imports: Vec<Import> = Vec::new();
+    for func in module.functions.iter() {
+        for inst in func.blocks.iter().flat_map(|block| block.insts.iter()) {
+            let Inst::Call { dst, name, args } = inst else {
+                continue;
+            };
+            if signatures.contains_key(name.as_str()) {
+                continue;
+            }
+            let ty = FuncType {
+                params: args.iter().map(|arg| operand_type(func, *arg)).collect(),
+                result: dst.map(|dst| val_type(func.reg_ty(dst))),
+            };
+            if imports
+                .iter()
+                .any(|import| import.name == *name && import.ty == ty)
+            {
+                continue;
+            }
+            let count = imports.iter().filter(|import| import.name == *name).count();
+            let id = match count {
+                0 => name.clone(),
+                _ => format!("{}.{}", name, count),
+            };
+            imports.push(Import {
+                name: name.clone(),
+                id,
+                ty,
+            });
+        }
+    }
+
+    let mut indices = HashMap::new();
+    for (i, func) in module.functions.iter().enumerate() {
+        indices.insert(func.name.clone(), (imports.len() + i) as u32);
+    }
+    let funcs = module
+        .functions
+        .iter()
+        .map(|func| {
+            let ty = signatures[func.name.as_str()].clone();
+            FuncGen::new(func, &addrs, &imports, &indices, &signatures, sibling_calls).function(ty)
+        })
+        .collect();
+
+    Module {
+        imports,
+        funcs,
+        memory_pages: (top / PAGE_SIZE) as u32,
+        stack_pointer: top as i32,
+        data,
+    }
+}
+
+// 即値は int として渡す
+fn operand_type(func: &Function, op: Operand) -> ValType {
+    match op {
+        Operand::Reg(reg) => val_type(func.reg_ty(reg)),
+        Operand::Imm(_) => ValType::I32,
+    }
+}
+
+// 分岐で抜ける先
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    IfThenElse,     // 抜ける先にはならない
+    Loop(BlockId),  // 先頭に戻る
+    Block(BlockId), // 抜けたところにそのブロックのコードを置く
+}
+
+struct FuncGen<'a> {
+    func: &'a Function,
+    addrs: &'a HashMap<String, usize>,
+    imports: &'a [Import],
+    indices: &'a HashMap<String, u32>,
+    signatures: &'a HashMap<&'a str, FuncType>,
+    dom: DomTree,
+    rpo_index: Vec<usize>,
+    forward_preds: Vec<usize>, // 後ろ向きでない辺で入ってくる数
+    loop_headers: Vec<bool>,
+    locals: Vec<Option<u32>>, // 仮想レジスタのローカル変数の番号 (使われないものは None)
+    fp: u32,                  // フレームの先頭のアドレスを置くローカル変数
+    slot_offsets: Vec<usize>,
+    frame_size: usize,
+    has_frame: bool,
+    sibling_calls: bool, // 末尾呼び出しを return_call にする
+    labels: Vec<Label>,
+    body: Vec<Instr>,
+}
+
+impl<'a> FuncGen<'a> {
+    fn new(
+        func: &'a Function,
+        addrs: &'a HashMap<String, usize>,
+        imports: &'a [Import],
+        indices: &'a HashMap<String, u32>,
+        signatures: &'a HashMap<&'a str, FuncType>,
+        sibling_calls: bool,
+    ) -> Self {
+        let n = func.blocks.len();
+        let rpo = cfg::reverse_postorder(func);
+        let mut rpo_index = vec![usize::MAX; n];
+        for (i, block) in rpo.iter().enumerate() {
+            rpo_index[block.0] = i;
+        }
+        let mut forward_preds = vec![0; n];
+        let mut loop_headers = vec![false; n];
+        for block in rpo.iter() {
+            for succ in func.blocks[block.0].term.successors() {
+                if rpo_index[succ.0] > rpo_index[block.0] {
+                    forward_preds[succ.0] += 1;
+                } else {
+                    loop_headers[succ.0] = true;
+                }
+            }
+        }
+
+        // 引数を先頭に、残りの使われている仮想レジスタを番号の順に並べる
+        let mut used = vec![false; func.regs.len()];
+        for block in func.blocks.iter() {
+            for inst in block.insts.iter() {
+                if let Some(dst) = inst.dst() {
+                    used[dst.0] = true;
+                }
+                for op in inst.operands() {
+                    if let Operand::Reg(reg) = op {
+                        used[reg.0] = true;
+                    }
+                }
+            }
+            for op in block.term.operands() {
+                if let Operand::Reg(reg) = op {
+                    used[reg.0] = true;
+                }
+            }
+        }
+        let mut locals = vec![None; func.regs.len()];
+        for (i, param) in func.params.iter().enumerate() {
+            locals[param.0] = Some(i as u32);
+        }
+        let mut next = func.params.len() as u32;
+        for (local, used) in locals.iter_mut().zip(used) {
+            if local.is_none() && used {
+                *local = Some(next);
+                next += 1;
+            }
+        }
+
+        let mut offset: usize = 0;
+        let mut slot_offsets = Vec::new();
+        for slot in func.slots.iter() {
+            offset = offset.next_multiple_of(slot.align.max(1));
+            slot_offsets.push(offset);
+            offset += slot.size;
+        }
+        let uses_stack = func.blocks.iter().flat_map(|b| b.insts.iter()).any(|inst| {
+            matches!(
+                inst,
+                Inst::Alloca { .. } | Inst::StackSave { .. } | Inst::StackRestore { .. }
+            )
+        });
+
+        FuncGen {
+            func,
+            addrs,
+            imports,
+            indices,
+            signatures,
+            dom: DomTree::new(func),
+            rpo_index,
+            forward_preds,
+            loop_headers,
+            locals,
+            fp: next,
+            slot_offsets,
+            frame_size: offset.next_multiple_of(16),
+            has_frame: !func.slots.is_empty() || uses_stack,
+            sibling_calls: sibling_calls && tailcall::may_reuse_frame(func),
+            labels: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn function(mut self, ty: FuncType) -> Func {
+        let func = self.func;
+        if self.has_frame {
+            self.body.push(Instr::GlobalGet(SP));
+            if self.frame_size > 0 {
+                self.body.push(Instr::I32Const(self.frame_size as i32));
+                self.body.push(Instr::Op(Op::I32Sub));
+                self.body.push(Instr::LocalSet(self.fp));
+                self.body.push(Instr::LocalGet(self.fp));
+                self.body.push(Instr::GlobalSet(SP));
+            } else {
+                self.body.push(Instr::LocalSet(self.fp));
+            }
+        }
+        self.do_tree(BlockId(0));
+        // すべての経路は br か return で終わるが、型の検査のために置く
+        if ty.result.is_some() {
+            self.body.push(Instr::Unreachable);
+        }
+
+        let count = self.fp as usize;
+        let mut locals = vec![ValType::I32; count - func.params.len()];
+        let mut local_names = vec![String::new(); count];
+        for (i, ty) in func.regs.iter().enumerate() {
+            let Some(index) = self.locals[i] else {
+                continue;
+            };
+            let index = index as usize;
+            local_names[index] = format!("r{}", i);
+            if index >= func.params.len() {
+                locals[index - func.params.len()] = val_type(*ty);
+            }
+        }
+        if self.has_frame {
+            locals.push(ValType::I32);
+            local_names.push("fp".to_string());
+        }
+        Func {
+            name: func.name.clone(),
+            ty,
+            export: !func.is_static,
+            locals,
+            local_names,
+            body: self.body,
+        }
+    }
+
+    fn is_merge(&self, block: BlockId) -> bool {
+        self.forward_preds[block.0] >= 2
+    }
+
+    // block を根とする支配木の部分木のコードを出す
+    fn do_tree(&mut self, block: BlockId) {
+        // 合流するブロックは block の後ろに順に置く。番号の大きいものほど外側で囲む
+        let mut merges: Vec<BlockId> = self
+            .dom
+            .children(block)
+            .iter()
+            .copied()
+            .filter(|child| self.is_merge(*child))
+            .collect();
+        merges.sort_by_key(|child| std::cmp::Reverse(self.rpo_index[child.0]));
+        if self.loop_headers[block.0] {
+            self.body.push(Instr::Loop);
+            self.labels.push(Label::Loop(block));
+            self.node_within(block, &merges);
+            self.labels.pop();
+            self.body.push(Instr::End);
+        } else {
+            self.node_within(block, &merges);
+        }
+    }
+
+    fn node_within(&mut self, block: BlockId, merges: &[BlockId]) {
+        match merges.split_first() {
+            Some((merge, rest)) => {
+                self.body.push(Instr::Block);
+                self.labels.push(Label::Block(*merge));
+                self.node_within(block, rest);
+                self.labels.pop();
+                self.body.push(Instr::End);
+                self.do_tree(*merge);
+            }
+            None => {
+                let insts = &self.func.blocks[block.0].insts;
+                if let Some(Inst::Call { name, args, .. }) = self.sibling_call(block) {
+                    for inst in insts[..insts.len() - 1].iter() {
+                        self.inst(inst);
+                    }
+                    let (index, ty) = self.callee(name, args, None);
+                    for arg in args.iter().take(ty.params.len()) {
+                        self.push(*arg);
+                    }
+                    self.epilogue();
+                    self.body.push(Instr::ReturnCall(index));
+                    return;
+                }
+                for inst in insts.iter() {
+                    self.inst(inst);
+                }
+                self.terminator(block);
+            }
+        }
+    }
+
+    // 呼び出した結果をそのまま返すなら、フレームを片付けてから return_call で呼び出す
+    // 結果の型が呼び出し元と同じでなければならない
+    fn sibling_call(&self, block: BlockId) -> Option<&'a Inst> {
+        if !self.sibling_calls {
+            return None;
+        }
+        let call = tailcall::tail_call(self.func, &self.func.blocks[block.0])?;
+        let Inst::Call { dst, name, args } = call else {
+            unreachable!();
+        };
+        let (_, ty) = self.callee(name, args, *dst);
+        (ty.result == self.func.ret.map(val_type)).then_some(call)
+    }
+
+    fn branch(&mut self, from: BlockId, to: BlockId) {
+        let label = if self.rpo_index[to.0] <= self.rpo_index[from.0] {
+            Label::Loop(to)
+        } else if self.is_merge(to) {
+            Label::Block(to)
+        } else {
+            // to は from だけから来るので、ここに置けばよい
+            self.do_tree(to);
+            return;
+        };
+        let depth = self
+            .labels
+            .iter()
+            .rev()
+            .position(|l| *l == label)
+            .expect("分岐先のブロックが囲まれていません");
+        self.body.push(Instr::Br(depth as u32));
+    }
+
+    fn terminator(&mut self, block: BlockId) {
+        match &self.func.blocks[block.0].term {
+            Terminator::Jump(target) => self.branch(block, *target),
+            Terminator::Branch { cond, then, els } => {
+                self.push(*cond);
+                self.body.push(Instr::If);
+                self.labels.push(Label::IfThenElse);
+                self.branch(block, *then);
+                self.body.push(Instr::Else);
+                self.branch(block, *els);
+                self.labels.pop();
+                self.body.push(Instr::End);
+            }
+            Terminator::Ret(val) => {
+                if self.func.ret.is_some() {
+                    self.push(val.unwrap_or(Operand::Imm(0)));
+                }
+                self.epilogue();
+                self.body.push(Instr::Return);
+            }
+            Terminator::Unreachable => self.body.push(Instr::Unreachable),
+        }
+    }
+
+    // スタックポインタを入口の値に戻す
+    fn epilogue(&mut self) {
+        if self.has_frame {
+            self.body.push(Instr::LocalGet(self.fp));
+            if self.frame_size > 0 {
+                self.body.push(Instr::I32Const(self.frame_size as i32));
+                self.op(Op::I32Add);
+            }
+            self.body.push(Instr::GlobalSet(SP));
+        }
+    }
+
+    // 呼び出す関数の番号と型
+    fn callee(&self, name: &str, args: &[Operand], dst: Option<Reg>) -> (u32, FuncType) {
+        if let Some(index) = self.indices.get(name) {
+            return (*index, self.signatures[name].clone());
+        }
+        let ty = FuncType {
+            params: args
+                .iter()
+                .map(|arg| operand_type(self.func, *arg))
+                .collect(),
+            result: dst.map(|dst| val_type(self.func.reg_ty(dst))),
+        };
+        let index = self
+            .imports
+            .iter()
+            .position(|import| import.name == name && import.ty == ty)
+            .expect("インポートしていない関数です");
+        (index as u32, ty)
+    }
+
+    // オペランドの値を積む
+    fn push(&mut self, op: Operand) {
+        self.body.push(match op {
+            Operand::Reg(reg) => Instr::LocalGet(self.locals[reg.0].unwrap()),
+            Operand::Imm(val) => Instr::I32Const(val as i32),
+        });
+    }
+
+    fn set(&mut self, dst: Reg) {
+        self.body.push(Instr::LocalSet(self.locals[dst.0].unwrap()));
+    }
+
+    // ポインタを i32 のアドレスとして積む
+    fn push_addr(&mut self, addr: Operand) {
+        self.push(addr);
+    }
+
+    fn op(&mut self, op: Op) {
+        self.body.push(Instr::Op(op));
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Copy { dst, src } => {
+                self.push(*src);
+                self.set(*dst);
+            }
+            // phi はコード生成の前にコピーに置き換えてある
+            Inst::Phi { .. } => unreachable!(),
+            Inst::Bin { op, dst, lhs, rhs } => {
+                self.push(*lhs);
+                self.push(*rhs);
+                self.op(bin_op(*op));
+                self.set(*dst);
+            }
+            Inst::Cmp {
+                op, dst, lhs, rhs, ..
+            } => {
+                self.push(*lhs);
+                self.push(*rhs);
+                self.op(cmp_op(*op));
+                self.set(*dst);
+            }
+            Inst::Load {
+                dst,
+                addr,
+                ty,
+                signed,
+            } => {
+                self.push_addr(*addr);
+                self.op(match (ty, signed) {
+                    (Ty::I8, true) => Op::I32Load8S,
+                    (Ty::I8, false) => Op::I32Load8U,
+                    (Ty::I32 | Ty::IPtr | Ty::Ptr, _) => Op::I32Load,
+                });
+                self.set(*dst);
+            }
+            Inst::Store { addr, val, ty } => {
+                self.push_addr(*addr);
+                self.push(*val);
+                self.op(match ty {
+                    Ty::I8 => Op::I32Store8,
+                    Ty::I32 | Ty::IPtr | Ty::Ptr => Op::I32Store,
+                });
+            }
+            Inst::LocalAddr { dst, slot } => {
+                self.body.push(Instr::LocalGet(self.fp));
+                let offset = self.slot_offsets[slot.0];
+                if offset > 0 {
+                    self.body.push(Instr::I32Const(offset as i32));
+                    self.op(Op::I32Add);
+                }
+                self.set(*dst);
+            }
+            Inst::GlobalAddr { dst, name } => {
+                self.body.push(Instr::I32Const(self.addrs[name] as i32));
+                self.set(*dst);
+            }
+            Inst::Call { dst, name, args } => {
+                let (index, ty) = self.callee(name, args, *dst);
+                for arg in args.iter().take(ty.params.len()) {
+                    self.push(*arg);
+                }
+                self.body.push(Instr::Call(index));
+                match (dst, ty.result) {
+                    (Some(dst), Some(_)) => self.set(*dst),
+                    (Some(dst), None) => {
+                        self.push(Operand::Imm(0));
+                        self.set(*dst);
+                    }
+                    (None, Some(_)) => self.body.push(Instr::Drop),
+                    (None, None) => {}
+                }
+            }
+            // int とポインタは同じ i32 なので、変換が要るのは char の拡張だけ
+            Inst::Cast { dst, src, kind } => {
+                self.push(*src);
+                match kind {
+                    CastKind::Sext(Ty::I8) => self.op(Op::I32Extend8S),
+                    CastKind::Zext(Ty::I8) => {
+                        self.body.push(Instr::I32Const(0xff));
+                        self.op(Op::I32And);
+                    }
+                    _ => {}
+                }
+                self.set(*dst);
+            }
+            Inst::Alloca { dst, size } => {
+                self.body.push(Instr::GlobalGet(SP));
+                self.push(*size);
+                self.op(Op::I32Sub);
+                self.body.push(Instr::I32Const(-16));
+                self.op(Op::I32And);
+                self.body.push(Instr::GlobalSet(SP));
+                self.body.push(Instr::GlobalGet(SP));
+                self.set(*dst);
+            }
+            Inst::StackSave { dst } => {
+                self.body.push(Instr::GlobalGet(SP));
+                self.set(*dst);
+            }
+            Inst::StackRestore { src } => {
+                self.push_addr(*src);
+                self.body.push(Instr::GlobalSet(SP));
+            }
+        }
+    }
+}
+
+fn bin_op(op: BinOp) -> Op {
+    match op {
+        BinOp::Add => Op::I32Add,
+        BinOp::Sub => Op::I32Sub,
+        BinOp::Mul => Op::I32Mul,
+        BinOp::SDiv => Op::I32DivS,
+        BinOp::UDiv => Op::I32DivU,
+        BinOp::And => Op::I32And,
+        BinOp::Or => Op::I32Or,
+        BinOp::Shl => Op::I32Shl,
+        BinOp::LShr => Op::I32ShrU,
+        BinOp::AShr => Op::I32ShrS,
+    }
+}
+
+fn cmp_op(op: CmpOp) -> Op {
+    match op {
+        CmpOp::Eq => Op::I32Eq,
+        CmpOp::Ne => Op::I32Ne,
+        CmpOp::Slt => Op::I32LtS,
+        CmpOp::Sle => Op::I32LeS,
+        CmpOp::Sgt => Op::I32GtS,
+        CmpOp::Sge => Op::I32GeS,
+        CmpOp::Ult => Op::I32LtU,
+        CmpOp::Ule => Op::I32LeU,
+        CmpOp::Ugt => Op::I32GtU,
+        CmpOp::Uge => Op::I32GeU,
+    }
+}
@@ -9,8 +9,9 @@ RESET="\033[0m"
 assert() {
   expected="$1"
   input="$2"
+  flags="$3"
 
-  cargo run -- "$input" > tmp.s
+  cargo run -- $flags "$input" > tmp.s
   cc -o tmp tmp.s
   ./tmp
   actual="$?"
@@ -23,6 +24,216 @@ assert() {
   fi
 }
 
+assert_error() {
+  input="$1"
+  flags="$2"
+
+  if cargo run -- $flags "$input" > tmp.s; then
+    echo -e "❌ \n${RED}$input => compile error expected, but compiled${RESET}"
+    exit 1
+  fi
+  echo -e "✅ \n${GREEN}Input: $input\nResult: compile error\n${RESET}"
+}
+
+# 警告が出ること (warning が空なら警告が出ないこと) を確認する
+assert_warning() {
+  flags="$1"
+  warning="$2"
+  input="$3"
+
+  output=$(cargo run -- $flags "$input" 2>&1 >/dev/null)
+  if [ -z "$warning" ]; then
+    if echo "$output" | grep -q "警告"; then
+      echo -e "❌ \n${RED}$input => no warnings expected, but got:\n$output${RESET}"
+      exit 1
+    fi
+  elif ! echo "$output" | grep -q -- "\[$warning\]"; then
+    echo -e "❌ \n${RED}$input => $warning expected${RESET}"
+    exit 1
+  fi
+  echo -e "✅ \n${GREEN}Input: $input\nWarning: ${warning:-none}\n${RESET}"
+}
+
+# --emit-ir の出力が期待どおりであることを確認する
+assert_ir() {
+  expected="$1"
+  input="$2"
+  flags="$3"
+
+  actual=$(cargo run -- --emit-ir $flags "$input")
+  if [ "$actual" = "$expected" ]; then
+    echo -e "✅ \n${GREEN}Input: $input\nIR: ok\n${RESET}"
+  else
+    echo -e "❌ \n${RED}$input => IR mismatch\n--- expected\n$expected\n--- actual\n$actual${RESET}"
+    exit 1
+  fi
+}
+
+# 出力するアセンブリが期待どおりであることを確認する
+assert_asm() {
+  expected="$1"
+  input="$2"
+  flags="$3"
+
+  actual=$(cargo run -- $flags "$input")
+  if [ "$actual" = "$expected" ]; then
+    echo -e "✅ \n${GREEN}Input: $input\nAssembly: ok\n${RESET}"
+  else
+    echo -e "❌ \n${RED}$input => assembly mismatch\n--- expected\n$expected\n--- actual\n$actual${RESET}"
+    exit 1
+  fi
+}
+
+# -c で出力したオブジェクトファイルをリンクして実行し、as でアセンブルしたものとバイト単位で一致することを確認する
+assert_obj() {
+  expected="$1"
+  input="$2"
+  flags="$3"
+  link="$4" # 一緒にリンクするファイル
+
+  cargo run -- -c $flags "$input" > tmp_obj.o
+  cargo run -- $flags "$input" > tmp_obj.s
+  as tmp_obj.s -o tmp_ref.o
+  cc -o tmp_obj tmp_obj.o $link
+  ./tmp_obj
+  actual="$?"
+
+  if [ "$actual" != "$expected" ]; then
+    echo -e "❌ \n${RED}$input => $expected expected, but got $actual (-c)${RESET}"
+    exit 1
+  fi
+  if ! cmp -s tmp_obj.o tmp_ref.o; then
+    echo -e "❌ \n${RED}$input => object mismatch\n--- as\n$(objdump -dr tmp_ref.o)\n--- -c\n$(objdump -dr tmp_obj.o)${RESET}"
+    exit 1
+  fi
+  echo -e "✅ \n${GREEN}Input: $input\nResult (-c): $actual\n${RESET}"
+}
+
+# 内蔵のリンカ (-static) で作った実行ファイルを実行して確認する (C のツールチェインを使わない)
+assert_static() {
+  expected="$1"
+  input="$2"
+  flags="$3"
+
+  cargo run -- -static $flags "$input" > tmp_static
+  chmod +x tmp_static
+  ./tmp_static
+  actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo -e "✅ \n${GREEN}Input: $input\nResult (-static): $actual\n${RESET}"
+  else
+    echo -e "❌ \n${RED}$input => $expected expected, but got $actual (-static)${RESET}"
+    exit 1
+  fi
+}
+
+# --run でアセンブラやリンカを使わずにその場で実行して確認する (args は main に渡す引数)
+assert_run() {
+  expected="$1"
+  input="$2"
+  flags="$3"
+  args="$4"
+
+  cargo run -- $flags --run "$input" $args
+  actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo -e "✅ \n${GREEN}Input: $input\nResult (--run): $actual\n${RESET}"
+  else
+    echo -e "❌ \n${RED}$input => $expected expected, but got $actual (--run)${RESET}"
+    exit 1
+  fi
+}
+
+# -fPIC でコンパイルした共有ライブラリを、同じ名前のシンボルを定義した実行ファイルとリンクして実行する
+assert_shared() {
+  expected="$1"
+  lib="$2"
+  flags="$3"
+  input="$4"
+
+  cargo run -- -c -fPIC $flags "$lib" > tmp_shared.o
+  cc -shared -o tmp_shared.so tmp_shared.o
+  cargo run -- "$input" > tmp_shared.s
+  cc -o tmp_shared tmp_shared.s ./tmp_shared.so
+  ./tmp_shared
+  actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo -e "✅ \n${GREEN}Input: $lib\nResult (-fPIC $flags): $actual\n${RESET}"
+  else
+    echo -e "❌ \n${RED}$lib => $expected expected, but got $actual (-fPIC $flags)${RESET}"
+    exit 1
+  fi
+}
+
+# クロスコンパイラと qemu-user があれば、生成したコードを実行して確認する
+AARCH64_CC=${AARCH64_CC:-aarch64-linux-gnu-gcc}
+QEMU_AARCH64=${QEMU_AARCH64:-qemu-aarch64}
+RISCV64_CC=${RISCV64_CC:-riscv64-linux-gnu-gcc}
+QEMU_RISCV64=${QEMU_RISCV64:-qemu-riscv64}
+I386_CC=${I386_CC:-i686-linux-gnu-gcc}
+QEMU_I386=${QEMU_I386:-qemu-i386}
+assert_cross() {
+  target="$1"
+  cross_cc="$2"
+  qemu="$3"
+  expected="$4"
+  input="$5"
+  flags="$6"
+
+  if ! command -v $cross_cc > /dev/null || ! command -v $qemu > /dev/null; then
+    echo -e "${YELLOW}skip ($target のツールチェインがありません): $input${RESET}"
+    return
+  fi
+  cargo run -- --target=$target $flags "$input" > tmp_cross.s
+  $cross_cc -static -o tmp_cross tmp_cross.s
+  $qemu ./tmp_cross
+  actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo -e "✅ \n${GREEN}Input: $input\nResult ($target): $actual\n${RESET}"
+  else
+    echo -e "❌ \n${RED}$input => $expected expected, but got $actual ($target)${RESET}"
+    exit 1
+  fi
+}
+
+assert_aarch64() {
+  assert_cross aarch64-linux-gnu $AARCH64_CC $QEMU_AARCH64 "$@"
+}
+
+assert_riscv64() {
+  assert_cross riscv64-linux-gnu $RISCV64_CC $QEMU_RISCV64 "$@"
+}
+
+assert_i386() {
+  assert_cross i386-linux-gnu $I386_CC $QEMU_I386 "$@"
+}
+
+# node があれば、生成した .wasm を wasm_run.mjs で実行して確認する
+assert_wasm() {
+  expected="$1"
+  input="$2"
+  flags="$3"
+
+  if ! command -v node > /dev/null; then
+    echo -e "${YELLOW}skip (node がありません): $input${RESET}"
+    return
+  fi
+  cargo run -- --target=wasm32 --emit-wasm $flags "$input" > tmp.wasm
+  node wasm_run.mjs tmp.wasm
+  actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo -e "✅ \n${GREEN}Input: $input\nResult (wasm32): $actual\n${RESET}"
+  else
+    echo -e "❌ \n${RED}$input => $expected expected, but got $actual (wasm32)${RESET}"
+    exit 1
+  fi
+}
+
 exec_with_include() {
   expected="$1"
   input="$2"
@@ -374,7 +585,8 @@ int main() {
 }
 "
 
-assert 42 "
+# int は参照外しできない
+assert_error "
 int main() {
 	int x = 42;
 	int y = &x;
@@ -383,6 +595,659 @@ int main() {
 }
 "
 
+# 後ろで定義された関数やグローバル変数も参照できる
+assert 42 "
+int main() {
+	g = 40;
+	return add2(g);
+}
+int add2(int x) {
+	return x + 2;
+}
+int g;
+"
+
+assert 42 "
+int main() {
+	int x = 10;
+	{
+		int x = 42;
+		if (x != 42) return 1;
+	}
+	return x + 32;
+}
+"
+
+assert 3 "
+int main() {
+	int a[10];
+	int *p = a + 1;
+	int *q = a + 4;
+	return q - p;
+}
+"
+
+assert 13 "
+int main() {
+	return sizeof(\"hello\\n\\tworld\");
+}
+"
+
+# 配列の要素数には整数定数式が書ける
+assert 96 "
+int a[2*8];
+int main() {
+	int b[(3+5)/2][sizeof(a)/sizeof(a[0])];
+	return sizeof(a) + sizeof(b[0]) - sizeof(b) / 4 + 48 - 16 * (1 < 2);
+}
+"
+
+assert_error "int a[1/0]; int main() { return 0; }"
+assert_error "int a[65536*65536]; int main() { return 0; }"
+assert_error "int a[0-1]; int main() { return 0; }"
+assert_error "int n; int a[n]; int main() { return 0; }"
+
+# 可変長配列と __builtin_alloca
+assert 36 "int main() { int n = 5; int a[n]; int i; for (i = 0; i < n; i = i + 1) a[i] = i * i; return a[4] + sizeof(a); }"
+assert 121 "int main() { int n = 3; int m = 4; int a[n][m]; a[2][3] = 7; a[1][0] = 5; return a[2][3] + a[1][0] * 10 + sizeof(a) + sizeof(a[0]); }"
+assert 33 "int main() { int n = 3; int a[2][n]; a[1][2] = 9; return a[1][2] + sizeof(a); }"
+assert 52 "int main() { int n = 4; return sizeof(int[n][3]) + sizeof(char[n]); }"
+assert 48 "
+int f(int n) {
+	int a[n];
+	int s = 0;
+	int i;
+	for (i = 0; i < n; i = i + 1) a[i] = i;
+	for (i = 0; i < n; i = i + 1) s = s + a[i];
+	return s;
+}
+int main() { return f(10) + f(3); }
+"
+# ブロックを抜けるたびに解放されるので、スタックを使い切らない
+assert 159 "
+int main() {
+	int s = 0;
+	int i;
+	for (i = 0; i < 100000; i = i + 1) {
+		int n = 100;
+		int a[n];
+		a[n - 1] = i;
+		s = a[99];
+	}
+	return s - s / 256 * 256;
+}
+"
+assert 112 "
+int main() {
+	int *p = __builtin_alloca(40);
+	int i;
+	for (i = 0; i < 10; i = i + 1) p[i] = i;
+	int *q = __builtin_alloca(8);
+	*q = 100;
+	return p[9] + *q + p[3];
+}
+"
+assert 16 "
+struct B { int n; char data[]; };
+int main() {
+	struct B *b = __builtin_alloca(sizeof(struct B) + 10);
+	b->n = 3; b->data[0] = 4; b->data[9] = 5;
+	return sizeof(struct B) + b->data[0] + b->data[9] + b->n;
+}
+"
+assert 4 "struct B { char c; int data[]; }; int main() { return sizeof(struct B); }"
+assert_error "struct B { char data[]; }; int main() { return 0; }"
+assert_error "struct B { char data[]; int n; }; int main() { return 0; }"
+assert_error "int main() { int n = 2; char s[n] = \"a\"; return 0; }"
+assert_error "int main() { void *p = 0; return *p; }"
+# 構造体とビットフィールド (サイズは gcc と同じになる)
+assert 12 "struct S { int a; char b; int c; }; int main() { return sizeof(struct S); }"
+assert 12 "struct S { char a; int b:3; int c:30; char d; }; int main() { return sizeof(struct S); }"
+assert 4 "struct S { unsigned a:1; unsigned b:7; char c; }; int main() { return sizeof(struct S); }"
+assert 2 "struct S { char a:3; char :0; char b:2; }; int main() { return sizeof(struct S); }"
+assert 5 "struct S { char a; int :0; char b; }; int main() { return sizeof(struct S); }"
+assert 3 "struct S { char a; int :4; char b; }; int main() { return sizeof(struct S); }"
+assert 8 "struct S { char a; int b:4; char c; int d:20; int e:9; }; int main() { return sizeof(struct S); }"
+assert 205 "
+struct S { char a; int b:4; char c; int d:20; int e:9; };
+int main() {
+	struct S s;
+	s.a = 1; s.b = 0-3; s.c = 7; s.d = 100000; s.e = 0-200;
+	return s.a + s.b + s.c + s.d / 1000 + s.e + 300;
+}
+"
+assert 11 "
+struct S { unsigned a:4; unsigned b:28; unsigned c:1; };
+int main() {
+	struct S s;
+	s.a = 15; s.b = 268435455; s.c = 1;
+	s.a = s.a + 1;
+	return s.a + s.c + (s.b == 268435455) * 10;
+}
+"
+assert 14 "struct S { int a:3; int b:5; }; int main() { struct S s; s.b = 31; return (s.a = 5) + 18 + s.b; }"
+assert 43 "
+struct S { int a[2]; int b; };
+struct S arr[3];
+int main() {
+	struct S *p = arr;
+	arr[2].b = 4;
+	arr[1].a[1] = 3;
+	return (p + 2)->b * 10 + p[1].a[1];
+}
+"
+assert 14 "
+struct N { int v; struct N *next; };
+int main() {
+	struct N a; struct N b;
+	a.v = 1; b.v = 2; a.next = &b; b = a; b.v = 7;
+	return a.next->v + b.next->v + sizeof(struct N) - 16;
+}
+"
+assert 7 "struct S *p; struct S { int a; int b; }; struct S g; int main() { p = &g; p->b = 7; return g.b; }"
+assert 1 "int main() { unsigned x = 0; x = x - 1; return x > 5; }"
+assert 0 "int main() { unsigned x = 3; return 0-1 < x; }"
+assert 100 "int main() { unsigned char c = 200; return c / 2; }"
+# 接尾辞 u の付いた定数は unsigned int で、int に収まらない値も書ける
+assert 1 "int main() { unsigned x = 4294967295u; return x == 0 - 1; }"
+assert 7 "int main() { return (2147483648u > 5) + (4294967295U / 65536 == 65535) * 2 + (3u - 5 > 0) * 4; }"
+assert 7 "int main() { return (2147483648u > 5) + (4294967295U / 65536 == 65535) * 2 + (3u - 5 > 0) * 4; }" "-O2 --verify-ir"
+assert 12 "int main() { int a[3u]; return sizeof(a) + 0U; }"
+assert_error "int main() { return 2147483648; }"
+assert_error "int main() { return 4294967296u; }"
+assert_error "struct S { int a; }; int main() { struct S s; return s.b; }"
+assert_error "struct S { int a:3; }; int main() { struct S s; int *p = &s.a; return 0; }"
+assert_error "struct S { int a:33; }; int main() { return 0; }"
+assert_error "struct S { int a:0; }; int main() { return 0; }"
+assert_error "struct S; int main() { struct S s; return 0; }"
+assert_error "struct S { int a; }; struct T { int a; }; int main() { struct S s; struct T t; s = t; return 0; }"
+assert_warning "-Wall" "" "struct S { int a; }; int main() { struct S s; s.a = 1; return s.a; }"
+assert_warning "-Wall" "-Wunused-variable" "int main() { int x; return 0; }"
+assert_warning "-Wextra" "-Wunused-parameter" "int f(int a) { return 0; } int main() { return f(1); }"
+assert_warning "-Wall" "" "int f(int a) { return 0; } int main() { return f(1); }"
+assert_warning "-Wall" "-Wuninitialized" "int main() { int x; if (1) x = 2; return x; }"
+assert_warning "-Wall" "" "int main() { int x; if (1) x = 2; else x = 3; return x; }"
+assert_warning "-Wall" "" "int main() { int x; int *p = &x; *p = 1; return x; }"
+assert_warning "-Wall" "-Wreturn-type" "int f(int a) { if (a) return 1; } int main() { return f(1); }"
+assert_warning "-Wall" "" "int f(int a) { if (a) return 1; else return 2; } int main() { return f(1); }"
+assert_warning "-Wall" "" "int f() { while (1) { return 1; } } int main() { return f(); }"
+assert_warning "" "-Wimplicit-function-declaration" "int main() { return foo(); }"
+assert_warning "-Wno-implicit-function-declaration" "" "int main() { return foo(); }"
+assert_warning "-Wall" "-Wparentheses" "int main() { int x; if (x = 1) return 1; return 0; }"
+assert_warning "-Wall" "" "int main() { int x; if ((x = 1)) return 1; return 0; }"
+assert_warning "" "-Wint-conversion" "int main() { int x; int *p = 1; x = p; return 0; }"
+assert_warning "-Wall" "" "int main() { int *p = 0; return p == 0; }"
+assert_warning "-Wall -Wno-unused-variable" "" "int main() { int x; return 0; }"
+assert_warning "-Wunreachable-code" "-Wunreachable-code" "int main() { int x = 1; if (x) { return 1; x = 2; } return x; }"
+assert_warning "-Wunreachable-code" "-Wunreachable-code" "int main() { while (1) { } return 0; }"
+assert_warning "-Wunreachable-code" "" "int main() { int x = 1; if (x) return 1; return 2; }"
+assert_warning "-Wall" "" "int main() { return 1; return 2; }"
+assert_error "int main() { int x; return 0; }" "-Wall -Werror"
+assert_error "int main() { return x; }"
+assert_error "int main() { 1 = 2; return 0; }"
+assert_error "int main() { int a[2]; int b[2]; a = b; return 0; }"
+assert_error "int main() { int x; int x; return 0; }"
+assert_error "int f(int a) { return a; } int main() { return f(1, 2); }"
+assert_error "int main() { int x; char *p = &x; return 0; }"
+assert_error "int *f() { char c; return &c; } int main() { return 0; }"
+assert_error "int main() { int *p; int *q; return p + q; }"
+assert 15 "void fill(int *p, int n) { int a[n]; int i; for (i = 0; i < n; i = i + 1) { a[i] = i; if (i == 5) { *p = a[i] * 3; return; } } *p = 0; } int main() { int x; int k; for (k = 0; k < 100000; k = k + 1) fill(&x, 10); return x; }"
+assert 15 "void fill(int *p, int n) { int a[n]; int i; for (i = 0; i < n; i = i + 1) { a[i] = i; if (i == 5) { *p = a[i] * 3; return; } } *p = 0; } int main() { int x; int k; for (k = 0; k < 100000; k = k + 1) fill(&x, 10); return x; }" "-O2 --verify-ir"
+assert 7 "void f(int *p) { *p = 7; return; } int main() { int x = 0; f(&x); return x; }"
+assert_error "void f() { return 1; } int main() { return 0; }"
+assert_error "int f() { return; } int main() { return 0; }"
+assert_error "int main() { return; }"
+
+# 中間表現
+assert_ir "global @g: size 4
+
+function main() -> i32 {
+  \$0: size 4, align 4
+bb0:
+  %0: ptr = local \$0
+  store i32 %0, 3
+  %1: ptr = local \$0
+  %2: i32 = load i32 %1
+  %3: i32 = cmp slt i32 %2, 5
+  br %3, bb1, bb2
+bb1:
+  %4: ptr = local \$0
+  %5: ptr = global @g
+  %6: i32 = load i32 %5
+  store i32 %4, %6
+  jmp bb2
+bb2:
+  %7: ptr = local \$0
+  %8: i32 = load i32 %7
+  ret %8
+bb3:
+  ret 3
+}" "int g; int main() { int a = 3; if (a < 5) a = g; return a; }"
+assert_ir "function f(%0: ptr, %1: i32) -> i32 {
+  \$0: size 8, align 8
+  \$1: size 4, align 4
+bb0:
+  %2: ptr = local \$0
+  store ptr %2, %0
+  %3: ptr = local \$1
+  store i32 %3, %1
+  %4: ptr = local \$0
+  %5: ptr = load ptr %4
+  %6: ptr = local \$1
+  %7: i32 = load i32 %6
+  %8: iptr = sext i32 %7
+  %9: ptr = add %5, %8
+  %10: i32 = load i8 %9
+  ret %10
+bb1:
+  ret 0
+}" "int f(char *s, int i) { return s[i]; }"
+assert_ir "function main() -> i32 {
+bb0:
+  jmp bb1
+bb1:
+  %0: i32 = call @f(1, 2)
+  %1: i32 = cmp ne i32 %0, 0
+  br %1, bb2, bb3
+bb2:
+  jmp bb1
+bb3:
+  ret 0
+bb4:
+  ret 0
+}" "int main() { while (f(1, 2) != 0) 0; return 0; }"
+assert_ir "function main() -> i32 {
+bb0:
+  jmp bb1
+bb1:
+  %18: i32 = phi [0, bb0], [%14, bb2]
+  %17: i32 = phi [0, bb0], [%10, bb2]
+  %4: i32 = cmp slt i32 %18, 4
+  br %4, bb2, bb3
+bb2:
+  %10: i32 = add %17, %18
+  %14: i32 = add %18, 1
+  jmp bb1
+bb3:
+  ret %17
+}" "int main() { int s = 0; int i; for (i = 0; i < 4; i = i + 1) s = s + i; return s; }" "--passes=mem2reg"
+assert_ir "function main() -> i32 {
+bb0:
+  ret 13
+}" "int main() { return -(3) + 2 * 8; }" "--passes=mem2reg,fold"
+assert_ir "function f(%0: i32) -> i32 {
+bb0:
+  %4: i32 = shl %0, 3
+  %15: i32 = ashr %0, 31
+  %16: i32 = lshr %15, 30
+  %17: i32 = add %0, %16
+  %7: i32 = ashr %17, 2
+  %8: i32 = add %4, %7
+  %11: i32 = sub 0, %0
+  %14: i32 = add %8, %0
+  ret %14
+}" "int f(int x) { return x * 8 + x / 4 + -(-x) * 1; }" "--passes=mem2reg,fold"
+assert_ir "static function helper(%0: i32) -> i32 {
+bb0:
+  %4: i32 = shl %0, 1
+  ret %4
+}
+
+function main() -> i32 {
+bb0:
+  %12: i32 = call @helper(3)
+  %13: i32 = add %12, 1
+  ret %13
+}" "static int unused(int x) { return x; } static int helper(int x) { return x * 2; } int main() { int a = 3; int dead = a * 7; if (0) { a = helper(a); } else { a = helper(a) + 1; } return a; a = 5; }" "--passes=mem2reg,fold,simplifycfg,dce,globaldce"
+assert_ir "static inline function add(%0: i32, %1: i32) -> i32 {
+bb0:
+  %8: i32 = add %0, %1
+  ret %8
+}
+
+noinline function keep(%0: i32) -> i32 {
+bb0:
+  ret %0
+}
+
+function main() -> i32 {
+bb0:
+  %1: i32 = call @keep(3)
+  %2: i32 = add %1, 3
+  ret %2
+}" "static inline int add(int a, int b) { return a + b; } __attribute__((noinline)) int keep(int x) { return x; } int main() { return add(1, 2) + keep(3); }" "--passes=mem2reg,inline,fold,simplifycfg"
+assert_ir "function main() -> i32 {
+bb0:
+  jmp bb1
+bb1:
+  %18: i32 = phi [0, bb0], [%14, bb1]
+  %17: i32 = phi [0, bb0], [%10, bb1]
+  %10: i32 = add %17, %18
+  %14: i32 = add %18, 1
+  %21: i32 = cmp slt i32 %14, 4
+  br %21, bb1, bb2
+bb2:
+  ret %10
+}" "int main() { int s = 0; int i; for (i = 0; i < 4; i = i + 1) s = s + i; return s; }" "--passes=mem2reg,rotate,fold,simplifycfg,dce"
+assert_ir "global @a: size 400
+
+function f(%0: i32, %1: i32) -> i32 {
+bb0:
+  %36: i32 = cmp slt i32 0, %1
+  br %36, bb3, bb2
+bb1:
+  %42: ptr = phi [%19, bb3], [%43, bb1]
+  %34: i32 = phi [%30, bb1], [0, bb3]
+  %33: i32 = phi [%26, bb1], [0, bb3]
+  %25: i32 = load i32 %42
+  %26: i32 = add %33, %25
+  %30: i32 = add %34, 1
+  %37: i32 = cmp slt i32 %30, %1
+  %43: ptr = add %42, 4
+  br %37, bb1, bb2
+bb2:
+  %35: i32 = phi [0, bb0], [%26, bb1]
+  ret %35
+bb3:
+  %14: ptr = global @a
+  %17: iptr = sext i32 %0
+  %18: iptr = mul %17, 40
+  %19: ptr = add %14, %18
+  jmp bb1
+}" "int a[10][10]; int f(int i, int n) { int s = 0; int j; for (j = 0; j < n; j = j + 1) s = s + a[i][j]; return s; }" "--passes=mem2reg,rotate,licm,indvars,fold,simplifycfg,dce"
+assert_ir "global @a: size 16
+
+function main() -> i32 {
+bb0:
+  %8: ptr = global @a
+  %14: i32 = load i32 %8
+  %32: ptr = global @a
+  %35: ptr = add %32, 4
+  %36: i32 = load i32 %35
+  %37: i32 = add %14, %36
+  %41: ptr = global @a
+  %44: ptr = add %41, 8
+  %45: i32 = load i32 %44
+  %46: i32 = add %37, %45
+  %50: ptr = global @a
+  %53: ptr = add %50, 12
+  %54: i32 = load i32 %53
+  %55: i32 = add %46, %54
+  ret %55
+}" "int a[4]; int main() { int s = 0; int i; for (i = 0; i < 4; i = i + 1) s = s + a[i]; return s; }" "--passes=mem2reg,rotate,indvars,unroll,fold,simplifycfg,fold,simplifycfg,dce"
+
+# 最適化 (--verify-ir でパスごとに中間表現を検査する)
+assert 21 "int main() { int a = 1; int b = 2; int i; for (i = 0; i < 3; i = i + 1) { int t = a; a = b; b = t; } return a * 10 + b; }" "-O1 --verify-ir"
+assert 44 "int main() { char c = 0; int i = 0; while (i < 200) { c = c + 1; i = i + 1; } return c + 100; }" "-O2 --verify-ir"
+assert 9 "int main() { int s = 0; int i; for (i = 0; i < 10; i = i + 1) { int n = i + 1; int a[n]; a[i] = i; s = s + a[i] + sizeof(a); } return s; }" "-O2 --verify-ir"
+assert 7 "int f(int x) { int y; if (x) y = 3; else y = 4; return y + x; } int main() { return f(4) + f(0) - 4; }" "-O1 --verify-ir"
+assert 42 "int main() { int x = 40; int *p = &x; *p = *p + 2; return x; }" "--passes=mem2reg --verify-ir"
+assert 109 "int d(int x) { return x / 8; } int main() { return (d(-15) + 10) * 10 + d(15) + d(-16) + 20; }" "-O1 --verify-ir"
+assert 8 "int main() { unsigned a = 0 - 1; int b = -2147483647 - 1; return a / 16 / 1048576 + (b / 2 < 0) + (b / 1073741824) + 10; }" "-O1 --verify-ir"
+assert 33 "int main() { int x = 5; int *p = &x; unsigned m = 0 - 1; return (p - p) + (3 < 4) * 10 + (4 <= 3) + m / 1073741824 * 7 + (m > 5) + (m * 4 == 0 - 4); }" "-O1 --verify-ir"
+assert 184 "int main() { char c = 100; int i = c * 4; char d = i; return d + 200 / 8 + (-200) / 8 + 40; }" "-O1 --verify-ir"
+assert 7 "static int f(int x) { return x + 1; } static int g(int x) { return f(x); } int main() { return f(6); }" "-O1 --verify-ir"
+assert 2 "int main() { int x = 0; int *p = &x; *p = 1; *p = 2; return x; }" "-O1 --verify-ir"
+assert 3 "int g; int f() { return g; } int main() { int *p = &g; *p = 1; *p = f() + 2; return *p; }" "-O1 --verify-ir"
+assert 5 "int main() { int i = 0; while (0) i = i + 1; if (1) return i + 5; return 0; i = 3; }" "-O1 --verify-ir"
+assert 6 "int main() { int a[2]; int s = 0; a[0] = 1; a[1] = 5; s = a[0] + a[1]; return s; a[0] = 9; }" "-O1 --verify-ir"
+assert_error "static int x; int main() { return 0; }"
+
+# インライン展開
+assert 113 "int add(int a, int b) { return a + b; } int no_arg() { return 3; } int sel(int x) { if (x > 0) return x; return 0 - x; } __attribute__((noinline)) int keep(int x) { return x + 1; } int fact(int n) { if (n < 2) return 1; return n * fact(n - 1); } static inline int twice(int x) { return x * 2; } __attribute__((always_inline)) static int big(int x) { int s = 0; int i; for (i = 0; i < x; i = i + 1) s = s + i * i + x; return s; } int main() { int y = 4; return add(no_arg(), y) + sel(y - 9) + keep(1) + fact(3) + twice(y) + big(y) + big(y + 1); }" "-O1 --verify-ir"
+assert 12 "int g; void set(int *p, int v) { *p = v; } int get() { return g; } int main() { int x; set(&x, 5); set(&g, 7); return x + get(); }" "-O1 --verify-ir"
+assert 63 "int sum(int *a, int n) { int s = 0; int i; for (i = 0; i < n; i = i + 1) s = s + a[i]; return s; } int main() { int a[3]; a[0] = 1; a[1] = 2; a[2] = 3; return sum(a, 3) * 10 + sum(a, 2); }" "-O2 --verify-ir"
+assert 30 "int fill() { int b[4]; int i; for (i = 0; i < 4; i = i + 1) b[i] = i * i; return b[3] + b[1]; } int main() { int i; int s = 0; for (i = 0; i < 3; i = i + 1) s = s + fill(); return s; }" "-O1 --verify-ir"
+assert 11 "int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); } int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); } int main() { return is_even(10) * 10 + is_odd(7); }" "-O1 --verify-ir"
+assert 247 "int f(int n) { int a[n]; a[0] = n; return a[0]; } int main() { int i; int s = 0; for (i = 1; i < 100; i = i + 1) s = s + f(i); return s / 20; }" "-O1 --verify-ir"
+assert 3 "inline int f() { return 3; } int main() { return f(); }"
+# 呼び出し先の到達できないブロック (return の後の暗黙の return) は複製しない
+assert 1 "int f(int x) { return x; } int main() { int a = 1; int b = f(a); return b; }" "--verify-ir --passes=inline"
+assert 9 "int sel(int x) { if (x > 2) return x * 2; return x + 1; } int main() { return sel(1) + sel(3) + 1; }" "--verify-ir --passes=inline,mem2reg"
+assert_error "int main() { return 0; } __attribute__((hot)) int f() { return 0; }"
+assert_error "inline int x; int main() { return 0; }"
+
+# ループの最適化
+assert 90 "int a[10][10]; int main() { int i; int j; int s = 0; for (i = 0; i < 10; i = i + 1) for (j = 0; j < 10; j = j + 1) a[i][j] = i + j; for (i = 0; i < 10; i = i + 1) s = s + a[i][i]; return s; }" "-O1 --verify-ir"
+assert 178 "int b[5][6]; int main() { int i; int j; int s = 0; for (i = 0; i < 5; i = i + 1) for (j = 0; j < 6; j = j + 1) b[i][j] = i * j + 1; for (j = 0; j < 6; j = j + 1) for (i = 0; i < 5; i = i + 1) s = s + b[i][j] * (i + 1); return s; }" "-O1 -funroll-loops --verify-ir"
+assert 61 "int f(int n) { int s = 0; int i; for (i = 0; i < n; i = i + 1) { if (i > 5) return s; s = s + i * i; } return s + 1; } int main() { return f(3) + f(10); }" "-O1 -funroll-loops --verify-ir"
+assert 20 "int main() { int i = 10; int s = 0; while (i > 0) { s = s + i; i = i - 3; } return s + i; }" "-O1 -funroll-loops --verify-ir"
+assert 150 "int g(int x) { return x + 1; } int main() { int i = 0; int s = 0; while (g(i) < 30) { s = s + i; i = i + 1; } return s; }" "-O1 --verify-ir"
+assert 45 "int main() { char s[10]; int i; for (i = 0; i < 9; i = i + 1) s[i] = 97 + i; s[9] = 0; int n = 0; char *p = s; while (*p) { n = n + *p - 96; p = p + 1; } return n; }" "-O1 -funroll-loops --verify-ir"
+assert 55 "int f(int n) { int s = 0; int i; for (i = 1; i <= n; i = i + 1) { int a[i]; a[i - 1] = i; s = s + a[i - 1]; } return s; } int main() { return f(10); }" "-O1 -funroll-loops --verify-ir"
+assert 28 "struct P { int x; int y; }; struct P ps[8]; int main() { int i; int s = 0; for (i = 0; i < 8; i = i + 1) { ps[i].x = i; ps[i].y = i * 2; } for (i = 7; i >= 0; i = i - 1) s = s + ps[i].y - ps[i].x; return s; }" "-O1 -funroll-loops --verify-ir"
+assert 5 "int main() { int i; for (i = 0; i < 0; i = i + 1) return 9; return i + 5; }" "-O1 -funroll-loops --verify-ir"
+assert 40 "int m[4][4]; int main() { int i; int j; int k; for (i = 0; i < 4; i = i + 1) for (j = 0; j < 4; j = j + 1) m[i][j] = i + j; int t = 0; for (k = 0; k < 3; k = k + 1) for (i = 0; i < 4; i = i + 1) for (j = 0; j < 4; j = j + 1) t = t + m[i][j] * m[j][i] * k; return t; }" "-O2 -funroll-loops --verify-ir"
+# 回数が定数のループを完全に展開すると、最後の複製は必ずループを抜ける
+assert_ir "function main() -> i32 {
+bb0:
+  jmp bb1
+bb1:
+  %16: i32 = phi [0, bb0], [%26, bb9]
+  %15: i32 = phi [100, bb0], [%25, bb9]
+  %4: i32 = cmp slt i32 %16, 3
+  br %4, bb2, bb3
+bb2:
+  %8: i32 = add %15, 1
+  %12: i32 = add %16, 1
+  jmp bb4
+bb3:
+  %17: i32 = phi [%15, bb1], [%8, bb4], [%19, bb6], [%22, bb8]
+  ret %17
+bb4:
+  %18: i32 = cmp slt i32 %12, 3
+  br %18, bb5, bb3
+bb5:
+  %19: i32 = add %8, 1
+  %20: i32 = add %12, 1
+  jmp bb6
+bb6:
+  %21: i32 = cmp slt i32 %20, 3
+  br %21, bb7, bb3
+bb7:
+  %22: i32 = add %19, 1
+  %23: i32 = add %20, 1
+  jmp bb8
+bb8:
+  %24: i32 = cmp slt i32 %23, 3
+  br 0, bb9, bb3
+bb9:
+  %25: i32 = add %22, 1
+  %26: i32 = add %23, 1
+  jmp bb1
+}" "int main() { int c = 100; int j; for (j = 0; j < 3; j = j + 1) c = c + 1; return c; }" "--passes=mem2reg,unroll"
+assert 103 "int main() { int c = 100; int j; for (j = 0; j < 3; j = j + 1) c = c + 1; return c; }" "--passes=mem2reg,unroll"
+assert 103 "int main() { int c = 100; int j; for (j = 0; j < 3; j = j + 1) c = c + 1; return c; }" "-O1 -funroll-loops --verify-ir"
+assert_error "int main() { return 0; }" "--passes=nosuchpass"
+assert_error "int main() { return 0; }" "-O9"
+
+# レジスタ割り当て (スピルと callee-saved レジスタの退避)
+assert 181 "int f(int x) { int a = x + 1; int b = x + 2; int c = x + 3; int d = x + 4; int e = x + 5; int g = x + 6; int h = x + 7; int i = x + 8; int j = x + 9; return a * b - c * d + e * g - h * i + j; } int main() { return f(1) + f(2); }" "-O1"
+assert 40 "int g(int x) { return x * 2; } int main() { int a = 1; int b = 2; int c = 3; int d = 4; int e = 5; int f = 6; int h = 7; int s = g(a) + g(b) + g(c); return s + a + b + c + d + e + f + h; }" "-O1"
+assert 96 "int sum(int a, int b, int c, int d, int e, int f) { return a + b * 2 + c * 3 + d * 4 + e * 5 + f * 6; } int main() { int x = 1; int y = 2; return sum(x, y, x + y, sum(1, 1, 1, 1, 1, 1), y * y, x - y + 9) - 70; }" "-O1"
+# ブロックの先頭の呼び出しをまたいで、入口で生きている値を保つ
+assert 12 "__attribute__((noinline)) int f(int a) { int x = a * 7; int y = x + 9; return y - x; } __attribute__((noinline)) int h(int c) { int s = 2; if (c) { if (c - 1) { s = 5; } else { s = 3; } } else { s = 4; } return s + f(s); } int main() { return h(1); }" "-O1"
+assert 12 "__attribute__((noinline)) int f(int a) { int x = a * 7; int y = x + 9; return y - x; } __attribute__((noinline)) int h(int c) { int s = 2; if (c) { if (c - 1) { s = 5; } else { s = 3; } } else { s = 4; } return s + f(s); } int main() { return h(1); }" "-O2"
+assert 12 "__attribute__((noinline)) int f(int a) { int x = a * 7; int y = x + 9; return y - x; } __attribute__((noinline)) int h(int c) { int s = 2; if (c) { if (c - 1) { s = 5; } else { s = 3; } } else { s = 4; } return s + f(s); } int main() { return h(1); }" "--passes=mem2reg"
+# コピーで結ばれた値を同じレジスタにまとめる (入れ替えや、ループで受け渡す値)
+assert 55 "int main() { int a = 0; int b = 1; int i; for (i = 0; i < 10; i = i + 1) { int t = a + b; a = b; b = t; } return a; }" "-O1"
+assert 21 "int main() { int a = 1; int b = 2; int i; for (i = 0; i < 5; i = i + 1) { int t = a; a = b; b = t; } return a * 10 + b; }" "-O1"
+assert 23 "int f(int a, int b) { int i; for (i = 0; i < 3; i = i + 1) { int t = a; a = b; b = t + b; } return a * 3 + b; } int main() { return f(1, 2); }" "-O2"
+
+# のぞき穴最適化 (-O1 以上)
+assert_asm "  .intel_syntax noprefix
+  .text
+  .global f
+f:
+  push rbp
+  mov rbp, rsp
+  sub rsp, 16
+  mov QWORD PTR [rbp-8], rbx
+  mov QWORD PTR [rbp-16], r12
+  mov r10, rdi
+  mov r11, rsi
+  mov eax, r10d
+  mov edi, 3
+  cdq
+  idiv edi
+  cmp eax, r11d
+  jbe .L.f.bb2
+  mov r11d, r10d
+  sub r11d, 1
+  mov rax, r11
+  mov rbx, QWORD PTR [rbp-8]
+  mov r12, QWORD PTR [rbp-16]
+  mov rsp, rbp
+  pop rbp
+  ret
+.L.f.bb2:
+  mov rax, 7
+  mov rbx, QWORD PTR [rbp-8]
+  mov r12, QWORD PTR [rbp-16]
+  mov rsp, rbp
+  pop rbp
+  ret" "int f(int a, unsigned b) { if (a / 3 > b) return a - 1; return 7; }" "-O1"
+assert 130 "int f(int a, int b) { int c = a < b; if (c) return c + 10; return c + 20; } int main() { return f(1, 2) * 10 + f(3, 2); }" "-O1"
+assert 118 "int f(unsigned a, unsigned b) { int n = 0; if (a < b) n = n + 1; if (a <= b) n = n + 2; if (a > b) n = n + 4; if (a >= b) n = n + 8; if (a == b) n = n + 16; if (a != b) n = n + 32; return n; } int g(int a, int b) { int n = 0; if (a < b) n = n + 1; if (a <= b) n = n + 2; if (a > b) n = n + 4; if (a >= b) n = n + 8; return n; } int main() { return f(0 - 1, 1) + f(2, 2) * 2 + g(0 - 1, 1) * 4 + g(5, 5); }" "-O1"
+assert 236 "int main() { char c = -3; int s = 0; int i; for (i = 0; i < 20; i = i + 1) { if (c < i - 5) s = s + i / 3; else s = s - 1; c = c + 1; } return s; }" "-O1"
+assert 24 "int f(int x) { int a = x + 1; int b = x * 3; int c = x - 7; int d = x / 2; int e = x + 11; int g = x * x; int h = a + b; int k = c * d; if (a + b > c * d) return a + b + c + d + e + g + h + k; return e - g; } int main() { return f(3) + f(20) + f(100); }" "-O1"
+
+# 共通部分式の削除 (ストアと呼び出しの後はメモリを読み直す)
+assert_ir "function f(%0: ptr, %1: ptr, %2: i32) -> i32 {
+bb0:
+  %10: iptr = sext i32 %2
+  %11: iptr = mul %10, 4
+  %12: ptr = add %0, %11
+  %20: i32 = load i32 %12
+  %27: ptr = add %1, %11
+  %28: i32 = load i32 %27
+  %37: i32 = mul %28, %28
+  %38: i32 = add %20, %37
+  store i32 %12, %38
+  ret %38
+}" "int f(int *a, int *x, int i) { a[i] = a[i] + x[i] * x[i]; return a[i]; }" "--passes=mem2reg,cse"
+assert_ir "function f(%0: ptr, %1: i32) -> i32 {
+bb0:
+  %7: i32 = load i32 %0
+  br %1, bb1, bb3
+bb1:
+  %16: i32 = add %7, %7
+  jmp bb2
+bb2:
+  %32: i32 = phi [%25, bb3], [%16, bb1]
+  %30: i32 = load i32 %0
+  %31: i32 = add %32, %30
+  ret %31
+bb3:
+  store i32 %0, 3
+  %25: i32 = add %7, 3
+  jmp bb2
+}" "int f(int *p, int c) { int s = *p; if (c) s = s + *p; else { *p = 3; s = s + *p; } return s + *p; }" "--passes=mem2reg,gvn"
+assert 59 "int f(int *a, int *x, int i) { a[i] = a[i] + x[i] * x[i]; return a[i]; } int main() { int a[2]; int x[2]; a[1] = 10; x[1] = 7; return f(a, x, 1); }" "-O1 --verify-ir"
+assert 20 "int f(int *p, int c) { int s = *p; if (c) s = s + *p; else { *p = 3; s = s + *p; } return s + *p; } int main() { int x = 1; int y = f(&x, 0); return y * 2 + f(&x, 1) - x; }" "-O2 --verify-ir"
+assert 21 "int f(int *p, int *q) { int a = *p; *q = 20; return a + *p; } int main() { int x = 1; return f(&x, &x); }" "-O2 --verify-ir"
+assert 11 "int g; int h() { g = g + 4; return 0; } int main() { g = 1; int a = g; h(); return a + g + g; }" "-O2 --verify-ir"
+assert 42 "int main() { int a[3]; a[0] = 1; a[1] = 2; a[2] = 3; int s = 0; int i; for (i = 0; i < 3; i = i + 1) { s = s + a[i] * a[i] + a[i] * a[i]; a[i] = a[i] * 2; } return s + a[0] + a[1] + a[2] + 2; }" "-O2 --verify-ir"
+
+# 別名解析 (型による区別と、アドレスが外に出ないローカル変数)
+assert_ir "function f(%0: ptr, %1: ptr) -> i32 {
+bb0:
+  %7: i32 = load i32 %0
+  store ptr %1, 0
+  %15: i32 = add %7, %7
+  ret %15
+}" "int f(int *p, int **q) { int a = *p; *q = 0; return a + *p; }" "--passes=mem2reg,gvn -fstrict-aliasing"
+assert_ir "function f(%0: ptr, %1: ptr) -> i32 {
+bb0:
+  %7: i32 = load i32 %0
+  store ptr %1, 0
+  %14: i32 = load i32 %0
+  %15: i32 = add %7, %14
+  ret %15
+}" "int f(int *p, int **q) { int a = *p; *q = 0; return a + *p; }" "--passes=mem2reg,gvn -fno-strict-aliasing"
+assert_ir "function f() -> i32 {
+bb0:
+  %7: i32 = call @g(4)
+  ret 3
+}" "int f() { int a[2]; a[0] = 3; a[1] = 4; g(a[1]); return a[0]; }" "--passes=mem2reg,gvn,dce"
+assert_ir "function f(%0: ptr, %1: ptr, %2: i32) -> i32 {
+bb0:
+  jmp bb4
+bb1:
+  %34: i32 = phi [%30, bb2], [0, bb5]
+  %33: i32 = phi [%19, bb2], [0, bb5]
+  %12: i32 = phi [%37, bb2], [%36, bb5]
+  jmp bb2
+bb2:
+  %19: i32 = add %33, %18
+  %24: iptr = sext i32 %34
+  %25: iptr = mul %24, 8
+  %26: ptr = add %1, %25
+  store ptr %26, 0
+  %30: i32 = add %34, 1
+  %37: i32 = cmp slt i32 %30, %2
+  br %37, bb1, bb3
+bb3:
+  %35: i32 = phi [0, bb4], [%19, bb2]
+  ret %35
+bb4:
+  %36: i32 = cmp slt i32 0, %2
+  br %36, bb5, bb3
+bb5:
+  %18: i32 = load i32 %0
+  jmp bb1
+}" "int f(int *p, int **q, int n) { int s = 0; int i; for (i = 0; i < n; i = i + 1) { s = s + *p; q[i] = 0; } return s; }" "--passes=mem2reg,rotate,licm -fstrict-aliasing"
+assert 14 "int f(int *p, int *q) { int a = *p; *q = 9; return a + *p; } int main() { int x = 5; return f(&x, &x); }" "-O2 --verify-ir"
+assert 84 "int g(int *p) { *p = *p + 1; return 0; } int main() { int a[2]; int b[2]; a[0] = 3; b[0] = 4; g(b); int r = a[0] + b[0]; g(a); return r * 10 + a[0]; }" "-O2 --verify-ir"
+assert 35 "int f(int *p, int **q, int n) { int s = 0; int i; for (i = 0; i < n; i = i + 1) { s = s + *p; q[i] = 0; } return s; } int main() { int x = 7; int *a[5]; return f(&x, a, 5); }" "-O2 --verify-ir"
+assert 3 "int f(int *p, int *q, int n) { int s = 0; int i; for (i = 0; i < n; i = i + 1) { s = s + *p; q[i] = i; } return s; } int main() { int a[5]; a[2] = 9; return f(a + 3, a, 5); }" "-O2 --verify-ir"
+assert 195 "int g() { return 0; } int main() { char *s = \"abc\"; int a = s[0]; g(); return a + s[1]; }" "-O2 --verify-ir"
+
+# 以降の試験で共通に使うプログラム
+# 可変長配列
+vla_sum="int f(int n) { int a[n]; int i; for (i = 0; i < n; i = i + 1) a[i] = i; int s = 0; for (i = 0; i < n; i = i + 1) s = s + a[i]; return s; } int main() { int t = 0; int k; for (k = 1; k < 30; k = k + 1) t = t + f(k); return t / 10; }"
+# 相互再帰の末尾呼び出し
+mutual_recursion="__attribute__((noinline)) int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); } __attribute__((noinline)) int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); } int main() { return is_even(1000000) * 10 + is_odd(777777); }"
+# グローバル変数、.bss の配列と static 関数
+globals="int g; char s[4]; static int h(int x) { return x * 3; } int main() { g = 5; s[1] = 7; return h(g) + s[1] * 3 + 6; }"
+
+# 末尾呼び出し (-O2 以上、深い再帰でもスタックを使い切らない)
+assert_ir "function sum(%0: i32, %1: i32) -> i32 {
+bb0:
+  jmp bb3
+bb1:
+  ret %19
+bb2:
+  %11: i32 = sub %18, 1
+  %16: i32 = add %19, %18
+  jmp bb3
+bb3:
+  %18: i32 = phi [%0, bb0], [%11, bb2]
+  %19: i32 = phi [%1, bb0], [%16, bb2]
+  %6: i32 = cmp eq i32 %18, 0
+  br %6, bb1, bb2
+}" "int sum(int n, int acc) { if (n == 0) return acc; return sum(n - 1, acc + n); }" "--passes=mem2reg,tailcall"
+assert_asm "  .intel_syntax noprefix
+  .text
+  .global f
+f:
+  push rbp
+  mov rbp, rsp
+  mov r10, rdi
+  mov r11d, r10d
+  shl r11d, 1
+  movsxd rdi, r11d
+  mov eax, 0
+  mov rsp, rbp
+  pop rbp
+  jmp g" "int f(int x) { return g(x * 2); }" "-O2"
+assert 150 "int sum(int n, int acc) { if (n == 0) return acc; return sum(n - 1, acc + n); } int h(int x) { if (x > 3) return g(x + 1, x); return x; } int g(int x, int y) { return x * 2 - y; } int main() { return sum(100000, 0) / 100000 + h(10); }" "-O2 --verify-ir"
+assert 11 "$mutual_recursion" "-O2 --verify-ir"
+assert 100 "void v(int *p, int n) { if (n) { *p = *p + 1; v(p, n - 1); } } int main() { int x = 0; v(&x, 1000000); return x / 10000; }" "-O1 -foptimize-sibling-calls --verify-ir"
+assert 5 "__attribute__((noinline)) int f(int *p) { return *p; } int g() { int x = 5; return f(&x); } int main() { return g(); }" "-O2 --verify-ir"
+assert 14 "__attribute__((noinline)) int g(int x) { return x * 3; } __attribute__((noinline)) int g2(int x, int y) { return x - y; } int k(int a, int b) { int c = g(a); int d = g(b); return g2(c + d, a + b); } int main() { int s = 0; int i; for (i = 0; i < 10; i = i + 1) s = s + k(i, 2 * i); return s; }" "-O2 --verify-ir"
+assert 55 "int fib(int n, int a, int b) { if (n == 0) return a; return fib(n - 1, b, a + b); } int main() { return fib(10, 0, 1); }" "-O2 -fno-optimize-sibling-calls"
+
 assert 55 "
 int main() {
 	int x = 21;
@@ -651,6 +1516,430 @@ int main() {
 print 12 "int main() { return print(\"hello, world\"); }" # this should entail output
 
 
+# AArch64 (--target=aarch64-linux-gnu)
+assert_asm "  .bss
+  .global g
+g:
+  .zero 4
+
+  .text
+  .p2align 2
+  .global f
+f:
+  stp x29, x30, [sp, #-16]!
+  mov x29, sp
+  mov x10, x0
+  mov x11, x1
+.L.f.bb0:
+  mov w17, #3
+  sdiv w12, w10, w17
+  cmp w12, w11
+  cset w13, hi
+  cbz w13, .L.f.bb2
+.L.f.bb1:
+  sub w11, w10, #1
+  mov x0, x11
+  mov sp, x29
+  ldp x29, x30, [sp], #16
+  ret
+.L.f.bb2:
+  adrp x10, g
+  add x10, x10, :lo12:g
+  ldr w11, [x10]
+  add w10, w11, #7
+  mov x0, x10
+  mov sp, x29
+  ldp x29, x30, [sp], #16
+  ret" "int g; int f(int a, unsigned b) { if (a / 3 > b) return a - 1; return g + 7; }" "--target=aarch64-linux-gnu -O1"
+assert_aarch64 42 "int main() { return 42; }"
+assert_aarch64 87 "int main() { int a[2000]; int i; for (i = 0; i < 2000; i = i + 1) a[i] = i * 3; int s = 0; for (i = 0; i < 2000; i = i + 7) s = s + a[i]; return s / 1000; }"
+assert_aarch64 165 "int f(int a, int b, int c, int d, int e, int g) { int x1 = a*b; int x2 = b*c; int x3 = c*d; int x4 = d*e; int x5 = e*g; int x6 = a+g; int x7 = b-e; int x8 = c*c; int x9 = d+d; int x10 = a-b; int x11 = x1+x2; int x12 = x3*x4; int x13 = x5-x6; int x14 = x7*x8; int x15=x9+x10; int x16 = x11 - x12; int x17 = x1*x16; int x18 = x2 + x17; int x19 = x3 + x18 + 1000000; return x1+x2+x3+x4+x5+x6+x7+x8+x9+x10+x11+x12+x13+x14+x15+x16+x17+x18+x19; } int main() { return f(1,2,3,4,5,6) + f(7, -3, 2, 100000, 9, 1) / 1000; }" "-O1"
+assert_aarch64 130 "int main() { unsigned a = 0 - 1; int b = -100000; char c = 200; unsigned char d = 200; int r = 0; if (a > 5) r = r + 1; if (b < -99999) r = r + 2; if (c < 0) r = r + 4; if (d > 100) r = r + 8; return r + (a / 65536) / 4096 + b / -1000; }"
+assert_aarch64 150 "$vla_sum" "-O2"
+assert_aarch64 10 "struct S { int a; char b; int *p; }; int main() { struct S s; struct S t; int v = 9; s.a = 3; s.b = -2; s.p = &v; t = s; return t.a + t.b + *t.p; }"
+assert_aarch64 74 "int g; int h(int *p) { *p = *p + 3; return *p; } int main() { g = 4; h(&g); int x = 1; h(&x); return g * 10 + x; }" "-O1"
+assert_aarch64 11 "$mutual_recursion" "-O2"
+assert_aarch64 12 "int main() { return printf(\"hello, world\\n\") - 1; }"
+
+# RISC-V (--target=riscv64-linux-gnu)
+assert_asm "  .option nopic
+  .bss
+  .global g
+g:
+  .zero 4
+
+  .text
+  .p2align 1
+  .global f
+f:
+  addi sp, sp, -16
+  sd ra, 8(sp)
+  sd s0, 0(sp)
+  addi s0, sp, 16
+  mv t3, a0
+  mv t4, a1
+.L.f.bb0:
+  li t2, 3
+  divw t5, t3, t2
+  sltu t6, t4, t5
+  beqz t6, .L.f.bb2
+.L.f.bb1:
+  addiw t4, t3, -1
+  mv a0, t4
+  addi sp, s0, -16
+  ld ra, 8(sp)
+  ld s0, 0(sp)
+  addi sp, sp, 16
+  ret
+.L.f.bb2:
+  lla t3, g
+  lw t4, 0(t3)
+  addiw t3, t4, 7
+  mv a0, t3
+  addi sp, s0, -16
+  ld ra, 8(sp)
+  ld s0, 0(sp)
+  addi sp, sp, 16
+  ret" "int g; int f(int a, unsigned b) { if (a / 3 > b) return a - 1; return g + 7; }" "--target=riscv64-linux-gnu -O1"
+assert_riscv64 42 "int main() { return 42; }"
+assert_riscv64 87 "int main() { int a[2000]; int i; for (i = 0; i < 2000; i = i + 1) a[i] = i * 3; int s = 0; for (i = 0; i < 2000; i = i + 7) s = s + a[i]; return s / 1000; }"
+assert_riscv64 141 "int main() { int a[100000]; a[99999] = 7; a[0] = 3; unsigned u = 3000000; u = u * 1000; int x = 305419896; return a[99999] + a[0] + (u > 2000000000) + (u / 2 < 1600000000) * 2 + (x - 305419000); }" "-O1"
+assert_riscv64 181 "int main() { unsigned a = 0 - 1; int b = -100000; char c = 200; unsigned char d = 200; int r = 0; if (a > 5) r = r + 1; if (b < -99999) r = r + 2; if (c < 0) r = r + 4; if (d > 100) r = r + 8; if (a >= 2000000000) r = r + 16; if (b <= -100000) r = r + 32; return r + (a / 65536) / 4096 + b / -1000 + a / 268435456 + (b / 8) / 1000; }"
+assert_riscv64 165 "int f(int a, int b, int c, int d, int e, int g) { int x1 = a*b; int x2 = b*c; int x3 = c*d; int x4 = d*e; int x5 = e*g; int x6 = a+g; int x7 = b-e; int x8 = c*c; int x9 = d+d; int x10 = a-b; int x11 = x1+x2; int x12 = x3*x4; int x13 = x5-x6; int x14 = x7*x8; int x15=x9+x10; int x16 = x11 - x12; int x17 = x1*x16; int x18 = x2 + x17; int x19 = x3 + x18 + 1000000; return x1+x2+x3+x4+x5+x6+x7+x8+x9+x10+x11+x12+x13+x14+x15+x16+x17+x18+x19; } int main() { return f(1,2,3,4,5,6) + f(7, -3, 2, 100000, 9, 1) / 1000; }" "-O1"
+assert_riscv64 150 "$vla_sum" "-O2"
+assert_riscv64 10 "struct S { int a; char b; int *p; }; int main() { struct S s; struct S t; int v = 9; s.a = 3; s.b = -2; s.p = &v; t = s; return t.a + t.b + *t.p; }"
+assert_riscv64 11 "$mutual_recursion" "-O2"
+# 条件分岐の届かない大きな関数
+body=""
+for i in $(seq 1 300); do
+  body="$body if (s > $((i * 7))) s = s - $i; else s = s + $((i * 3));"
+done
+assert_riscv64 224 "int main() { int s = 0; int i; for (i = 0; i < 50; i = i + 1) { $body } return s; }"
+assert_riscv64 12 "int main() { return printf(\"hello, world\\n\") - 1; }"
+
+# WebAssembly (--target=wasm32)
+assert_asm "(module
+  (memory (export \"memory\") 17)
+  (global \$__stack_pointer (mut i32) (i32.const 1114112))
+  (func \$f (export \"f\") (param \$r0 i32) (result i32)
+    (local \$r14 i32)
+    (local \$r18 i32)
+    (local \$r21 i32)
+    (local \$r22 i32)
+    (local \$r23 i32)
+    (local \$r24 i32)
+    (local \$r25 i32)
+    (local \$r26 i32)
+    (local \$r27 i32)
+    (local \$r28 i32)
+    (local \$r29 i32)
+    (local \$r30 i32)
+    (local \$r31 i32)
+    block
+      i32.const 0
+      local.get \$r0
+      i32.lt_s
+      local.set \$r24
+      i32.const 0
+      local.set \$r30
+      local.get \$r30
+      local.set \$r23
+      local.get \$r24
+      if
+        i32.const 0
+        local.set \$r28
+        i32.const 0
+        local.set \$r29
+        local.get \$r28
+        local.set \$r22
+        local.get \$r29
+        local.set \$r21
+        loop
+          local.get \$r21
+          local.get \$r22
+          i32.add
+          local.set \$r14
+          local.get \$r22
+          i32.const 1
+          i32.add
+          local.set \$r18
+          local.get \$r18
+          local.get \$r0
+          i32.lt_s
+          local.set \$r25
+          local.get \$r18
+          local.set \$r26
+          local.get \$r14
+          local.set \$r27
+          local.get \$r26
+          local.set \$r22
+          local.get \$r27
+          local.set \$r21
+          local.get \$r25
+          if
+            br 1
+          else
+            local.get \$r14
+            local.set \$r31
+            local.get \$r31
+            local.set \$r23
+            br 3
+          end
+        end
+      else
+        br 1
+      end
+    end
+    local.get \$r23
+    return
+    unreachable
+  )
+)" "int f(int n) { int s = 0; int i; for (i = 0; i < n; i = i + 1) s = s + i; return s; }" "--target=wasm32 -O1"
+assert_wasm 42 "int main() { return 42; }"
+assert_wasm 87 "int main() { int a[2000]; int i; for (i = 0; i < 2000; i = i + 1) a[i] = i * 3; int s = 0; for (i = 0; i < 2000; i = i + 7) s = s + a[i]; return s / 1000; }"
+assert_wasm 141 "int main() { int a[100000]; a[99999] = 7; a[0] = 3; unsigned u = 3000000; u = u * 1000; int x = 305419896; return a[99999] + a[0] + (u > 2000000000) + (u / 2 < 1600000000) * 2 + (x - 305419000); }" "-O1"
+assert_wasm 181 "int main() { unsigned a = 0 - 1; int b = -100000; char c = 200; unsigned char d = 200; int r = 0; if (a > 5) r = r + 1; if (b < -99999) r = r + 2; if (c < 0) r = r + 4; if (d > 100) r = r + 8; if (a >= 2000000000) r = r + 16; if (b <= -100000) r = r + 32; return r + (a / 65536) / 4096 + b / -1000 + a / 268435456 + (b / 8) / 1000; }"
+assert_wasm 150 "$vla_sum" "-O2"
+assert_wasm 10 "struct S { int a; char b; int *p; }; int main() { struct S s; struct S t; int v = 9; s.a = 3; s.b = -2; s.p = &v; t = s; return t.a + t.b + *t.p; }"
+assert_wasm 35 "int x; int *p; int main() { char *s = \"hello\"; int i = 0; int n = 0; x = 3; p = &x; while (s[i]) { n = n + s[i] - 100; i = i + 1; } return *p + n; }"
+assert_wasm 11 "$mutual_recursion" "-O2"
+assert_wasm 12 "int main() { return printf(\"hello, world\\n\") - 1; }"
+assert_wasm 48 "struct S { char c; int *p; }; int main() { return sizeof(int*) * 10 + sizeof(struct S); }"
+assert_wasm 9 "int main() { int a[4]; int *p = &a[1]; int *q = &a[3]; a[3] = 7; return (q - p) + *q + (&a[2] == p + 1) - 1; }"
+
+# i386 (-m32、引数はスタックで渡す)
+assert_asm "  .intel_syntax noprefix
+  .text
+  .global sub
+sub:
+  push ebp
+  mov ebp, esp
+  sub esp, 24
+  mov DWORD PTR [ebp-4], ebx
+  mov DWORD PTR [ebp-8], esi
+  mov DWORD PTR [ebp-12], edi
+  mov ebx, DWORD PTR [ebp+8]
+  mov esi, DWORD PTR [ebp+12]
+.L.sub.bb0:
+  mov edi, ebx
+  sub edi, esi
+  mov eax, edi
+  mov ebx, DWORD PTR [ebp-4]
+  mov esi, DWORD PTR [ebp-8]
+  mov edi, DWORD PTR [ebp-12]
+  mov esp, ebp
+  pop ebp
+  ret
+  .text
+  .global main
+main:
+  push ebp
+  mov ebp, esp
+  sub esp, 8
+  mov DWORD PTR [ebp-4], ebx
+.L.main.bb0:
+  sub esp, 16
+  mov DWORD PTR [esp], 50
+  mov DWORD PTR [esp+4], 8
+  call sub
+  add esp, 16
+  mov ebx, eax
+  mov eax, ebx
+  mov ebx, DWORD PTR [ebp-4]
+  mov esp, ebp
+  pop ebp
+  ret" "__attribute__((noinline)) int sub(int a, int b) { return a - b; } int main() { return sub(50, 8); }" "-m32 -O1"
+assert_i386 42 "int main() { return 42; }"
+assert_i386 42 "struct N { int v; struct N *next; }; int main() { int *p; struct N n; return sizeof(p) * 10 + sizeof(n) - 6; }"
+assert_i386 42 "int f(char a, int b, char *c, int d, int e, int g) { return a + b + c[1] + d * e - g; } int main() { return f(2, 3, \"xyz\", 4, 5, 8) - 96; }"
+assert_i386 87 "int main() { int a[2000]; int i; for (i = 0; i < 2000; i = i + 1) a[i] = i * 3; int s = 0; for (i = 0; i < 2000; i = i + 7) s = s + a[i]; return s / 1000; }"
+assert_i386 181 "int main() { unsigned a = 0 - 1; int b = -100000; char c = 200; unsigned char d = 200; int r = 0; if (a > 5) r = r + 1; if (b < -99999) r = r + 2; if (c < 0) r = r + 4; if (d > 100) r = r + 8; if (a >= 2000000000) r = r + 16; if (b <= -100000) r = r + 32; return r + (a / 65536) / 4096 + b / -1000 + a / 268435456 + (b / 8) / 1000; }"
+assert_i386 165 "int f(int a, int b, int c, int d, int e, int g) { int x1 = a*b; int x2 = b*c; int x3 = c*d; int x4 = d*e; int x5 = e*g; int x6 = a+g; int x7 = b-e; int x8 = c*c; int x9 = d+d; int x10 = a-b; int x11 = x1+x2; int x12 = x3*x4; int x13 = x5-x6; int x14 = x7*x8; int x15=x9+x10; int x16 = x11 - x12; int x17 = x1*x16; int x18 = x2 + x17; int x19 = x3 + x18 + 1000000; return x1+x2+x3+x4+x5+x6+x7+x8+x9+x10+x11+x12+x13+x14+x15+x16+x17+x18+x19; } int main() { return f(1,2,3,4,5,6) + f(7, -3, 2, 100000, 9, 1) / 1000; }" "-O1"
+assert_i386 150 "$vla_sum" "-O2"
+assert_i386 10 "struct S { int a; char b; int *p; }; int main() { struct S s; struct S t; int v = 9; s.a = 3; s.b = -2; s.p = &v; t = s; return t.a + t.b + *t.p; }"
+assert_i386 11 "$mutual_recursion" "-O2"
+assert_i386 12 "int main() { return printf(\"hello, world\\n\") - 1; }"
+
+# AT&T 記法 (-masm=att)
+assert_asm "  .bss
+  .global g
+g:
+  .zero 4
+
+  .text
+  .global f
+f:
+  pushq %rbp
+  movq %rsp, %rbp
+  subq \$32, %rsp
+  movq %rbx, -16(%rbp)
+  movq %r12, -24(%rbp)
+  movq %rdi, %r10
+  movq %rsi, %r11
+  leaq -8(%rbp), %rbx
+  movq %rbx, %r12
+  addq \$1, %r12
+  movb %r10b, (%r12)
+  movl %r10d, %eax
+  movl \$3, %edi
+  cltd
+  idivl %edi
+  cmpl %r11d, %eax
+  jbe .L.f.bb2
+  movl %r10d, %r11d
+  subl \$1, %r11d
+  leaq -8(%rbp), %r10
+  movq %r10, %rbx
+  addq \$1, %rbx
+  movsbl (%rbx), %r10d
+  movl %r11d, %ebx
+  addl %r10d, %ebx
+  movq %rbx, %rax
+  movq -16(%rbp), %rbx
+  movq -24(%rbp), %r12
+  movq %rbp, %rsp
+  popq %rbp
+  ret
+.L.f.bb2:
+  leaq g(%rip), %r10
+  movl (%r10), %r10d
+  imull \$7, %r10d, %r10d
+  movq %r10, %rax
+  movq -16(%rbp), %rbx
+  movq -24(%rbp), %r12
+  movq %rbp, %rsp
+  popq %rbp
+  ret" "int g; int f(int a, unsigned b) { char c[3]; c[1] = a; if (a / 3 > b) return a - 1 + c[1]; return g * 7; }" "-masm=att -O1"
+assert 27 "int g; int f(int a, unsigned b) { char c[3]; c[1] = a; if (a / 3 > b) return a - 1 + c[1]; return g * 7; } int main() { g = 6; return f(5, 9) + f(-3, 100) - f(10, 1) + 11; }" "-masm=att"
+assert 150 "$vla_sum" "-masm=att -O2"
+assert 11 "$mutual_recursion" "-masm=att -O2"
+assert_i386 181 "int main() { unsigned a = 0 - 1; int b = -100000; char c = 200; unsigned char d = 200; int r = 0; if (a > 5) r = r + 1; if (b < -99999) r = r + 2; if (c < 0) r = r + 4; if (d > 100) r = r + 8; if (a >= 2000000000) r = r + 16; if (b <= -100000) r = r + 32; return r + (a / 65536) / 4096 + b / -1000 + a / 268435456 + (b / 8) / 1000; }" "-masm=att"
+
+# オブジェクトファイル (-c)
+assert_obj 12 "int main() { return printf(\"hello, %s\\n\", \"world\") - 1; }"
+assert_obj 42 "$globals"
+assert_obj 42 "$globals" "-O2"
+assert_obj 181 "int main() { unsigned a = 0 - 1; int b = -100000; char c = 200; unsigned char d = 200; int r = 0; if (a > 5) r = r + 1; if (b < -99999) r = r + 2; if (c < 0) r = r + 4; if (d > 100) r = r + 8; if (a >= 2000000000) r = r + 16; if (b <= -100000) r = r + 32; return r + (a / 65536) / 4096 + b / -1000 + a / 268435456 + (b / 8) / 1000; }" "-O1"
+assert_obj 150 "$vla_sum" "-O2"
+assert_obj 11 "$mutual_recursion" "-O2"
+# static 関数はローカルなシンボルにして、文字列リテラルは .rodata から参照する
+local_symbols="static char *name() { return \"abcdef\"; } int count; static int bump(int n) { count = count + n; return count; } int main() { char *s = name(); bump(s[0] - 90); return bump(s[5] - 100) + 3; }"
+assert_obj 12 "$local_symbols"
+assert_obj 12 "$local_symbols" "-O2"
+assert_obj 224 "int main() { int s = 0; int i; for (i = 0; i < 50; i = i + 1) { $body } return s; }"
+assert_obj 224 "int main() { int s = 0; int i; for (i = 0; i < 50; i = i + 1) { $body } return s; }" "-O2"
+echo "int fibonacchi(int n) { if (n <= 1) return n; return fibonacchi(n - 1) + fibonacchi(n - 2); }" > tmp_fib.c
+assert_obj 55 "int main() { return fibonacchi(10); }" "" tmp_fib.c
+assert_error "int main() { return 0; }" "-c --target=aarch64-linux-gnu"
+
+# 静的リンク (-static)
+assert_static 42 "$globals"
+assert_static 150 "$vla_sum" "-O2"
+assert_static 11 "$mutual_recursion" "-O2"
+assert_static 115 "int main() { char *s = \"hello\"; return s[1] + s[4] - 100 + 3 * (s[5] + 1); }"
+assert_static 3 "int main(int argc, char **argv) { return argc + (argv[0][0] != 0) + (argv[1] == 0); }"
+cargo run -- -c "int add3(int a, int b, int c) { return a + b + c; } int counter; int next() { counter = counter + 1; return counter; }" > tmp_lib.o
+assert_static 10 "int main() { int a = next(); int b = next(); int c = next(); return add3(a, b, c) + next(); }" "tmp_lib.o"
+# 両方のオブジェクトに .bss がある
+assert_static 25 "int total; int buf[8]; int main() { buf[7] = next(); total = next() + 20; return total + buf[7] + next() - 1; }" "tmp_lib.o"
+# 同じ名前の static 関数は別のシンボルで、ライブラリから実行ファイル側の関数も呼べる
+cargo run -- -c "static int helper() { return 1; } int from_lib() { return helper() + twice(4); }" > tmp_lib2.o
+assert_static 39 "static int helper() { return 30; } int twice(int x) { return x * 2; } int main() { return helper() + from_lib(); }" "tmp_lib.o tmp_lib2.o"
+assert_error "int main() { return printf(\"x\"); }" "-static"
+assert_error "int add3(int a, int b, int c) { return 0; } int main() { return 0; }" "-static tmp_lib.o"
+
+# JIT (--run)
+assert_run 42 "$globals"
+assert_run 12 "int main() { return printf(\"hello, %s\\n\", \"world\") - 1; }"
+assert_run 150 "$vla_sum" "-O2"
+assert_run 11 "$mutual_recursion" "-O2"
+assert_run 224 "int main() { int s = 0; int i; for (i = 0; i < 50; i = i + 1) { $body } return s; }" "-O1"
+assert_run 13 "int main(int argc, char **argv) { return argc * 5 + strlen(argv[1]) + atoi(argv[2]); }" "" "abc -5"
+assert_run 3 "int main() { return abs(-7) - 4; }"
+assert_run 13 "int main(int argc, char **argv) { char buf[8]; memset(buf, 0, 8); memcpy(buf, argv[1], 3); return (strcmp(buf, \"abc\") == 0) * 10 + buf[2] - 96; }" "" "abcdef"
+assert_error "int main() { return no_such_function(); }" "--run"
+
+# 位置独立コード (-fPIC, -fno-pic) とシンボルの可視性
+assert_asm "  .intel_syntax noprefix
+  .bss
+  .global g
+g:
+  .zero 4
+
+  .bss
+  .global h
+  .hidden h
+h:
+  .zero 4
+
+  .text
+  .global f
+f:
+  push rbp
+  mov rbp, rsp
+  mov r10, rdi
+  mov r11, QWORD PTR g@GOTPCREL[rip]
+  mov DWORD PTR [r11], r10d
+  lea r11, h[rip]
+  mov DWORD PTR [r11], r10d
+  mov r11d, r10d
+  add r11d, r10d
+  movsxd rdi, r11d
+  mov eax, 0
+  call put@PLT
+  mov rsp, rbp
+  pop rbp
+  ret" "int g; __attribute__((visibility(\"hidden\"))) int h; int f(int x) { g = x; h = x; return put(g + h); }" "-fPIC -O1"
+assert_asm "  .intel_syntax noprefix
+  .bss
+  .global g
+g:
+  .zero 4
+
+  .text
+  .global f
+f:
+  push rbp
+  mov rbp, rsp
+  mov r10, rdi
+  lea r11, [g]
+  mov DWORD PTR [r11], r10d
+  mov rax, r10
+  mov rsp, rbp
+  pop rbp
+  ret" "int g; int f(int x) { g = x; return g; }" "-fno-pic -O1"
+assert_ir "hidden global @g: size 4
+
+function f() -> i32 {
+bb0:
+  ret 0
+bb1:
+  ret 0
+}" "int g; __attribute__((visibility(\"default\"))) int f() { return 0; }" "-fvisibility=hidden"
+assert_obj 42 "$globals" "-fPIC"
+assert_obj 11 "$mutual_recursion" "-fPIC -O2"
+assert_obj 12 "int main() { return printf(\"hello, %s\\n\", \"world\") - 1; }" "-fPIC -O2"
+assert_obj 42 "$globals" "-fno-pic" "-no-pie"
+assert_obj 115 "int main() { char *s = \"hello\"; return s[1] + s[4] - 100 + 3 * (s[5] + 1); }" "-fno-pic -O2" "-no-pie"
+assert_static 42 "$globals" "-fPIC"
+assert_static 115 "int main() { char *s = \"hello\"; return s[1] + s[4] - 100 + 3 * (s[5] + 1); }" "-fno-pic"
+assert_run 42 "$globals" "-fPIC"
+assert_run 12 "int main() { return printf(\"hello, %s\\n\", \"world\") - 1; }" "-fno-pic"
+# GOT を経由するグローバル変数と、経由しない hidden な変数や static 関数
+pic_globals="int a[4]; __attribute__((visibility(\"hidden\"))) int b; static int twice(int x) { return x * 2; } __attribute__((visibility(\"protected\"))) int c; int main() { a[2] = 5; b = twice(a[2]); c = b + 1; return a[2] + b + c - 5; }"
+assert_obj 21 "$pic_globals" "-fPIC"
+assert_static 21 "$pic_globals" "-fPIC"
+assert_run 21 "$pic_globals" "-fPIC"
+# 実行ファイルの定義がライブラリの中の参照を置き換える (hidden と protected では置き換えない)
+assert_shared 11 "int counter; int bump() { counter = counter + 10; return counter; }" "" "int counter; int main() { counter = 1; return bump(); }"
+assert_shared 10 "__attribute__((visibility(\"hidden\"))) int counter; int bump() { counter = counter + 10; return counter; }" "" "int counter; int main() { counter = 1; return bump(); }"
+assert_shared 40 "int base() { return 1; } int get() { return base() * 10; }" "-O2" "int base() { return 4; } int main() { return get(); }"
+assert_shared 10 "__attribute__((visibility(\"protected\"))) int base() { return 1; } int get() { return base() * 10; }" "-O2" "int base() { return 4; } int main() { return get(); }"
+assert_shared 15 "int base() { return 1; } __attribute__((visibility(\"default\"))) int get() { return base() * 10 + 5; }" "-fvisibility=hidden" "int base() { return 4; } int main() { return get(); }"
+assert_error "int main() { return 0; }" "-fPIC --target=aarch64-linux-gnu"
+assert_error "int main() { return 0; }" "-fvisibility=internal"
+assert_error "__attribute__((visibility(\"local\"))) int main() { return 0; }"
+
 rm -f tmp* *.s *.c *.o 
 
 echo OK
@@ -0,0 +1,59 @@
+// rustcc --target=wasm32 --emit-wasm が出力したモジュールを node で実行する
+// main の戻り値を終了コードにする。インポートする関数は下の env に書いたものだけ使える
+// 使い方: node wasm_run.mjs tmp.wasm
+import fs from "fs";
+
+let memory;
+
+// ポインタは線形メモリの i32 のアドレスで渡される
+function cstring(ptr) {
+  const bytes = new Uint8Array(memory.buffer);
+  let end = Number(ptr);
+  while (bytes[end] !== 0) end++;
+  return Buffer.from(bytes.subarray(Number(ptr), end)).toString("latin1");
+}
+
+function write(str) {
+  fs.writeSync(1, Buffer.from(str, "latin1"));
+  return str.length;
+}
+
+function format(fmt, args) {
+  let i = 0;
+  return fmt.replace(/%([%dcsux])/g, (_, conv) => {
+    if (conv === "%") return "%";
+    const arg = args[i++];
+    switch (conv) {
+      case "d":
+        return String(Number(arg) | 0);
+      case "u":
+        return String(Number(arg) >>> 0);
+      case "x":
+        return (Number(arg) >>> 0).toString(16);
+      case "c":
+        return String.fromCharCode(Number(arg) & 0xff);
+      case "s":
+        return cstring(arg);
+    }
+  });
+}
+
+const env = {
+  printf: (fmt, ...args) => write(format(cstring(fmt), args)),
+  puts: (str) => write(cstring(str) + "\n"),
+  putchar: (c) => {
+    write(String.fromCharCode(c & 0xff));
+    return c;
+  },
+};
+
+const module = new WebAssembly.Module(fs.readFileSync(process.argv[2]));
+for (const imp of WebAssembly.Module.imports(module)) {
+  if (!(imp.name in env)) {
+    console.error(`未定義の関数です: ${imp.name}`);
+    process.exit(127);
+  }
+}
+const instance = new WebAssembly.Instance(module, { env });
+memory = instance.exports.memory;
+process.exit(instance.exports.main() & 0xff);